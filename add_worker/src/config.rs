@@ -1,4 +1,6 @@
 use anyhow::Context;
+use connections::QueueBackend;
+use protocols::marginfi::ACCOUNT_DISABLED;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
@@ -6,6 +8,7 @@ pub struct Config {
   pub(crate) ws_url: String,
   pub(crate) redis_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
   pub(crate) capacity: usize,
   pub(crate) accounts_batch_size: usize,
   pub(crate) min_asset_value: Option<f64>,
@@ -15,7 +18,11 @@ pub struct Config {
   pub(crate) min_maint_percentage: Option<f64>,
   pub(crate) max_maint_percentage: Option<f64>,
   pub(crate) min_maint: Option<f64>,
-  pub(crate) max_maint: Option<f64>
+  pub(crate) max_maint: Option<f64>,
+  /// Account flags that all have to be set, see `ACCOUNT_*`
+  pub(crate) with_flags: u64,
+  /// Account flags of which none may be set, disabled accounts by default
+  pub(crate) without_flags: u64,
 }
 
 impl Config {
@@ -25,6 +32,7 @@ impl Config {
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
     let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
     let capacity = env_usize("CAPACITY", 1).context("invalid \"CAPACITY\" value")?;
     let accounts_batch_size = env_usize("ACCOUNTS_BATCH_SIZE", 1000).context("invalid \"ACCOUNTS_BATCH_SIZE\" value")?;
     let min_asset_value = std::env::var("MIN_ASSET_VALUE").ok().filter(|s| !s.is_empty()).and_then(|v| v.parse::<f64>().ok());
@@ -35,11 +43,14 @@ impl Config {
     let max_maint_percentage = std::env::var("MAX_MAINT_PERCENTAGE").ok().filter(|s| !s.is_empty()).and_then(|v| v.parse::<f64>().ok());
     let min_maint = std::env::var("MIN_MAINT").ok().filter(|s| !s.is_empty()).and_then(|v| v.parse::<f64>().ok());
     let max_maint = std::env::var("MAX_MAINT").ok().filter(|s| !s.is_empty()).and_then(|v| v.parse::<f64>().ok());
+    let with_flags = env_u64("WITH_FLAGS", 0).context("invalid \"WITH_FLAGS\" value")?;
+    let without_flags = env_u64("WITHOUT_FLAGS", ACCOUNT_DISABLED).context("invalid \"WITHOUT_FLAGS\" value")?;
     let config = Config {
       http_url,
       ws_url,
       redis_url,
       pubsub_url,
+      pubsub_backend,
       capacity,
       accounts_batch_size,
       min_asset_value,
@@ -50,6 +61,8 @@ impl Config {
       max_maint_percentage,
       min_maint,
       max_maint,
+      with_flags,
+      without_flags,
     };
 
     Ok(config)
@@ -63,4 +76,13 @@ fn env_usize(name: &str, default: usize) -> Result<usize, std::num::ParseIntErro
     .map(|s| s.parse::<usize>())
     .transpose()
     .map(|opt| opt.unwrap_or(default))
+}
+
+fn env_u64(name: &str, default: u64) -> Result<u64, std::num::ParseIntError> {
+  std::env::var(name)
+    .ok()
+    .filter(|s| !s.is_empty())
+    .map(|s| s.parse::<u64>())
+    .transpose()
+    .map(|opt| opt.unwrap_or(default))
 }
\ No newline at end of file
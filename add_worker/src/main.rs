@@ -3,9 +3,9 @@ mod config;
 use std::{marker::PhantomData, sync::Arc};
 
 use config::Config;
-use connections::{PubRedis, Redis, SubRedis, queue_keys};
+use connections::{DEFAULT_BLOCK_TIMEOUT, IndexStore, PubRedis, Publisher, Redis, SubRedis, Subscriber, queue_keys};
 use fixed::types::I80F48;
-use protocols::marginfi::{AccountFilter, Marginfi, MarginfiUser};
+use protocols::{marginfi::{AccountFilter, Marginfi, MarginfiUser}, utils::AccountSource};
 use solana_pubkey::Pubkey;
 use tokio::{signal, sync::Semaphore, time::Instant};
 
@@ -34,19 +34,23 @@ async fn start(config: Config) -> anyhow::Result<()> {
     max_maint_percentage: config.max_maint_percentage,
     min_maint: config.min_maint,
     max_maint: config.max_maint,
+    with_flags: config.with_flags,
+    without_flags: config.without_flags,
   });
 
   let marginfi = Arc::new(Marginfi::new(config.http_url, config.ws_url).await?);
   let redis = Redis::new(&config.redis_url).await?;
-  let pub_redis = PubRedis::new(&config.pubsub_url).await?;
-  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?;
+  let pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
+  let consumer = hostname::get()?.to_string_lossy().into_owned();
+  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend).with_consumer(consumer);
+  sub_redis.spawn_reaper(queue_keys::ADD_QUEUE);
   println!("connection established, listening");
 
   let semaphore = Arc::new(Semaphore::new(config.capacity));
 
   loop {
     tokio::select! {
-      result = sub_redis.builder::<()>(queue_keys::ADD_QUEUE, config.accounts_batch_size).recv() => {
+      result = sub_redis.builder::<()>(queue_keys::ADD_QUEUE, config.accounts_batch_size).block(DEFAULT_BLOCK_TIMEOUT).recv() => {
         let results = match result {
           Ok(messages) => messages,
           Err(err) => {
@@ -57,7 +61,7 @@ async fn start(config: Config) -> anyhow::Result<()> {
 
         for result in &results {
           if let Err(err) = result {
-            println!("failed to accept a message: {}", err);
+            println!("dead-lettered a malformed message: {}", err);
           }
         }
 
@@ -72,11 +76,23 @@ async fn start(config: Config) -> anyhow::Result<()> {
         let pub_redis_clone = pub_redis.clone();
         let marginfi_clone = Arc::clone(&marginfi);
         let filter_clone = Arc::clone(&filter);
+        let mut sub_redis_clone = sub_redis.clone();
         tokio::spawn(async move {
           let _guard = permit.acquire().await.unwrap();
 
-          if let Err(err) = handle(&marginfi_clone, redis_clone, pub_redis_clone, accounts, &filter_clone).await {
-            println!("error removing accounts: {}", err);
+          let delivered = accounts.clone();
+          match handle(marginfi_clone.rpc_ref(), redis_clone, pub_redis_clone, accounts, &filter_clone).await {
+            Ok(()) => if let Err(err) = sub_redis_clone.ack(queue_keys::ADD_QUEUE, &delivered).await {
+              println!("failed to ack {} accounts: {}", delivered.len(), err);
+            },
+            Err(err) => {
+              println!("error adding accounts: {}", err);
+              match sub_redis_clone.fail(queue_keys::ADD_QUEUE, &delivered, &format!("{:#}", err)).await {
+                Ok(0) => {},
+                Ok(dead) => println!("dead-lettered {} accounts", dead),
+                Err(err) => println!("failed to report {} accounts: {}", delivered.len(), err),
+              }
+            },
           };
         });
       }
@@ -90,17 +106,17 @@ async fn start(config: Config) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedis, accounts: Vec<Pubkey>, filter: &AccountFilter<T>) -> anyhow::Result<()>
+async fn handle<T, S: AccountSource, I: IndexStore, P: Publisher>(source: &S, mut index: I, mut pub_redis: P, accounts: Vec<Pubkey>, filter: &AccountFilter<T>) -> anyhow::Result<()>
   where I80F48: PartialOrd<T> {
   let mut accounts_that_dont_exist = Vec::new();
-  for (exists, account) in redis.exists_multiple(&accounts).await?.into_iter().zip(accounts) {
+  for (exists, account) in index.exists_multiple(&accounts).await?.into_iter().zip(accounts) {
     if !exists {
       accounts_that_dont_exist.push(account);
     }
   }
 
   let start = Instant::now();
-  let items = check_pubkeys(marginfi, &accounts_that_dont_exist, filter).await?;
+  let items = check_pubkeys(source, &accounts_that_dont_exist, filter).await?;
   let duration = start.elapsed();
 
   let len = items.len();
@@ -108,12 +124,11 @@ async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedi
     return Ok(());
   }
 
-  let (users, pubkeys): (Vec<_>, Vec<_>) = items.into_iter().unzip();
-  let bank_accounts: Vec<_> = users
+  let accounts: Vec<(Pubkey, Vec<Pubkey>)> = items
     .iter()
-    .map(|user| user.bank_accounts().iter().map(|bank_account| &bank_account.balance.bank_pk).collect::<Vec<_>>())
+    .map(|(user, pubkey)| (**pubkey, user.bank_accounts().iter().map(|bank_account| bank_account.balance.bank_pk).collect()))
     .collect();
-  let (new_banks, amount) = redis.add_multiple(pubkeys, bank_accounts).await?;
+  let (new_banks, amount) = index.add_multiple(&accounts).await?;
 
   if amount > 0 {
     println!("* added {} accounts ({:?})", amount, duration);
@@ -128,9 +143,9 @@ async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedi
   Ok(())
 }
 
-async fn check_pubkeys<'a, T>(protocol: &Marginfi, pubkeys: &'a [Pubkey], filter: &AccountFilter<T>) -> anyhow::Result<Vec<(MarginfiUser, &'a Pubkey)>>
+async fn check_pubkeys<'a, T, S: AccountSource>(source: &S, pubkeys: &'a [Pubkey], filter: &AccountFilter<T>) -> anyhow::Result<Vec<(MarginfiUser, &'a Pubkey)>>
   where I80F48: PartialOrd<T> {
-  let users = protocol.load_users(pubkeys).await?;
+  let users = MarginfiUser::from_pubkeys(source, pubkeys).await?;
   
   let mut hits = Vec::new();
   for (result, pubkey) in users.into_iter().zip(pubkeys) {
@@ -156,4 +171,61 @@ async fn check_pubkeys<'a, T>(protocol: &Marginfi, pubkeys: &'a [Pubkey], filter
   }
 
   anyhow::Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeSet;
+
+  use connections::{MemoryIndex, MemoryQueues};
+  use protocols::{marginfi::ACCOUNT_DISABLED, test_utils::{chain, insert_user}};
+
+  use super::*;
+
+  fn filter() -> AccountFilter<I80F48> {
+    AccountFilter { without_flags: ACCOUNT_DISABLED, ..Default::default() }
+  }
+
+  #[tokio::test]
+  async fn indexes_new_accounts_that_pass_the_filter() {
+    let [known, fresh, disabled, x, y, z] = [(); 6].map(|_| Pubkey::new_unique());
+    let cache = chain(&[x, y, z]);
+    insert_user(&cache, known, 0, &[z]);
+    insert_user(&cache, fresh, 0, &[x, y]);
+    insert_user(&cache, disabled, ACCOUNT_DISABLED, &[z]);
+
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(known, vec![x])]).await.unwrap();
+    let queues = MemoryQueues::new();
+
+    handle(&cache, index.clone(), queues.publisher(), vec![known, fresh, disabled], &filter()).await.unwrap();
+
+    let snapshot = index.snapshot();
+    assert_eq!(snapshot.accounts, BTreeSet::from([known, fresh]));
+    // the known account keeps its indexed banks, updating them is ws_account_worker's job
+    assert_eq!(snapshot.account_banks[&known], BTreeSet::from([x]));
+    assert!(snapshot.check(None).is_empty());
+
+    let queued = queues.subscriber().read::<()>(queue_keys::BANK_ADD_QUEUE, 10).await.unwrap();
+    let queued: Vec<Pubkey> = queued.into_iter().map(|result| result.unwrap().0).collect();
+    assert_eq!(queued, vec![y]);
+  }
+
+  #[tokio::test]
+  async fn queues_a_new_bank_that_is_already_waiting_once() {
+    let [first, second, x] = [(); 3].map(|_| Pubkey::new_unique());
+    let cache = chain(&[x]);
+    insert_user(&cache, first, 0, &[x]);
+    insert_user(&cache, second, 0, &[x]);
+
+    let index = MemoryIndex::new();
+    let queues = MemoryQueues::new();
+    queues.publisher().publish(queue_keys::BANK_ADD_QUEUE, &[(x, ())]).await.unwrap();
+
+    handle(&cache, index.clone(), queues.publisher(), vec![first], &filter()).await.unwrap();
+    handle(&cache, index.clone(), queues.publisher(), vec![second], &filter()).await.unwrap();
+
+    assert_eq!(index.snapshot().bank_accounts[&x].len(), 2);
+    assert_eq!(queues.queued(queue_keys::BANK_ADD_QUEUE), 1);
+  }
 }
\ No newline at end of file
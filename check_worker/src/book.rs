@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+
+use fixed::types::I80F48;
+use protocols::marginfi::{MarginfiAccount, MarginfiUser, MarketState};
+use solana_pubkey::Pubkey;
+
+/// Healthy accounts whose maintenance margin is below this share of their asset value are
+/// close enough to the edge to be rechecked on a timer.
+const NEAR_MAINTENANCE_RATIO: f64 = 0.02;
+
+struct Entry {
+  slot: u64,
+  account: MarginfiAccount,
+  healthy: Option<bool>,
+}
+
+/// Outcome of `AccountBook::evaluate`.
+#[derive(Default)]
+pub(crate) struct Evaluation {
+  /// Accounts that just went below zero
+  pub(crate) crossed: Vec<Pubkey>,
+  /// Healthy accounts just above maintenance
+  pub(crate) near_maintenance: Vec<Pubkey>,
+  /// Accounts that couldn't be priced, usually because an oracle is stale
+  pub(crate) unpriced: Vec<Pubkey>,
+}
+
+/// Decoded marginfi accounts indexed by bank, with the last health verdict of each.
+#[derive(Default)]
+pub(crate) struct AccountBook {
+  accounts: HashMap<Pubkey, Entry>,
+  bank_accounts: HashMap<Pubkey, HashSet<Pubkey>>,
+}
+
+impl AccountBook {
+  pub(crate) fn len(&self) -> usize {
+    self.accounts.len()
+  }
+
+  pub(crate) fn contains(&self, pubkey: &Pubkey) -> bool {
+    self.accounts.contains_key(pubkey)
+  }
+
+  /// Stores the account unless a newer slot is already known. Returns false when the update was dropped.
+  pub(crate) fn insert(&mut self, pubkey: Pubkey, slot: u64, account: MarginfiAccount) -> bool {
+    let healthy = match self.accounts.get(&pubkey) {
+      Some(entry) if entry.slot > slot => return false,
+      Some(entry) => entry.healthy,
+      None => None,
+    };
+
+    self.unindex(&pubkey);
+    for balance in account.lending_account.get_active_balances_iter() {
+      self.bank_accounts.entry(balance.bank_pk).or_default().insert(pubkey);
+    }
+
+    self.accounts.insert(pubkey, Entry { slot, account, healthy });
+    true
+  }
+
+  pub(crate) fn accounts_by_bank(&self, bank: &Pubkey) -> Vec<Pubkey> {
+    self.bank_accounts.get(bank).map(|accounts| accounts.iter().copied().collect()).unwrap_or_default()
+  }
+
+  pub(crate) fn all_accounts(&self) -> Vec<Pubkey> {
+    self.accounts.keys().copied().collect()
+  }
+
+  pub(crate) fn banks(&self) -> Vec<Pubkey> {
+    self.bank_accounts.keys().copied().collect()
+  }
+
+  /// Forgets the last verdict of the accounts, so the next evaluation returns them if they're
+  /// underwater even when they already were.
+  pub(crate) fn reset(&mut self, pubkeys: &[Pubkey]) {
+    for pubkey in pubkeys {
+      if let Some(entry) = self.accounts.get_mut(pubkey) {
+        entry.healthy = None;
+      }
+    }
+  }
+
+  /// Recomputes maintenance from the market state. Accounts that stay underwater aren't returned
+  /// as crossed again until they recover.
+  pub(crate) fn evaluate(&mut self, market: &MarketState, pubkeys: impl IntoIterator<Item = Pubkey>) -> Evaluation {
+    let mut evaluation = Evaluation::default();
+
+    for pubkey in pubkeys {
+      let entry = match self.accounts.get_mut(&pubkey) {
+        Some(entry) => entry,
+        None => continue,
+      };
+
+      let user = match market.user_from_account(pubkey, entry.account) {
+        Ok(user) => user,
+        Err(_) => {
+          evaluation.unpriced.push(pubkey);
+          continue
+        },
+      };
+
+      let eligible = match user.eligible_for_liquidation() {
+        Ok(eligible) => eligible,
+        Err(_) => {
+          evaluation.unpriced.push(pubkey);
+          continue
+        },
+      };
+
+      if eligible && entry.healthy != Some(false) {
+        evaluation.crossed.push(pubkey);
+      } else if !eligible && near_maintenance(&user) {
+        evaluation.near_maintenance.push(pubkey);
+      }
+
+      entry.healthy = Some(!eligible);
+    }
+
+    evaluation
+  }
+
+  fn unindex(&mut self, pubkey: &Pubkey) {
+    let entry = match self.accounts.get(pubkey) {
+      Some(entry) => entry,
+      None => return,
+    };
+
+    for balance in entry.account.lending_account.get_active_balances_iter() {
+      if let Some(accounts) = self.bank_accounts.get_mut(&balance.bank_pk) {
+        accounts.remove(pubkey);
+        if accounts.is_empty() {
+          self.bank_accounts.remove(&balance.bank_pk);
+        }
+      }
+    }
+  }
+}
+
+fn near_maintenance(user: &MarginfiUser) -> bool {
+  if user.liquidation_blocker().is_some() {
+    return false;
+  }
+
+  match (user.maintenance(), user.asset_value()) {
+    (Ok(maintenance), Ok(assets)) => maintenance < assets * I80F48::from_num(NEAR_MAINTENANCE_RATIO),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use protocols::{marginfi::ACCOUNT_DISABLED, test_utils::{account, chain, user_account, weighted_bank}};
+
+  use super::*;
+
+  /// Market of a collateral and a debt bank, both at a price of one with full weights, so an
+  /// account's maintenance is its assets minus its liabilities.
+  async fn market() -> (Arc<MarketState>, Pubkey, Pubkey) {
+    let [collateral, debt] = [(); 2].map(|_| Pubkey::new_unique());
+    let source = chain(&[]);
+    source.insert(collateral, account(&weighted_bank(1.0, 1.0, 1.0)));
+    source.insert(debt, account(&weighted_bank(1.0, 1.0, 1.0)));
+
+    let market = Arc::new(MarketState::new());
+    market.track_banks(&source, &[collateral, debt]).await.unwrap();
+    (market, collateral, debt)
+  }
+
+  fn reprice(market: &Arc<MarketState>, bank_pk: Pubkey, slot: u64, price: f64) {
+    assert!(market.update(bank_pk, slot, account(&weighted_bank(price, 1.0, 1.0))).unwrap());
+  }
+
+  /// Account with `assets` tokens in `collateral` and `liabilities` tokens borrowed from `debt`.
+  fn borrower(flags: u64, collateral: Pubkey, assets: f64, debt: Pubkey, liabilities: f64) -> MarginfiAccount {
+    let mut account = user_account(flags, &[collateral, debt]);
+    account.lending_account.balances[0].asset_shares = I80F48::from_num(assets).into();
+    account.lending_account.balances[1].liability_shares = I80F48::from_num(liabilities).into();
+    account
+  }
+
+  #[tokio::test]
+  async fn reports_a_crossing_once_until_the_account_recovers() {
+    let (market, collateral, debt) = market().await;
+    let pubkey = Pubkey::new_unique();
+    let mut book = AccountBook::default();
+    book.insert(pubkey, 1, borrower(0, collateral, 100.0, debt, 90.0));
+
+    assert!(book.evaluate(&market, [pubkey]).crossed.is_empty());
+
+    reprice(&market, debt, 2, 1.5);
+    assert_eq!(book.evaluate(&market, [pubkey]).crossed, vec![pubkey]);
+    // still underwater, already reported
+    assert!(book.evaluate(&market, [pubkey]).crossed.is_empty());
+
+    reprice(&market, debt, 3, 1.0);
+    assert!(book.evaluate(&market, [pubkey]).crossed.is_empty());
+    reprice(&market, debt, 4, 1.5);
+    assert_eq!(book.evaluate(&market, [pubkey]).crossed, vec![pubkey]);
+
+    // a reset reports it again without recovering first
+    book.reset(&[pubkey]);
+    assert_eq!(book.evaluate(&market, [pubkey]).crossed, vec![pubkey]);
+  }
+
+  #[tokio::test]
+  async fn ignores_updates_older_than_the_stored_slot() {
+    let (market, collateral, debt) = market().await;
+    let other_bank = Pubkey::new_unique();
+    let pubkey = Pubkey::new_unique();
+    let mut book = AccountBook::default();
+
+    assert!(book.insert(pubkey, 10, borrower(0, collateral, 100.0, debt, 200.0)));
+    assert!(!book.insert(pubkey, 9, user_account(0, &[other_bank])));
+    assert_eq!(book.accounts_by_bank(&collateral), vec![pubkey]);
+    assert!(book.accounts_by_bank(&other_bank).is_empty());
+    assert_eq!(book.evaluate(&market, [pubkey]).crossed, vec![pubkey]);
+
+    // the same slot is taken, the verdict carries over and the banks are reindexed
+    assert!(book.insert(pubkey, 10, borrower(0, collateral, 100.0, debt, 300.0)));
+    assert!(book.evaluate(&market, [pubkey]).crossed.is_empty());
+    assert!(book.insert(pubkey, 11, user_account(0, &[other_bank])));
+    assert!(book.accounts_by_bank(&collateral).is_empty());
+    assert_eq!(book.accounts_by_bank(&other_bank), vec![pubkey]);
+    assert_eq!(book.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn flags_healthy_accounts_close_to_maintenance() {
+    let (market, collateral, debt) = market().await;
+    let [near, safe, underwater, disabled, unpriced] = [(); 5].map(|_| Pubkey::new_unique());
+    let mut book = AccountBook::default();
+    // the threshold is 2% of the 100 in assets
+    book.insert(near, 1, borrower(0, collateral, 100.0, debt, 98.5));
+    book.insert(safe, 1, borrower(0, collateral, 100.0, debt, 97.5));
+    book.insert(underwater, 1, borrower(0, collateral, 100.0, debt, 101.0));
+    book.insert(disabled, 1, borrower(ACCOUNT_DISABLED, collateral, 100.0, debt, 98.5));
+    book.insert(unpriced, 1, user_account(0, &[Pubkey::new_unique()]));
+
+    let evaluation = book.evaluate(&market, [near, safe, underwater, disabled, unpriced]);
+    assert_eq!(evaluation.near_maintenance, vec![near]);
+    assert_eq!(evaluation.crossed, vec![underwater]);
+    assert_eq!(evaluation.unpriced, vec![unpriced]);
+  }
+}
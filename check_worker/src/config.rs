@@ -1,10 +1,14 @@
 use anyhow::Context;
+use connections::{QueueBackend, QueueOrder};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
   pub(crate) http_url: String,
   pub(crate) ws_url: String,
+  pub(crate) redis_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
+  pub(crate) liquidation_order: QueueOrder,
   pub(crate) capacity: usize,
   pub(crate) accounts_batch_size: usize
 }
@@ -14,13 +18,25 @@ impl Config {
     let _ = dotenvy::dotenv();
     let http_url = std::env::var("HTTP_URL").context("\"HTTP_URL\" is required")?;
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
+    let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
+    let liquidation_order = std::env::var("LIQUIDATION_ORDER")
+      .ok()
+      .filter(|s| !s.is_empty())
+      .map(|s| s.parse::<QueueOrder>())
+      .transpose()
+      .context("invalid \"LIQUIDATION_ORDER\" value")?
+      .unwrap_or_default();
     let capacity = env_usize("CAPACITY", 1).context("invalid \"CAPACITY\" value")?;
     let accounts_batch_size = env_usize("ACCOUNTS_BATCH_SIZE", 1000).context("invalid \"ACCOUNTS_BATCH_SIZE\" value")?;
     let config = Config {
       http_url,
       ws_url,
+      redis_url,
       pubsub_url,
+      pubsub_backend,
+      liquidation_order,
       capacity,
       accounts_batch_size
     };
@@ -1,11 +1,26 @@
+mod book;
 mod config;
 
+use book::{AccountBook, Evaluation};
 use config::Config;
-use connections::{PubRedis, SubRedis, queue_keys};
-use protocols::marginfi::{Marginfi, MarginfiUser};
+use connections::{DEFAULT_BLOCK_TIMEOUT, PubRedis, Publisher, QueueOrder, Ranking, Redis, SubRedis, Subscriber, queue_keys};
+use fixed::types::I80F48;
+use protocols::marginfi::{FeeState, Marginfi, MarginfiAccount, MarginfiAccountType, MarginfiUser, MarketState};
+use protocols::utils::AccountSource;
+use serde::Deserialize;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::nonblocking::pubsub_client::PubsubClient;
 use solana_pubkey::Pubkey;
-use std::sync::Arc;
-use tokio::{signal, sync::{self, Semaphore}, time::Instant};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, RwLock}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::{signal, sync::{self, Semaphore, broadcast::error::RecvError, mpsc}, time::Instant};
+
+const DEFERRED_RETRY_SECS: u64 = 30;
+/// Healthy accounts close to maintenance are looked at again after this long.
+const NEAR_MAINTENANCE_RECHECK: Duration = Duration::from_secs(30);
+/// Accounts that couldn't be priced are looked at again once the feeds had a chance to refresh.
+const STALE_ORACLE_RECHECK: Duration = Duration::from_secs(10);
+/// Pause before reading a queue again after a failed read.
+const READ_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() {
@@ -23,27 +38,140 @@ async fn main() {
 }
 
 async fn start(config: Config) -> anyhow::Result<()> {
+  let pubsub = Arc::new(PubsubClient::new(&config.ws_url).await?);
   let marginfi = Arc::new(Marginfi::new(config.http_url, config.ws_url).await?);
-  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?;
-  let pub_redis = Arc::new(sync::Mutex::new(PubRedis::new(&config.pubsub_url).await?));
-  println!("connection established, listening");
+  let market = Arc::new(MarketState::with_subscriptions(pubsub));
+  let mut redis = Redis::new(&config.redis_url).await?;
+  let consumer = hostname::get()?.to_string_lossy().into_owned();
+  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend).with_consumer(consumer);
+  sub_redis.spawn_reaper(queue_keys::CHECK_QUEUE);
+  sub_redis.spawn_reaper(queue_keys::ACCOUNT_UPDATE_QUEUE);
+  let pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
+  pub_redis.spawn_mover(queue_keys::CHECK_QUEUE);
+  let pub_redis = Arc::new(sync::Mutex::new(pub_redis));
+  let fee_state = marginfi.get_fee_state().await?;
+
+  let confirmer = Confirmer {
+    semaphore: Arc::new(Semaphore::new(config.capacity)),
+    pub_redis,
+    order: config.liquidation_order,
+    market: Arc::clone(&market),
+    marginfi: Arc::clone(&marginfi),
+    fee_state: Arc::new(RwLock::new(fee_state)),
+    deferred: Arc::new(Mutex::new(HashSet::new())),
+    scheduled: Arc::new(Mutex::new(HashMap::new())),
+  };
+
+  let mut book = AccountBook::default();
+  let mut market_updates = market.subscribe();
+  let mut retry_interval = tokio::time::interval(Duration::from_secs(DEFERRED_RETRY_SECS));
+
+  let pubkeys = redis.get_all_accounts().await?;
+  snapshot(marginfi.rpc_ref(), &market, &mut book, &pubkeys, config.accounts_batch_size).await?;
+  redis.book_accounts(&book.all_accounts(), true).await?;
+  println!("loaded {} accounts across {} banks, listening", book.len(), book.banks().len());
+
+  let all_accounts = book.all_accounts();
+  let evaluation = book.evaluate(&market, all_accounts);
+  confirmer.dispatch(evaluation);
+
+  let market_clone = Arc::clone(&market);
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+      interval.tick().await;
+      market_clone.refresh_price_feeds();
+    }
+  });
 
-  let semaphore = Arc::new(Semaphore::new(config.capacity));
+  let mut account_updates = spawn_consumer::<(u64, MarginfiAccount)>(sub_redis.clone(), queue_keys::ACCOUNT_UPDATE_QUEUE, config.accounts_batch_size);
+  let mut checks = spawn_consumer::<()>(sub_redis.clone(), queue_keys::CHECK_QUEUE, config.accounts_batch_size);
 
   loop {
     tokio::select! {
-      result = sub_redis.builder::<()>(queue_keys::CHECK_QUEUE, config.accounts_batch_size).recv() => {
-        let results = match result {
-          Ok(messages) => messages,
-          Err(err) => {
-            println!("error while reading: {}", err);
-            continue
+      _ = retry_interval.tick() => {
+        let confirmer = confirmer.clone();
+        tokio::spawn(async move {
+          confirmer.retry_deferred().await;
+        });
+      }
+      result = market_updates.recv() => {
+        let mut banks = match result {
+          Ok(bank) => vec![bank],
+          Err(RecvError::Lagged(missed)) => {
+            println!("missed {} bank updates, re-evaluating every bank", missed);
+            book.banks()
           },
+          Err(RecvError::Closed) => break,
+        };
+
+        // coalesce whatever piled up while the previous batch was evaluated
+        while let Ok(bank) = market_updates.try_recv() {
+          banks.push(bank);
+        }
+        banks.sort();
+        banks.dedup();
+
+        let mut accounts: Vec<Pubkey> = banks.iter().flat_map(|bank| book.accounts_by_bank(bank)).collect();
+        accounts.sort();
+        accounts.dedup();
+
+        let evaluation = book.evaluate(&market, accounts);
+        confirmer.dispatch(evaluation);
+      }
+      results = account_updates.recv() => {
+        let results = match results {
+          Some(results) => results,
+          None => break,
+        };
+
+        for result in &results {
+          if let Err(err) = result {
+            println!("dead-lettered a malformed message: {}", err);
+          }
+        }
+
+        let updates: Vec<_> = results.into_iter().filter_map(Result::ok).collect();
+
+        if updates.is_empty() {
+          continue;
+        }
+
+        let banks: Vec<Pubkey> = updates
+          .iter()
+          .flat_map(|(_, (_, account))| account.lending_account.get_active_balances_iter().map(|balance| balance.bank_pk))
+          .collect();
+
+        if let Err(err) = market.track_banks(marginfi.rpc_ref(), &banks).await {
+          println!("failed to track banks: {}", err);
+        }
+
+        let delivered: Vec<Pubkey> = updates.iter().map(|(pubkey, _)| *pubkey).collect();
+        let unbooked: Vec<Pubkey> = delivered.iter().filter(|pk| !book.contains(pk)).copied().collect();
+        let changed: Vec<Pubkey> = updates
+          .into_iter()
+          .filter_map(|(pubkey, (slot, account))| book.insert(pubkey, slot, account).then_some(pubkey))
+          .collect();
+
+        // the book holds the update now, evaluation failures are retried off market updates
+        if let Err(err) = sub_redis.ack(queue_keys::ACCOUNT_UPDATE_QUEUE, &delivered).await {
+          println!("failed to ack {} updates: {}", delivered.len(), err);
+        }
+
+        book_accounts(&mut redis, &unbooked).await;
+
+        let evaluation = book.evaluate(&market, changed);
+        confirmer.dispatch(evaluation);
+      }
+      results = checks.recv() => {
+        let results = match results {
+          Some(results) => results,
+          None => break,
         };
 
         for result in &results {
           if let Err(err) = result {
-            println!("failed to accept a message: {}", err);
+            println!("dead-lettered a malformed message: {}", err);
           }
         }
 
@@ -52,17 +180,32 @@ async fn start(config: Config) -> anyhow::Result<()> {
         if accounts.is_empty() {
           continue;
         }
-        
-        let permit = semaphore.clone();
-        let marginfi_clone = Arc::clone(&marginfi);
-        let pub_redis_clone = Arc::clone(&pub_redis);
-        tokio::spawn(async move {
-          let _guard =  permit.acquire().await.unwrap();
 
-          if let Err(err) = handle(pub_redis_clone, &marginfi_clone, accounts).await {
-            println!("error liquidating accounts: {}", err);
-          };
-        });
+        let unknown: Vec<Pubkey> = accounts.iter().filter(|pk| !book.contains(pk)).copied().collect();
+        if let Err(err) = snapshot(marginfi.rpc_ref(), &market, &mut book, &unknown, config.accounts_batch_size).await {
+          // left unacked, the reaper hands them out again until they run out of deliveries
+          println!("failed to load {} accounts: {}", unknown.len(), err);
+          match sub_redis.fail(queue_keys::CHECK_QUEUE, &accounts, &format!("{:#}", err)).await {
+            Ok(0) => {},
+            Ok(dead) => println!("dead-lettered {} accounts", dead),
+            Err(err) => println!("failed to report {} accounts: {}", accounts.len(), err),
+          }
+          continue;
+        }
+
+        if let Err(err) = sub_redis.ack(queue_keys::CHECK_QUEUE, &accounts).await {
+          println!("failed to ack {} accounts: {}", accounts.len(), err);
+        }
+
+        let booked: Vec<Pubkey> = unknown.into_iter().filter(|pk| book.contains(pk)).collect();
+        book_accounts(&mut redis, &booked).await;
+
+        // a recheck we scheduled wants a verdict even for accounts already underwater
+        let promoted = confirmer.take_promoted(&accounts);
+        book.reset(&promoted);
+
+        let evaluation = book.evaluate(&market, accounts);
+        confirmer.dispatch(evaluation);
       }
       _ = signal::ctrl_c() => {
         println!("shutting down");
@@ -74,35 +217,265 @@ async fn start(config: Config) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn handle(pub_redis_mutex: Arc<sync::Mutex<PubRedis>>, marginfi: &Marginfi, accounts: Vec<Pubkey>) -> anyhow::Result<()> {
-  let start = Instant::now();
-  let hits = check_pubkeys(marginfi, &accounts).await?;
-  let duration = start.elapsed();
-    
-  println!("{} HITS OUT OF {} ({:?})", hits.len(), accounts.len(), duration);  
-  let mut pub_redis = pub_redis_mutex.lock().await;
-  let _ = pub_redis.builder::<MarginfiUser>(queue_keys::LIQUIDATION_QUEUE).items(hits.into_iter().map(|(pk, user)| (*pk, user))).send().await?;
+/// Tells ws_subscription_worker the accounts are watched here now, so oracle updates stop
+/// triggering checks of them.
+async fn book_accounts(redis: &mut Redis, accounts: &[Pubkey]) {
+  if accounts.is_empty() {
+    return;
+  }
 
-  Ok(())
+  if let Err(err) = redis.book_accounts(accounts, false).await {
+    println!("failed to mark {} accounts as booked: {}", accounts.len(), err);
+  }
+}
+
+/// Reads `queue` from its own task, so the other branches of the main loop can't cancel a read
+/// halfway through. A batch is only read once the previous one was taken off the channel.
+fn spawn_consumer<T>(mut sub_redis: SubRedis, queue: &'static str, batch_size: usize) -> mpsc::Receiver<Vec<anyhow::Result<(Pubkey, T)>>>
+where
+  T: for<'de> Deserialize<'de> + Send + 'static,
+{
+  let (tx, rx) = mpsc::channel(1);
+  tokio::spawn(async move {
+    loop {
+      let results = match sub_redis.builder::<T>(queue, batch_size).block(DEFAULT_BLOCK_TIMEOUT).recv().await {
+        Ok(results) => results,
+        Err(err) => {
+          println!("error while reading {}: {}", queue, err);
+          tokio::time::sleep(READ_RETRY_DELAY).await;
+          continue
+        },
+      };
+
+      if results.is_empty() {
+        continue;
+      }
+
+      if tx.send(results).await.is_err() {
+        break;
+      }
+    }
+  });
+
+  rx
+}
+
+/// Fetches and decodes the accounts into the book and starts tracking every bank they reference.
+/// Snapshots are stored at slot 0 so streamed updates always take precedence.
+async fn snapshot(rpc_client: &RpcClient, market: &Arc<MarketState>, book: &mut AccountBook, pubkeys: &[Pubkey], batch_size: usize) -> anyhow::Result<()> {
+  for chunk in pubkeys.chunks(batch_size.max(1)) {
+    let accounts = rpc_client.get_multiple(chunk).await?;
+
+    let mut banks = Vec::new();
+    for (pubkey, account) in chunk.iter().zip(accounts) {
+      let account = match account {
+        Some(account) => account,
+        None => continue,
+      };
+
+      let account = match MarginfiAccount::try_from_account_data(&account.data) {
+        Ok(account) => account,
+        Err(err) => {
+          println!("failed to parse account data of {}: {}", pubkey, err);
+          continue
+        },
+      };
+
+      banks.extend(account.lending_account.get_active_balances_iter().map(|balance| balance.bank_pk));
+      book.insert(*pubkey, 0, account);
+    }
+
+    market.track_banks(rpc_client, &banks).await?;
+  }
+
+  anyhow::Ok(())
+}
+
+/// Locally computed crossings are only candidates; they're refetched before anything is queued.
+/// Candidates that can't be liquidated right now, because the protocol is paused or they only owe
+/// paused banks, are held back and retried until that clears. Accounts worth another look later
+/// are scheduled back into the check queue, at most one recheck per account at a time.
+#[derive(Clone)]
+struct Confirmer {
+  semaphore: Arc<Semaphore>,
+  pub_redis: Arc<sync::Mutex<PubRedis>>,
+  order: QueueOrder,
+  market: Arc<MarketState>,
+  marginfi: Arc<Marginfi>,
+  fee_state: Arc<RwLock<FeeState>>,
+  deferred: Arc<Mutex<HashSet<Pubkey>>>,
+  /// Rechecks handed to the delayed queue and when they're due
+  scheduled: Arc<Mutex<HashMap<Pubkey, Instant>>>,
+}
+
+impl Confirmer {
+  fn dispatch(&self, evaluation: Evaluation) {
+    self.spawn(evaluation.crossed);
+    self.recheck(evaluation.near_maintenance, NEAR_MAINTENANCE_RECHECK, "near maintenance");
+    self.recheck(evaluation.unpriced, STALE_ORACLE_RECHECK, "stale oracle");
+  }
+
+  fn recheck(&self, accounts: Vec<Pubkey>, delay: Duration, reason: &'static str) {
+    let now = Instant::now();
+    let accounts: Vec<Pubkey> = {
+      let mut scheduled = self.scheduled.lock().unwrap();
+      accounts
+        .into_iter()
+        .filter(|account| {
+          if scheduled.get(account).is_some_and(|due| *due > now) {
+            return false;
+          }
+          scheduled.insert(*account, now + delay);
+          true
+        })
+        .collect()
+    };
+
+    if accounts.is_empty() {
+      return;
+    }
+
+    let pub_redis = Arc::clone(&self.pub_redis);
+    tokio::spawn(async move {
+      let items: Vec<(Pubkey, ())> = accounts.into_iter().map(|account| (account, ())).collect();
+      if let Err(err) = pub_redis.lock().await.schedule(queue_keys::CHECK_QUEUE, &items, delay, reason).await {
+        println!("failed to schedule {} rechecks: {}", items.len(), err);
+      }
+    });
+  }
+
+  /// Accounts of a check queue batch that are our own rechecks coming due, they're no longer
+  /// outstanding.
+  fn take_promoted(&self, accounts: &[Pubkey]) -> Vec<Pubkey> {
+    let now = Instant::now();
+    let mut scheduled = self.scheduled.lock().unwrap();
+    accounts
+      .iter()
+      .filter(|account| scheduled.get(account).is_some_and(|due| *due <= now) && scheduled.remove(account).is_some())
+      .copied()
+      .collect()
+  }
+
+  fn spawn(&self, candidates: Vec<Pubkey>) {
+    if candidates.is_empty() {
+      return;
+    }
+
+    let fee_state = *self.fee_state.read().unwrap();
+    if fee_state.is_paused(unix_timestamp()) {
+      println!("protocol is paused until {}, deferring {} accounts", fee_state.pause_expires_at().unwrap_or_default(), candidates.len());
+      self.deferred.lock().unwrap().extend(candidates);
+      return;
+    }
+
+    let confirmer = self.clone();
+    tokio::spawn(async move {
+      let _guard =  confirmer.semaphore.acquire().await.unwrap();
+
+      if let Err(err) = confirmer.handle(candidates).await {
+        println!("error liquidating accounts: {}", err);
+      };
+    });
+  }
+
+  /// Picks up pause changes from the fee state and gives every deferred account another go.
+  async fn retry_deferred(&self) {
+    match self.marginfi.get_fee_state().await {
+      Ok(fee_state) => *self.fee_state.write().unwrap() = fee_state,
+      Err(err) => println!("failed to refresh fee state: {}", err),
+    }
+
+    let deferred: Vec<Pubkey> = self.deferred.lock().unwrap().drain().collect();
+    self.spawn(deferred);
+  }
+
+  async fn handle(&self, accounts: Vec<Pubkey>) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let (slot, hits, unpriced) = check_pubkeys(&self.marginfi, &self.market, &accounts).await?;
+    let duration = start.elapsed();
+    self.recheck(unpriced, STALE_ORACLE_RECHECK, "stale oracle");
+
+    // hits of the local book come from cached accounts, the flags are checked again on the
+    // reloaded ones so nothing the program would refuse gets published
+    let hits: Vec<_> = hits
+      .into_iter()
+      .filter(|(pk, user)| match user.liquidation_blocker() {
+        Some(reason) => {
+          println!("{} skipped, {}", pk, reason);
+          false
+        },
+        None => true,
+      })
+      .collect();
+
+    let (hits, blocked): (Vec<_>, Vec<_>) = hits
+      .into_iter()
+      .partition(|(_, user)| user.can_repay_any_liability());
+
+    if !blocked.is_empty() {
+      println!("{} accounts only owe paused banks, deferring", blocked.len());
+      self.deferred.lock().unwrap().extend(blocked.iter().map(|(pk, _)| **pk));
+    }
+
+    println!("{} HITS OUT OF {} CANDIDATES ({:?})", hits.len(), accounts.len(), duration);
+    let mut pub_redis = self.pub_redis.lock().await;
+    match self.order {
+      QueueOrder::Fifo => {
+        let _ = pub_redis.builder::<MarginfiUser>(queue_keys::LIQUIDATION_QUEUE).items(hits.into_iter().map(|(pk, user)| (*pk, user))).send().await?;
+      },
+      QueueOrder::Priority => {
+        // ranked by what receivership would pay, at the slot the hits were confirmed at so the
+        // worker can tell how old they are
+        if hits.is_empty() {
+          return Ok(());
+        }
+        let slot = match slot {
+          Some(slot) => slot,
+          None => anyhow::bail!("no slot known for the snapshot of {} hits, not publishing", hits.len()),
+        };
+        let liquidation_max_fee: I80F48 = self.fee_state.read().unwrap().liquidation_max_fee.into();
+        let ranked: Vec<_> = hits
+          .into_iter()
+          .map(|(pk, user)| {
+            let value = user.receivership_profit(liquidation_max_fee).map(|profit| profit.to_num::<f64>()).unwrap_or_default();
+            (*pk, user, Ranking { value, slot })
+          })
+          .collect();
+        let _ = pub_redis.publish_ranked(queue_keys::LIQUIDATION_QUEUE, &ranked).await?;
+      },
+    }
+
+    Ok(())
+  }
 }
 
-async fn check_pubkeys<'a>(protocol: &Marginfi, pubkeys: &'a [Pubkey]) -> anyhow::Result<Vec<(&'a Pubkey, MarginfiUser)>> {
-  let users = protocol.load_users(pubkeys).await?;
+fn unix_timestamp() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+/// Returns the slot the accounts were fetched at, the accounts below maintenance and the ones
+/// that couldn't be priced.
+async fn check_pubkeys<'a>(protocol: &Marginfi, market: &Arc<MarketState>, pubkeys: &'a [Pubkey]) -> anyhow::Result<(Option<u64>, Vec<(&'a Pubkey, MarginfiUser)>, Vec<Pubkey>)> {
+  let (slot, users) = market.load_users_at(protocol.rpc_ref(), pubkeys).await?;
   
   let mut hits = Vec::new();
+  let mut unpriced = Vec::new();
   for (result, pubkey) in users.into_iter().zip(pubkeys) {
     let user = match result {
       Ok(user) => user,
       Err(error) => {
         // println!("Error, skipping: {}", error);
+        unpriced.push(*pubkey);
         continue;   
       },
     };
-    
-    let result = match user.eligible_for_liquidation() {
-      Ok(result) => result,
+
+    // flags that block liquidation are left to `Confirmer::handle`, which reports them
+    let result = match user.maintenance() {
+      Ok(maint) => maint < 0,
       Err(error) => {
         println!("Error: {}", error);
+        unpriced.push(*pubkey);
         continue;
       },
     };
@@ -112,5 +485,5 @@ async fn check_pubkeys<'a>(protocol: &Marginfi, pubkeys: &'a [Pubkey]) -> anyhow
     }
   }
 
-  anyhow::Ok(hits)
+  anyhow::Ok((slot, hits, unpriced))
 }
\ No newline at end of file
@@ -0,0 +1,65 @@
+//! Queue entry throughput of the framed wire format against the legacy hex format, run with
+//! `cargo bench -p connections --bench wire`.
+
+use std::{
+  hint::black_box,
+  time::{Duration, Instant},
+};
+
+use base64::{Engine as _, engine::general_purpose};
+use connections::{decode_payload, encode_payload};
+use solana_pubkey::Pubkey;
+
+const ROUNDS: usize = 20_000;
+
+type AccountUpdate = (u64, Vec<u8>);
+
+/// Shaped like a queued account update: a slot and a mostly zeroed account body.
+fn account_update() -> AccountUpdate {
+  let mut data = vec![0u8; 2304];
+  for (i, byte) in data.iter_mut().enumerate().step_by(97) {
+    *byte = i as u8;
+  }
+  (312_000_000, data)
+}
+
+fn framed(pubkey: &Pubkey, update: &AccountUpdate) -> usize {
+  let entry = format!("{}|{}", pubkey, general_purpose::STANDARD.encode(encode_payload(update).unwrap()));
+
+  let (_, payload) = entry.split_once('|').unwrap();
+  let decoded: AccountUpdate = decode_payload(&general_purpose::STANDARD.decode(payload).unwrap()).unwrap();
+  black_box(decoded);
+
+  entry.len()
+}
+
+fn legacy(pubkey: &Pubkey, update: &AccountUpdate) -> usize {
+  let entry = format!("{}|{}", pubkey, hex::encode(bincode::serialize(&(pubkey.to_bytes(), update)).unwrap()));
+
+  let (_, payload) = entry.split_once('|').unwrap();
+  let decoded: ([u8; 32], AccountUpdate) = bincode::deserialize(&hex::decode(payload).unwrap()).unwrap();
+  black_box(decoded);
+
+  entry.len()
+}
+
+fn run(name: &str, round: impl Fn() -> usize) -> Duration {
+  let mut len = 0;
+  let start = Instant::now();
+  for _ in 0..ROUNDS {
+    len = round();
+  }
+  let elapsed = start.elapsed();
+
+  println!("{name}: {len} bytes, {:.0} entries/s", ROUNDS as f64 / elapsed.as_secs_f64());
+  elapsed
+}
+
+fn main() {
+  let pubkey = Pubkey::new_unique();
+  let update = account_update();
+
+  let framed = run("framed", || framed(black_box(&pubkey), black_box(&update)));
+  let legacy = run("legacy", || legacy(black_box(&pubkey), black_box(&update)));
+  println!("framed/legacy time: {:.2}", framed.as_secs_f64() / legacy.as_secs_f64());
+}
\ No newline at end of file
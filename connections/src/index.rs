@@ -0,0 +1,414 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fmt};
+
+use solana_pubkey::Pubkey;
+
+use super::{Redis, redis::{ACCOUNT_KEY, BANK_KEY}};
+
+const ACCOUNT_BANKS_PATTERN: &str = "account:banks:*";
+const BANK_ACCOUNTS_PATTERN: &str = "bank:accounts:*";
+
+/// Keys read per round trip while scanning the per-account and per-bank sets.
+const SCAN_BATCH_SIZE: usize = 1000;
+
+/// Contents of the account/bank index as read key by key, so it isn't a point in time view of a
+/// live index. Repairs recheck every fix against the current state.
+#[derive(Debug, Default, Clone)]
+pub struct IndexSnapshot {
+  /// `accounts`
+  pub accounts: BTreeSet<Pubkey>,
+  /// `banks`
+  pub banks: BTreeSet<Pubkey>,
+  /// `account:banks:*`
+  pub account_banks: BTreeMap<Pubkey, BTreeSet<Pubkey>>,
+  /// `bank:accounts:*`
+  pub bank_accounts: BTreeMap<Pubkey, BTreeSet<Pubkey>>,
+}
+
+/// Where the index disagrees with itself or with the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexIssue {
+  /// `account:banks:*` left behind by an account that isn't in `accounts`
+  OrphanAccountBanks { account: Pubkey },
+  /// Account listed under a bank its own bank set doesn't reference
+  DanglingBankAccount { bank: Pubkey, account: Pubkey },
+  /// Bank referenced by an account that isn't listing the account back
+  MissingBankAccount { bank: Pubkey, account: Pubkey },
+  /// Bank in `banks` no indexed account references
+  OrphanBank { bank: Pubkey },
+  /// Bank referenced by indexed accounts but missing from `banks`
+  MissingBank { bank: Pubkey },
+  /// Indexed account that no longer exists on chain
+  ClosedAccount { account: Pubkey },
+  /// Indexed banks of an account that differ from its active balances on chain
+  StaleAccountBanks { account: Pubkey, on_chain: Vec<Pubkey> },
+}
+
+impl fmt::Display for IndexIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::OrphanAccountBanks { account } => write!(f, "account:banks:{} exists but the account isn't indexed", account),
+      Self::DanglingBankAccount { bank, account } => write!(f, "bank:accounts:{} lists {} which doesn't reference it", bank, account),
+      Self::MissingBankAccount { bank, account } => write!(f, "{} references {} but bank:accounts:{} doesn't list it", account, bank, bank),
+      Self::OrphanBank { bank } => write!(f, "{} is in banks but no indexed account references it", bank),
+      Self::MissingBank { bank } => write!(f, "{} is referenced but missing from banks", bank),
+      Self::ClosedAccount { account } => write!(f, "{} is indexed but closed on chain", account),
+      Self::StaleAccountBanks { account, on_chain } => write!(f, "{} is indexed with stale banks, {} active on chain", account, on_chain.len()),
+    }
+  }
+}
+
+impl IndexSnapshot {
+  /// Compares the index with itself, and with `on_chain` when given: the active banks of each
+  /// indexed account, `None` for accounts that are gone. Accounts missing from `on_chain` are only
+  /// checked internally. Issues come in the order `Redis::repair_index` should apply them.
+  pub fn check(&self, on_chain: Option<&HashMap<Pubkey, Option<Vec<Pubkey>>>>) -> Vec<IndexIssue> {
+    let mut issues = Vec::new();
+
+    let orphans = self.account_banks.keys().filter(|account| !self.accounts.contains(account));
+    issues.extend(orphans.map(|account| IndexIssue::OrphanAccountBanks { account: *account }));
+
+    // every edge as the indexed accounts see it, the bank sets are expected to mirror it
+    let mut referenced: BTreeMap<Pubkey, BTreeSet<Pubkey>> = BTreeMap::new();
+    for account in &self.accounts {
+      for bank in self.account_banks.get(account).into_iter().flatten() {
+        referenced.entry(*bank).or_default().insert(*account);
+      }
+    }
+
+    for (bank, accounts) in &self.bank_accounts {
+      let expected = referenced.get(bank);
+      let dangling = accounts.iter().filter(|account| !expected.is_some_and(|expected| expected.contains(account)));
+      issues.extend(dangling.map(|account| IndexIssue::DanglingBankAccount { bank: *bank, account: *account }));
+    }
+
+    for (bank, accounts) in &referenced {
+      let listed = self.bank_accounts.get(bank);
+      let missing = accounts.iter().filter(|account| !listed.is_some_and(|listed| listed.contains(account)));
+      issues.extend(missing.map(|account| IndexIssue::MissingBankAccount { bank: *bank, account: *account }));
+    }
+
+    let orphans = self.banks.iter().filter(|bank| !referenced.contains_key(bank));
+    issues.extend(orphans.map(|bank| IndexIssue::OrphanBank { bank: *bank }));
+
+    let missing = referenced.keys().filter(|bank| !self.banks.contains(bank));
+    issues.extend(missing.map(|bank| IndexIssue::MissingBank { bank: *bank }));
+
+    let on_chain = match on_chain {
+      Some(on_chain) => on_chain,
+      None => return issues,
+    };
+
+    for account in &self.accounts {
+      match on_chain.get(account) {
+        Some(None) => issues.push(IndexIssue::ClosedAccount { account: *account }),
+        Some(Some(banks)) => {
+          let banks: BTreeSet<Pubkey> = banks.iter().copied().collect();
+          let indexed = self.account_banks.get(account).cloned().unwrap_or_default();
+          if banks != indexed {
+            issues.push(IndexIssue::StaleAccountBanks { account: *account, on_chain: banks.into_iter().collect() });
+          }
+        },
+        None => {},
+      }
+    }
+
+    issues
+  }
+}
+
+/// Banks that entered or left `banks` while repairing, their subscriptions have to follow.
+#[derive(Debug, Default, Clone)]
+pub struct IndexRepair {
+  pub added_banks: Vec<Pubkey>,
+  pub removed_banks: Vec<Pubkey>,
+}
+
+impl Redis {
+  /// Reads `accounts`, `banks` and every `account:banks:*` and `bank:accounts:*` set.
+  pub async fn index_snapshot(&mut self) -> anyhow::Result<IndexSnapshot> {
+    let accounts = self.get_all_accounts().await?.into_iter().collect();
+    let banks = self.get_all_banks().await?.into_iter().collect();
+    let account_banks = self.scan_sets(ACCOUNT_BANKS_PATTERN).await?;
+    let bank_accounts = self.scan_sets(BANK_ACCOUNTS_PATTERN).await?;
+
+    Ok(IndexSnapshot { accounts, banks, account_banks, bank_accounts })
+  }
+
+  /// Members of every set matching `pattern`, keyed by the pubkey after the last `:`.
+  async fn scan_sets(&mut self, pattern: &str) -> anyhow::Result<BTreeMap<Pubkey, BTreeSet<Pubkey>>> {
+    let mut sets = BTreeMap::new();
+    let mut cursor: u64 = 0;
+
+    loop {
+      let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(SCAN_BATCH_SIZE)
+        .query_async(&mut self.con)
+        .await?;
+
+      if !keys.is_empty() {
+        let mut pipe = redis::pipe();
+        for key in &keys {
+          pipe.cmd("SMEMBERS").arg(key);
+        }
+        let members: Vec<Vec<String>> = pipe.query_async(&mut self.con).await?;
+
+        for (key, members) in keys.iter().zip(members) {
+          // a set emptied since the scan saw it is just gone
+          if members.is_empty() {
+            continue;
+          }
+
+          let owner = key.rsplit(':').next().unwrap_or_default();
+          let owner: Pubkey = owner.parse().map_err(|err| anyhow::anyhow!("invalid index key {}: {}", key, err))?;
+          let members = members
+            .iter()
+            .map(|member| member.parse().map_err(|err| anyhow::anyhow!("invalid member {} of {}: {}", member, key, err)))
+            .collect::<anyhow::Result<BTreeSet<Pubkey>>>()?;
+
+          sets.insert(owner, members);
+        }
+      }
+
+      cursor = next;
+      if cursor == 0 {
+        break;
+      }
+    }
+
+    Ok(sets)
+  }
+
+  /// Drops the bank sets of an account that isn't indexed anymore.
+  const REPAIR_ORPHAN_ACCOUNT_SCRIPT: &str = r#"
+    local account = ARGV[1]
+    if redis.call('SISMEMBER', KEYS[1], account) == 1 then
+      return 0
+    end
+
+    for _, bank in ipairs(redis.call('SMEMBERS', 'account:banks:' .. account)) do
+      redis.call('SREM', 'bank:accounts:' .. bank, account)
+    end
+
+    return redis.call('DEL', 'account:banks:' .. account)
+  "#;
+
+  /// Makes the bank's listing of the account match the account's own bank set.
+  const REPAIR_EDGE_SCRIPT: &str = r#"
+    local bank    = ARGV[1]
+    local account = ARGV[2]
+
+    local wanted = redis.call('SISMEMBER', KEYS[1], account) == 1
+      and redis.call('SISMEMBER', 'account:banks:' .. account, bank) == 1
+
+    if wanted then
+      return redis.call('SADD', 'bank:accounts:' .. bank, account)
+    end
+
+    return redis.call('SREM', 'bank:accounts:' .. bank, account)
+  "#;
+
+  /// Keeps the bank in `banks` exactly while some account is listed under it. Returns 1 when it
+  /// was added, -1 when it was removed.
+  const REPAIR_BANK_SCRIPT: &str = r#"
+    local bank = ARGV[1]
+
+    if redis.call('SCARD', 'bank:accounts:' .. bank) == 0 then
+      return -redis.call('SREM', KEYS[1], bank)
+    end
+
+    return redis.call('SADD', KEYS[1], bank)
+  "#;
+
+  /// Fixes one issue, after checking it still applies. Internal issues only touch what the
+  /// index itself can tell is wrong, on-chain ones remove or rewrite the account.
+  pub async fn repair_index(&mut self, issue: &IndexIssue) -> anyhow::Result<IndexRepair> {
+    let mut repair = IndexRepair::default();
+
+    match issue {
+      IndexIssue::OrphanAccountBanks { account } => {
+        let _: i64 = redis::Script::new(Self::REPAIR_ORPHAN_ACCOUNT_SCRIPT)
+          .key(ACCOUNT_KEY)
+          .arg(account.to_string())
+          .invoke_async(&mut self.con)
+          .await?;
+      },
+      IndexIssue::DanglingBankAccount { bank, account } | IndexIssue::MissingBankAccount { bank, account } => {
+        let _: i64 = redis::Script::new(Self::REPAIR_EDGE_SCRIPT)
+          .key(ACCOUNT_KEY)
+          .arg(bank.to_string())
+          .arg(account.to_string())
+          .invoke_async(&mut self.con)
+          .await?;
+      },
+      IndexIssue::OrphanBank { bank } | IndexIssue::MissingBank { bank } => {
+        let changed: i64 = redis::Script::new(Self::REPAIR_BANK_SCRIPT)
+          .key(BANK_KEY)
+          .arg(bank.to_string())
+          .invoke_async(&mut self.con)
+          .await?;
+
+        match changed {
+          1 => repair.added_banks.push(*bank),
+          -1 => repair.removed_banks.push(*bank),
+          _ => {},
+        }
+      },
+      IndexIssue::ClosedAccount { account } => {
+        let (removed_banks, _) = self.rem_multiple([account]).await?;
+        repair.removed_banks = removed_banks;
+      },
+      IndexIssue::StaleAccountBanks { account, on_chain } => {
+        let (added_banks, removed_banks) = self.set_account_banks(account, on_chain).await?;
+        repair.added_banks = added_banks;
+        repair.removed_banks = removed_banks;
+      },
+    }
+
+    Ok(repair)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{TestRedis, empty_redis};
+
+  /// Index over the empty test database.
+  async fn empty_index() -> (TestRedis, Redis) {
+    let db = empty_redis().await;
+    let redis = Redis::new(&db.url).await.unwrap();
+    (db, redis)
+  }
+
+  async fn seed(redis: &mut Redis, key: &str, members: &[Pubkey]) {
+    let members: Vec<String> = members.iter().map(ToString::to_string).collect();
+    let _: () = redis::cmd("SADD").arg(key).arg(members).query_async(&mut redis.con).await.unwrap();
+  }
+
+  fn set(members: &[Pubkey]) -> BTreeSet<Pubkey> {
+    members.iter().copied().collect()
+  }
+
+  async fn repair_all(redis: &mut Redis, issues: &[IndexIssue]) -> (BTreeSet<Pubkey>, BTreeSet<Pubkey>) {
+    let (mut added, mut removed) = (BTreeSet::new(), BTreeSet::new());
+    for issue in issues {
+      let repair = redis.repair_index(issue).await.unwrap();
+      added.extend(repair.added_banks);
+      removed.extend(repair.removed_banks);
+    }
+    (added, removed)
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn finds_and_repairs_internal_inconsistencies() {
+    let (_db, mut redis) = empty_index().await;
+    let [a, b, c, x, y, z, w] = [(); 7].map(|_| Pubkey::new_unique());
+
+    seed(&mut redis, ACCOUNT_KEY, &[a, b]).await;
+    // z is referenced by nobody, w is referenced but missing
+    seed(&mut redis, BANK_KEY, &[x, y, z]).await;
+    seed(&mut redis, &format!("account:banks:{}", a), &[x, y]).await;
+    seed(&mut redis, &format!("account:banks:{}", b), &[w]).await;
+    // c was removed from accounts but left its bank set behind
+    seed(&mut redis, &format!("account:banks:{}", c), &[y]).await;
+    seed(&mut redis, &format!("bank:accounts:{}", x), &[a]).await;
+    // y lists c instead of a
+    seed(&mut redis, &format!("bank:accounts:{}", y), &[c]).await;
+    seed(&mut redis, &format!("bank:accounts:{}", w), &[b]).await;
+
+    let issues = redis.index_snapshot().await.unwrap().check(None);
+    assert_eq!(issues, vec![
+      IndexIssue::OrphanAccountBanks { account: c },
+      IndexIssue::DanglingBankAccount { bank: y, account: c },
+      IndexIssue::MissingBankAccount { bank: y, account: a },
+      IndexIssue::OrphanBank { bank: z },
+      IndexIssue::MissingBank { bank: w },
+    ]);
+
+    let (added, removed) = repair_all(&mut redis, &issues).await;
+    assert_eq!(added, set(&[w]));
+    assert_eq!(removed, set(&[z]));
+
+    let snapshot = redis.index_snapshot().await.unwrap();
+    assert_eq!(snapshot.check(None), Vec::new());
+    assert_eq!(snapshot.accounts, set(&[a, b]));
+    assert_eq!(snapshot.banks, set(&[x, y, w]));
+    assert_eq!(snapshot.account_banks, BTreeMap::from([(a, set(&[x, y])), (b, set(&[w]))]));
+    assert_eq!(snapshot.bank_accounts, BTreeMap::from([(x, set(&[a])), (y, set(&[a])), (w, set(&[b]))]));
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn finds_and_repairs_differences_with_the_chain() {
+    let (_db, mut redis) = empty_index().await;
+    let [a, b, x, y, z] = [(); 5].map(|_| Pubkey::new_unique());
+
+    redis.add_multiple([&a, &b], vec![vec![&x], vec![&x, &y]]).await.unwrap();
+
+    let on_chain = HashMap::from([(a, None), (b, Some(vec![z, y]))]);
+    let issues = redis.index_snapshot().await.unwrap().check(Some(&on_chain));
+    assert_eq!(issues.len(), 2);
+    assert!(issues.contains(&IndexIssue::ClosedAccount { account: a }));
+    assert!(issues.contains(&IndexIssue::StaleAccountBanks { account: b, on_chain: set(&[y, z]).into_iter().collect() }));
+
+    let (added, removed) = repair_all(&mut redis, &issues).await;
+    assert_eq!(added, set(&[z]));
+    assert_eq!(removed, set(&[x]));
+
+    let snapshot = redis.index_snapshot().await.unwrap();
+    assert_eq!(snapshot.check(Some(&HashMap::from([(b, Some(vec![y, z]))]))), Vec::new());
+    assert_eq!(snapshot.accounts, set(&[b]));
+    assert_eq!(snapshot.banks, set(&[y, z]));
+    assert_eq!(snapshot.account_banks, BTreeMap::from([(b, set(&[y, z]))]));
+    assert_eq!(snapshot.bank_accounts, BTreeMap::from([(y, set(&[b])), (z, set(&[b]))]));
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn removes_accounts_and_the_banks_only_they_used() {
+    let (_db, mut redis) = empty_index().await;
+    let [a, b, c, x, y] = [(); 5].map(|_| Pubkey::new_unique());
+
+    redis.add_multiple([&a, &b, &c], vec![vec![&x], vec![&x, &y], vec![&y]]).await.unwrap();
+
+    // x goes once both of its accounts are gone, y stays with c, unknown accounts count for nothing
+    let (removed_banks, removed) = redis.rem_multiple([&a, &b, &Pubkey::new_unique()]).await.unwrap();
+    assert_eq!(removed, 2);
+    assert_eq!(removed_banks, vec![x]);
+
+    let snapshot = redis.index_snapshot().await.unwrap();
+    assert_eq!(snapshot.check(None), Vec::new());
+    assert_eq!(snapshot.accounts, set(&[c]));
+    assert_eq!(snapshot.banks, set(&[y]));
+    assert_eq!(snapshot.account_banks, BTreeMap::from([(c, set(&[y]))]));
+    assert_eq!(snapshot.bank_accounts, BTreeMap::from([(y, set(&[c]))]));
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn rewrites_the_banks_of_indexed_accounts_only() {
+    let (_db, mut redis) = empty_index().await;
+    let [a, b, unknown, x, y, z] = [(); 6].map(|_| Pubkey::new_unique());
+
+    redis.add_multiple([&a, &b], vec![vec![&x, &y], vec![&y]]).await.unwrap();
+
+    let (added, removed) = redis.set_account_banks(&unknown, [&z]).await.unwrap();
+    assert_eq!((added, removed), (Vec::new(), Vec::new()));
+
+    // y stays with b, x loses its only account
+    let (added, removed) = redis.set_account_banks(&a, [&z]).await.unwrap();
+    assert_eq!((added, removed), (vec![z], vec![x]));
+
+    let snapshot = redis.index_snapshot().await.unwrap();
+    assert_eq!(snapshot.check(None), Vec::new());
+    assert_eq!(snapshot.accounts, set(&[a, b]));
+    assert_eq!(snapshot.banks, set(&[y, z]));
+    assert_eq!(snapshot.account_banks, BTreeMap::from([(a, set(&[z])), (b, set(&[y]))]));
+    assert_eq!(snapshot.bank_accounts, BTreeMap::from([(y, set(&[b])), (z, set(&[a]))]));
+  }
+}
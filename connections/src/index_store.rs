@@ -0,0 +1,235 @@
+use std::{collections::BTreeSet, sync::{Arc, Mutex}};
+
+use solana_pubkey::Pubkey;
+
+use super::{IndexSnapshot, Redis};
+
+/// The account/bank index the add, rem and account workers maintain. Every call is atomic on
+/// its own, the returned banks are the ones that entered or left `banks` on the way.
+pub trait IndexStore: Send {
+  fn exists_multiple(&mut self, accounts: &[Pubkey]) -> impl Future<Output = anyhow::Result<Vec<bool>>> + Send;
+
+  /// Indexes the accounts with their banks. Returns the added banks and how many accounts are new.
+  fn add_multiple(&mut self, accounts: &[(Pubkey, Vec<Pubkey>)]) -> impl Future<Output = anyhow::Result<(Vec<Pubkey>, usize)>> + Send;
+
+  /// Returns the removed banks and how many accounts were indexed.
+  fn rem_multiple(&mut self, accounts: &[Pubkey]) -> impl Future<Output = anyhow::Result<(Vec<Pubkey>, usize)>> + Send;
+
+  /// Replaces the banks of an indexed account, returns the added and the removed banks.
+  fn set_account_banks(&mut self, account: &Pubkey, banks: &[Pubkey]) -> impl Future<Output = anyhow::Result<(Vec<Pubkey>, Vec<Pubkey>)>> + Send;
+}
+
+impl IndexStore for Redis {
+  async fn exists_multiple(&mut self, accounts: &[Pubkey]) -> anyhow::Result<Vec<bool>> {
+    Redis::exists_multiple(self, accounts).await
+  }
+
+  async fn add_multiple(&mut self, accounts: &[(Pubkey, Vec<Pubkey>)]) -> anyhow::Result<(Vec<Pubkey>, usize)> {
+    let bank_accounts = accounts.iter().map(|(_, banks)| banks).collect();
+    Redis::add_multiple(self, accounts.iter().map(|(account, _)| account), bank_accounts).await
+  }
+
+  async fn rem_multiple(&mut self, accounts: &[Pubkey]) -> anyhow::Result<(Vec<Pubkey>, usize)> {
+    Redis::rem_multiple(self, accounts).await
+  }
+
+  async fn set_account_banks(&mut self, account: &Pubkey, banks: &[Pubkey]) -> anyhow::Result<(Vec<Pubkey>, Vec<Pubkey>)> {
+    Redis::set_account_banks(self, account, banks).await
+  }
+}
+
+/// Index living in the process, for tests and single-process runs. Follows the redis scripts,
+/// including keeping the banks of accounts that are added again.
+#[derive(Clone, Default)]
+pub struct MemoryIndex {
+  index: Arc<Mutex<IndexSnapshot>>,
+}
+
+impl MemoryIndex {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Copy of the current contents, `IndexSnapshot::check` works on it like on a redis snapshot.
+  pub fn snapshot(&self) -> IndexSnapshot {
+    self.index.lock().unwrap().clone()
+  }
+}
+
+impl IndexSnapshot {
+  fn link(&mut self, account: &Pubkey, bank: &Pubkey) -> bool {
+    self.account_banks.entry(*account).or_default().insert(*bank);
+    self.bank_accounts.entry(*bank).or_default().insert(*account);
+    self.banks.insert(*bank)
+  }
+
+  /// Returns whether the bank lost its last account and left `banks`.
+  fn unlink(&mut self, account: &Pubkey, bank: &Pubkey) -> bool {
+    if let Some(banks) = self.account_banks.get_mut(account) {
+      banks.remove(bank);
+      if banks.is_empty() {
+        self.account_banks.remove(account);
+      }
+    }
+
+    let Some(accounts) = self.bank_accounts.get_mut(bank) else {
+      return self.banks.remove(bank);
+    };
+    accounts.remove(account);
+    if !accounts.is_empty() {
+      return false;
+    }
+    self.bank_accounts.remove(bank);
+    self.banks.remove(bank)
+  }
+}
+
+impl IndexStore for MemoryIndex {
+  async fn exists_multiple(&mut self, accounts: &[Pubkey]) -> anyhow::Result<Vec<bool>> {
+    let index = self.index.lock().unwrap();
+    Ok(accounts.iter().map(|account| index.accounts.contains(account)).collect())
+  }
+
+  async fn add_multiple(&mut self, accounts: &[(Pubkey, Vec<Pubkey>)]) -> anyhow::Result<(Vec<Pubkey>, usize)> {
+    let mut index = self.index.lock().unwrap();
+
+    let mut added_banks = Vec::new();
+    let mut accounts_added = 0;
+    for (account, banks) in accounts {
+      if index.accounts.insert(*account) {
+        accounts_added += 1;
+      }
+
+      for bank in banks {
+        if index.link(account, bank) {
+          added_banks.push(*bank);
+        }
+      }
+    }
+
+    Ok((added_banks, accounts_added))
+  }
+
+  async fn rem_multiple(&mut self, accounts: &[Pubkey]) -> anyhow::Result<(Vec<Pubkey>, usize)> {
+    let mut index = self.index.lock().unwrap();
+
+    let mut removed_banks = Vec::new();
+    let mut accounts_removed = 0;
+    for account in accounts {
+      for bank in index.account_banks.get(account).cloned().unwrap_or_default() {
+        if index.unlink(account, &bank) {
+          removed_banks.push(bank);
+        }
+      }
+
+      if index.accounts.remove(account) {
+        accounts_removed += 1;
+      }
+    }
+
+    Ok((removed_banks, accounts_removed))
+  }
+
+  async fn set_account_banks(&mut self, account: &Pubkey, banks: &[Pubkey]) -> anyhow::Result<(Vec<Pubkey>, Vec<Pubkey>)> {
+    let mut index = self.index.lock().unwrap();
+
+    if !index.accounts.contains(account) {
+      return Ok((Vec::new(), Vec::new()));
+    }
+
+    let wanted: BTreeSet<Pubkey> = banks.iter().copied().collect();
+    let current = index.account_banks.get(account).cloned().unwrap_or_default();
+
+    let mut removed = Vec::new();
+    for bank in current.difference(&wanted) {
+      if index.unlink(account, bank) {
+        removed.push(*bank);
+      }
+    }
+
+    let mut added = Vec::new();
+    for bank in wanted.difference(&current) {
+      if index.link(account, bank) {
+        added.push(*bank);
+      }
+    }
+
+    Ok((added, removed))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set(members: &[Pubkey]) -> BTreeSet<Pubkey> {
+    members.iter().copied().collect()
+  }
+
+  fn sorted(mut banks: Vec<Pubkey>) -> Vec<Pubkey> {
+    banks.sort();
+    banks
+  }
+
+  #[tokio::test]
+  async fn adds_accounts_and_reports_new_banks_once() {
+    let mut index = MemoryIndex::new();
+    let [a, b, x, y] = [(); 4].map(|_| Pubkey::new_unique());
+
+    let (added, count) = index.add_multiple(&[(a, vec![x]), (b, vec![x, y])]).await.unwrap();
+    assert_eq!(sorted(added), sorted(vec![x, y]));
+    assert_eq!(count, 2);
+
+    // adding again only links the new bank and doesn't count the account
+    let (added, count) = index.add_multiple(&[(a, vec![y])]).await.unwrap();
+    assert!(added.is_empty());
+    assert_eq!(count, 0);
+
+    assert_eq!(index.exists_multiple(&[a, x, b]).await.unwrap(), vec![true, false, true]);
+    let snapshot = index.snapshot();
+    assert_eq!(snapshot.account_banks[&a], set(&[x, y]));
+    assert_eq!(snapshot.bank_accounts[&y], set(&[a, b]));
+    assert!(snapshot.check(None).is_empty());
+  }
+
+  #[tokio::test]
+  async fn removes_banks_with_their_last_account() {
+    let mut index = MemoryIndex::new();
+    let [a, b, c, x, y] = [(); 5].map(|_| Pubkey::new_unique());
+    index.add_multiple(&[(a, vec![x, y]), (b, vec![y])]).await.unwrap();
+
+    let (removed, count) = index.rem_multiple(&[a, c]).await.unwrap();
+    assert_eq!(removed, vec![x]);
+    assert_eq!(count, 1);
+
+    let (removed, count) = index.rem_multiple(&[b]).await.unwrap();
+    assert_eq!(removed, vec![y]);
+    assert_eq!(count, 1);
+
+    let snapshot = index.snapshot();
+    assert!(snapshot.accounts.is_empty());
+    assert!(snapshot.banks.is_empty());
+    assert!(snapshot.account_banks.is_empty());
+    assert!(snapshot.bank_accounts.is_empty());
+  }
+
+  #[tokio::test]
+  async fn sets_the_banks_of_indexed_accounts_only() {
+    let mut index = MemoryIndex::new();
+    let [a, b, unknown, x, y, z] = [(); 6].map(|_| Pubkey::new_unique());
+    index.add_multiple(&[(a, vec![x, y]), (b, vec![y])]).await.unwrap();
+
+    assert_eq!(index.set_account_banks(&unknown, &[z]).await.unwrap(), (vec![], vec![]));
+    assert!(!index.snapshot().banks.contains(&z));
+
+    // y is still held by b
+    assert_eq!(index.set_account_banks(&a, &[z]).await.unwrap(), (vec![z], vec![x]));
+    assert_eq!(index.set_account_banks(&a, &[z]).await.unwrap(), (vec![], vec![]));
+
+    let snapshot = index.snapshot();
+    assert_eq!(snapshot.banks, set(&[y, z]));
+    assert_eq!(snapshot.account_banks[&a], set(&[z]));
+    assert_eq!(snapshot.bank_accounts[&y], set(&[b]));
+    assert!(snapshot.check(None).is_empty());
+  }
+}
@@ -1,5 +1,11 @@
+mod index;
+mod index_store;
 mod redis;
 mod pubsub;
+#[cfg(test)]
+mod test_utils;
 
+pub use index::*;
+pub use index_store::*;
 pub use redis::*;
 pub use pubsub::*;
\ No newline at end of file
@@ -0,0 +1,420 @@
+use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{PubRedis, QueueBackend, Ranking, priority, redis::PublishCall, wire::entry_account};
+
+/// Deliveries after which a failing entry is dead-lettered instead of handed out again.
+pub const DEFAULT_MAX_DELIVERIES: u32 = 5;
+
+pub(super) fn queue_to_dead_letters(queue: &str) -> String {
+  format!("{}_dead", queue)
+}
+
+/// Letters on their way back into the queue. A letter leaves it in the same step that publishes
+/// it, or that moves it to the tail of the dead-letter list when publishing fails. Letters of a
+/// requeue that died halfway stay here until `DeadLetters::recover` hands them back.
+fn queue_to_requeueing(queue: &str) -> String {
+  format!("{}_dead_requeueing", queue)
+}
+
+/// Entry taken out of a queue for good. Written by the consumers as JSON, the lua scripts
+/// produce the same shape with `cjson`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+  /// The entry as it was queued, `"{account}|{payload}"`
+  pub entry: String,
+  pub error: String,
+  /// Unix time in milliseconds
+  pub timestamp: u64,
+  /// How many times the entry was handed out
+  pub attempts: u32,
+}
+
+impl DeadLetter {
+  pub fn new(entry: String, error: String, attempts: u32) -> Self {
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|duration| duration.as_millis() as u64)
+      .unwrap_or_default();
+
+    Self { entry, error, timestamp, attempts }
+  }
+
+  pub fn account(&self) -> &str {
+    entry_account(&self.entry)
+  }
+}
+
+/// Outcome of `DeadLetters::requeue`.
+#[derive(Debug, Default)]
+pub struct Requeued {
+  /// Letters whose account was newly queued
+  pub queued: usize,
+  /// Why each letter that couldn't be published failed, those letters went to the tail
+  pub failed: Vec<String>,
+}
+
+/// Inspects and drains the dead-letter lists, oldest letters first.
+pub struct DeadLetters {
+  publisher: PubRedis,
+  /// Queues read in `QueueOrder::Priority` and how their requeued letters rank
+  ranked: HashMap<String, Ranking>,
+}
+
+impl DeadLetters {
+  pub async fn new(connection_info: &str) -> anyhow::Result<Self> {
+    let publisher = PubRedis::new(connection_info).await?;
+
+    Ok(Self { publisher, ranked: HashMap::new() })
+  }
+
+  /// Requeued entries go back through this backend.
+  pub fn with_backend(mut self, backend: QueueBackend) -> Self {
+    self.publisher = self.publisher.with_backend(backend);
+    self
+  }
+
+  /// Requeues the letters of `queue` into its priority variant, see `QueueOrder`. Letters don't
+  /// keep the ranking they were published with, so all of them rank with `ranking`.
+  pub fn with_priority(mut self, queue: &str, ranking: Ranking) -> Self {
+    self.ranked.insert(queue.to_string(), ranking);
+    self
+  }
+
+  pub async fn count(&mut self, queue: &str) -> anyhow::Result<usize> {
+    let len: usize = redis::cmd("LLEN")
+      .arg(queue_to_dead_letters(queue))
+      .query_async(&mut self.publisher.con)
+      .await?;
+
+    Ok(len)
+  }
+
+  pub async fn list(&mut self, queue: &str, start: usize, count: usize) -> anyhow::Result<Vec<DeadLetter>> {
+    if count == 0 {
+      return Ok(Vec::new());
+    }
+
+    let raw: Vec<String> = redis::cmd("LRANGE")
+      .arg(queue_to_dead_letters(queue))
+      .arg(start)
+      .arg(start + count - 1)
+      .query_async(&mut self.publisher.con)
+      .await?;
+
+    raw.iter()
+      .map(|letter| serde_json::from_str(letter).map_err(Into::into))
+      .collect()
+  }
+
+  /// Puts the oldest `count` entries back into the queue, publishing starts them with a fresh
+  /// delivery count. Accounts that are already waiting again keep their queued entry and count.
+  /// Letters are taken off the list one at a time, so concurrent purges and newly dead-lettered
+  /// entries never get a letter published twice or dropped unpublished. A letter that fails to
+  /// publish goes to the tail instead of blocking the ones behind it. Starts by recovering what an
+  /// interrupted requeue left behind, so requeues of one queue mustn't run at the same time.
+  pub async fn requeue(&mut self, queue: &str, count: usize) -> anyhow::Result<Requeued> {
+    self.recover(queue).await?;
+
+    let mut requeued = Requeued::default();
+    for _ in 0..count {
+      let raw: Option<String> = redis::cmd("LMOVE")
+        .arg(queue_to_dead_letters(queue))
+        .arg(queue_to_requeueing(queue))
+        .arg("LEFT")
+        .arg("RIGHT")
+        .query_async(&mut self.publisher.con)
+        .await?;
+
+      let raw = match raw {
+        Some(raw) => raw,
+        None => break,
+      };
+
+      match self.publish_letter(queue, &raw).await {
+        Ok(queued) => requeued.queued += queued,
+        Err(err) => {
+          self.send_back(queue, &raw).await?;
+          requeued.failed.push(format!("{:#}", err));
+        },
+      }
+    }
+
+    Ok(requeued)
+  }
+
+  /// Moves letters an interrupted requeue left in the holding list back to the head of the
+  /// dead-letter list, in their order. Returns how many were recovered.
+  pub async fn recover(&mut self, queue: &str) -> anyhow::Result<usize> {
+    let mut recovered = 0;
+    loop {
+      let raw: Option<String> = redis::cmd("LMOVE")
+        .arg(queue_to_requeueing(queue))
+        .arg(queue_to_dead_letters(queue))
+        .arg("RIGHT")
+        .arg("LEFT")
+        .query_async(&mut self.publisher.con)
+        .await?;
+
+      match raw {
+        Some(_) => recovered += 1,
+        None => return Ok(recovered),
+      }
+    }
+  }
+
+  /// Wraps a publish script so it only runs while the letter is still held, and takes the letter
+  /// out of the holding list in the same step, so a crash can't leave it both published and held.
+  /// The holding list and the letter go last in KEYS and ARGV, the publish script sees the rest.
+  fn releasing(call: PublishCall, holding: String, raw: &str) -> PublishCall {
+    let script = format!(r#"
+      local holding = KEYS[#KEYS]
+      local letter  = ARGV[#ARGV]
+      if not redis.call('LPOS', holding, letter) then return {{}} end
+
+      local keys, args = {{}}, {{}}
+      for i = 1, #KEYS - 1 do keys[i] = KEYS[i] end
+      for i = 1, #ARGV - 1 do args[i] = ARGV[i] end
+
+      local function publish(KEYS, ARGV)
+        {}
+      end
+
+      local pushed = publish(keys, args)
+      redis.call('LREM', holding, 1, letter)
+      return pushed
+    "#, call.script);
+
+    let mut keys = call.keys;
+    keys.push(holding);
+    let mut args = call.args;
+    args.push(raw.to_string());
+
+    PublishCall { script, keys, args }
+  }
+
+  /// Publishes a held letter and releases it, into the priority variant of the queue when it's
+  /// read in that order.
+  async fn publish_letter(&mut self, queue: &str, raw: &str) -> anyhow::Result<usize> {
+    let letter: DeadLetter = serde_json::from_str(raw).context("not a dead letter")?;
+    let account = letter.account().to_string();
+
+    let queued = async {
+      let call = match self.ranked.get(queue) {
+        Some(ranking) => priority::publish_call(queue, vec![account.clone(), letter.entry, ranking.score().to_string(), ranking.slot.to_string()]),
+        None => self.publisher.publish_entries_call(queue, &[account.clone(), letter.entry])?,
+      };
+      Self::releasing(call, queue_to_requeueing(queue), raw).invoke(&mut self.publisher.con).await
+    }.await.with_context(|| format!("failed to requeue {}", account))?;
+
+    Ok(queued.len())
+  }
+
+  /// Takes a letter out of the holding list and appends it to the dead-letter list in one step,
+  /// so an interrupted requeue can't leave it in both and have `recover` hand it back twice.
+  const SEND_BACK_SCRIPT: &str = r#"
+    if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 1 then
+      redis.call('RPUSH', KEYS[2], ARGV[1])
+    end
+  "#;
+
+  async fn send_back(&mut self, queue: &str, raw: &str) -> anyhow::Result<()> {
+    let script = redis::Script::new(Self::SEND_BACK_SCRIPT);
+    let _: () = script
+      .key(queue_to_requeueing(queue))
+      .key(queue_to_dead_letters(queue))
+      .arg(raw)
+      .invoke_async(&mut self.publisher.con)
+      .await?;
+
+    Ok(())
+  }
+
+  /// Counts and drops in one step, so a letter a concurrent requeue takes off the head in
+  /// between isn't counted in and a newer one dropped in its place. Letters are only ever
+  /// appended, so dropping from the head never loses a newer one.
+  const PURGE_SCRIPT: &str = r#"
+    local len   = redis.call('LLEN', KEYS[1])
+    local count = tonumber(ARGV[1])
+    if count < 0 or count > len then count = len end
+
+    if count > 0 then redis.call('LTRIM', KEYS[1], count, -1) end
+
+    return count
+  "#;
+
+  /// Drops the oldest `count` letters, all of them without a count. Returns how many were dropped.
+  pub async fn purge(&mut self, queue: &str, count: Option<usize>) -> anyhow::Result<usize> {
+    let count = count.map_or(-1, |count| count.min(i64::MAX as usize) as i64);
+
+    let script = redis::Script::new(Self::PURGE_SCRIPT);
+    let purged: usize = script
+      .key(queue_to_dead_letters(queue))
+      .arg(count)
+      .invoke_async(&mut self.publisher.con)
+      .await?;
+
+    Ok(purged)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use solana_pubkey::Pubkey;
+
+  use crate::pubsub::{SubRedis, wire::encode_entry};
+  use crate::test_utils::empty_redis;
+
+  use super::*;
+
+  const QUEUE: &str = "dead_letter_test";
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn requeues_the_oldest_letters_once() {
+    let db = empty_redis().await;
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [a, b, c] = [(); 3].map(|_| Pubkey::new_unique());
+
+    let mut push = redis::cmd("RPUSH");
+    push.arg(queue_to_dead_letters(QUEUE));
+    for (account, payload) in [(a, 1u64), (b, 2), (c, 3)] {
+      let entry = encode_entry(&account, &payload).unwrap();
+      push.arg(serde_json::to_string(&DeadLetter::new(entry, "failed".to_string(), 5)).unwrap());
+    }
+    let _: () = push.query_async(&mut dead_letters.publisher.con).await.unwrap();
+
+    assert_eq!(dead_letters.requeue(QUEUE, 2).await.unwrap().queued, 2);
+    assert_eq!(dead_letters.list(QUEUE, 0, 10).await.unwrap().iter().map(DeadLetter::account).collect::<Vec<_>>(), vec![c.to_string()]);
+
+    let requeueing: usize = redis::cmd("LLEN")
+      .arg(queue_to_requeueing(QUEUE))
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+    assert_eq!(requeueing, 0);
+
+    let read = subscriber.read::<u64>(QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(a, 1), (b, 2)]);
+
+    assert_eq!(dead_letters.requeue(QUEUE, 10).await.unwrap().queued, 1);
+    assert_eq!(dead_letters.count(QUEUE).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn moves_letters_that_fail_to_requeue_to_the_tail() {
+    let db = empty_redis().await;
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let account = Pubkey::new_unique();
+    let letter = DeadLetter::new(encode_entry(&account, &1u64).unwrap(), "failed".to_string(), 5);
+
+    let _: () = redis::cmd("RPUSH")
+      .arg(queue_to_dead_letters(QUEUE))
+      .arg("not a letter")
+      .arg(serde_json::to_string(&letter).unwrap())
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+
+    // the broken letter doesn't hold up the one behind it
+    let requeued = dead_letters.requeue(QUEUE, 2).await.unwrap();
+    assert_eq!(requeued.queued, 1);
+    assert_eq!(requeued.failed.len(), 1);
+
+    let left: Vec<String> = redis::cmd("LRANGE")
+      .arg(queue_to_dead_letters(QUEUE))
+      .arg(0)
+      .arg(-1)
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+    assert_eq!(left, vec!["not a letter".to_string()]);
+
+    let read = subscriber.read::<u64>(QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn recovers_letters_of_an_interrupted_requeue() {
+    let db = empty_redis().await;
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [a, b, c] = [(); 3].map(|_| Pubkey::new_unique());
+    let letter = |account: Pubkey| {
+      let entry = encode_entry(&account, &1u64).unwrap();
+      serde_json::to_string(&DeadLetter::new(entry, "failed".to_string(), 5)).unwrap()
+    };
+
+    // a and b were taken off the head when the requeue died
+    let _: () = redis::cmd("RPUSH")
+      .arg(queue_to_requeueing(QUEUE))
+      .arg(letter(a))
+      .arg(letter(b))
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+    let _: () = redis::cmd("RPUSH")
+      .arg(queue_to_dead_letters(QUEUE))
+      .arg(letter(c))
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+
+    assert_eq!(dead_letters.requeue(QUEUE, 1).await.unwrap().queued, 1);
+    let read = subscriber.read::<u64>(QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(a, 1)]);
+    assert_eq!(dead_letters.list(QUEUE, 0, 10).await.unwrap().iter().map(DeadLetter::account).collect::<Vec<_>>(), vec![b.to_string(), c.to_string()]);
+    assert_eq!(dead_letters.recover(QUEUE).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn requeues_letters_of_priority_queues_ranked() {
+    let db = empty_redis().await;
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap().with_priority(QUEUE, Ranking { value: 0.0, slot: 10 });
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let account = Pubkey::new_unique();
+    let letter = DeadLetter::new(encode_entry(&account, &1u64).unwrap(), "failed".to_string(), 5);
+
+    let _: () = redis::cmd("RPUSH")
+      .arg(queue_to_dead_letters(QUEUE))
+      .arg(serde_json::to_string(&letter).unwrap())
+      .query_async(&mut dead_letters.publisher.con)
+      .await
+      .unwrap();
+
+    assert_eq!(dead_letters.requeue(QUEUE, 1).await.unwrap().queued, 1);
+    assert!(subscriber.read::<u64>(QUEUE, 10).await.unwrap().is_empty());
+
+    let batch = subscriber.read_ranked::<u64>(QUEUE, 10, 10, None).await.unwrap();
+    assert_eq!(batch.items.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+    assert_eq!(dead_letters.recover(QUEUE).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn purges_the_oldest_letters() {
+    let db = empty_redis().await;
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap();
+    let [a, b, c] = [(); 3].map(|_| Pubkey::new_unique());
+
+    let mut push = redis::cmd("RPUSH");
+    push.arg(queue_to_dead_letters(QUEUE));
+    for account in [a, b, c] {
+      let entry = encode_entry(&account, &1u64).unwrap();
+      push.arg(serde_json::to_string(&DeadLetter::new(entry, "failed".to_string(), 5)).unwrap());
+    }
+    let _: () = push.query_async(&mut dead_letters.publisher.con).await.unwrap();
+
+    assert_eq!(dead_letters.purge(QUEUE, Some(2)).await.unwrap(), 2);
+    assert_eq!(dead_letters.list(QUEUE, 0, 10).await.unwrap().iter().map(DeadLetter::account).collect::<Vec<_>>(), vec![c.to_string()]);
+    assert_eq!(dead_letters.purge(QUEUE, Some(5)).await.unwrap(), 1);
+    assert_eq!(dead_letters.purge(QUEUE, None).await.unwrap(), 0);
+  }
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Serialize;
+use solana_pubkey::Pubkey;
+use tokio::task::JoinHandle;
+
+use super::{PubRedis, wire::encode_entry};
+
+/// Accounts waiting to be queued, scored by their due time in unix milliseconds.
+fn queue_to_delayed(queue: &str) -> String {
+  format!("{}_delayed", queue)
+}
+
+fn queue_to_delayed_entries(queue: &str) -> String {
+  format!("{}_delayed_entries", queue)
+}
+
+fn queue_to_delayed_reasons(queue: &str) -> String {
+  format!("{}_delayed_reasons", queue)
+}
+
+const MOVER_INTERVAL: Duration = Duration::from_secs(1);
+const MOVER_BATCH_SIZE: usize = 1000;
+
+impl PubRedis {
+  /// The due time comes from the redis clock so schedulers and movers on different hosts agree.
+  /// An account scheduled again only moves to the earlier of its due times, its entry and reason
+  /// follow the due time that won.
+  const SCHEDULE_SCRIPT: &str = r#"
+    local now    = redis.call('TIME')
+    local due    = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000) + tonumber(ARGV[1])
+    local moved  = 0
+    local n      = (#ARGV - 1) / 3
+
+    for i = 1, n do
+      local account = ARGV[(i-1)*3 + 2]
+      if redis.call('ZADD', KEYS[1], 'LT', 'CH', due, account) == 1 then
+        redis.call('HSET', KEYS[2], account, ARGV[(i-1)*3 + 3])
+        redis.call('HSET', KEYS[3], account, ARGV[(i-1)*3 + 4])
+        moved = moved + 1
+      end
+    end
+
+    return moved
+  "#;
+
+  /// Takes the due accounts off the delayed set, returns `account, entry, reason` triples.
+  const TAKE_DUE_SCRIPT: &str = r#"
+    local now    = redis.call('TIME')
+    local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+
+    local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', now_ms, 'LIMIT', 0, tonumber(ARGV[1]))
+    local out = {}
+
+    for _, account in ipairs(due) do
+      local entry  = redis.call('HGET', KEYS[2], account)
+      local reason = redis.call('HGET', KEYS[3], account) or ''
+      redis.call('ZREM', KEYS[1], account)
+      redis.call('HDEL', KEYS[2], account)
+      redis.call('HDEL', KEYS[3], account)
+      if entry then
+        table.insert(out, account)
+        table.insert(out, entry)
+        table.insert(out, reason)
+      end
+    end
+
+    return out
+  "#;
+
+  /// Publishes the items into `queue` once `delay` has passed. `reason` travels with them and is
+  /// reported when they're promoted. Returns how many accounts were scheduled or moved earlier,
+  /// accounts already due sooner keep their schedule.
+  pub async fn schedule<T: Serialize>(&mut self, queue: &str, items: &[(Pubkey, T)], delay: Duration, reason: &str) -> anyhow::Result<usize> {
+    let mut args = Vec::with_capacity(items.len() * 3);
+    for (pubkey, payload) in items {
+      args.push(pubkey.to_string());
+      args.push(encode_entry(pubkey, payload)?);
+      args.push(reason.to_string());
+    }
+
+    self.schedule_entries(queue, delay, &args).await
+  }
+
+  /// `args` are `account, entry, reason` triples.
+  async fn schedule_entries(&mut self, queue: &str, delay: Duration, args: &[String]) -> anyhow::Result<usize> {
+    if args.is_empty() {
+      return Ok(0);
+    }
+
+    let script = redis::Script::new(Self::SCHEDULE_SCRIPT);
+    let mut inv = script.prepare_invoke();
+    inv
+      .key(queue_to_delayed(queue))
+      .key(queue_to_delayed_entries(queue))
+      .key(queue_to_delayed_reasons(queue))
+      .arg(delay.as_millis() as u64);
+    for arg in args {
+      inv.arg(arg);
+    }
+
+    Ok(inv.invoke_async(&mut self.con).await?)
+  }
+
+  /// Moves up to `limit` due entries into `queue`, following its backend and dedupe policy.
+  /// Safe to run from every process at once. Returns how many entries were promoted for each
+  /// reason, entries whose account was already waiting in the queue count as promoted.
+  pub async fn promote_due(&mut self, queue: &str, limit: usize) -> anyhow::Result<HashMap<String, usize>> {
+    let script = redis::Script::new(Self::TAKE_DUE_SCRIPT);
+    let mut inv = script.prepare_invoke();
+    inv
+      .key(queue_to_delayed(queue))
+      .key(queue_to_delayed_entries(queue))
+      .key(queue_to_delayed_reasons(queue))
+      .arg(limit);
+
+    let due: Vec<String> = inv.invoke_async(&mut self.con).await?;
+
+    if due.is_empty() {
+      return Ok(HashMap::new());
+    }
+
+    let args: Vec<String> = due
+      .chunks(3)
+      .flat_map(|triple| triple[..2].iter().cloned())
+      .collect();
+
+    if let Err(err) = self.publish_entries(queue, &args).await {
+      // they were taken off the delayed set already, put them back to be retried right away
+      if let Err(err) = self.schedule_entries(queue, Duration::ZERO, &due).await {
+        println!("lost {} delayed entries of {}: {}", due.len() / 3, queue, err);
+      }
+      return Err(err);
+    }
+
+    let mut reasons = HashMap::new();
+    for triple in due.chunks(3) {
+      *reasons.entry(triple[2].clone()).or_default() += 1;
+    }
+
+    Ok(reasons)
+  }
+
+  /// Runs `promote_due` for `queue` forever in the background.
+  pub fn spawn_mover(&self, queue: &'static str) -> JoinHandle<()> {
+    let mut pub_redis = self.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(MOVER_INTERVAL);
+      loop {
+        interval.tick().await;
+        match pub_redis.promote_due(queue, MOVER_BATCH_SIZE).await {
+          Ok(reasons) if reasons.is_empty() => {},
+          Ok(reasons) => {
+            let reasons: Vec<String> = reasons.iter().map(|(reason, count)| format!("{} {}", count, reason)).collect();
+            println!("promoted delayed entries into {}: {}", queue, reasons.join(", "));
+          },
+          Err(err) => println!("failed to promote delayed entries of {}: {}", queue, err),
+        }
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::pubsub::{SubRedis, queue_keys::CHECK_QUEUE};
+  use crate::test_utils::empty_redis;
+
+  use super::*;
+
+  async fn delayed(publisher: &mut PubRedis, queue: &str) -> Vec<String> {
+    redis::cmd("ZRANGE")
+      .arg(queue_to_delayed(queue))
+      .arg(0)
+      .arg(-1)
+      .query_async(&mut publisher.con)
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn promotes_due_entries_in_due_order() {
+    let db = empty_redis().await;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [later, sooner] = [(); 2].map(|_| Pubkey::new_unique());
+
+    assert_eq!(publisher.schedule(CHECK_QUEUE, &[(later, 1u64)], Duration::from_millis(200), "later").await.unwrap(), 1);
+    assert_eq!(publisher.schedule(CHECK_QUEUE, &[(sooner, 2u64)], Duration::ZERO, "sooner").await.unwrap(), 1);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let reasons = publisher.promote_due(CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(reasons, HashMap::from([("later".to_string(), 1), ("sooner".to_string(), 1)]));
+    assert!(delayed(&mut publisher, CHECK_QUEUE).await.is_empty());
+
+    let read = subscriber.read::<u64>(CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(sooner, 2), (later, 1)]);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn leaves_entries_that_are_not_due() {
+    let db = empty_redis().await;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [due, waiting] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.schedule(CHECK_QUEUE, &[(due, 1u64)], Duration::ZERO, "due").await.unwrap();
+    publisher.schedule(CHECK_QUEUE, &[(waiting, 2u64)], Duration::from_secs(60), "waiting").await.unwrap();
+    // a later due time doesn't move it, an earlier one would
+    assert_eq!(publisher.schedule(CHECK_QUEUE, &[(waiting, 3u64)], Duration::from_secs(120), "later").await.unwrap(), 0);
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(publisher.promote_due(CHECK_QUEUE, 10).await.unwrap(), HashMap::from([("due".to_string(), 1)]));
+    assert_eq!(delayed(&mut publisher, CHECK_QUEUE).await, vec![waiting.to_string()]);
+
+    let read = subscriber.read::<u64>(CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(due, 1)]);
+    assert!(publisher.promote_due(CHECK_QUEUE, 10).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn promoted_accounts_already_waiting_stay_queued_once() {
+    let db = empty_redis().await;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let account = Pubkey::new_unique();
+
+    publisher.publish(CHECK_QUEUE, &[(account, 1u64)]).await.unwrap();
+    publisher.schedule(CHECK_QUEUE, &[(account, 2u64)], Duration::ZERO, "recheck").await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(publisher.promote_due(CHECK_QUEUE, 10).await.unwrap(), HashMap::from([("recheck".to_string(), 1)]));
+
+    // the check queue keeps the entry that was waiting first
+    let read = subscriber.read::<u64>(CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+    assert!(subscriber.read::<u64>(CHECK_QUEUE, 10).await.unwrap().is_empty());
+  }
+}
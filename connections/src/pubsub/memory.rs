@@ -0,0 +1,263 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use solana_pubkey::Pubkey;
+use tokio::sync::{self, mpsc};
+
+use super::{DedupePolicy, Publisher, Subscriber, dedupe_policy, wire::{decode_entry, encode_entry, entry_slot}};
+
+/// The channel carries the order of accounts, their entries wait in `pending` until read, which
+/// is where a replaced payload is swapped without moving the account.
+struct MemoryQueue {
+  tx: mpsc::UnboundedSender<String>,
+  rx: sync::Mutex<mpsc::UnboundedReceiver<String>>,
+  pending: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryQueue {
+  fn new() -> Self {
+    let (tx, rx) = mpsc::unbounded_channel();
+    Self { tx, rx: sync::Mutex::new(rx), pending: Mutex::new(HashMap::new()) }
+  }
+}
+
+/// Queues living in the process, for tests and single-process runs. Entries are encoded like
+/// they are in redis, so payloads that don't decode fail the same way. Reads are final, there
+/// are no leases to ack.
+#[derive(Clone, Default)]
+pub struct MemoryQueues {
+  queues: Arc<Mutex<HashMap<String, Arc<MemoryQueue>>>>,
+}
+
+impl MemoryQueues {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn publisher(&self) -> MemoryPublisher {
+    MemoryPublisher { queues: self.clone() }
+  }
+
+  pub fn subscriber(&self) -> MemorySubscriber {
+    MemorySubscriber { queues: self.clone() }
+  }
+
+  /// Number of entries waiting in `queue`.
+  pub fn queued(&self, queue: &str) -> usize {
+    self.queue(queue).pending.lock().unwrap().len()
+  }
+
+  fn queue(&self, queue: &str) -> Arc<MemoryQueue> {
+    self.queues
+      .lock()
+      .unwrap()
+      .entry(queue.to_string())
+      .or_insert_with(|| Arc::new(MemoryQueue::new()))
+      .clone()
+  }
+}
+
+#[derive(Clone)]
+pub struct MemoryPublisher {
+  queues: MemoryQueues,
+}
+
+impl Publisher for MemoryPublisher {
+  async fn publish<T: Serialize + Sync>(&mut self, queue: &str, items: &[(Pubkey, T)]) -> anyhow::Result<Vec<Pubkey>> {
+    let policy = dedupe_policy(queue);
+    let queue = self.queues.queue(queue);
+    let mut pending = queue.pending.lock().unwrap();
+
+    let mut pushed = Vec::new();
+    for (pubkey, payload) in items {
+      let entry = encode_entry(pubkey, payload)?;
+      let account = pubkey.to_string();
+      match pending.get_mut(&account) {
+        Some(queued) => match policy {
+          DedupePolicy::KeepFirst => {},
+          DedupePolicy::ReplacePayload => *queued = entry,
+          DedupePolicy::ReplaceNewer => if entry_slot(queued)? <= entry_slot(&entry)? {
+            *queued = entry;
+          },
+        },
+        None => {
+          pending.insert(account.clone(), entry);
+          queue.tx.send(account)?;
+          pushed.push(*pubkey);
+        }
+      }
+    }
+
+    Ok(pushed)
+  }
+}
+
+#[derive(Clone)]
+pub struct MemorySubscriber {
+  queues: MemoryQueues,
+}
+
+impl MemorySubscriber {
+  async fn read_with_timeout<T: for<'de> Deserialize<'de>>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Option<Duration>,
+  ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    if batch_size == 0 {
+      return Ok(Vec::new());
+    }
+
+    let queue = self.queues.queue(queue);
+    let mut rx = queue.rx.lock().await;
+
+    let mut accounts = Vec::with_capacity(batch_size);
+    if let Some(timeout) = timeout.filter(|_| rx.is_empty()) {
+      accounts.extend(tokio::time::timeout(timeout, rx.recv()).await.ok().flatten());
+    }
+    while accounts.len() < batch_size {
+      match rx.try_recv() {
+        Ok(account) => accounts.push(account),
+        Err(_) => break,
+      }
+    }
+    drop(rx);
+
+    let mut pending = queue.pending.lock().unwrap();
+    let raw: Vec<String> = accounts.iter().filter_map(|account| pending.remove(account)).collect();
+
+    Ok(raw.iter().map(|entry| decode_entry(entry)).collect())
+  }
+}
+
+impl Subscriber for MemorySubscriber {
+  async fn read<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+  ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    self.read_with_timeout(queue, batch_size, None).await
+  }
+
+  async fn read_blocking<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Duration,
+  ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    self.read_with_timeout(queue, batch_size, Some(timeout)).await
+  }
+
+  async fn ack(&mut self, _queue: &str, _pubkeys: &[Pubkey]) -> anyhow::Result<usize> {
+    Ok(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::queue_keys;
+
+  fn payloads<T>(batch: Vec<anyhow::Result<(Pubkey, T)>>) -> Vec<(Pubkey, T)> {
+    batch.into_iter().map(Result::unwrap).collect()
+  }
+
+  #[tokio::test]
+  async fn reads_in_publish_order_up_to_the_batch_size() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let [a, b, c] = [(); 3].map(|_| Pubkey::new_unique());
+
+    let pushed = publisher.publish(queue_keys::CHECK_QUEUE, &[(a, 1u32), (b, 2), (c, 3)]).await.unwrap();
+    assert_eq!(pushed, vec![a, b, c]);
+    assert_eq!(queues.queued(queue_keys::CHECK_QUEUE), 3);
+
+    let first = subscriber.read::<u32>(queue_keys::CHECK_QUEUE, 2).await.unwrap();
+    assert_eq!(payloads(first), vec![(a, 1), (b, 2)]);
+    let rest = subscriber.read::<u32>(queue_keys::CHECK_QUEUE, 2).await.unwrap();
+    assert_eq!(payloads(rest), vec![(c, 3)]);
+    assert!(subscriber.read::<u32>(queue_keys::CHECK_QUEUE, 2).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn keeps_the_first_payload_of_a_waiting_account() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue_keys::CHECK_QUEUE, &[(a, 1u32)]).await.unwrap();
+    let pushed = publisher.publish(queue_keys::CHECK_QUEUE, &[(a, 2u32), (b, 3)]).await.unwrap();
+    assert_eq!(pushed, vec![b]);
+
+    let batch = subscriber.read::<u32>(queue_keys::CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(payloads(batch), vec![(a, 1), (b, 3)]);
+
+    // once read the account can wait again
+    assert_eq!(publisher.publish(queue_keys::CHECK_QUEUE, &[(a, 4u32)]).await.unwrap(), vec![a]);
+  }
+
+  #[tokio::test]
+  async fn replaces_the_payload_in_place() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue_keys::LIQUIDATION_QUEUE, &[(a, 1u32), (b, 2)]).await.unwrap();
+    let pushed = publisher.publish(queue_keys::LIQUIDATION_QUEUE, &[(a, 3u32)]).await.unwrap();
+    assert!(pushed.is_empty());
+
+    let batch = subscriber.read::<u32>(queue_keys::LIQUIDATION_QUEUE, 10).await.unwrap();
+    assert_eq!(payloads(batch), vec![(a, 3), (b, 2)]);
+  }
+
+  #[tokio::test]
+  async fn replaces_account_updates_only_with_newer_slots() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let a = Pubkey::new_unique();
+
+    publisher.publish(queue_keys::ACCOUNT_UPDATE_QUEUE, &[(a, (10u64, 1u32))]).await.unwrap();
+    publisher.publish(queue_keys::ACCOUNT_UPDATE_QUEUE, &[(a, (9u64, 2u32))]).await.unwrap();
+    let batch = subscriber.read::<(u64, u32)>(queue_keys::ACCOUNT_UPDATE_QUEUE, 10).await.unwrap();
+    assert_eq!(payloads(batch), vec![(a, (10, 1))]);
+
+    publisher.publish(queue_keys::ACCOUNT_UPDATE_QUEUE, &[(a, (10u64, 1u32))]).await.unwrap();
+    publisher.publish(queue_keys::ACCOUNT_UPDATE_QUEUE, &[(a, (10u64, 2u32)), (a, (11, 3))]).await.unwrap();
+    let batch = subscriber.read::<(u64, u32)>(queue_keys::ACCOUNT_UPDATE_QUEUE, 10).await.unwrap();
+    assert_eq!(payloads(batch), vec![(a, (11, 3))]);
+  }
+
+  #[tokio::test]
+  async fn blocking_reads_wait_for_a_publish() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let a = Pubkey::new_unique();
+
+    let empty = subscriber.read_blocking::<u32>(queue_keys::CHECK_QUEUE, 10, Duration::from_millis(10)).await.unwrap();
+    assert!(empty.is_empty());
+
+    let reader = tokio::spawn(async move {
+      subscriber.read_blocking::<u32>(queue_keys::CHECK_QUEUE, 10, Duration::from_secs(5)).await.unwrap()
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    publisher.publish(queue_keys::CHECK_QUEUE, &[(a, 1u32)]).await.unwrap();
+
+    assert_eq!(payloads(reader.await.unwrap()), vec![(a, 1)]);
+  }
+
+  #[tokio::test]
+  async fn returns_entries_that_dont_decode_as_errors() {
+    let queues = MemoryQueues::new();
+    let (mut publisher, mut subscriber) = (queues.publisher(), queues.subscriber());
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue_keys::CHECK_QUEUE, &[(a, u64::MAX)]).await.unwrap();
+    publisher.publish(queue_keys::CHECK_QUEUE, &[(b, String::from("ok"))]).await.unwrap();
+
+    let batch = subscriber.read::<String>(queue_keys::CHECK_QUEUE, 10).await.unwrap();
+    assert_eq!(batch.len(), 2);
+    assert!(batch[0].is_err());
+    assert_eq!(batch[1].as_ref().unwrap(), &(b, String::from("ok")));
+    assert_eq!(queues.queued(queue_keys::CHECK_QUEUE), 0);
+  }
+}
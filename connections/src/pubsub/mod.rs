@@ -1,3 +1,16 @@
+mod dead_letter;
+mod delayed;
+mod memory;
+mod priority;
+mod queue;
 mod redis;
+mod stream;
+mod wire;
 
-pub use redis::*;
\ No newline at end of file
+pub use dead_letter::*;
+pub use memory::*;
+pub use priority::{QueueOrder, RankedBatch, Ranking};
+pub use queue::*;
+pub use redis::*;
+pub use stream::{StreamLag, stream_maxlen};
+pub use wire::{SCHEMA_VERSION, WireError, decode_payload, encode_payload};
\ No newline at end of file
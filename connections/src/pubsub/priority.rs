@@ -0,0 +1,231 @@
+use std::{str::FromStr, time::Duration};
+
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use solana_pubkey::Pubkey;
+
+use super::redis::PublishCall;
+
+/// Accounts waiting in the priority variant of a queue, scored by their value.
+pub(super) fn queue_to_ranked(queue: &str) -> String {
+  format!("{}_ranked", queue)
+}
+
+fn queue_to_ranked_entries(queue: &str) -> String {
+  format!("{}_ranked_entries", queue)
+}
+
+fn queue_to_ranked_slots(queue: &str) -> String {
+  format!("{}_ranked_slots", queue)
+}
+
+/// Holds a single data-less token while the priority queue got new accounts nobody waited for
+/// yet. Waiting pops the token instead of an entry, so a wait never takes anything off the queue.
+fn queue_to_ranked_notify(queue: &str) -> String {
+  format!("{}_ranked_notify", queue)
+}
+
+/// Order a queue is read in. Fifo queues support every backend and acknowledged reads, priority
+/// queues hand out the most valuable entry first and their reads are final. Both sides of a queue
+/// have to agree on the order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueOrder {
+  #[default]
+  Fifo,
+  Priority,
+}
+
+impl FromStr for QueueOrder {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "fifo" => Ok(Self::Fifo),
+      "priority" => Ok(Self::Priority),
+      _ => anyhow::bail!("unknown queue order \"{}\", expected \"fifo\" or \"priority\"", s),
+    }
+  }
+}
+
+/// Where an entry of a priority queue ranks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ranking {
+  /// Higher values are read first, e.g. the expected profit in usd
+  pub value: f64,
+  /// Slot the payload was observed at, readers drop entries that got too old
+  pub slot: u64,
+}
+
+impl Ranking {
+  /// Score in the ranked set, values that aren't finite rank last.
+  pub(super) fn score(&self) -> f64 {
+    match self.value.is_finite() {
+      true => self.value,
+      false => f64::MIN,
+    }
+  }
+}
+
+/// Result of a priority read.
+pub struct RankedBatch<T> {
+  pub items: Vec<anyhow::Result<(Pubkey, T)>>,
+  /// Entries dropped on the way for being older than the cutoff slot
+  pub stale: usize,
+}
+
+/// An account waits at most once. Publishing it again replaces its payload and ranking, unless
+/// the queued payload was observed at a later slot.
+const PUBLISH_SCRIPT: &str = r#"
+  local pushed = {}
+  local n      = #ARGV / 4
+
+  for i = 1, n do
+    local account = ARGV[(i-1)*4 + 1]
+    local slot    = tonumber(ARGV[(i-1)*4 + 4])
+    local queued  = redis.call('HGET', KEYS[3], account)
+
+    if not queued or tonumber(queued) <= slot then
+      if redis.call('ZADD', KEYS[1], ARGV[(i-1)*4 + 3], account) == 1 then
+        table.insert(pushed, account)
+      end
+      redis.call('HSET', KEYS[2], account, ARGV[(i-1)*4 + 2])
+      redis.call('HSET', KEYS[3], account, slot)
+    end
+  end
+
+  if #pushed > 0 then
+    redis.call('DEL', KEYS[4])
+    redis.call('RPUSH', KEYS[4], 1)
+  end
+
+  return pushed
+"#;
+
+/// `args` are `account, entry, score, slot` quadruples, see `Ranking::score`.
+pub(super) fn publish_call(queue: &str, args: Vec<String>) -> PublishCall {
+  let keys = vec![
+    queue_to_ranked(queue),
+    queue_to_ranked_entries(queue),
+    queue_to_ranked_slots(queue),
+    queue_to_ranked_notify(queue),
+  ];
+
+  PublishCall { script: PUBLISH_SCRIPT.to_string(), keys, args }
+}
+
+/// Stale entries are popped and dropped until enough fresh ones are found or the queue runs dry.
+const TAKE_SCRIPT: &str = r#"
+  local n        = tonumber(ARGV[1])
+  local min_slot = tonumber(ARGV[2])
+  local items    = {}
+  local stale    = 0
+
+  while #items < n do
+    local top = redis.call('ZPOPMAX', KEYS[1])
+    if #top == 0 then break end
+
+    local account = top[1]
+    local entry   = redis.call('HGET', KEYS[2], account)
+    local slot    = tonumber(redis.call('HGET', KEYS[3], account) or '0')
+    redis.call('HDEL', KEYS[2], account)
+    redis.call('HDEL', KEYS[3], account)
+
+    if entry then
+      if slot < min_slot then
+        stale = stale + 1
+      else
+        table.insert(items, entry)
+      end
+    end
+  end
+
+  if redis.call('ZCARD', KEYS[1]) == 0 then redis.call('DEL', KEYS[4]) end
+
+  return { stale, items }
+"#;
+
+/// Returns the number of stale entries dropped and the taken entries, most valuable first.
+pub(super) async fn take(con: &mut ConnectionManager, queue: &str, batch_size: usize, min_slot: u64) -> anyhow::Result<(usize, Vec<String>)> {
+  let script = redis::Script::new(TAKE_SCRIPT);
+  let mut inv = script.prepare_invoke();
+  inv
+    .key(queue_to_ranked(queue))
+    .key(queue_to_ranked_entries(queue))
+    .key(queue_to_ranked_slots(queue))
+    .key(queue_to_ranked_notify(queue))
+    .arg(batch_size)
+    .arg(min_slot);
+
+  Ok(inv.invoke_async(con).await?)
+}
+
+/// Blocks until the priority queue has an entry or `timeout` passes. Only the notify token is
+/// popped, the entries stay ranked where they are and are taken by the regular script afterwards.
+/// Another consumer may win the race, the read is empty then.
+pub(super) async fn wait(waiter: &mut ConnectionManager, queue: &str, timeout: Duration) -> anyhow::Result<bool> {
+  let waiting: usize = redis::cmd("ZCARD")
+    .arg(queue_to_ranked(queue))
+    .query_async(waiter)
+    .await?;
+  if waiting > 0 {
+    return Ok(true);
+  }
+
+  let token: Option<(String, String)> = redis::cmd("BLPOP")
+    .arg(queue_to_ranked_notify(queue))
+    .arg(timeout.as_secs_f64())
+    .query_async(waiter)
+    .await?;
+
+  Ok(token.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::pubsub::{PubRedis, SubRedis};
+  use crate::test_utils::empty_redis;
+
+  use super::*;
+
+  const QUEUE: &str = "ranked_test";
+
+  fn ranking(value: f64) -> Ranking {
+    Ranking { value, slot: 1 }
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn waits_leave_entries_ranked() {
+    let db = empty_redis().await;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [low, high] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish_ranked(QUEUE, &[(low, 1u64, ranking(1.0)), (high, 2u64, ranking(2.0))]).await.unwrap();
+    assert!(wait(&mut publisher.con, QUEUE, Duration::from_millis(100)).await.unwrap());
+    assert!(wait(&mut publisher.con, QUEUE, Duration::from_millis(100)).await.unwrap());
+
+    let batch = subscriber.read_ranked::<u64>(QUEUE, 10, 0, None).await.unwrap();
+    assert_eq!(batch.items.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(high, 2), (low, 1)]);
+    assert!(!wait(&mut publisher.con, QUEUE, Duration::from_millis(100)).await.unwrap());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn waits_wake_up_on_a_publish() {
+    let db = empty_redis().await;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let account = Pubkey::new_unique();
+
+    let read = tokio::spawn(async move {
+      subscriber.read_ranked::<u64>(QUEUE, 10, 0, Some(Duration::from_secs(5))).await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    publisher.publish_ranked(QUEUE, &[(account, 1u64, ranking(1.0))]).await.unwrap();
+
+    let batch = read.await.unwrap().unwrap();
+    assert_eq!(batch.items.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+  }
+}
\ No newline at end of file
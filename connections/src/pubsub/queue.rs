@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use solana_pubkey::Pubkey;
+
+use super::queue_keys;
+
+/// What publishing an account that is already waiting in a queue does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupePolicy {
+  /// The waiting entry stays as it is and the new payload is dropped
+  KeepFirst,
+  /// The new payload replaces the waiting one, the entry keeps its place in the queue
+  ReplacePayload,
+  /// Like `ReplacePayload`, but only payloads observed at the same or a later slot replace the
+  /// waiting one. Payloads of these queues are `(u64, T)` tuples leading with that slot.
+  ReplaceNewer,
+}
+
+impl DedupePolicy {
+  /// How the publish scripts are told the policy.
+  pub(super) fn script_arg(self) -> &'static str {
+    match self {
+      Self::KeepFirst => "keep",
+      Self::ReplacePayload => "replace",
+      Self::ReplaceNewer => "newer",
+    }
+  }
+}
+
+/// Liquidation candidates are acted on as they were queued, so a stale snapshot there would be
+/// liquidated against outdated balances. Account updates feed the local health book, which
+/// needs the latest balances but must never go back to older ones.
+pub fn dedupe_policy(queue: &str) -> DedupePolicy {
+  match queue {
+    queue_keys::LIQUIDATION_QUEUE => DedupePolicy::ReplacePayload,
+    queue_keys::ACCOUNT_UPDATE_QUEUE => DedupePolicy::ReplaceNewer,
+    _ => DedupePolicy::KeepFirst,
+  }
+}
+
+/// Publishing side of a queue. An account waits in a queue at most once, publishing it again
+/// before it's read follows the queue's `dedupe_policy`.
+pub trait Publisher: Send {
+  /// Returns the accounts that were newly queued, the ones already waiting are left out even
+  /// when their payload got replaced.
+  fn publish<T: Serialize + Sync>(&mut self, queue: &str, items: &[(Pubkey, T)]) -> impl Future<Output = anyhow::Result<Vec<Pubkey>>> + Send;
+
+  fn builder<'a, T: Serialize + Sync>(&'a mut self, queue: &'a str) -> PublishBuilder<'a, T, Self> where Self: Sized {
+    PublishBuilder {
+      publisher: self,
+      queue,
+      items: Vec::new(),
+    }
+  }
+}
+
+/// Reading side of a queue. Entries that don't decode come back as errors in the batch.
+pub trait Subscriber: Send {
+  /// Takes up to `batch_size` entries, returns right away when the queue is empty.
+  fn read<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+  ) -> impl Future<Output = anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>>> + Send;
+
+  /// Like `read`, but waits up to `timeout` for the queue to fill when it's empty.
+  fn read_blocking<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Duration,
+  ) -> impl Future<Output = anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>>> + Send;
+
+  /// Marks the entries of these accounts as done. Returns how many entries were acked, zero
+  /// when reads are already final.
+  fn ack(&mut self, queue: &str, pubkeys: &[Pubkey]) -> impl Future<Output = anyhow::Result<usize>> + Send;
+
+  fn builder<'a, T: for<'de> Deserialize<'de> + Send>(&'a mut self, queue: &'a str, batch_size: usize) -> ReadBuilder<'a, T, Self> where Self: Sized {
+    ReadBuilder {
+      subscriber: self,
+      queue,
+      batch_size,
+      block: None,
+      _marker: std::marker::PhantomData
+    }
+  }
+}
+
+pub struct PublishBuilder<'a, T: Serialize + Sync, P: Publisher> {
+  publisher: &'a mut P,
+  queue: &'a str,
+  items: Vec<(Pubkey, T)>,
+}
+
+impl<'a, T: Serialize + Sync, P: Publisher> PublishBuilder<'a, T, P> {
+  pub fn item(mut self, pubkey: Pubkey, payload: T) -> Self {
+    self.items.push((pubkey, payload));
+    self
+  }
+
+  pub fn items(mut self, items: impl IntoIterator<Item = (Pubkey, T)>) -> Self {
+    self.items.extend(items);
+    self
+  }
+
+  pub async fn send(self) -> anyhow::Result<Vec<Pubkey>> {
+    self.publisher.publish(self.queue, &self.items).await
+  }
+}
+
+pub struct ReadBuilder<'a, T: for<'de> Deserialize<'de> + Send, S: Subscriber> {
+  subscriber: &'a mut S,
+  queue: &'a str,
+  batch_size: usize,
+  block: Option<Duration>,
+  _marker: std::marker::PhantomData<T>,
+}
+
+impl<'a, T: for<'de> Deserialize<'de> + Send, S: Subscriber> ReadBuilder<'a, T, S> {
+  pub fn batch_size(mut self, n: usize) -> Self {
+      self.batch_size = n;
+      self
+  }
+
+  /// Wait up to `timeout` for entries instead of returning an empty batch right away.
+  pub fn block(mut self, timeout: Duration) -> Self {
+      self.block = Some(timeout);
+      self
+  }
+
+  pub async fn recv(self) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    match self.block {
+      Some(timeout) => self.subscriber.read_blocking(self.queue, self.batch_size, timeout).await,
+      None => self.subscriber.read(self.queue, self.batch_size).await,
+    }
+  }
+}
\ No newline at end of file
@@ -1,47 +1,112 @@
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 
+use anyhow::Context;
 use redis::aio::{ConnectionManager, ConnectionManagerConfig};
 use serde::{Deserialize, Serialize};
 use solana_pubkey::Pubkey;
+use tokio::task::JoinHandle;
+
+use super::{DEFAULT_MAX_DELIVERIES, DeadLetter, DedupePolicy, Publisher, Subscriber, dead_letter::queue_to_dead_letters, dedupe_policy, priority::{self, RankedBatch, Ranking}, stream::{self, StreamLag}, wire::{decode_entry, encode_entry, entry_account, entry_slot}};
 
 pub mod queue_keys {
   pub const ADD_QUEUE: &str = "accounts_add_queue";
   pub const CHECK_QUEUE: &str = "accounts_check_queue";
+  pub const ACCOUNT_UPDATE_QUEUE: &str = "accounts_update_queue";
   pub const LIQUIDATION_QUEUE: &str = "accounts_liquidation_queue";
   pub const REM_QUEUE: &str = "accounts_rem_queue";
   pub const BANK_ADD_QUEUE: &str = "bank_add_queue";
   pub const BANK_REM_QUEUE: &str = "bank_rem_queue";
 }
 
+/// How long a leased entry may stay unacknowledged before the reaper hands it to someone else.
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(60);
+
 fn queue_to_pending_set(queue: &str) -> String {
   format!("{}_pending", queue)
 }
 
-pub struct PublishBuilder<'a, T: Serialize> {
-  redis: &'a mut PubRedis,
-  queue: &'a str,
-  items: Vec<(Pubkey, T)>,
+fn queue_to_processing_list(queue: &str, consumer: &str) -> String {
+  format!("{}_processing:{}", queue, consumer)
 }
 
-impl<'a, T: Serialize> PublishBuilder<'a, T> {
-  pub fn item(mut self, pubkey: Pubkey, payload: T) -> Self {
-    self.items.push((pubkey, payload));
-    self
-  }
+/// Lease deadlines of the entries in a consumer's processing list.
+fn queue_to_leases(queue: &str, consumer: &str) -> String {
+  format!("{}_leases:{}", queue, consumer)
+}
 
-  pub fn items(mut self, items: impl IntoIterator<Item = (Pubkey, T)>) -> Self {
-    self.items.extend(items);
-    self
+/// Consumers that may hold leased entries, which is where the reaper finds their keys.
+fn queue_to_consumers(queue: &str) -> String {
+  format!("{}_consumers", queue)
+}
+
+pub(super) fn queue_to_deliveries(queue: &str) -> String {
+  format!("{}_deliveries", queue)
+}
+
+/// Waiting entry of each account, only kept for queues that replace payloads.
+fn queue_to_entries(queue: &str) -> String {
+  format!("{}_entries", queue)
+}
+
+/// Slot of the waiting entry of each account, only kept for `DedupePolicy::ReplaceNewer` queues.
+fn queue_to_slots(queue: &str) -> String {
+  format!("{}_slots", queue)
+}
+
+/// Slot of each leased entry, so a requeued entry still defends its place against older ones.
+/// Kept alongside `queue_to_slots` until the entry is acked, dead-lettered or requeued.
+fn queue_to_leased_slots(queue: &str) -> String {
+  format!("{}_leased_slots", queue)
+}
+
+/// Where queue entries live. Lists are the original layout, streams add consumer groups,
+/// replay and lag inspection. Both sides of a queue have to agree on the backend.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+  #[default]
+  List,
+  Stream,
+}
+
+impl FromStr for QueueBackend {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "list" => Ok(Self::List),
+      "stream" => Ok(Self::Stream),
+      _ => anyhow::bail!("unknown queue backend \"{}\", expected \"list\" or \"stream\"", s),
+    }
   }
+}
+
+impl QueueBackend {
+  /// Backend named by `PUBSUB_BACKEND`, lists when it's unset or empty.
+  pub fn from_env() -> anyhow::Result<Self> {
+    let backend = std::env::var("PUBSUB_BACKEND")
+      .ok()
+      .filter(|s| !s.is_empty())
+      .map(|s| s.parse::<Self>())
+      .transpose()
+      .context("invalid \"PUBSUB_BACKEND\" value")?
+      .unwrap_or_default();
 
-  pub async fn send(self) -> anyhow::Result<Vec<Pubkey>> {
-    self.redis.publish(self.queue, &self.items).await
+    Ok(backend)
   }
 }
 
+#[derive(Clone)]
+struct Lease {
+  consumer: String,
+  visibility_timeout: Duration,
+  max_deliveries: u32,
+}
+
 #[derive(Clone)]
 pub struct PubRedis {
-  con: ConnectionManager
+  pub(super) con: ConnectionManager,
+  backend: QueueBackend,
 }
 
 impl PubRedis {
@@ -51,19 +116,49 @@ impl PubRedis {
       .set_response_timeout(Some(Duration::from_secs(60)));
     let con = client.get_connection_manager_with_config(config).await?;
 
-    let publish = Self { con };
+    let publish = Self { con, backend: QueueBackend::List };
 
     Ok(publish)
   }
 
-  pub fn builder<'a, T: Serialize>(&'a mut self, queue: &'a str) -> PublishBuilder<'a, T> {
-    PublishBuilder {
-      redis: self,
-      queue,
-      items: Vec::new(),
-    }
+  pub fn with_backend(mut self, backend: QueueBackend) -> Self {
+    self.backend = backend;
+    self
   }
 
+  /// A replaced entry is found through the entries hash and overwritten where it sits in the list.
+  /// Under the `newer` policy the slots hash decides whether it's replaced at all. New and replaced
+  /// entries start without deliveries, the count of a superseded entry isn't carried over.
+  const PUBLISH_SCRIPT: &str = r#"
+    local policy = ARGV[1]
+    local pushed = {}
+    local n      = (#ARGV - 1) / 3
+
+    for i = 1, n do
+      local account = ARGV[(i-1)*3 + 2]
+      local entry   = ARGV[(i-1)*3 + 3]
+      local slot    = tonumber(ARGV[(i-1)*3 + 4])
+      if redis.call('SADD', KEYS[1], account) == 1 then
+        redis.call('RPUSH', KEYS[2], entry)
+        if policy ~= 'keep' then redis.call('HSET', KEYS[3], account, entry) end
+        if policy == 'newer' then redis.call('HSET', KEYS[4], account, slot) end
+        redis.call('HDEL', KEYS[5], account)
+        table.insert(pushed, account)
+      elseif policy == 'replace' or (policy == 'newer' and tonumber(redis.call('HGET', KEYS[4], account) or '0') <= slot) then
+        local queued = redis.call('HGET', KEYS[3], account)
+        local index  = queued and redis.call('LPOS', KEYS[2], queued)
+        if index then
+          redis.call('LSET', KEYS[2], index, entry)
+          redis.call('HSET', KEYS[3], account, entry)
+          if policy == 'newer' then redis.call('HSET', KEYS[4], account, slot) end
+          redis.call('HDEL', KEYS[5], account)
+        end
+      end
+    end
+
+    return pushed
+  "#;
+
   pub async fn publish<T: Serialize>(
     &mut self,
     queue: &str,
@@ -75,47 +170,136 @@ impl PubRedis {
 
     let mut args: Vec<String> = Vec::with_capacity(items.len() * 2);
     for (pubkey, payload) in items {
-      let entry = bincode::serialize(&(pubkey.to_bytes(), payload))?;
-      let pubkey_str = pubkey.to_string();
-      let list_value = format!("{}|{}", pubkey_str, hex::encode(entry));
-      args.push(pubkey_str);
-      args.push(list_value);
+      args.push(pubkey.to_string());
+      args.push(encode_entry(pubkey, payload)?);
     }
 
-    let pending_set = queue_to_pending_set(queue);
-    let script = redis::Script::new(r"
-      local pushed = {}
-      local n = #ARGV / 2
-
-      for i = 1, n do
-        local account = ARGV[(i-1)*2 + 1]
-        local entry   = ARGV[(i-1)*2 + 2]
-        if redis.call('SADD', KEYS[1], account) == 1 then
-          redis.call('RPUSH', KEYS[2], entry)
-          table.insert(pushed, account)
-        end
-      end
+    let raw = self.publish_entries(queue, &args).await?;
 
-      return pushed
-    ");
+    Ok(raw.iter().filter_map(|s| s.parse().ok()).collect())
+  }
 
-    let mut inv = script.prepare_invoke();
-    inv.key(&pending_set).key(queue);
-    for arg in &args {
-      inv.arg(arg);
+  /// Publishes already encoded entries, `args` are `account, entry` pairs. Returns the accounts
+  /// that were newly queued.
+  pub(super) async fn publish_entries(&mut self, queue: &str, args: &[String]) -> anyhow::Result<Vec<String>> {
+    self.publish_entries_call(queue, args)?.invoke(&mut self.con.clone()).await
+  }
+
+  /// Script publishing already encoded entries through this backend, see `publish_entries`.
+  pub(super) fn publish_entries_call(&self, queue: &str, args: &[String]) -> anyhow::Result<PublishCall> {
+    let policy = dedupe_policy(queue);
+
+    // the scripts take `account, entry, slot` triples, the slot only matters to `ReplaceNewer`
+    let mut slotted = Vec::with_capacity(args.len() / 2 * 3);
+    for pair in args.chunks(2) {
+      let slot = match policy {
+        DedupePolicy::ReplaceNewer => entry_slot(&pair[1])?,
+        _ => 0,
+      };
+      slotted.extend_from_slice(pair);
+      slotted.push(slot.to_string());
     }
 
-    let raw: Vec<String> = inv
-      .invoke_async(&mut self.con.clone())
-      .await?;
+    if self.backend == QueueBackend::Stream {
+      return Ok(stream::publish_call(queue, policy, slotted));
+    }
+
+    let keys = vec![
+      queue_to_pending_set(queue),
+      queue.to_string(),
+      queue_to_entries(queue),
+      queue_to_slots(queue),
+      queue_to_deliveries(queue),
+    ];
+    let mut script_args = vec![policy.script_arg().to_string()];
+    script_args.extend(slotted);
+
+    Ok(PublishCall { script: Self::PUBLISH_SCRIPT.to_string(), keys, args: script_args })
+  }
+
+  /// Publishes into the priority variant of `queue`, see `QueueOrder`. Values that aren't finite
+  /// rank last. Returns the accounts that were newly queued.
+  pub async fn publish_ranked<T: Serialize>(
+    &mut self,
+    queue: &str,
+    items: &[(Pubkey, T, Ranking)],
+  ) -> anyhow::Result<Vec<Pubkey>> {
+    if items.is_empty() {
+      return Ok(Vec::new());
+    }
+
+    let mut args: Vec<String> = Vec::with_capacity(items.len() * 4);
+    for (pubkey, payload, ranking) in items {
+      args.push(pubkey.to_string());
+      args.push(encode_entry(pubkey, payload)?);
+      args.push(ranking.score().to_string());
+      args.push(ranking.slot.to_string());
+    }
+
+    let raw = priority::publish_call(queue, args).invoke(&mut self.con).await?;
 
     Ok(raw.iter().filter_map(|s| s.parse().ok()).collect())
   }
 }
 
-#[derive(Clone)]
+/// A publish script with its keys and arguments. Returns the accounts that were newly queued.
+pub(super) struct PublishCall {
+  pub(super) script: String,
+  pub(super) keys: Vec<String>,
+  pub(super) args: Vec<String>,
+}
+
+impl PublishCall {
+  pub(super) async fn invoke(self, con: &mut ConnectionManager) -> anyhow::Result<Vec<String>> {
+    let script = redis::Script::new(&self.script);
+    let mut inv = script.prepare_invoke();
+    for key in &self.keys {
+      inv.key(key);
+    }
+    for arg in &self.args {
+      inv.arg(arg);
+    }
+
+    Ok(inv.invoke_async(con).await?)
+  }
+}
+
+/// Wait used by the pipeline consumers, short enough to notice shutdowns and reconnects quickly.
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a blocking read may wait, it has to stay below the connection's response timeout.
+pub const MAX_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads queue entries. By default an entry is gone once it's read. With a consumer set, reads are
+/// at-least-once: entries move to the consumer's processing list and come back to the queue when
+/// they aren't acked within the visibility timeout.
 pub struct SubRedis {
-  con: ConnectionManager
+  client: redis::Client,
+  con: ConnectionManager,
+  /// Connection parked on blocking waits. Never shared between clones so one consumer's wait
+  /// can't hold up another's reads or acks.
+  waiter: Option<ConnectionManager>,
+  /// Wait still parked on the server. Outlives a cancelled read so the next one picks it up
+  /// instead of stacking another blocking command behind it.
+  wait: Option<(String, JoinHandle<anyhow::Result<bool>>)>,
+  /// Newest stream id seen by the last read of each queue, where a stream wait starts from
+  stream_tail: Option<(String, String)>,
+  lease: Option<Lease>,
+  backend: QueueBackend,
+}
+
+impl Clone for SubRedis {
+  fn clone(&self) -> Self {
+    Self {
+      client: self.client.clone(),
+      con: self.con.clone(),
+      waiter: None,
+      wait: None,
+      stream_tail: None,
+      lease: self.lease.clone(),
+      backend: self.backend,
+    }
+  }
 }
 
 impl SubRedis {
@@ -125,90 +309,886 @@ impl SubRedis {
       .set_response_timeout(Some(Duration::from_secs(60)));
     let con = client.get_connection_manager_with_config(config).await?;
 
-    let subscribe = Self { con };
+    let subscribe = Self { client, con, waiter: None, wait: None, stream_tail: None, lease: None, backend: QueueBackend::List };
 
     Ok(subscribe)
   }
 
-  pub fn builder<'a, T: for<'de> Deserialize<'de>>(&'a mut self, queue: &'a str, batch_size: usize) -> ReadBuilder<'a, T> {
-    ReadBuilder {
-      redis: self,
-      queue,
-      batch_size,
-      _marker: std::marker::PhantomData
+  /// Switches to acknowledged reads under `consumer`, which has to be unique per process.
+  pub fn with_consumer(mut self, consumer: impl Into<String>) -> Self {
+    self.lease = Some(Lease {
+      consumer: consumer.into(),
+      visibility_timeout: DEFAULT_VISIBILITY_TIMEOUT,
+      max_deliveries: DEFAULT_MAX_DELIVERIES,
+    });
+    self
+  }
+
+  pub fn with_backend(mut self, backend: QueueBackend) -> Self {
+    self.backend = backend;
+    self
+  }
+
+  pub fn with_visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+    if let Some(lease) = self.lease.as_mut() {
+      lease.visibility_timeout = visibility_timeout;
+    }
+    self
+  }
+
+  /// Deliveries after which a failing entry is dead-lettered, zero keeps redelivering it forever.
+  pub fn with_max_deliveries(mut self, max_deliveries: u32) -> Self {
+    if let Some(lease) = self.lease.as_mut() {
+      lease.max_deliveries = max_deliveries;
     }
+    self
   }
 
+  /// Takes up to `batch_size` entries, returns right away when the queue is empty.
   pub async fn read<T: for<'de> Deserialize<'de>>(
     &mut self,
     queue: &str,
     batch_size: usize,
+  ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    self.read_with_timeout(queue, batch_size, None).await
+  }
+
+  /// Like `read`, but waits up to `timeout` for the queue to fill when it's empty.
+  pub async fn read_blocking<T: for<'de> Deserialize<'de>>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Duration,
+  ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
+    self.read_with_timeout(queue, batch_size, Some(timeout)).await
+  }
+
+  async fn read_with_timeout<T: for<'de> Deserialize<'de>>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Option<Duration>,
   ) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
     if batch_size == 0 {
       return Ok(Vec::new());
     }
 
+    let mut raw = self.take(queue, batch_size).await?;
+    if let Some(timeout) = timeout.filter(|_| raw.is_empty()) {
+      let ready = self.wait_for_entries(queue, timeout).await?;
+      if ready {
+        raw = self.take(queue, batch_size).await?;
+      }
+    }
+
+    let mut malformed = Vec::new();
+    let items = raw
+      .into_iter()
+      .map(|s| {
+        let item = decode_entry(&s);
+        if let Err(err) = &item {
+          malformed.push((s, err.to_string()));
+        }
+        item
+      })
+      .collect();
+
+    // nobody can ever ack an entry that doesn't decode, park it instead of redelivering it forever
+    if !malformed.is_empty() {
+      self.dead_letter_malformed(queue, malformed).await?;
+    }
+
+    Ok(items)
+  }
+
+  /// Takes up to `batch_size` of the most valuable entries from the priority variant of `queue`,
+  /// see `QueueOrder`. Entries observed before `min_slot` are dropped on the way. Waits up to
+  /// `timeout` when nothing is queued. Reads are final, there are no leases to ack.
+  pub async fn read_ranked<T: for<'de> Deserialize<'de>>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    min_slot: u64,
+    timeout: Option<Duration>,
+  ) -> anyhow::Result<RankedBatch<T>> {
+    if batch_size == 0 {
+      return Ok(RankedBatch { items: Vec::new(), stale: 0 });
+    }
+
+    let (mut stale, mut raw) = priority::take(&mut self.con, queue, batch_size, min_slot).await?;
+    if let Some(timeout) = timeout.filter(|_| raw.is_empty()) {
+      let ready = self.wait_for_ranked(queue, timeout).await?;
+      if ready {
+        let (more_stale, taken) = priority::take(&mut self.con, queue, batch_size, min_slot).await?;
+        stale += more_stale;
+        raw = taken;
+      }
+    }
+
+    let mut malformed = Vec::new();
+    let items = raw
+      .into_iter()
+      .map(|s| {
+        let item = decode_entry(&s);
+        if let Err(err) = &item {
+          malformed.push(DeadLetter::new(s, err.to_string(), 1));
+        }
+        item
+      })
+      .collect();
+
+    if !malformed.is_empty() {
+      self.push_dead_letters(queue, malformed).await?;
+    }
+
+    Ok(RankedBatch { items, stale })
+  }
+
+  /// Moves `(entry, error)` pairs to the dead-letter list, acking them when they're leased.
+  async fn dead_letter_malformed(&mut self, queue: &str, malformed: Vec<(String, String)>) -> anyhow::Result<()> {
+    let accounts: Vec<String> = malformed.iter().map(|(entry, _)| entry_account(entry).to_string()).collect();
+    let attempts = self.account_delivery_counts(queue, &accounts).await?;
+    self.ack_accounts(queue, &accounts).await?;
+
+    let letters = malformed
+      .into_iter()
+      .zip(attempts)
+      .map(|((entry, error), attempts)| DeadLetter::new(entry, error, attempts.max(1)))
+      .collect();
+
+    self.push_dead_letters(queue, letters).await
+  }
+
+  async fn push_dead_letters(&mut self, queue: &str, letters: Vec<DeadLetter>) -> anyhow::Result<()> {
+    let mut cmd = redis::cmd("RPUSH");
+    cmd.arg(queue_to_dead_letters(queue));
+    for letter in &letters {
+      cmd.arg(serde_json::to_string(letter)?);
+    }
+    let _: () = cmd.query_async(&mut self.con).await?;
+
+    Ok(())
+  }
+
+  async fn take(&mut self, queue: &str, batch_size: usize) -> anyhow::Result<Vec<String>> {
+    if self.backend == QueueBackend::Stream {
+      let consumer = self.lease.as_ref().map(|lease| lease.consumer.clone());
+      let visibility_timeout = self.lease.as_ref().map(|lease| lease.visibility_timeout).unwrap_or(DEFAULT_VISIBILITY_TIMEOUT);
+      let max_deliveries = self.lease.as_ref().map(|lease| lease.max_deliveries).unwrap_or_default();
+      let taken = stream::take(&mut self.con, queue, batch_size, consumer.as_deref(), visibility_timeout, max_deliveries).await?;
+      self.stream_tail = Some((queue.to_string(), taken.tail));
+      return Ok(taken.entries);
+    }
+
+    match self.lease.clone() {
+      Some(lease) => self.read_leased(queue, batch_size, &lease).await,
+      None => self.read_unleased(queue, batch_size).await,
+    }
+  }
+
+  /// Blocks until `queue` has an entry or `timeout` passes. Scripts can't block, so on lists this
+  /// waits with a `BLMOVE` of the queue onto itself: the head is handed back to the head, nothing
+  /// is consumed, and the entries are then taken by the regular read script with the pending set
+  /// and leases kept in sync. Streams wait with an `XREAD` past the last seen id instead.
+  /// Another consumer may win the race, the read is empty then.
+  async fn wait_for_entries(&mut self, queue: &str, timeout: Duration) -> anyhow::Result<bool> {
+    if !matches!(&self.wait, Some((waiting_on, _)) if waiting_on == queue) {
+      let mut waiter = self.waiter().await?;
+      let queue = queue.to_string();
+      let timeout = timeout.min(MAX_BLOCK_TIMEOUT);
+
+      let stream_tail = match (&self.stream_tail, self.backend) {
+        (Some((tail_queue, tail)), QueueBackend::Stream) if tail_queue == &queue => Some(tail.clone()),
+        (_, QueueBackend::Stream) => Some("$".to_string()),
+        (_, QueueBackend::List) => None,
+      };
+
+      let wait = tokio::spawn({
+        let queue = queue.clone();
+        async move {
+          if let Some(tail) = stream_tail {
+            return stream::wait(&mut waiter, &queue, &tail, timeout).await;
+          }
+
+          let head: Option<String> = redis::cmd("BLMOVE")
+            .arg(&queue)
+            .arg(&queue)
+            .arg("LEFT")
+            .arg("LEFT")
+            .arg(timeout.as_secs_f64())
+            .query_async(&mut waiter)
+            .await?;
+
+          Ok(head.is_some())
+        }
+      });
+      self.wait = Some((queue, wait));
+    }
+
+    self.join_wait().await
+  }
+
+  /// Blocks until the priority variant of `queue` has an entry or `timeout` passes. Parked like
+  /// the other waits, under the key of the priority queue.
+  async fn wait_for_ranked(&mut self, queue: &str, timeout: Duration) -> anyhow::Result<bool> {
+    let key = priority::queue_to_ranked(queue);
+    if !matches!(&self.wait, Some((waiting_on, _)) if waiting_on == &key) {
+      let mut waiter = self.waiter().await?;
+      let queue = queue.to_string();
+      let timeout = timeout.min(MAX_BLOCK_TIMEOUT);
+
+      let wait = tokio::spawn(async move {
+        priority::wait(&mut waiter, &queue, timeout).await
+      });
+      self.wait = Some((key, wait));
+    }
+
+    self.join_wait().await
+  }
+
+  async fn join_wait(&mut self) -> anyhow::Result<bool> {
+    let ready = match self.wait.as_mut() {
+      Some((_, wait)) => wait.await,
+      None => return Ok(false),
+    };
+    self.wait = None;
+
+    ready?
+  }
+
+  async fn waiter(&mut self) -> anyhow::Result<ConnectionManager> {
+    if let Some(waiter) = &self.waiter {
+      return Ok(waiter.clone());
+    }
+
+    let config = ConnectionManagerConfig::new()
+      .set_response_timeout(Some(MAX_BLOCK_TIMEOUT * 2));
+    let waiter = self.client.get_connection_manager_with_config(config).await?;
+    self.waiter = Some(waiter.clone());
+
+    Ok(waiter)
+  }
+
+  const READ_SCRIPT: &str = r#"
+    local n     = tonumber(ARGV[1])
+    local items = {}
+
+    for i = 1, n do
+      local entry = redis.call('LPOP', KEYS[2])
+      if not entry then break end
+
+      local sep = string.find(entry, '|')
+      if sep then
+        local account = string.sub(entry, 1, sep - 1)
+        redis.call('SREM', KEYS[1], account)
+        redis.call('HDEL', KEYS[3], account)
+        redis.call('HDEL', KEYS[4], account)
+      end
+
+      table.insert(items, entry)
+    end
+
+    return items
+  "#;
+
+  async fn read_unleased(&mut self, queue: &str, batch_size: usize) -> anyhow::Result<Vec<String>> {
     let pending_set = queue_to_pending_set(queue);
-    let script = redis::Script::new(r#"
-      local n     = tonumber(ARGV[1])
-      local items = {}
-
-      for i = 1, n do
-        local entry = redis.call('LPOP', KEYS[2])
-        if not entry then break end
-
-        local sep = string.find(entry, '|')
-        if sep then
-          local account = string.sub(entry, 1, sep - 1)
-          redis.call('SREM', KEYS[1], account)
-        end
+    let script = redis::Script::new(Self::READ_SCRIPT);
+
+    let mut inv = script.prepare_invoke();
+    inv.key(&pending_set).key(queue).key(queue_to_entries(queue)).key(queue_to_slots(queue)).arg(batch_size);
+
+    Ok(inv.invoke_async(&mut self.con).await?)
+  }
+
+  const READ_LEASED_SCRIPT: &str = r#"
+    local n          = tonumber(ARGV[1])
+    local visibility = tonumber(ARGV[2])
+    local consumer   = ARGV[3]
+    local items      = {}
 
-        table.insert(items, entry)
+    local now      = redis.call('TIME')
+    local deadline = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000) + visibility
+
+    for i = 1, n do
+      local entry = redis.call('LMOVE', KEYS[2], KEYS[3], 'LEFT', 'RIGHT')
+      if not entry then break end
+
+      local sep = string.find(entry, '|')
+      if sep then
+        local account = string.sub(entry, 1, sep - 1)
+        redis.call('SREM', KEYS[1], account)
+        redis.call('HDEL', KEYS[6], account)
+        local slot = redis.call('HGET', KEYS[7], account)
+        if slot then redis.call('HSET', KEYS[9], entry, slot) end
+        redis.call('HDEL', KEYS[7], account)
+        redis.call('HINCRBY', KEYS[5], account, 1)
       end
 
-      return items
-    "#);
+      redis.call('ZADD', KEYS[4], deadline, entry)
+      table.insert(items, entry)
+    end
+
+    if #items > 0 then redis.call('SADD', KEYS[8], consumer) end
+
+    return items
+  "#;
+
+  async fn read_leased(&mut self, queue: &str, batch_size: usize, lease: &Lease) -> anyhow::Result<Vec<String>> {
+    let script = redis::Script::new(Self::READ_LEASED_SCRIPT);
 
     let mut inv = script.prepare_invoke();
-    inv.key(&pending_set).key(queue).arg(batch_size);
+    inv
+      .key(queue_to_pending_set(queue))
+      .key(queue)
+      .key(queue_to_processing_list(queue, &lease.consumer))
+      .key(queue_to_leases(queue, &lease.consumer))
+      .key(queue_to_deliveries(queue))
+      .key(queue_to_entries(queue))
+      .key(queue_to_slots(queue))
+      .key(queue_to_consumers(queue))
+      .key(queue_to_leased_slots(queue))
+      .arg(batch_size)
+      .arg(lease.visibility_timeout.as_millis() as u64)
+      .arg(&lease.consumer);
 
-    let raw: Vec<String> = inv
-      .invoke_async(&mut self.con)
+    Ok(inv.invoke_async(&mut self.con).await?)
+  }
+
+  const ACK_SCRIPT: &str = r#"
+    local wanted = {}
+    for i = 1, #ARGV do
+      wanted[ARGV[i]] = true
+    end
+
+    local acked = 0
+    for _, entry in ipairs(redis.call('LRANGE', KEYS[1], 0, -1)) do
+      local sep     = string.find(entry, '|')
+      local account = sep and string.sub(entry, 1, sep - 1) or entry
+
+      if wanted[account] then
+        redis.call('LREM', KEYS[1], 1, entry)
+        redis.call('ZREM', KEYS[2], entry)
+        redis.call('HDEL', KEYS[3], account)
+        redis.call('HDEL', KEYS[4], entry)
+        acked = acked + 1
+      end
+    end
+
+    return acked
+  "#;
+
+  /// Marks the leased entries of these accounts as done. A no-op without a consumer, reads
+  /// are already final then. Returns how many entries were acked.
+  pub async fn ack(&mut self, queue: &str, pubkeys: &[Pubkey]) -> anyhow::Result<usize> {
+    let accounts: Vec<String> = pubkeys.iter().map(|pubkey| pubkey.to_string()).collect();
+    self.ack_accounts(queue, &accounts).await
+  }
+
+  async fn ack_accounts(&mut self, queue: &str, accounts: &[String]) -> anyhow::Result<usize> {
+    let lease = match &self.lease {
+      Some(lease) if !accounts.is_empty() => lease,
+      _ => return Ok(0),
+    };
+
+    if self.backend == QueueBackend::Stream {
+      let consumer = lease.consumer.clone();
+      return stream::ack(&mut self.con, queue, &consumer, accounts).await;
+    }
+
+    let script = redis::Script::new(Self::ACK_SCRIPT);
+    let mut inv = script.prepare_invoke();
+    inv
+      .key(queue_to_processing_list(queue, &lease.consumer))
+      .key(queue_to_leases(queue, &lease.consumer))
+      .key(queue_to_deliveries(queue))
+      .key(queue_to_leased_slots(queue));
+    for account in accounts {
+      inv.arg(account);
+    }
+
+    Ok(inv.invoke_async(&mut self.con).await?)
+  }
+
+  const FAIL_SCRIPT: &str = r#"
+    local max    = tonumber(ARGV[1])
+    local reason = ARGV[2]
+    local wanted = {}
+    for i = 3, #ARGV do
+      wanted[ARGV[i]] = true
+    end
+
+    local now       = redis.call('TIME')
+    local timestamp = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+
+    local dead = 0
+    for _, entry in ipairs(redis.call('LRANGE', KEYS[1], 0, -1)) do
+      local sep     = string.find(entry, '|')
+      local account = sep and string.sub(entry, 1, sep - 1) or entry
+
+      if wanted[account] then
+        local attempts = tonumber(redis.call('HGET', KEYS[3], account) or '0')
+        if max > 0 and attempts >= max then
+          redis.call('LREM', KEYS[1], 1, entry)
+          redis.call('ZREM', KEYS[2], entry)
+          redis.call('HDEL', KEYS[3], account)
+          redis.call('HDEL', KEYS[5], entry)
+          redis.call('RPUSH', KEYS[4], cjson.encode({ entry = entry, error = reason, timestamp = timestamp, attempts = attempts }))
+          dead = dead + 1
+        end
+      end
+    end
+
+    return dead
+  "#;
+
+  /// Reports that handling the leased entries of these accounts failed. Entries already handed
+  /// out `max_deliveries` times are dead-lettered with `error`, the rest come back once their
+  /// lease expires. A no-op without a consumer. Returns how many entries were dead-lettered.
+  pub async fn fail(&mut self, queue: &str, pubkeys: &[Pubkey], error: &str) -> anyhow::Result<usize> {
+    let lease = match &self.lease {
+      Some(lease) if !pubkeys.is_empty() => lease.clone(),
+      _ => return Ok(0),
+    };
+
+    let accounts: Vec<String> = pubkeys.iter().map(|pubkey| pubkey.to_string()).collect();
+    if self.backend == QueueBackend::Stream {
+      return stream::fail(&mut self.con, queue, &lease.consumer, lease.max_deliveries, error, &accounts).await;
+    }
+
+    let script = redis::Script::new(Self::FAIL_SCRIPT);
+    let mut inv = script.prepare_invoke();
+    inv
+      .key(queue_to_processing_list(queue, &lease.consumer))
+      .key(queue_to_leases(queue, &lease.consumer))
+      .key(queue_to_deliveries(queue))
+      .key(queue_to_dead_letters(queue))
+      .key(queue_to_leased_slots(queue))
+      .arg(lease.max_deliveries)
+      .arg(error);
+    for account in &accounts {
+      inv.arg(account);
+    }
+
+    Ok(inv.invoke_async(&mut self.con).await?)
+  }
+
+  /// Reaps the leases of one consumer, whose processing list and leases come in as keys. The
+  /// consumer leaves the registry once it holds nothing, its next leased read adds it again.
+  /// Under the `newer` policy a requeued entry takes its slot back, and replaces a waiting entry
+  /// of the same account observed at an older slot.
+  const REQUEUE_EXPIRED_SCRIPT: &str = r#"
+    local now    = redis.call('TIME')
+    local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+
+    local expired  = redis.call('ZRANGEBYSCORE', KEYS[3], '-inf', now_ms, 'LIMIT', 0, tonumber(ARGV[1]))
+    local max      = tonumber(ARGV[3])
+    local requeued = 0
+    local dead     = 0
+
+    for _, entry in ipairs(expired) do
+      redis.call('ZREM', KEYS[3], entry)
+      local slot = redis.call('HGET', KEYS[9], entry)
+      redis.call('HDEL', KEYS[9], entry)
+
+      if redis.call('LREM', KEYS[7], 1, entry) > 0 then
+        local sep      = string.find(entry, '|')
+        local account  = sep and string.sub(entry, 1, sep - 1) or entry
+        local attempts = tonumber(redis.call('HGET', KEYS[5], account) or '0')
+
+        if max > 0 and attempts >= max then
+          redis.call('HDEL', KEYS[5], account)
+          redis.call('RPUSH', KEYS[6], cjson.encode({
+            entry = entry,
+            error = 'lease expired after ' .. attempts .. ' deliveries',
+            timestamp = now_ms,
+            attempts = attempts,
+          }))
+          dead = dead + 1
+        elseif redis.call('SADD', KEYS[2], account) == 1 then
+          redis.call('LPUSH', KEYS[1], entry)
+          if ARGV[2] ~= 'keep' then redis.call('HSET', KEYS[4], account, entry) end
+          if ARGV[2] == 'newer' and slot then redis.call('HSET', KEYS[10], account, slot) end
+          requeued = requeued + 1
+        else
+          -- another entry of the same account is already waiting, it supersedes this one unless
+          -- it was observed at an older slot
+          local queued = ARGV[2] == 'newer' and slot
+            and tonumber(redis.call('HGET', KEYS[10], account) or '0') < tonumber(slot)
+            and redis.call('HGET', KEYS[4], account)
+          local index = queued and redis.call('LPOS', KEYS[1], queued)
+          if index then
+            redis.call('LSET', KEYS[1], index, entry)
+            redis.call('HSET', KEYS[4], account, entry)
+            redis.call('HSET', KEYS[10], account, slot)
+            requeued = requeued + 1
+          else
+            redis.call('HDEL', KEYS[5], account)
+          end
+        end
+      end
+    end
+
+    if redis.call('LLEN', KEYS[7]) == 0 and redis.call('ZCARD', KEYS[3]) == 0 then
+      redis.call('SREM', KEYS[8], ARGV[4])
+    end
+
+    return { requeued, dead }
+  "#;
+
+  /// Puts entries whose lease ran out back at the head of the queue, entries that ran out of
+  /// deliveries go to the dead-letter list instead. Runs over every registered consumer, up to
+  /// `limit` entries each, and is safe to run from every consumer at once. Returns how many
+  /// entries were requeued and dead-lettered. Streams need no reaper, expired entries are claimed
+  /// by the next read of any consumer.
+  pub async fn requeue_expired(&mut self, queue: &str, limit: usize) -> anyhow::Result<(usize, usize)> {
+    if self.backend == QueueBackend::Stream {
+      return Ok((0, 0));
+    }
+
+    let consumers: Vec<String> = redis::cmd("SMEMBERS")
+      .arg(queue_to_consumers(queue))
+      .query_async(&mut self.con)
       .await?;
 
-    let items = raw
-      .into_iter()
-      .map(|s| -> anyhow::Result<(Pubkey, T)> {
-        let (pubkey_str, hex_payload) = s
-          .split_once('|')
-          .ok_or_else(|| anyhow::anyhow!("malformed entry, missing '|': {s}"))?;
+    let max_deliveries = self.lease.as_ref().map(|lease| lease.max_deliveries).unwrap_or(DEFAULT_MAX_DELIVERIES);
+    let policy = dedupe_policy(queue);
+    let script = redis::Script::new(Self::REQUEUE_EXPIRED_SCRIPT);
 
-        let pubkey: Pubkey = pubkey_str.parse()?;
-        let bytes = hex::decode(hex_payload)?;
-        let (_, payload): ([u8; 32], T) = bincode::deserialize(&bytes)?;
+    let (mut requeued, mut dead) = (0, 0);
+    for consumer in &consumers {
+      let mut inv = script.prepare_invoke();
+      inv
+        .key(queue)
+        .key(queue_to_pending_set(queue))
+        .key(queue_to_leases(queue, consumer))
+        .key(queue_to_entries(queue))
+        .key(queue_to_deliveries(queue))
+        .key(queue_to_dead_letters(queue))
+        .key(queue_to_processing_list(queue, consumer))
+        .key(queue_to_consumers(queue))
+        .key(queue_to_leased_slots(queue))
+        .key(queue_to_slots(queue))
+        .arg(limit)
+        .arg(policy.script_arg())
+        .arg(max_deliveries)
+        .arg(consumer);
 
-        Ok((pubkey, payload))
-      })
-      .collect();
+      let (consumer_requeued, consumer_dead): (usize, usize) = inv.invoke_async(&mut self.con).await?;
+      requeued += consumer_requeued;
+      dead += consumer_dead;
+    }
 
-    Ok(items)
+    Ok((requeued, dead))
+  }
+
+  /// How many times the entry of each account has been handed out without being acked.
+  pub async fn delivery_counts(&mut self, queue: &str, pubkeys: &[Pubkey]) -> anyhow::Result<Vec<u32>> {
+    let accounts: Vec<String> = pubkeys.iter().map(|pubkey| pubkey.to_string()).collect();
+    self.account_delivery_counts(queue, &accounts).await
+  }
+
+  async fn account_delivery_counts(&mut self, queue: &str, accounts: &[String]) -> anyhow::Result<Vec<u32>> {
+    if accounts.is_empty() {
+      return Ok(Vec::new());
+    }
+
+    let counts: Vec<Option<u32>> = redis::cmd("HMGET")
+      .arg(queue_to_deliveries(queue))
+      .arg(accounts)
+      .query_async(&mut self.con)
+      .await?;
+
+    Ok(counts.into_iter().map(Option::unwrap_or_default).collect())
+  }
+
+  /// Consumer group state of a queue, `None` on the list backend or before the stream exists.
+  pub async fn stream_lag(&mut self, queue: &str) -> anyhow::Result<Option<StreamLag>> {
+    match self.backend {
+      QueueBackend::Stream => stream::lag(&mut self.con, queue).await,
+      QueueBackend::List => Ok(None),
+    }
+  }
+
+  /// Runs `requeue_expired` for `queue` forever in the background.
+  pub fn spawn_reaper(&self, queue: &'static str) -> JoinHandle<()> {
+    let mut sub_redis = self.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(REAPER_INTERVAL);
+      loop {
+        interval.tick().await;
+        match sub_redis.requeue_expired(queue, REAPER_BATCH_SIZE).await {
+          Ok((0, 0)) => {},
+          Ok((requeued, dead)) => println!("requeued {} and dead-lettered {} expired entries of {}", requeued, dead, queue),
+          Err(err) => println!("failed to requeue expired entries of {}: {}", queue, err),
+        }
+      }
+    })
   }
 }
 
-pub struct ReadBuilder<'a, T: for<'de> Deserialize<'de>> {
-  redis: &'a mut SubRedis,
-  queue: &'a str,
-  batch_size: usize,
-  _marker: std::marker::PhantomData<T>,
+const REAPER_INTERVAL: Duration = Duration::from_secs(10);
+const REAPER_BATCH_SIZE: usize = 1000;
+
+impl Publisher for PubRedis {
+  fn publish<T: Serialize + Sync>(&mut self, queue: &str, items: &[(Pubkey, T)]) -> impl Future<Output = anyhow::Result<Vec<Pubkey>>> + Send {
+    PubRedis::publish(self, queue, items)
+  }
 }
 
-impl<'a, T: for<'de> Deserialize<'de>> ReadBuilder<'a, T> {
-  pub fn batch_size(mut self, n: usize) -> Self {
-      self.batch_size = n;
-      self
+impl Subscriber for SubRedis {
+  fn read<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+  ) -> impl Future<Output = anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>>> + Send {
+    SubRedis::read(self, queue, batch_size)
   }
 
-  pub async fn recv(self) -> anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>> {
-      self.redis.read(self.queue, self.batch_size).await
+  fn read_blocking<T: for<'de> Deserialize<'de> + Send>(
+    &mut self,
+    queue: &str,
+    batch_size: usize,
+    timeout: Duration,
+  ) -> impl Future<Output = anyhow::Result<Vec<anyhow::Result<(Pubkey, T)>>>> + Send {
+    SubRedis::read_blocking(self, queue, batch_size, timeout)
   }
-}
\ No newline at end of file
+
+  fn ack(&mut self, queue: &str, pubkeys: &[Pubkey]) -> impl Future<Output = anyhow::Result<usize>> + Send {
+    SubRedis::ack(self, queue, pubkeys)
+  }
+}
+#[cfg(test)]
+mod tests {
+  use crate::pubsub::DeadLetters;
+  use crate::test_utils::empty_redis;
+
+  use super::*;
+
+  const VISIBILITY_TIMEOUT: Duration = Duration::from_millis(100);
+
+  fn items<T>(read: Vec<anyhow::Result<(Pubkey, T)>>) -> Vec<(Pubkey, T)> {
+    read.into_iter().map(Result::unwrap).collect()
+  }
+
+  async fn leased(url: &str, consumer: &str) -> SubRedis {
+    SubRedis::new(url).await.unwrap()
+      .with_consumer(consumer)
+      .with_visibility_timeout(VISIBILITY_TIMEOUT)
+  }
+
+  async fn expire_leases() {
+    tokio::time::sleep(VISIBILITY_TIMEOUT * 2).await;
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn acked_entries_are_done() {
+    let db = empty_redis().await;
+    let queue = queue_keys::CHECK_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = leased(&db.url, "acking").await;
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue, &[(a, 1u64), (b, 2u64)]).await.unwrap();
+    assert_eq!(items(subscriber.read::<u64>(queue, 10).await.unwrap()), vec![(a, 1), (b, 2)]);
+    assert_eq!(subscriber.delivery_counts(queue, &[a, b]).await.unwrap(), vec![1, 1]);
+
+    assert_eq!(subscriber.ack(queue, &[a]).await.unwrap(), 1);
+    assert_eq!(subscriber.ack(queue, &[a]).await.unwrap(), 0);
+    assert_eq!(subscriber.delivery_counts(queue, &[a, b]).await.unwrap(), vec![0, 1]);
+
+    // only the unacked entry comes back
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (1, 0));
+    assert_eq!(items(subscriber.read::<u64>(queue, 10).await.unwrap()), vec![(b, 2)]);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn expired_leases_are_requeued_to_the_head() {
+    let db = empty_redis().await;
+    let queue = queue_keys::CHECK_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut first = leased(&db.url, "first").await;
+    let mut second = leased(&db.url, "second").await;
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue, &[(a, 1u64)]).await.unwrap();
+    assert_eq!(items(first.read::<u64>(queue, 10).await.unwrap()), vec![(a, 1)]);
+    publisher.publish(queue, &[(b, 2u64)]).await.unwrap();
+
+    // not expired yet
+    assert_eq!(second.requeue_expired(queue, 10).await.unwrap(), (0, 0));
+
+    expire_leases().await;
+    assert_eq!(second.requeue_expired(queue, 10).await.unwrap(), (1, 0));
+    assert_eq!(items(second.read::<u64>(queue, 10).await.unwrap()), vec![(a, 1), (b, 2)]);
+    assert_eq!(second.delivery_counts(queue, &[a, b]).await.unwrap(), vec![2, 1]);
+
+    // the first consumer lost the lease, its ack no longer finds the entry
+    assert_eq!(first.ack(queue, &[a]).await.unwrap(), 0);
+    assert_eq!(second.ack(queue, &[a, b]).await.unwrap(), 2);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn entries_out_of_deliveries_are_dead_lettered() {
+    let db = empty_redis().await;
+    let queue = queue_keys::CHECK_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = leased(&db.url, "failing").await.with_max_deliveries(2);
+    let mut dead_letters = DeadLetters::new(&db.url).await.unwrap();
+    let [failed, expired] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue, &[(failed, 1u64), (expired, 2u64)]).await.unwrap();
+    assert_eq!(subscriber.read::<u64>(queue, 10).await.unwrap().len(), 2);
+    // one delivery left, the entry comes back once its lease expires
+    assert_eq!(subscriber.fail(queue, &[failed], "first").await.unwrap(), 0);
+
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (2, 0));
+    assert_eq!(subscriber.read::<u64>(queue, 10).await.unwrap().len(), 2);
+    assert_eq!(subscriber.fail(queue, &[failed], "second").await.unwrap(), 1);
+
+    // the other one never got acked and runs out of deliveries in the reaper
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (0, 1));
+    assert!(subscriber.read::<u64>(queue, 10).await.unwrap().is_empty());
+    assert_eq!(subscriber.delivery_counts(queue, &[failed, expired]).await.unwrap(), vec![0, 0]);
+
+    let letters = dead_letters.list(queue, 0, 10).await.unwrap();
+    assert_eq!(letters.iter().map(|letter| (letter.account().to_string(), letter.attempts)).collect::<Vec<_>>(), vec![
+      (failed.to_string(), 2),
+      (expired.to_string(), 2),
+    ]);
+    assert_eq!(letters[0].error, "second");
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn expired_entries_are_superseded_by_waiting_ones() {
+    let db = empty_redis().await;
+    let queue = queue_keys::CHECK_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = leased(&db.url, "superseded").await;
+    let account = Pubkey::new_unique();
+
+    publisher.publish(queue, &[(account, 1u64)]).await.unwrap();
+    subscriber.read::<u64>(queue, 10).await.unwrap();
+    // nothing of the account waits while it's leased, so it's queued again
+    assert_eq!(publisher.publish(queue, &[(account, 2u64)]).await.unwrap(), vec![account]);
+
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (0, 0));
+    assert_eq!(subscriber.delivery_counts(queue, &[account]).await.unwrap(), vec![0]);
+    assert_eq!(items(subscriber.read::<u64>(queue, 10).await.unwrap()), vec![(account, 2)]);
+    assert!(subscriber.read::<u64>(queue, 10).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn requeued_entries_keep_their_slot() {
+    let db = empty_redis().await;
+    let queue = queue_keys::ACCOUNT_UPDATE_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = leased(&db.url, "reaped").await;
+    let account = Pubkey::new_unique();
+
+    publisher.publish(queue, &[(account, (10u64, 1u64))]).await.unwrap();
+    assert_eq!(items(subscriber.read::<(u64, u64)>(queue, 10).await.unwrap()), vec![(account, (10, 1))]);
+
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (1, 0));
+
+    // observed before the requeued entry, it must not replace it
+    assert!(publisher.publish(queue, &[(account, (5u64, 2u64))]).await.unwrap().is_empty());
+    assert_eq!(items(subscriber.read::<(u64, u64)>(queue, 10).await.unwrap()), vec![(account, (10, 1))]);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn requeued_entries_replace_older_waiting_ones() {
+    let db = empty_redis().await;
+    let queue = queue_keys::ACCOUNT_UPDATE_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = leased(&db.url, "reaped").await;
+    let account = Pubkey::new_unique();
+
+    publisher.publish(queue, &[(account, (10u64, 1u64))]).await.unwrap();
+    subscriber.read::<(u64, u64)>(queue, 10).await.unwrap();
+    // nothing of the account waits while it's leased, so the older update is queued
+    assert_eq!(publisher.publish(queue, &[(account, (5u64, 2u64))]).await.unwrap(), vec![account]);
+
+    expire_leases().await;
+    assert_eq!(subscriber.requeue_expired(queue, 10).await.unwrap(), (1, 0));
+
+    assert_eq!(items(subscriber.read::<(u64, u64)>(queue, 10).await.unwrap()), vec![(account, (10, 1))]);
+    assert!(subscriber.read::<(u64, u64)>(queue, 10).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn replaced_payloads_keep_their_place() {
+    let db = empty_redis().await;
+    let queue = queue_keys::LIQUIDATION_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    assert_eq!(publisher.publish(queue, &[(a, 1u64), (b, 2u64)]).await.unwrap(), vec![a, b]);
+    assert!(publisher.publish(queue, &[(a, 3u64)]).await.unwrap().is_empty());
+
+    assert_eq!(items(subscriber.read::<u64>(queue, 10).await.unwrap()), vec![(a, 3), (b, 2)]);
+    assert!(subscriber.read::<u64>(queue, 10).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn only_newer_payloads_replace_waiting_ones() {
+    let db = empty_redis().await;
+    let queue = queue_keys::ACCOUNT_UPDATE_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let [a, b] = [(); 2].map(|_| Pubkey::new_unique());
+
+    publisher.publish(queue, &[(a, (10u64, 1u64)), (b, (10u64, 2u64))]).await.unwrap();
+    // an older slot is ignored, the same slot still replaces
+    assert!(publisher.publish(queue, &[(a, (9u64, 3u64)), (b, (10u64, 4u64))]).await.unwrap().is_empty());
+    assert!(publisher.publish(queue, &[(a, (11u64, 5u64))]).await.unwrap().is_empty());
+
+    assert_eq!(items(subscriber.read::<(u64, u64)>(queue, 10).await.unwrap()), vec![(a, (11, 5)), (b, (10, 4))]);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn blocked_reads_wake_up_on_a_publish() {
+    let db = empty_redis().await;
+    let queue = queue_keys::CHECK_QUEUE;
+    let mut publisher = PubRedis::new(&db.url).await.unwrap();
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let account = Pubkey::new_unique();
+
+    let started = std::time::Instant::now();
+    let read = tokio::spawn(async move {
+      subscriber.read_blocking::<u64>(queue, 10, Duration::from_secs(5)).await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    publisher.publish(queue, &[(account, 1u64)]).await.unwrap();
+
+    assert_eq!(items(read.await.unwrap().unwrap()), vec![(account, 1)]);
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn blocked_reads_come_back_empty_after_the_timeout() {
+    let db = empty_redis().await;
+    let mut subscriber = SubRedis::new(&db.url).await.unwrap();
+    let timeout = Duration::from_millis(500);
+
+    let started = std::time::Instant::now();
+    assert!(subscriber.read_blocking::<u64>(queue_keys::CHECK_QUEUE, 10, timeout).await.unwrap().is_empty());
+    assert!(started.elapsed() >= timeout);
+  }
+}
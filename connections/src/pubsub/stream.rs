@@ -0,0 +1,433 @@
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+
+use super::{DedupePolicy, dead_letter::queue_to_dead_letters, queue_keys, redis::PublishCall};
+
+/// Every queue stream has a single consumer group, consumers are told apart by name.
+const GROUP: &str = "pipeline";
+
+/// Consumer name used by readers that don't ack, they read with `NOACK` so it owns nothing.
+const ANONYMOUS_CONSUMER: &str = "anonymous";
+
+pub(crate) fn queue_to_stream(queue: &str) -> String {
+  format!("{}_stream", queue)
+}
+
+fn queue_to_pending_set(queue: &str) -> String {
+  format!("{}_stream_pending", queue)
+}
+
+/// Newest payload of accounts already waiting in the stream, for queues that replace payloads.
+/// Stream entries can't be edited, so the payload is swapped in when the entry is delivered.
+fn queue_to_latest(queue: &str) -> String {
+  format!("{}_stream_latest", queue)
+}
+
+/// Slot of the newest payload of each waiting account, for `DedupePolicy::ReplaceNewer` queues.
+fn queue_to_latest_slots(queue: &str) -> String {
+  format!("{}_stream_latest_slots", queue)
+}
+
+fn queue_to_delivered_ids(queue: &str, consumer: &str) -> String {
+  format!("{}_stream_ids:{}", queue, consumer)
+}
+
+/// Approximate number of entries kept per stream. Acked entries stay around until trimmed,
+/// which is what makes replay and lag inspection possible.
+pub fn stream_maxlen(queue: &str) -> usize {
+  match queue {
+    queue_keys::ACCOUNT_UPDATE_QUEUE => 200_000,
+    queue_keys::LIQUIDATION_QUEUE => 10_000,
+    queue_keys::BANK_ADD_QUEUE | queue_keys::BANK_REM_QUEUE => 1_000,
+    _ => 100_000,
+  }
+}
+
+const PUBLISH_SCRIPT: &str = r#"
+  local maxlen = ARGV[1]
+  local policy = ARGV[2]
+  local pushed = {}
+  local n      = (#ARGV - 2) / 3
+
+  for i = 1, n do
+    local account = ARGV[(i-1)*3 + 3]
+    local entry   = ARGV[(i-1)*3 + 4]
+    local slot    = tonumber(ARGV[(i-1)*3 + 5])
+    if redis.call('SADD', KEYS[1], account) == 1 then
+      redis.call('XADD', KEYS[2], 'MAXLEN', '~', maxlen, '*', 'entry', entry)
+      if policy ~= 'keep' then redis.call('HDEL', KEYS[3], account) end
+      if policy == 'newer' then redis.call('HSET', KEYS[4], account, slot) end
+      redis.call('HDEL', KEYS[5], account)
+      table.insert(pushed, account)
+    elseif policy == 'replace' or (policy == 'newer' and tonumber(redis.call('HGET', KEYS[4], account) or '0') <= slot) then
+      redis.call('HSET', KEYS[3], account, entry)
+      if policy == 'newer' then redis.call('HSET', KEYS[4], account, slot) end
+      redis.call('HDEL', KEYS[5], account)
+    end
+  end
+
+  return pushed
+"#;
+
+/// Same dedupe as the list backend: an account is only appended while it isn't already waiting,
+/// newer payloads are kept for delivery as `policy` allows and start without deliveries.
+/// `args` are `account, entry, slot` triples.
+pub(super) fn publish_call(queue: &str, policy: DedupePolicy, args: Vec<String>) -> PublishCall {
+  let keys = vec![
+    queue_to_pending_set(queue),
+    queue_to_stream(queue),
+    queue_to_latest(queue),
+    queue_to_latest_slots(queue),
+    super::redis::queue_to_deliveries(queue),
+  ];
+  let mut script_args = vec![stream_maxlen(queue).to_string(), policy.script_arg().to_string()];
+  script_args.extend(args);
+
+  PublishCall { script: PUBLISH_SCRIPT.to_string(), keys, args: script_args }
+}
+
+const READ_SCRIPT: &str = r#"
+  local group      = ARGV[1]
+  local consumer   = ARGV[2]
+  local n          = tonumber(ARGV[3])
+  local min_idle   = ARGV[4]
+  local leased     = ARGV[5] == '1'
+  local max        = tonumber(ARGV[6])
+  local items      = {}
+
+  redis.pcall('XGROUP', 'CREATE', KEYS[2], group, '0', 'MKSTREAM')
+
+  local function deliver(id, fields, fresh)
+    local entry
+    for i = 1, #fields, 2 do
+      if fields[i] == 'entry' then entry = fields[i + 1] end
+    end
+
+    if not entry then
+      if leased then redis.call('XACK', KEYS[2], group, id) end
+      return
+    end
+
+    local sep     = string.find(entry, '|')
+    local account = sep and string.sub(entry, 1, sep - 1) or entry
+
+    -- claimed entries that ran out of deliveries are parked instead of handed out again
+    if not fresh and max > 0 then
+      local attempts = tonumber(redis.call('HGET', KEYS[4], account) or '0')
+      if attempts >= max then
+        local now = redis.call('TIME')
+        redis.call('XACK', KEYS[2], group, id)
+        redis.call('HDEL', KEYS[4], account)
+        redis.call('RPUSH', KEYS[6], cjson.encode({
+          entry = entry,
+          error = 'lease expired after ' .. attempts .. ' deliveries',
+          timestamp = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000),
+          attempts = attempts,
+        }))
+        return
+      end
+    end
+
+    -- a redelivery is the entry as it was handed out, only first deliveries take the account out
+    -- of the stream and pick up newer payloads. A redelivered account may already wait in the
+    -- stream again under a newer entry, which has to keep deduping later publishes.
+    if fresh then
+      redis.call('SREM', KEYS[1], account)
+      redis.call('HDEL', KEYS[7], account)
+      local latest = redis.call('HGET', KEYS[5], account)
+      if latest then
+        redis.call('HDEL', KEYS[5], account)
+        entry = latest
+      end
+    end
+
+    if leased then
+      local ids = redis.call('HGET', KEYS[3], account)
+      redis.call('HSET', KEYS[3], account, ids and (ids .. ',' .. id) or id)
+      redis.call('HINCRBY', KEYS[4], account, 1)
+    end
+
+    table.insert(items, entry)
+  end
+
+  -- entries other consumers didn't ack in time come first, they've waited the longest
+  if leased then
+    local claimed = redis.call('XAUTOCLAIM', KEYS[2], group, consumer, min_idle, '0-0', 'COUNT', n)
+    for _, message in ipairs(claimed[2]) do
+      if message and message[2] then deliver(message[1], message[2], false) end
+    end
+  end
+
+  if #items < n then
+    local fresh
+    if leased then
+      fresh = redis.call('XREADGROUP', 'GROUP', group, consumer, 'COUNT', n - #items, 'STREAMS', KEYS[2], '>')
+    else
+      fresh = redis.call('XREADGROUP', 'GROUP', group, consumer, 'COUNT', n - #items, 'NOACK', 'STREAMS', KEYS[2], '>')
+    end
+
+    if fresh then
+      for _, message in ipairs(fresh[1][2]) do deliver(message[1], message[2], true) end
+    end
+  end
+
+  local tail = redis.call('XREVRANGE', KEYS[2], '+', '-', 'COUNT', 1)
+  table.insert(items, 1, #tail > 0 and tail[1][1] or '0-0')
+
+  return items
+"#;
+
+/// Entries taken by one read plus the newest id in the stream, which is where a blocking wait
+/// has to start so nothing appended in between is missed.
+pub(crate) struct Taken {
+  pub(crate) entries: Vec<String>,
+  pub(crate) tail: String,
+}
+
+/// Hands out up to `batch_size` entries. With a consumer, entries other consumers left
+/// unacked for longer than `visibility_timeout` are claimed first, then new ones are read.
+/// Claimed entries already delivered `max_deliveries` times are dead-lettered instead.
+pub(crate) async fn take(
+  con: &mut ConnectionManager,
+  queue: &str,
+  batch_size: usize,
+  consumer: Option<&str>,
+  visibility_timeout: Duration,
+  max_deliveries: u32,
+) -> anyhow::Result<Taken> {
+  let script = redis::Script::new(READ_SCRIPT);
+  let mut inv = script.prepare_invoke();
+  inv
+    .key(queue_to_pending_set(queue))
+    .key(queue_to_stream(queue))
+    .key(queue_to_delivered_ids(queue, consumer.unwrap_or(ANONYMOUS_CONSUMER)))
+    .key(super::redis::queue_to_deliveries(queue))
+    .key(queue_to_latest(queue))
+    .key(queue_to_dead_letters(queue))
+    .key(queue_to_latest_slots(queue))
+    .arg(GROUP)
+    .arg(consumer.unwrap_or(ANONYMOUS_CONSUMER))
+    .arg(batch_size)
+    .arg(visibility_timeout.as_millis() as u64)
+    .arg(if consumer.is_some() { "1" } else { "0" })
+    .arg(max_deliveries);
+
+  let mut raw: Vec<String> = inv.invoke_async(con).await?;
+  let tail = match raw.is_empty() {
+    true => "0-0".to_string(),
+    false => raw.remove(0),
+  };
+
+  Ok(Taken { entries: raw, tail })
+}
+
+/// Blocks until something is appended after `tail` or `timeout` passes. Plain `XREAD` doesn't
+/// touch the group, the entries are taken by the next `take`.
+pub(crate) async fn wait(con: &mut ConnectionManager, queue: &str, tail: &str, timeout: Duration) -> anyhow::Result<bool> {
+  let result: redis::Value = redis::cmd("XREAD")
+    .arg("COUNT")
+    .arg(1)
+    .arg("BLOCK")
+    .arg(timeout.as_millis() as u64)
+    .arg("STREAMS")
+    .arg(queue_to_stream(queue))
+    .arg(tail)
+    .query_async(con)
+    .await?;
+
+  Ok(!matches!(result, redis::Value::Nil))
+}
+
+const ACK_SCRIPT: &str = r#"
+  local group = ARGV[1]
+  local acked = 0
+
+  for i = 2, #ARGV do
+    local ids = redis.call('HGET', KEYS[2], ARGV[i])
+    if ids then
+      for id in string.gmatch(ids, '[^,]+') do
+        acked = acked + redis.call('XACK', KEYS[1], group, id)
+      end
+      redis.call('HDEL', KEYS[2], ARGV[i])
+      redis.call('HDEL', KEYS[3], ARGV[i])
+    end
+  end
+
+  return acked
+"#;
+
+pub(crate) async fn ack(con: &mut ConnectionManager, queue: &str, consumer: &str, accounts: &[String]) -> anyhow::Result<usize> {
+  let script = redis::Script::new(ACK_SCRIPT);
+  let mut inv = script.prepare_invoke();
+  inv
+    .key(queue_to_stream(queue))
+    .key(queue_to_delivered_ids(queue, consumer))
+    .key(super::redis::queue_to_deliveries(queue))
+    .arg(GROUP);
+  for account in accounts {
+    inv.arg(account);
+  }
+
+  Ok(inv.invoke_async(con).await?)
+}
+
+const FAIL_SCRIPT: &str = r#"
+  local group  = ARGV[1]
+  local max    = tonumber(ARGV[2])
+  local reason = ARGV[3]
+
+  local now       = redis.call('TIME')
+  local timestamp = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+
+  local dead = 0
+  for i = 4, #ARGV do
+    local account  = ARGV[i]
+    local attempts = tonumber(redis.call('HGET', KEYS[3], account) or '0')
+    local ids      = redis.call('HGET', KEYS[2], account)
+
+    if max > 0 and attempts >= max and ids then
+      for id in string.gmatch(ids, '[^,]+') do
+        -- the entry may have been trimmed already, the id is acked either way
+        local message = redis.call('XRANGE', KEYS[1], id, id)[1]
+        local entry
+        if message then
+          for j = 1, #message[2], 2 do
+            if message[2][j] == 'entry' then entry = message[2][j + 1] end
+          end
+        end
+
+        redis.call('XACK', KEYS[1], group, id)
+        if entry then
+          redis.call('RPUSH', KEYS[4], cjson.encode({ entry = entry, error = reason, timestamp = timestamp, attempts = attempts }))
+        end
+      end
+
+      redis.call('HDEL', KEYS[2], account)
+      redis.call('HDEL', KEYS[3], account)
+      dead = dead + 1
+    end
+  end
+
+  return dead
+"#;
+
+/// Dead-letters the entries of these accounts that were delivered `max_deliveries` times already.
+pub(crate) async fn fail(
+  con: &mut ConnectionManager,
+  queue: &str,
+  consumer: &str,
+  max_deliveries: u32,
+  error: &str,
+  accounts: &[String],
+) -> anyhow::Result<usize> {
+  let script = redis::Script::new(FAIL_SCRIPT);
+  let mut inv = script.prepare_invoke();
+  inv
+    .key(queue_to_stream(queue))
+    .key(queue_to_delivered_ids(queue, consumer))
+    .key(super::redis::queue_to_deliveries(queue))
+    .key(queue_to_dead_letters(queue))
+    .arg(GROUP)
+    .arg(max_deliveries)
+    .arg(error);
+  for account in accounts {
+    inv.arg(account);
+  }
+
+  Ok(inv.invoke_async(con).await?)
+}
+
+/// Consumer group state of a queue stream.
+#[derive(Debug, Clone)]
+pub struct StreamLag {
+  /// Entries currently in the stream, acked or not
+  pub length: usize,
+  /// Entries handed out but not acked yet
+  pub pending: usize,
+  /// Entries not handed out to anyone yet, `None` when redis can't tell after trimming
+  pub lag: Option<usize>,
+}
+
+pub(crate) async fn lag(con: &mut ConnectionManager, queue: &str) -> anyhow::Result<Option<StreamLag>> {
+  let stream = queue_to_stream(queue);
+  let length: usize = redis::cmd("XLEN").arg(&stream).query_async(con).await?;
+
+  let groups: Vec<std::collections::HashMap<String, redis::Value>> = match redis::cmd("XINFO")
+    .arg("GROUPS")
+    .arg(&stream)
+    .query_async(con)
+    .await {
+    Ok(groups) => groups,
+    // the stream doesn't exist before its first publish or read
+    Err(_) => return Ok(None),
+  };
+
+  let group = match groups.into_iter().find(|group| matches!(group.get("name"), Some(redis::Value::BulkString(name)) if name == GROUP.as_bytes())) {
+    Some(group) => group,
+    None => return Ok(None),
+  };
+
+  let number = |key: &str| match group.get(key) {
+    Some(redis::Value::Int(n)) => Some(*n as usize),
+    _ => None,
+  };
+
+  Ok(Some(StreamLag {
+    length,
+    pending: number("pending").unwrap_or_default(),
+    lag: number("lag"),
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use solana_pubkey::Pubkey;
+
+  use crate::pubsub::{PubRedis, QueueBackend, SubRedis, queue_keys::LIQUIDATION_QUEUE};
+  use crate::test_utils::empty_redis;
+
+  use super::*;
+
+  #[tokio::test]
+  #[ignore = "needs a redis-server at REDIS_TEST_URL"]
+  async fn redeliveries_leave_a_republished_account_waiting() {
+    let db = empty_redis().await;
+    let visibility_timeout = Duration::from_millis(100);
+    let mut publisher = PubRedis::new(&db.url).await.unwrap().with_backend(QueueBackend::Stream);
+    let mut first = SubRedis::new(&db.url).await.unwrap()
+      .with_backend(QueueBackend::Stream)
+      .with_consumer("first")
+      .with_visibility_timeout(visibility_timeout);
+    let mut second = SubRedis::new(&db.url).await.unwrap()
+      .with_backend(QueueBackend::Stream)
+      .with_consumer("second")
+      .with_visibility_timeout(visibility_timeout);
+    let account = Pubkey::new_unique();
+
+    assert_eq!(publisher.publish(LIQUIDATION_QUEUE, &[(account, 1u64)]).await.unwrap(), vec![account]);
+    let read = first.read::<u64>(LIQUIDATION_QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+
+    // published again while the first delivery is still unacked
+    assert_eq!(publisher.publish(LIQUIDATION_QUEUE, &[(account, 2u64)]).await.unwrap(), vec![account]);
+
+    tokio::time::sleep(visibility_timeout * 2).await;
+    let redelivered = second.read::<u64>(LIQUIDATION_QUEUE, 1).await.unwrap();
+    assert_eq!(redelivered.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 1)]);
+
+    // the newer entry still waits, so this publish only replaces its payload
+    assert!(publisher.publish(LIQUIDATION_QUEUE, &[(account, 3u64)]).await.unwrap().is_empty());
+    let pending: bool = redis::cmd("SISMEMBER")
+      .arg(queue_to_pending_set(LIQUIDATION_QUEUE))
+      .arg(account.to_string())
+      .query_async(&mut publisher.con)
+      .await
+      .unwrap();
+    assert!(pending);
+
+    let read = second.read::<u64>(LIQUIDATION_QUEUE, 10).await.unwrap();
+    assert_eq!(read.into_iter().map(Result::unwrap).collect::<Vec<_>>(), vec![(account, 3)]);
+    assert!(second.read::<u64>(LIQUIDATION_QUEUE, 10).await.unwrap().is_empty());
+  }
+}
\ No newline at end of file
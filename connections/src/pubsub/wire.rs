@@ -0,0 +1,268 @@
+use std::{borrow::Cow, fmt};
+
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use solana_pubkey::Pubkey;
+
+/// Starts every frame. Its base64 is `TFFG`, which a legacy hex payload can never start with.
+const MAGIC: [u8; 3] = *b"LQF";
+const MAGIC_BASE64: &str = "TFFG";
+
+/// Layout version of the queued payloads. Bump it whenever a type that goes through a queue
+/// changes shape, so consumers reject in-flight entries of the old layout instead of misreading them.
+pub const SCHEMA_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const FLAG_COMPRESSED: u8 = 0b1;
+
+/// Smaller bodies aren't worth compressing, account-only entries stay a few bytes.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Upper bound for a decompressed body, the size prefix of a frame isn't trusted beyond it.
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+  /// Frame shorter than its header
+  Truncated,
+  /// Bytes that don't start with the frame magic
+  BadMagic,
+  /// Written under a different `SCHEMA_VERSION` with no migration to the current one
+  UnsupportedVersion { version: u8, expected: u8 },
+  /// Flags this release doesn't know about
+  UnknownFlags(u8),
+  /// Compressed body larger than `MAX_BODY_LEN` or not decompressing
+  BadCompression,
+}
+
+impl fmt::Display for WireError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Truncated => write!(f, "frame is shorter than its header"),
+      Self::BadMagic => write!(f, "not a framed payload"),
+      Self::UnsupportedVersion { version, expected } => write!(f, "unsupported schema version {}, expected {}", version, expected),
+      Self::UnknownFlags(flags) => write!(f, "unknown frame flags {:#04b}", flags),
+      Self::BadCompression => write!(f, "compressed body is corrupt or too large"),
+    }
+  }
+}
+
+impl std::error::Error for WireError {}
+
+/// Frames a payload: magic, schema version, flags, then the bincode body, lz4 compressed
+/// when that makes it smaller.
+pub fn encode_payload<T: Serialize>(payload: &T) -> anyhow::Result<Vec<u8>> {
+  let body = bincode::serialize(payload)?;
+
+  let compressed = match body.len() > COMPRESSION_THRESHOLD {
+    true => Some(lz4_flex::compress_prepend_size(&body)).filter(|compressed| compressed.len() < body.len()),
+    false => None,
+  };
+  let (flags, body) = match compressed {
+    Some(compressed) => (FLAG_COMPRESSED, compressed),
+    None => (0, body),
+  };
+
+  let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+  frame.extend_from_slice(&MAGIC);
+  frame.push(SCHEMA_VERSION);
+  frame.push(flags);
+  frame.extend_from_slice(&body);
+
+  Ok(frame)
+}
+
+pub fn decode_payload<T: for<'de> Deserialize<'de>>(frame: &[u8]) -> anyhow::Result<T> {
+  Ok(bincode::deserialize(&frame_body(frame)?)?)
+}
+
+/// Checks the header of a frame and returns its bincode body, decompressed.
+fn frame_body(frame: &[u8]) -> anyhow::Result<Cow<'_, [u8]>> {
+  if frame.len() < HEADER_LEN {
+    return Err(WireError::Truncated.into());
+  }
+  if frame[..MAGIC.len()] != MAGIC {
+    return Err(WireError::BadMagic.into());
+  }
+
+  let version = frame[MAGIC.len()];
+  let flags = frame[MAGIC.len() + 1];
+  let body = &frame[HEADER_LEN..];
+
+  // older layouts get a migration arm here once `SCHEMA_VERSION` moves past them
+  if version != SCHEMA_VERSION {
+    return Err(WireError::UnsupportedVersion { version, expected: SCHEMA_VERSION }.into());
+  }
+
+  if flags & !FLAG_COMPRESSED != 0 {
+    return Err(WireError::UnknownFlags(flags).into());
+  }
+
+  if flags & FLAG_COMPRESSED == 0 {
+    return Ok(Cow::Borrowed(body));
+  }
+
+  let size = body.get(..4)
+    .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+    .ok_or(WireError::BadCompression)?;
+  if size > MAX_BODY_LEN {
+    return Err(WireError::BadCompression.into());
+  }
+
+  let body = lz4_flex::decompress_size_prepended(body).map_err(|_| WireError::BadCompression)?;
+  Ok(Cow::Owned(body))
+}
+
+/// Entries are `"{account}|{payload}"`, anything before the separator identifies the account.
+pub(super) fn entry_account(entry: &str) -> &str {
+  entry.split_once('|').map(|(account, _)| account).unwrap_or(entry)
+}
+
+/// The account stays readable for the lua scripts, the frame is base64 so entries remain text
+/// for the scripts and the dead-letter records.
+pub(super) fn encode_entry<T: Serialize>(pubkey: &Pubkey, payload: &T) -> anyhow::Result<String> {
+  let frame = encode_payload(payload)?;
+  Ok(format!("{}|{}", pubkey, general_purpose::STANDARD.encode(frame)))
+}
+
+pub(super) fn decode_entry<T: for<'de> Deserialize<'de>>(s: &str) -> anyhow::Result<(Pubkey, T)> {
+  let (pubkey_str, payload) = s
+    .split_once('|')
+    .ok_or_else(|| anyhow::anyhow!("malformed entry, missing '|': {s}"))?;
+
+  let pubkey: Pubkey = pubkey_str.parse()?;
+  let payload = match payload.starts_with(MAGIC_BASE64) {
+    true => decode_payload(&general_purpose::STANDARD.decode(payload)?)?,
+    false => decode_legacy(payload)?,
+  };
+
+  Ok((pubkey, payload))
+}
+
+/// Slot of an entry whose payload is a `(u64, T)` tuple, see `DedupePolicy::ReplaceNewer`. Bincode
+/// writes the slot first, so it's read off the body without knowing `T`.
+pub(super) fn entry_slot(entry: &str) -> anyhow::Result<u64> {
+  let (_, payload) = entry
+    .split_once('|')
+    .ok_or_else(|| anyhow::anyhow!("malformed entry, missing '|': {entry}"))?;
+
+  let body = match payload.starts_with(MAGIC_BASE64) {
+    true => frame_body(&general_purpose::STANDARD.decode(payload)?)?.into_owned(),
+    // legacy bodies lead with the account's 32 bytes
+    false => hex::decode(payload)?.get(32..).unwrap_or_default().to_vec(),
+  };
+
+  let slot = body.get(..8).ok_or_else(|| anyhow::anyhow!("payload doesn't lead with a slot"))?;
+  Ok(u64::from_le_bytes(slot.try_into().unwrap()))
+}
+
+/// Entries queued before framing: hex of `bincode((pubkey_bytes, payload))`. Their payloads have
+/// the layout of schema version 1, so they're read as is while they drain.
+fn decode_legacy<T: for<'de> Deserialize<'de>>(payload: &str) -> anyhow::Result<T> {
+  if SCHEMA_VERSION != 1 {
+    return Err(WireError::UnsupportedVersion { version: 0, expected: SCHEMA_VERSION }.into());
+  }
+
+  let bytes = hex::decode(payload)?;
+  let (_, payload): ([u8; 32], T) = bincode::deserialize(&bytes)?;
+
+  Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Shaped like a queued account update: a slot and a mostly zeroed account body.
+  fn account_update() -> (u64, Vec<u8>) {
+    let mut data = vec![0u8; 2304];
+    for (i, byte) in data.iter_mut().enumerate().step_by(97) {
+      *byte = i as u8;
+    }
+    (312_000_000, data)
+  }
+
+  fn legacy_entry<T: Serialize>(pubkey: &Pubkey, payload: &T) -> String {
+    format!("{}|{}", pubkey, hex::encode(bincode::serialize(&(pubkey.to_bytes(), payload)).unwrap()))
+  }
+
+  fn reframe(entry: &str, edit: impl FnOnce(&mut Vec<u8>)) -> String {
+    let (account, payload) = entry.split_once('|').unwrap();
+    let mut frame = general_purpose::STANDARD.decode(payload).unwrap();
+    edit(&mut frame);
+    format!("{}|{}", account, general_purpose::STANDARD.encode(frame))
+  }
+
+  #[test]
+  fn round_trips_small_payloads_uncompressed() {
+    let pubkey = Pubkey::new_unique();
+    let entry = encode_entry(&pubkey, &()).unwrap();
+
+    let (_, payload) = entry.split_once('|').unwrap();
+    assert!(payload.starts_with(MAGIC_BASE64));
+    assert_eq!(general_purpose::STANDARD.decode(payload).unwrap()[MAGIC.len() + 1], 0);
+    assert_eq!(decode_entry::<()>(&entry).unwrap(), (pubkey, ()));
+  }
+
+  #[test]
+  fn round_trips_large_payloads_compressed() {
+    let pubkey = Pubkey::new_unique();
+    let update = account_update();
+    let entry = encode_entry(&pubkey, &update).unwrap();
+
+    let (_, payload) = entry.split_once('|').unwrap();
+    let frame = general_purpose::STANDARD.decode(payload).unwrap();
+    assert_eq!(frame[MAGIC.len() + 1], FLAG_COMPRESSED);
+    assert!(frame.len() < update.1.len());
+    assert_eq!(decode_entry::<(u64, Vec<u8>)>(&entry).unwrap(), (pubkey, update));
+  }
+
+  #[test]
+  fn decodes_legacy_entries() {
+    let pubkey = Pubkey::new_unique();
+    let update = account_update();
+    let entry = legacy_entry(&pubkey, &update);
+
+    assert_eq!(decode_entry::<(u64, Vec<u8>)>(&entry).unwrap(), (pubkey, update));
+  }
+
+  #[test]
+  fn rejects_unknown_versions() {
+    let entry = reframe(&encode_entry(&Pubkey::new_unique(), &7u64).unwrap(), |frame| frame[MAGIC.len()] = SCHEMA_VERSION + 1);
+
+    let err = decode_entry::<u64>(&entry).unwrap_err();
+    assert_eq!(err.downcast_ref::<WireError>(), Some(&WireError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION }));
+  }
+
+  #[test]
+  fn rejects_unknown_flags_and_broken_frames() {
+    let entry = encode_entry(&Pubkey::new_unique(), &7u64).unwrap();
+
+    let flagged = reframe(&entry, |frame| frame[MAGIC.len() + 1] = 0b10);
+    assert_eq!(decode_entry::<u64>(&flagged).unwrap_err().downcast_ref::<WireError>(), Some(&WireError::UnknownFlags(0b10)));
+
+    let truncated = reframe(&entry, |frame| frame.truncate(HEADER_LEN - 1));
+    assert_eq!(decode_entry::<u64>(&truncated).unwrap_err().downcast_ref::<WireError>(), Some(&WireError::Truncated));
+
+    assert_eq!(decode_payload::<u64>(b"XYZ\x01\x00").unwrap_err().downcast_ref::<WireError>(), Some(&WireError::BadMagic));
+  }
+
+  #[test]
+  fn reads_the_slot_of_framed_and_legacy_entries() {
+    let pubkey = Pubkey::new_unique();
+    let update = account_update();
+
+    assert_eq!(entry_slot(&encode_entry(&pubkey, &update).unwrap()).unwrap(), update.0);
+    assert_eq!(entry_slot(&legacy_entry(&pubkey, &update)).unwrap(), update.0);
+    assert!(entry_slot(&encode_entry(&pubkey, &()).unwrap()).is_err());
+  }
+
+  /// Throughput is measured by `cargo bench -p connections --bench wire`.
+  #[test]
+  fn frames_are_smaller_than_legacy_hex() {
+    let pubkey = Pubkey::new_unique();
+    let update = account_update();
+
+    assert!(encode_entry(&pubkey, &update).unwrap().len() < legacy_entry(&pubkey, &update).len());
+  }
+}
\ No newline at end of file
@@ -1,12 +1,15 @@
 use redis::{AsyncTypedCommands, aio::ConnectionManager};
 use solana_pubkey::Pubkey;
 
-const ACCOUNT_KEY: &str = "accounts";
-const BANK_KEY: &str = "banks";
+pub(crate) const ACCOUNT_KEY: &str = "accounts";
+pub(crate) const BANK_KEY: &str = "banks";
+const HEATMAP_KEY: &str = "heatmap";
+/// Accounts check_worker holds in its book and watches the oracles of itself.
+const BOOKED_KEY: &str = "booked_accounts";
 
 #[derive(Clone)]
 pub struct Redis {
-  con: ConnectionManager
+  pub(crate) con: ConnectionManager
 }
 
 impl Redis {
@@ -118,17 +121,15 @@ impl Redis {
 
       for _, bank in ipairs(banks) do
         redis.call('SREM', 'bank:accounts:' .. bank, account)
+
+        if redis.call('SCARD', 'bank:accounts:' .. bank) == 0 and redis.call('SREM', bank_key, bank) == 1 then
+          table.insert(removed_banks, bank)
+        end
       end
 
       redis.call('DEL', 'account:banks:' .. account)
 
       removed = removed + redis.call('SREM', account_key, account)
-
-      if redis.call('SCARD', 'bank:accounts:' .. bank) == 0 then
-        redis.call('SREM', bank_key, bank)
-        redis.call('DEL', 'bank:accounts:' .. bank)
-        table.insert(removed_banks, bank)
-      end
     end
 
     local result = { tostring(removed) }
@@ -166,6 +167,69 @@ impl Redis {
     Ok((removed_banks, accounts_removed))
   }
 
+  /// Banks move in and out of the account's index in one step, so readers never see it half
+  /// rewritten. Accounts that aren't indexed are left alone.
+  const SET_ACCOUNT_BANKS_SCRIPT: &str = r#"
+    local account = ARGV[1]
+    local added   = {}
+    local removed = {}
+
+    if redis.call('SISMEMBER', KEYS[1], account) == 0 then
+      return { added, removed }
+    end
+
+    local wanted = {}
+    for i = 2, #ARGV do
+      wanted[ARGV[i]] = true
+    end
+
+    for _, bank in ipairs(redis.call('SMEMBERS', 'account:banks:' .. account)) do
+      if wanted[bank] then
+        wanted[bank] = nil
+      else
+        redis.call('SREM', 'account:banks:' .. account, bank)
+        redis.call('SREM', 'bank:accounts:' .. bank, account)
+
+        if redis.call('SCARD', 'bank:accounts:' .. bank) == 0 and redis.call('SREM', KEYS[2], bank) == 1 then
+          table.insert(removed, bank)
+        end
+      end
+    end
+
+    for bank in pairs(wanted) do
+      redis.call('SADD', 'account:banks:' .. account, bank)
+      redis.call('SADD', 'bank:accounts:' .. bank, account)
+
+      if redis.call('SADD', KEYS[2], bank) == 1 then
+        table.insert(added, bank)
+      end
+    end
+
+    return { added, removed }
+  "#;
+
+  /// Replaces the banks indexed for an already indexed account. Returns the banks that were
+  /// added to and removed from `banks` on the way.
+  pub async fn set_account_banks<'a, I>(&mut self, account: &Pubkey, banks: I) -> anyhow::Result<(Vec<Pubkey>, Vec<Pubkey>)>
+    where I: IntoIterator<Item = &'a Pubkey> {
+    let script = redis::Script::new(Self::SET_ACCOUNT_BANKS_SCRIPT);
+
+    let mut invocation = script.prepare_invoke();
+    invocation.key(ACCOUNT_KEY);
+    invocation.key(BANK_KEY);
+    invocation.arg(account.to_string());
+    for bank in banks {
+      invocation.arg(bank.to_string());
+    }
+
+    let (added, removed) = invocation.invoke_async::<(Vec<String>, Vec<String>)>(&mut self.con).await?;
+
+    let added = added.iter().map(|s| s.parse()).collect::<Result<Vec<Pubkey>, _>>()?;
+    let removed = removed.iter().map(|s| s.parse()).collect::<Result<Vec<Pubkey>, _>>()?;
+
+    Ok((added, removed))
+  }
+
   pub async fn get_all_accounts(&mut self) -> anyhow::Result<Vec<Pubkey>> {
     let strings = self.con.smembers(ACCOUNT_KEY).await?;
     
@@ -188,6 +252,33 @@ impl Redis {
     Ok(pubkeys?)
   }
 
+  /// Accounts of the bank check_worker hasn't booked yet.
+  pub async fn get_unbooked_accounts_by_bank(&mut self, bank: &Pubkey) -> anyhow::Result<Vec<Pubkey>> {
+    let strings = self.con.sdiff(&[format!("bank:accounts:{}", bank), BOOKED_KEY.to_string()]).await?;
+
+    let pubkeys: Result<Vec<Pubkey>, _> = strings
+      .iter()
+      .map(|s| s.parse::<Pubkey>())
+      .collect();
+
+    Ok(pubkeys?)
+  }
+
+  /// Marks the accounts as booked, `reset` forgets the previously booked ones first.
+  pub async fn book_accounts(&mut self, accounts: &[Pubkey], reset: bool) -> anyhow::Result<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if reset {
+      pipe.del(BOOKED_KEY).ignore();
+    }
+    for chunk in accounts.chunks(1000) {
+      pipe.sadd(BOOKED_KEY, chunk.iter().map(|account| account.to_string()).collect::<Vec<_>>()).ignore();
+    }
+    let _: () = pipe.query_async(&mut self.con).await?;
+
+    Ok(())
+  }
+
   pub async fn get_all_banks(&mut self) -> anyhow::Result<Vec<Pubkey>> {
     let strings = self.con.smembers(BANK_KEY).await?;
     
@@ -198,4 +289,11 @@ impl Redis {
     
     Ok(pubkeys?)
   }
+
+  /// Stores the serialized liquidation ladder of a mint in the `heatmap` hash.
+  pub async fn set_heatmap(&mut self, mint: &Pubkey, ladder: &str) -> anyhow::Result<()> {
+    self.con.hset(HEATMAP_KEY, mint.to_string(), ladder).await?;
+
+    Ok(())
+  }
 }
\ No newline at end of file
@@ -0,0 +1,30 @@
+//! Redis-server the tests run against, set by `REDIS_TEST_URL`. Tests needing one are marked
+//! `#[ignore]` and run with `cargo test -- --ignored`. They flush the database, so point it at a
+//! disposable one.
+
+use redis::aio::ConnectionManager;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// The tests flush the database they run against, they share it one at a time.
+static REDIS_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Turn at the empty test database, held until dropped.
+pub(crate) struct TestRedis {
+  pub(crate) url: String,
+  _turn: MutexGuard<'static, ()>,
+}
+
+/// Waits for the test database and empties it. Panics when `REDIS_TEST_URL` isn't set, an ignored
+/// test run without a server fails instead of passing.
+pub(crate) async fn empty_redis() -> TestRedis {
+  let url = std::env::var("REDIS_TEST_URL")
+    .ok()
+    .filter(|url| !url.is_empty())
+    .expect("\"REDIS_TEST_URL\" has to point at a redis-server to run the redis tests");
+
+  let turn = REDIS_LOCK.lock().await;
+  let mut con = ConnectionManager::new(redis::Client::open(url.as_str()).unwrap()).await.unwrap();
+  let _: () = redis::cmd("FLUSHDB").query_async(&mut con).await.unwrap();
+
+  TestRedis { url, _turn: turn }
+}
@@ -0,0 +1,35 @@
+use anyhow::Context;
+use connections::{QueueBackend, QueueOrder};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+  pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
+  pub(crate) liquidation_order: QueueOrder,
+  /// Only needed to requeue liquidation letters in priority order, they rank at the current slot
+  pub(crate) http_url: Option<String>,
+}
+
+impl Config {
+  pub fn open() -> anyhow::Result<Config> {
+    let _ = dotenvy::dotenv();
+    let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
+    let liquidation_order = std::env::var("LIQUIDATION_ORDER")
+      .ok()
+      .filter(|s| !s.is_empty())
+      .map(|s| s.parse::<QueueOrder>())
+      .transpose()
+      .context("invalid \"LIQUIDATION_ORDER\" value")?
+      .unwrap_or_default();
+    let http_url = std::env::var("HTTP_URL").ok().filter(|s| !s.is_empty());
+    let config = Config {
+      pubsub_url,
+      pubsub_backend,
+      liquidation_order,
+      http_url
+    };
+
+    Ok(config)
+  }
+}
\ No newline at end of file
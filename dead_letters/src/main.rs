@@ -0,0 +1,102 @@
+mod config;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use config::Config;
+use connections::{DeadLetters, QueueOrder, Ranking, queue_keys};
+use solana_client::nonblocking::rpc_client::RpcClient;
+
+const USAGE: &str = "usage: dead_letters <queue> [list [start] [count] | requeue [count] | purge [count]], queue is one of add, check, update, liquidation, rem, bank_add, bank_rem or a full queue name";
+
+const DEFAULT_LIST_COUNT: usize = 20;
+
+#[tokio::main]
+async fn main() {
+  let config = Config::open().unwrap();
+
+  let result = start(config).await;
+
+  if let Err(err) = result {
+    eprintln!("error: {err}");
+
+    err.chain()
+      .skip(1)
+      .for_each(|cause| eprintln!("caused by:\n  {cause}"));
+  }
+}
+
+async fn start(config: Config) -> anyhow::Result<()> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let queue = match args.first() {
+    Some(queue) => parse_queue(queue),
+    None => anyhow::bail!(USAGE),
+  };
+  let command = args.get(1).map(String::as_str).unwrap_or("list");
+  let numbers = args.iter()
+    .skip(2)
+    .map(|arg| arg.parse::<usize>().with_context(|| format!("invalid number \"{}\", {}", arg, USAGE)))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  let mut dead_letters = DeadLetters::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
+
+  match command {
+    "list" => {
+      let start = numbers.first().copied().unwrap_or(0);
+      let count = numbers.get(1).copied().unwrap_or(DEFAULT_LIST_COUNT);
+
+      let total = dead_letters.count(&queue).await?;
+      println!("* {} dead letters in {}", total, queue);
+
+      let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+      for (index, letter) in dead_letters.list(&queue, start, count).await?.iter().enumerate() {
+        let age = Duration::from_secs(now.saturating_sub(letter.timestamp) / 1000);
+        println!("  #{} {} after {} attempts, {:?} ago: {}", start + index, letter.account(), letter.attempts, age, letter.error);
+      }
+    }
+    "requeue" => {
+      let count = match numbers.first() {
+        Some(count) => *count,
+        None => dead_letters.count(&queue).await?,
+      };
+
+      if queue == queue_keys::LIQUIDATION_QUEUE && config.liquidation_order == QueueOrder::Priority {
+        let http_url = config.http_url.as_deref().context("\"HTTP_URL\" is required to requeue liquidations in priority order")?;
+        let slot = RpcClient::new(http_url.to_string()).get_slot().await?;
+        // letters don't keep what they were worth, they rank as if they were worth nothing
+        dead_letters = dead_letters.with_priority(&queue, Ranking { value: 0.0, slot });
+      }
+
+      let requeued = dead_letters.requeue(&queue, count).await?;
+      println!("* requeued {} of {} dead letters in {}", requeued.queued, count, queue);
+      if !requeued.failed.is_empty() {
+        println!("* {} failed to requeue and went to the back of the list:", requeued.failed.len());
+        for error in &requeued.failed {
+          println!("  {}", error);
+        }
+      }
+    }
+    "purge" => {
+      let purged = dead_letters.purge(&queue, numbers.first().copied()).await?;
+      println!("* purged {} dead letters in {}", purged, queue);
+    }
+    _ => anyhow::bail!(USAGE),
+  }
+
+  Ok(())
+}
+
+fn parse_queue(queue: &str) -> String {
+  let queue = match queue {
+    "add" => queue_keys::ADD_QUEUE,
+    "check" => queue_keys::CHECK_QUEUE,
+    "update" => queue_keys::ACCOUNT_UPDATE_QUEUE,
+    "liquidation" => queue_keys::LIQUIDATION_QUEUE,
+    "rem" => queue_keys::REM_QUEUE,
+    "bank_add" => queue_keys::BANK_ADD_QUEUE,
+    "bank_rem" => queue_keys::BANK_REM_QUEUE,
+    queue => queue,
+  };
+
+  queue.to_string()
+}
\ No newline at end of file
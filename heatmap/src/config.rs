@@ -0,0 +1,49 @@
+use anyhow::Context;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Config {
+  pub(crate) http_url: String,
+  pub(crate) ws_url: String,
+  pub(crate) redis_url: String,
+  pub(crate) accounts_batch_size: usize,
+  pub(crate) interval_secs: usize,
+  pub(crate) ladder_range: usize,
+  pub(crate) ladder_step: usize
+}
+
+impl Config {
+  pub fn open() -> anyhow::Result<Config> {
+    let _ = dotenvy::dotenv();
+    let http_url = std::env::var("HTTP_URL").context("\"HTTP_URL\" is required")?;
+    let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
+    let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
+    let accounts_batch_size = env_usize("ACCOUNTS_BATCH_SIZE", 100).context("invalid \"ACCOUNTS_BATCH_SIZE\" value")?;
+    let interval_secs = env_usize("HEATMAP_INTERVAL", 300).context("invalid \"HEATMAP_INTERVAL\" value")?;
+    let ladder_range = env_usize("HEATMAP_RANGE", 50).context("invalid \"HEATMAP_RANGE\" value")?;
+    let ladder_step = env_usize("HEATMAP_STEP", 5).context("invalid \"HEATMAP_STEP\" value")?;
+    if ladder_step == 0 || ladder_range >= 100 {
+      anyhow::bail!("\"HEATMAP_STEP\" must be positive and \"HEATMAP_RANGE\" below 100");
+    }
+
+    let config = Config {
+      http_url,
+      ws_url,
+      redis_url,
+      accounts_batch_size,
+      interval_secs,
+      ladder_range,
+      ladder_step
+    };
+
+    Ok(config)
+  }
+}
+
+fn env_usize(name: &str, default: usize) -> Result<usize, std::num::ParseIntError> {
+  std::env::var(name)
+    .ok()
+    .filter(|s| !s.is_empty())
+    .map(|s| s.parse::<usize>())
+    .transpose()
+    .map(|opt| opt.unwrap_or(default))
+}
\ No newline at end of file
@@ -0,0 +1,178 @@
+mod config;
+
+use std::{collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use config::Config;
+use connections::Redis;
+use fixed::types::I80F48;
+use protocols::marginfi::{MarginfiUser, MarketState, OraclePriceType, PriceAdapter, stress::price_ladder};
+use serde::Serialize;
+use solana_client::nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient};
+use solana_pubkey::Pubkey;
+use tokio::{signal, time::{Duration, Instant, sleep}};
+
+#[derive(Serialize)]
+struct MintHeatmap {
+  mint: String,
+  price: f64,
+  updated_at: u64,
+  levels: Vec<Level>,
+}
+
+#[derive(Serialize)]
+struct Level {
+  price_change: f64,
+  price: f64,
+  liquidatable_accounts: usize,
+  seizable: f64,
+  repayable: f64,
+}
+
+#[tokio::main]
+async fn main() {
+  let config = Config::open().unwrap();
+
+  let result = start(config).await;
+
+  if let Err(err) = result {
+    eprintln!("error: {err}");
+
+    err.chain()
+      .skip(1)
+      .for_each(|cause| eprintln!("caused by:\n  {cause}"));
+  }
+}
+
+async fn start(config: Config) -> anyhow::Result<()> {
+  let pubsub = Arc::new(PubsubClient::new(&config.ws_url).await?);
+  let rpc_client = RpcClient::new(config.http_url.clone());
+  let market = Arc::new(MarketState::with_subscriptions(pubsub));
+  let mut redis = Redis::new(&config.redis_url).await?;
+
+  let price_changes = price_changes(config.ladder_range, config.ladder_step);
+
+  loop {
+    if let Err(err) = build(&config, &rpc_client, &market, &mut redis, &price_changes).await {
+      eprintln!("error: {err}");
+
+      err.chain()
+        .skip(1)
+        .for_each(|cause| eprintln!("caused by:\n  {cause}"));
+    }
+
+    tokio::select! {
+      _ = sleep(Duration::from_secs(config.interval_secs as u64)) => {}
+      _ = signal::ctrl_c() => {
+        println!("shutting down");
+        break;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+async fn build(config: &Config, rpc_client: &RpcClient, market: &Arc<MarketState>, redis: &mut Redis, price_changes: &[I80F48]) -> anyhow::Result<()> {
+  let start = Instant::now();
+
+  let banks = redis.get_all_banks().await?;
+  market.track_banks(rpc_client, &banks).await?;
+  market.refresh_price_feeds();
+
+  let mut mint_banks: HashMap<Pubkey, Vec<Pubkey>> = HashMap::new();
+  for bank_pk in &banks {
+    if let Some(bank) = market.bank(bank_pk) {
+      mint_banks.entry(bank.mint).or_default().push(*bank_pk);
+    }
+  }
+
+  let pubkeys = redis.get_all_accounts().await?;
+  let mut users: HashMap<Pubkey, MarginfiUser> = HashMap::with_capacity(pubkeys.len());
+  for chunk in pubkeys.chunks(config.accounts_batch_size.max(1)) {
+    for (pubkey, result) in chunk.iter().zip(market.load_users(rpc_client, chunk).await?) {
+      if let Ok(user) = result {
+        users.insert(*pubkey, user);
+      }
+    }
+  }
+
+  let updated_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+  for (mint, bank_pks) in &mint_banks {
+    let price = match mint_price(market, bank_pks) {
+      Some(price) => price,
+      None => {
+        println!("no usable price for {}, skipping", mint);
+        continue;
+      }
+    };
+
+    let mut accounts = Vec::new();
+    for bank_pk in bank_pks {
+      accounts.extend(redis.get_accounts_by_bank(bank_pk).await?);
+    }
+    accounts.sort();
+    accounts.dedup();
+
+    let mint_users: Vec<&MarginfiUser> = accounts.iter().filter_map(|pubkey| users.get(pubkey)).collect();
+    let levels = price_ladder(mint_users.iter().copied(), mint, price, price_changes)
+      .into_iter()
+      .map(|level| Level {
+        price_change: level.price_change.to_num(),
+        price: level.price.to_num(),
+        liquidatable_accounts: level.liquidatable_accounts,
+        seizable: level.seizable.to_num(),
+        repayable: level.repayable.to_num(),
+      })
+      .collect();
+
+    let heatmap = MintHeatmap {
+      mint: mint.to_string(),
+      price: price.to_num(),
+      updated_at,
+      levels,
+    };
+    redis.set_heatmap(mint, &serde_json::to_string(&heatmap)?).await?;
+  }
+
+  println!("* heatmap of {} mints over {} accounts ({:?})", mint_banks.len(), users.len(), start.elapsed());
+
+  Ok(())
+}
+
+/// Moves of `step` percent outward from 0 up to `range` percent each way, so the current price
+/// is always a level even when `range` isn't a multiple of `step`.
+fn price_changes(range: usize, step: usize) -> Vec<I80F48> {
+  let ups: Vec<i64> = (0..=range as i64).step_by(step).collect();
+
+  ups.iter().skip(1).rev().map(|percent| -percent)
+    .chain(ups.iter().copied())
+    .map(|percent| I80F48::from_num(percent) / I80F48::from_num(100))
+    .collect()
+}
+
+/// Unbiased price of the first bank of the mint with a healthy price feed.
+fn mint_price(market: &MarketState, bank_pks: &[Pubkey]) -> Option<I80F48> {
+  bank_pks.iter().find_map(|bank_pk| {
+    let bank = market.bank(bank_pk)?;
+    let price_feed = market.price_feed(bank_pk)?.ok()?;
+
+    price_feed.get_price_of_type(OraclePriceType::RealTime, None, bank.config.oracle_max_confidence).ok()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn percents(range: usize, step: usize) -> Vec<i64> {
+    price_changes(range, step).iter().map(|change| (*change * I80F48::from_num(100)).round().to_num()).collect()
+  }
+
+  #[test]
+  fn ladders_always_include_the_current_price() {
+    assert_eq!(percents(10, 5), vec![-10, -5, 0, 5, 10]);
+    assert_eq!(percents(12, 5), vec![-10, -5, 0, 5, 10]);
+    assert_eq!(percents(3, 5), vec![0]);
+    assert_eq!(percents(0, 1), vec![0]);
+  }
+}
\ No newline at end of file
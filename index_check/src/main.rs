@@ -0,0 +1,123 @@
+mod config;
+
+use std::collections::HashMap;
+
+use config::Config;
+use connections::{IndexRepair, PubRedis, Publisher, Redis, queue_keys};
+use protocols::marginfi::{MarginfiAccount, MarginfiAccountType};
+use protocols::utils::AccountSource;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_pubkey::Pubkey;
+use tokio::time::Instant;
+
+const USAGE: &str = "usage: index_check [check | repair] [--skip-chain], check only reports, --skip-chain leaves out the comparison with on-chain balances";
+
+#[tokio::main]
+async fn main() {
+  let config = Config::open().unwrap();
+
+  let result = start(config).await;
+
+  if let Err(err) = result {
+    eprintln!("error: {err}");
+
+    err.chain()
+      .skip(1)
+      .for_each(|cause| eprintln!("caused by:\n  {cause}"));
+  }
+}
+
+async fn start(config: Config) -> anyhow::Result<()> {
+  let mut repair = false;
+  let mut skip_chain = false;
+  for arg in std::env::args().skip(1) {
+    match arg.as_str() {
+      "check" => repair = false,
+      "repair" => repair = true,
+      "--skip-chain" => skip_chain = true,
+      _ => anyhow::bail!(USAGE),
+    }
+  }
+
+  let mut redis = Redis::new(&config.redis_url).await?;
+
+  let start = Instant::now();
+  let snapshot = redis.index_snapshot().await?;
+  println!(
+    "* read {} accounts, {} banks, {} account bank sets and {} bank account sets ({:?})",
+    snapshot.accounts.len(), snapshot.banks.len(), snapshot.account_banks.len(), snapshot.bank_accounts.len(), start.elapsed()
+  );
+
+  let on_chain = match skip_chain {
+    true => None,
+    false => {
+      let start = Instant::now();
+      let accounts: Vec<Pubkey> = snapshot.accounts.iter().copied().collect();
+      let on_chain = load_active_banks(&RpcClient::new(config.http_url.clone()), &accounts, config.accounts_batch_size).await?;
+      println!("* loaded {} of {} accounts from chain ({:?})", on_chain.len(), accounts.len(), start.elapsed());
+      Some(on_chain)
+    },
+  };
+
+  let issues = snapshot.check(on_chain.as_ref());
+  println!("* {} issues", issues.len());
+  for issue in &issues {
+    println!("  {}", issue);
+  }
+
+  if !repair || issues.is_empty() {
+    return Ok(());
+  }
+
+  let mut repaired = IndexRepair::default();
+  for issue in &issues {
+    let repair = redis.repair_index(issue).await?;
+    repaired.added_banks.extend(repair.added_banks);
+    repaired.removed_banks.extend(repair.removed_banks);
+  }
+  println!("* repaired {} issues, {} banks added and {} removed", issues.len(), repaired.added_banks.len(), repaired.removed_banks.len());
+
+  // the subscription workers follow the bank set through the same queues the add and rem workers use
+  let mut pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
+  if !repaired.added_banks.is_empty() {
+    let _ = pub_redis.builder::<()>(queue_keys::BANK_ADD_QUEUE).items(repaired.added_banks.into_iter().map(|bank| (bank, ()))).send().await?;
+  }
+  if !repaired.removed_banks.is_empty() {
+    let _ = pub_redis.builder::<()>(queue_keys::BANK_REM_QUEUE).items(repaired.removed_banks.into_iter().map(|bank| (bank, ()))).send().await?;
+  }
+
+  Ok(())
+}
+
+/// Active banks of each account, `None` for accounts that are gone. Accounts whose data doesn't
+/// decode are left out, they're only checked against the index itself.
+async fn load_active_banks(rpc_client: &RpcClient, pubkeys: &[Pubkey], batch_size: usize) -> anyhow::Result<HashMap<Pubkey, Option<Vec<Pubkey>>>> {
+  let mut on_chain = HashMap::with_capacity(pubkeys.len());
+
+  for chunk in pubkeys.chunks(batch_size.max(1)) {
+    let accounts = rpc_client.get_multiple(chunk).await?;
+
+    for (pubkey, account) in chunk.iter().zip(accounts) {
+      let account = match account {
+        Some(account) => account,
+        None => {
+          on_chain.insert(*pubkey, None);
+          continue
+        },
+      };
+
+      let account = match MarginfiAccount::try_from_account_data(&account.data) {
+        Ok(account) => account,
+        Err(err) => {
+          println!("failed to parse account data of {}: {}", pubkey, err);
+          continue
+        },
+      };
+
+      let banks = account.lending_account.get_active_balances_iter().map(|balance| balance.bank_pk).collect();
+      on_chain.insert(*pubkey, Some(banks));
+    }
+  }
+
+  Ok(on_chain)
+}
\ No newline at end of file
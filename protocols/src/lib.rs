@@ -1,3 +1,5 @@
 pub mod utils;
 pub mod consts;
-pub mod marginfi;
\ No newline at end of file
+pub mod marginfi;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::fmt;
+
+use bytemuck::Pod;
+
+use crate::marginfi::{discriminators, types::{Bank, FeeState, MarginfiAccount}};
+
+const DISCRIMINATOR_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountParseError {
+  /// Fewer bytes than the discriminator plus the struct
+  TooShort { expected: usize, actual: usize },
+  /// Account of a different type
+  WrongDiscriminator { expected: [u8; 8], actual: [u8; 8] },
+  /// Data isn't aligned for a zero-copy borrow, the owned parsers don't care
+  Misaligned,
+}
+
+impl fmt::Display for AccountParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::TooShort { expected, actual } => write!(f, "account data too short, expected at least {} bytes, got {}", expected, actual),
+      Self::WrongDiscriminator { expected, actual } => write!(f, "unexpected account discriminator {:?}, expected {:?}", actual, expected),
+      Self::Misaligned => write!(f, "account data is not aligned for a zero-copy read"),
+    }
+  }
+}
+
+impl std::error::Error for AccountParseError {}
+
+/// Program-owned marginfi accounts: an 8 byte anchor discriminator followed by the `Pod` struct.
+pub trait MarginfiAccountType: Pod {
+  const DISCRIMINATOR: [u8; 8];
+  /// Size of the struct, without the discriminator
+  const LEN: usize = std::mem::size_of::<Self>();
+
+  /// Checks the discriminator and length and returns the struct bytes. Trailing bytes are ignored.
+  fn account_bytes(data: &[u8]) -> Result<&[u8], AccountParseError> {
+    let expected = DISCRIMINATOR_LEN + Self::LEN;
+    if data.len() < expected {
+      return Err(AccountParseError::TooShort { expected, actual: data.len() });
+    }
+
+    let (discriminator, rest) = data.split_at(DISCRIMINATOR_LEN);
+    if discriminator != Self::DISCRIMINATOR {
+      return Err(AccountParseError::WrongDiscriminator {
+        expected: Self::DISCRIMINATOR,
+        actual: discriminator.try_into().unwrap(),
+      });
+    }
+
+    Ok(&rest[..Self::LEN])
+  }
+
+  /// Validated owned copy, works on data of any alignment.
+  fn try_from_account_data(data: &[u8]) -> Result<Self, AccountParseError> {
+    Self::account_bytes(data).map(bytemuck::pod_read_unaligned::<Self>)
+  }
+
+  /// Validated borrow of the account data without copying the struct.
+  fn try_ref_from_account_data(data: &[u8]) -> Result<&Self, AccountParseError> {
+    bytemuck::try_from_bytes(Self::account_bytes(data)?).map_err(|_| AccountParseError::Misaligned)
+  }
+}
+
+impl MarginfiAccountType for MarginfiAccount {
+  const DISCRIMINATOR: [u8; 8] = discriminators::ACCOUNT;
+}
+
+impl MarginfiAccountType for Bank {
+  const DISCRIMINATOR: [u8; 8] = discriminators::BANK;
+}
+
+impl MarginfiAccountType for FeeState {
+  const DISCRIMINATOR: [u8; 8] = discriminators::FEE_STATE;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{account_data, user_account};
+
+  /// `data` moved one byte off the alignment of the allocation.
+  fn shifted(data: &[u8]) -> Vec<u8> {
+    [&[0][..], data].concat()
+  }
+
+  #[test]
+  fn parses_accounts_with_trailing_bytes() {
+    let data = [account_data(&user_account(3, &[])), vec![7; 16]].concat();
+
+    assert_eq!(MarginfiAccount::try_from_account_data(&data).unwrap().account_flags, 3);
+    assert_eq!(MarginfiAccount::try_ref_from_account_data(&data).unwrap().account_flags, 3);
+  }
+
+  #[test]
+  fn rejects_short_data() {
+    let data = account_data(&user_account(0, &[]));
+    let short = &data[..data.len() - 1];
+
+    let err = AccountParseError::TooShort { expected: data.len(), actual: data.len() - 1 };
+    assert_eq!(MarginfiAccount::try_from_account_data(short).unwrap_err(), err);
+    assert_eq!(MarginfiAccount::try_ref_from_account_data(short).unwrap_err(), err);
+    assert_eq!(MarginfiAccount::try_from_account_data(&[]).unwrap_err(), AccountParseError::TooShort { expected: data.len(), actual: 0 });
+  }
+
+  #[test]
+  fn rejects_other_account_types() {
+    // a user is larger than a bank, so only the discriminator tells them apart
+    let data = account_data(&user_account(0, &[]));
+    assert!(data.len() > 8 + Bank::LEN);
+
+    let err = AccountParseError::WrongDiscriminator { expected: discriminators::BANK, actual: discriminators::ACCOUNT };
+    assert_eq!(Bank::try_from_account_data(&data).unwrap_err(), err);
+    assert_eq!(Bank::try_ref_from_account_data(&data).unwrap_err(), err);
+  }
+
+  #[test]
+  fn borrows_aligned_data_only() {
+    let data = shifted(&account_data(&user_account(5, &[])));
+    let misaligned = &data[1..];
+
+    assert_eq!(MarginfiAccount::try_ref_from_account_data(misaligned).unwrap_err(), AccountParseError::Misaligned);
+    assert_eq!(MarginfiAccount::try_from_account_data(misaligned).unwrap().account_flags, 5);
+  }
+}
@@ -191,14 +191,20 @@ pub mod discriminators {
 }
 
 pub mod ix_discriminators {
+    pub const MARGINFI_ACCOUNT_INITIALIZE: [u8; 8] = [43, 78, 61, 255, 148, 52, 249, 154];
     pub const INIT_LIQUIDATION_RECORD: [u8; 8] = [236, 213, 238, 126, 147, 251, 164, 8];
     pub const START_LIQUIDATION: [u8; 8] = [244, 93, 90, 214, 192, 166, 191, 21];
     pub const END_LIQUIDATION: [u8; 8] = [110, 11, 244, 54, 229, 181, 22, 184];
     pub const LENDING_ACCOUNT_PULSE_HEALTH: [u8; 8] = [186, 52, 117, 97, 34, 74, 39, 253];
+    pub const LENDING_ACCOUNT_DEPOSIT: [u8; 8] = [171, 94, 235, 103, 82, 64, 212, 140];
+    pub const LENDING_ACCOUNT_BORROW: [u8; 8] = [4, 126, 116, 53, 48, 5, 212, 31];
     pub const LENDING_ACCOUNT_WITHDRAW: [u8; 8] = [36, 72, 74, 19, 210, 210, 192, 192];
     pub const LENDING_ACCOUNT_REPAY: [u8; 8] = [79, 209, 172, 177, 222, 51, 173, 151];
-    pub const LENDING_SETTLE_EMISSIONS: [u8; 8] = [234, 22, 84, 214, 118, 176, 140, 170];
-    pub const LENDING_WITHDRAW_EMISSIONS: [u8; 8] = [161, 58, 136, 174, 242, 223, 156, 176];
+    pub const LENDING_ACCOUNT_LIQUIDATE: [u8; 8] = [214, 169, 151, 213, 251, 167, 86, 219];
+    pub const LENDING_ACCOUNT_CLOSE_BALANCE: [u8; 8] = [245, 54, 41, 4, 243, 202, 31, 17];
+    pub const LENDING_SETTLE_EMISSIONS: [u8; 8] = [161, 58, 136, 174, 242, 223, 156, 176];
+    pub const LENDING_WITHDRAW_EMISSIONS: [u8; 8] = [234, 22, 84, 214, 118, 176, 140, 170];
+    pub const LENDING_POOL_HANDLE_BANKRUPTCY: [u8; 8] = [162, 11, 56, 139, 90, 128, 70, 173];
     pub const KAMINO_WITHDRAW: [u8; 8] = [199, 101, 41, 45, 213, 98, 224, 200];
     pub const START_FLASHLOAN: [u8; 8] = [14, 131, 33, 220, 81, 186, 180, 107];
     pub const END_FLASHLOAN: [u8; 8] = [105, 124, 201, 106, 153, 2, 8, 156];
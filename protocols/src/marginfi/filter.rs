@@ -1,6 +1,6 @@
 use fixed::types::I80F48;
 
-use crate::marginfi::MarginfiUser;
+use crate::marginfi::{MarginfiAccount, MarginfiUser};
 
 #[derive(Debug)]
 pub struct AccountFilter<T> {
@@ -12,6 +12,10 @@ pub struct AccountFilter<T> {
   pub max_maint_percentage: Option<T>,
   pub min_maint: Option<T>,
   pub max_maint: Option<T>,
+  /// Account flags that all have to be set, see `ACCOUNT_*`
+  pub with_flags: u64,
+  /// Account flags of which none may be set, see `ACCOUNT_*`
+  pub without_flags: u64,
 }
 
 impl<T> Default for AccountFilter<T> {
@@ -25,6 +29,8 @@ impl<T> Default for AccountFilter<T> {
       max_maint_percentage: None,
       min_maint: None,
       max_maint: None,
+      with_flags: 0,
+      without_flags: 0,
     }
   }
 }
@@ -35,6 +41,10 @@ impl<T> AccountFilter<T> where I80F48: PartialOrd<T> {
   // and max fee is the maximum allowed profit currently configured at 10%.
   // Note that equity value is the price of the token without any weights applied, but inclusive of oracle confidence interval adjustments.
   pub fn check(&self, user: &MarginfiUser) -> anyhow::Result<bool> {
+    if !self.check_flags(user.account()) {
+      return Ok(false);
+    }
+
     let asset_value = user.asset_value()?;
     let liability_value = user.liability_value()?;
     let maint = user.maintenance()?;
@@ -90,4 +100,60 @@ impl<T> AccountFilter<T> where I80F48: PartialOrd<T> {
     
     Ok(true)
   }    
+
+  /// Flag predicates only, cheap enough to run before anything is priced.
+  pub fn check_flags(&self, account: &MarginfiAccount) -> bool {
+    account.account_flags & self.with_flags == self.with_flags
+      && account.account_flags & self.without_flags == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::marginfi::{ACCOUNT_DISABLED, ACCOUNT_IN_DELEVERAGE, ACCOUNT_IN_FLASHLOAN, ACCOUNT_IN_RECEIVERSHIP};
+  use crate::test_utils::user_account;
+
+  fn filter(with_flags: u64, without_flags: u64) -> AccountFilter<I80F48> {
+    AccountFilter { with_flags, without_flags, ..Default::default() }
+  }
+
+  #[test]
+  fn passes_every_account_without_flag_predicates() {
+    for flags in [0, ACCOUNT_DISABLED, ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_IN_DELEVERAGE] {
+      assert!(filter(0, 0).check_flags(&user_account(flags, &[])));
+    }
+  }
+
+  #[test]
+  fn requires_every_with_flag() {
+    let filter = filter(ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_IN_DELEVERAGE, 0);
+
+    assert!(!filter.check_flags(&user_account(0, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_IN_DELEVERAGE, &[])));
+    assert!(filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_IN_DELEVERAGE, &[])));
+    assert!(filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_IN_DELEVERAGE | ACCOUNT_DISABLED, &[])));
+  }
+
+  #[test]
+  fn rejects_any_without_flag() {
+    let filter = filter(0, ACCOUNT_DISABLED | ACCOUNT_IN_FLASHLOAN);
+
+    assert!(filter.check_flags(&user_account(0, &[])));
+    assert!(filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_DISABLED, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_IN_FLASHLOAN, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_DISABLED | ACCOUNT_IN_FLASHLOAN, &[])));
+  }
+
+  #[test]
+  fn combines_with_and_without_flags() {
+    let filter = filter(ACCOUNT_IN_RECEIVERSHIP, ACCOUNT_DISABLED);
+
+    assert!(filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP, &[])));
+    assert!(filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_IN_DELEVERAGE, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_IN_RECEIVERSHIP | ACCOUNT_DISABLED, &[])));
+    assert!(!filter.check_flags(&user_account(ACCOUNT_IN_DELEVERAGE, &[])));
+  }
 }
\ No newline at end of file
@@ -0,0 +1,41 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
+
+/// `remaining_accounts` must already include the borrowed bank if the balance is new.
+pub fn make_borrow_ix(
+	group: Pubkey,
+	marginfi_account: Pubkey,
+	authority: Pubkey,
+	bank: Pubkey,
+	destination_token_account: Pubkey,
+	liquidity_vault: Pubkey,
+	token_program: Pubkey,
+	amount: u64,
+	remaining_accounts: Vec<AccountMeta>
+) -> Instruction {
+	let (bank_liquidity_vault_authority, _) = pda::find_liquidity_vault_authority(&bank);
+	let mut accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(bank, false),
+		AccountMeta::new(destination_token_account, false),
+		AccountMeta::new_readonly(bank_liquidity_vault_authority, false),
+		AccountMeta::new(liquidity_vault, false),
+		AccountMeta::new_readonly(token_program, false),
+	];
+	accounts.extend(remaining_accounts);
+
+	let mut data = ix_discriminators::LENDING_ACCOUNT_BORROW.to_vec();
+	data.extend_from_slice(&amount.to_le_bytes());
+
+	let borrow_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: data,
+	};
+
+	borrow_ix
+}
\ No newline at end of file
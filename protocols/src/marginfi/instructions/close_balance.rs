@@ -0,0 +1,21 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
+
+pub fn make_close_balance_ix(group: Pubkey, marginfi_account: Pubkey, authority: Pubkey, bank: Pubkey) -> Instruction {
+	let accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(bank, false),
+	];
+
+	let close_balance_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::LENDING_ACCOUNT_CLOSE_BALANCE.to_vec(),
+	};
+
+	close_balance_ix
+}
\ No newline at end of file
@@ -0,0 +1,51 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
+
+pub fn make_deposit_ix(
+	group: Pubkey,
+	marginfi_account: Pubkey,
+	authority: Pubkey,
+	bank: Pubkey,
+	signer_token_account: Pubkey,
+	liquidity_vault: Pubkey,
+	token_program: Pubkey,
+	amount: u64,
+	deposit_up_to_limit: Option<bool>
+) -> Instruction {
+	let accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(bank, false),
+		AccountMeta::new(signer_token_account, false),
+		AccountMeta::new(liquidity_vault, false),
+		AccountMeta::new_readonly(token_program, false),
+	];
+
+	let mut data = ix_discriminators::LENDING_ACCOUNT_DEPOSIT.to_vec();
+	data.extend_from_slice(&amount.to_le_bytes());
+
+	match deposit_up_to_limit {
+		Some(true) => {
+			data.push(1); // Some
+			data.push(1); // true
+		}
+		Some(false) => {
+			data.push(1); // Some
+			data.push(0); // false
+		}
+		None => {
+			data.push(0); // None
+		}
+	}
+
+	let deposit_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: data,
+	};
+
+	deposit_ix
+}
\ No newline at end of file
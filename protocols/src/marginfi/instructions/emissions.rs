@@ -0,0 +1,51 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
+
+pub fn make_settle_emissions_ix(marginfi_account: Pubkey, bank: Pubkey) -> Instruction {
+	let accounts = vec![
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new(bank, false),
+	];
+
+	let settle_emissions_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::LENDING_SETTLE_EMISSIONS.to_vec(),
+	};
+
+	settle_emissions_ix
+}
+
+pub fn make_withdraw_emissions_ix(
+	group: Pubkey,
+	marginfi_account: Pubkey,
+	authority: Pubkey,
+	bank: Pubkey,
+	emissions_mint: Pubkey,
+	destination_account: Pubkey,
+	token_program: Pubkey
+) -> Instruction {
+	let (emissions_auth, _) = pda::find_emissions_auth(&bank, &emissions_mint);
+	let (emissions_vault, _) = pda::find_emissions_token_account(&bank, &emissions_mint);
+	let accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(bank, false),
+		AccountMeta::new_readonly(emissions_mint, false),
+		AccountMeta::new_readonly(emissions_auth, false),
+		AccountMeta::new(emissions_vault, false),
+		AccountMeta::new(destination_account, false),
+		AccountMeta::new_readonly(token_program, false),
+	];
+
+	let withdraw_emissions_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::LENDING_WITHDRAW_EMISSIONS.to_vec(),
+	};
+
+	withdraw_emissions_ix
+}
\ No newline at end of file
@@ -0,0 +1,40 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
+
+/// `end_index` is the position of the matching end flashloan instruction in the transaction.
+pub fn make_start_flashloan_ix(marginfi_account: Pubkey, authority: Pubkey, end_index: u64) -> Instruction {
+	let accounts = vec![
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new_readonly(solana_sdk_ids::sysvar::instructions::id(), false),
+	];
+
+	let mut data = ix_discriminators::START_FLASHLOAN.to_vec();
+	data.extend_from_slice(&end_index.to_le_bytes());
+
+	let start_flashloan_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: data,
+	};
+
+	start_flashloan_ix
+}
+
+pub fn make_end_flashloan_ix(marginfi_account: Pubkey, authority: Pubkey, remaining_accounts: Vec<AccountMeta>) -> Instruction {
+	let mut accounts = vec![
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+	];
+	accounts.extend(remaining_accounts);
+
+	let end_flashloan_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::END_FLASHLOAN.to_vec(),
+	};
+
+	end_flashloan_ix
+}
\ No newline at end of file
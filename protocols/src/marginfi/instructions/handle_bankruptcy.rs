@@ -0,0 +1,38 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
+
+/// Permissionless once the group sets `PERMISSIONLESS_BAD_DEBT_SETTLEMENT_FLAG`, `remaining_accounts`
+/// are the bankrupt account's health accounts.
+pub fn make_handle_bankruptcy_ix(
+	group: Pubkey,
+	signer: Pubkey,
+	bank: Pubkey,
+	marginfi_account: Pubkey,
+	liquidity_vault: Pubkey,
+	insurance_vault: Pubkey,
+	token_program: Pubkey,
+	remaining_accounts: Vec<AccountMeta>
+) -> Instruction {
+	let (insurance_vault_authority, _) = pda::find_insurance_vault_authority(&bank);
+	let mut accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new_readonly(signer, true),
+		AccountMeta::new(bank, false),
+		AccountMeta::new(marginfi_account, false),
+		AccountMeta::new(liquidity_vault, false),
+		AccountMeta::new(insurance_vault, false),
+		AccountMeta::new_readonly(insurance_vault_authority, false),
+		AccountMeta::new_readonly(token_program, false),
+	];
+	accounts.extend(remaining_accounts);
+
+	let handle_bankruptcy_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::LENDING_POOL_HANDLE_BANKRUPTCY.to_vec(),
+	};
+
+	handle_bankruptcy_ix
+}
\ No newline at end of file
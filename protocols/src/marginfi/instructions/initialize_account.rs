@@ -0,0 +1,24 @@
+use solana_pubkey::Pubkey;
+use solana_system_interface::program;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
+
+/// `marginfi_account` is a fresh keypair that signs its own creation.
+pub fn make_initialize_account_ix(group: Pubkey, marginfi_account: Pubkey, authority: Pubkey, fee_payer: Pubkey) -> Instruction {
+	let accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(marginfi_account, true),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(fee_payer, true),
+		AccountMeta::new_readonly(program::ID, false),
+	];
+
+	let initialize_account_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::MARGINFI_ACCOUNT_INITIALIZE.to_vec(),
+	};
+
+	initialize_account_ix
+}
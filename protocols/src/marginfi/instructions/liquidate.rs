@@ -0,0 +1,54 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
+
+/// Classic liquidation: the liquidator repays `liab_bank` debt of the liquidatee out of its own
+/// marginfi account and receives `asset_amount` of `asset_bank` collateral at a discount.
+///
+/// `remaining_accounts` are, in order: the asset bank oracles, the liability bank oracles, the
+/// liquidator's health accounts and the liquidatee's health accounts. `liquidatee_accounts` and
+/// `liquidator_accounts` count the health accounts of each side, the program splits on them.
+pub fn make_liquidate_ix(
+	group: Pubkey,
+	asset_bank: Pubkey,
+	liab_bank: Pubkey,
+	liquidator_marginfi_account: Pubkey,
+	authority: Pubkey,
+	liquidatee_marginfi_account: Pubkey,
+	liab_bank_liquidity_vault: Pubkey,
+	liab_bank_insurance_vault: Pubkey,
+	token_program: Pubkey,
+	asset_amount: u64,
+	liquidatee_accounts: u8,
+	liquidator_accounts: u8,
+	remaining_accounts: Vec<AccountMeta>
+) -> Instruction {
+	let (bank_liquidity_vault_authority, _) = pda::find_liquidity_vault_authority(&liab_bank);
+	let mut accounts = vec![
+		AccountMeta::new_readonly(group, false),
+		AccountMeta::new(asset_bank, false),
+		AccountMeta::new(liab_bank, false),
+		AccountMeta::new(liquidator_marginfi_account, false),
+		AccountMeta::new_readonly(authority, true),
+		AccountMeta::new(liquidatee_marginfi_account, false),
+		AccountMeta::new_readonly(bank_liquidity_vault_authority, false),
+		AccountMeta::new(liab_bank_liquidity_vault, false),
+		AccountMeta::new(liab_bank_insurance_vault, false),
+		AccountMeta::new_readonly(token_program, false),
+	];
+	accounts.extend(remaining_accounts);
+
+	let mut data = ix_discriminators::LENDING_ACCOUNT_LIQUIDATE.to_vec();
+	data.extend_from_slice(&asset_amount.to_le_bytes());
+	data.push(liquidatee_accounts);
+	data.push(liquidator_accounts);
+
+	let liquidate_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: data,
+	};
+
+	liquidate_ix
+}
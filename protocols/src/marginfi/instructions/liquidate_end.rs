@@ -2,11 +2,11 @@ use solana_pubkey::Pubkey;
 use solana_system_interface::program;
 use solana_instruction::{AccountMeta, Instruction};
 
-use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{FEE_STATE_SEED, ix_discriminators}};
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
 
-pub fn make_end_liquidation_ix(marginfi_account: Pubkey, liquidation_record: Pubkey, liquidation_receiver: Pubkey, global_fee_wallet: Pubkey) -> Instruction {
-	let (fee_state, expected_bump) = Pubkey::find_program_address(&[FEE_STATE_SEED.as_bytes()], &MARGINFI_PROGRAM_ID);
-	let accounts = vec![
+pub fn make_end_liquidation_ix(marginfi_account: Pubkey, liquidation_record: Pubkey, liquidation_receiver: Pubkey, global_fee_wallet: Pubkey, remaining_accounts: Vec<AccountMeta>) -> Instruction {
+	let (fee_state, _) = pda::find_fee_state();
+	let mut accounts = vec![
 		AccountMeta::new(marginfi_account, false),
 		AccountMeta::new(liquidation_record, false),
 		AccountMeta::new(liquidation_receiver, true),
@@ -14,6 +14,7 @@ pub fn make_end_liquidation_ix(marginfi_account: Pubkey, liquidation_record: Pub
 		AccountMeta::new(global_fee_wallet, false),
 		AccountMeta::new_readonly(program::ID, false),
 	];
+	accounts.extend(remaining_accounts);
 
 	let end_liquidation_ix = Instruction {
 		program_id: MARGINFI_PROGRAM_ID,
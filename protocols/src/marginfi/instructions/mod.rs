@@ -1,9 +1,263 @@
+mod borrow;
+mod close_balance;
+mod deposit;
+mod emissions;
+mod flashloan;
+mod handle_bankruptcy;
+mod initialize_account;
+mod liquidate;
 mod liquidate_end;
 mod liquidate_start;
+mod pulse_health;
+mod remaining_accounts;
 mod repay;
 mod withdraw;
 
+pub use borrow::*;
+pub use close_balance::*;
+pub use deposit::*;
+pub use emissions::*;
+pub use flashloan::*;
+pub use handle_bankruptcy::*;
+pub use initialize_account::*;
+pub use liquidate::*;
 pub use liquidate_end::*;
 pub use liquidate_start::*;
+pub use pulse_health::*;
+pub use remaining_accounts::*;
 pub use repay::*;
-pub use withdraw::*;
\ No newline at end of file
+pub use withdraw::*;
+
+#[cfg(test)]
+mod tests {
+	use anchor_lang::{AnchorSerialize, prelude::borsh};
+	use solana_instruction::{AccountMeta, Instruction};
+	use solana_pubkey::Pubkey;
+	use solana_sdk::hash::hash;
+	use solana_system_interface::program;
+
+	use super::*;
+	use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::pda};
+
+	/// A built instruction with the anchor instruction it must call, its accounts and its borsh
+	/// encoded arguments.
+	struct Case {
+		name: &'static str,
+		ix: Instruction,
+		accounts: Vec<AccountMeta>,
+		args: Vec<u8>,
+	}
+
+	fn args(args: impl AnchorSerialize) -> Vec<u8> {
+		borsh::to_vec(&args).unwrap()
+	}
+
+	fn with_remaining(mut accounts: Vec<AccountMeta>, remaining: &[AccountMeta]) -> Vec<AccountMeta> {
+		accounts.extend_from_slice(remaining);
+		accounts
+	}
+
+	fn cases() -> Vec<Case> {
+		let [group, account, authority, bank, token_account, vault, token_program, mint] = [(); 8].map(|_| Pubkey::new_unique());
+		let [liab_bank, liquidatee, insurance_vault, record, receiver, fee_wallet] = [(); 6].map(|_| Pubkey::new_unique());
+		let remaining: Vec<AccountMeta> = (0..3).map(|_| AccountMeta::new_readonly(Pubkey::new_unique(), false)).collect();
+		let vault_authority = pda::find_liquidity_vault_authority(&bank).0;
+
+		let mut cases = vec![
+			Case {
+				name: "lending_account_borrow",
+				ix: make_borrow_ix(group, account, authority, bank, token_account, vault, token_program, 42, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(bank, false),
+					AccountMeta::new(token_account, false),
+					AccountMeta::new_readonly(vault_authority, false),
+					AccountMeta::new(vault, false),
+					AccountMeta::new_readonly(token_program, false),
+				], &remaining),
+				args: args(42u64),
+			},
+			Case {
+				name: "lending_account_close_balance",
+				ix: make_close_balance_ix(group, account, authority, bank),
+				accounts: vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(bank, false),
+				],
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_account_settle_emissions",
+				ix: make_settle_emissions_ix(account, bank),
+				accounts: vec![AccountMeta::new(account, false), AccountMeta::new(bank, false)],
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_account_withdraw_emissions",
+				ix: make_withdraw_emissions_ix(group, account, authority, bank, mint, token_account, token_program),
+				accounts: vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(bank, false),
+					AccountMeta::new_readonly(mint, false),
+					AccountMeta::new_readonly(pda::find_emissions_auth(&bank, &mint).0, false),
+					AccountMeta::new(pda::find_emissions_token_account(&bank, &mint).0, false),
+					AccountMeta::new(token_account, false),
+					AccountMeta::new_readonly(token_program, false),
+				],
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_account_start_flashloan",
+				ix: make_start_flashloan_ix(account, authority, 3),
+				accounts: vec![
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new_readonly(solana_sdk_ids::sysvar::instructions::id(), false),
+				],
+				args: args(3u64),
+			},
+			Case {
+				name: "lending_account_end_flashloan",
+				ix: make_end_flashloan_ix(account, authority, remaining.clone()),
+				accounts: with_remaining(vec![AccountMeta::new(account, false), AccountMeta::new_readonly(authority, true)], &remaining),
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_pool_handle_bankruptcy",
+				ix: make_handle_bankruptcy_ix(group, authority, bank, account, vault, insurance_vault, token_program, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(bank, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new(vault, false),
+					AccountMeta::new(insurance_vault, false),
+					AccountMeta::new_readonly(pda::find_insurance_vault_authority(&bank).0, false),
+					AccountMeta::new_readonly(token_program, false),
+				], &remaining),
+				args: Vec::new(),
+			},
+			Case {
+				name: "marginfi_account_initialize",
+				ix: make_initialize_account_ix(group, account, authority, receiver),
+				accounts: vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(account, true),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(receiver, true),
+					AccountMeta::new_readonly(program::ID, false),
+				],
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_account_liquidate",
+				ix: make_liquidate_ix(group, bank, liab_bank, account, authority, liquidatee, vault, insurance_vault, token_program, 1_000, 4, 2, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(bank, false),
+					AccountMeta::new(liab_bank, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(liquidatee, false),
+					AccountMeta::new_readonly(pda::find_liquidity_vault_authority(&liab_bank).0, false),
+					AccountMeta::new(vault, false),
+					AccountMeta::new(insurance_vault, false),
+					AccountMeta::new_readonly(token_program, false),
+				], &remaining),
+				args: args((1_000u64, 4u8, 2u8)),
+			},
+			Case {
+				name: "start_liquidation",
+				ix: make_start_liquidation_ix(liquidatee, record, receiver, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new(liquidatee, false),
+					AccountMeta::new(record, false),
+					AccountMeta::new(receiver, false),
+					AccountMeta::new_readonly(solana_sdk_ids::sysvar::instructions::id(), false),
+				], &remaining),
+				args: Vec::new(),
+			},
+			Case {
+				name: "end_liquidation",
+				ix: make_end_liquidation_ix(liquidatee, record, receiver, fee_wallet, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new(liquidatee, false),
+					AccountMeta::new(record, false),
+					AccountMeta::new(receiver, true),
+					AccountMeta::new(pda::find_fee_state().0, false),
+					AccountMeta::new(fee_wallet, false),
+					AccountMeta::new_readonly(program::ID, false),
+				], &remaining),
+				args: Vec::new(),
+			},
+			Case {
+				name: "lending_account_pulse_health",
+				ix: make_pulse_health_ix(account, remaining.clone()),
+				accounts: with_remaining(vec![AccountMeta::new(account, false)], &remaining),
+				args: Vec::new(),
+			},
+		];
+
+		// deposit/repay and withdraw share their layouts, only the withdraw side signs for the vault
+		for flag in [None, Some(false), Some(true)] {
+			let transfer_in = vec![
+				AccountMeta::new_readonly(group, false),
+				AccountMeta::new(account, false),
+				AccountMeta::new_readonly(authority, true),
+				AccountMeta::new(bank, false),
+				AccountMeta::new(token_account, false),
+				AccountMeta::new(vault, false),
+				AccountMeta::new_readonly(token_program, false),
+			];
+			cases.push(Case {
+				name: "lending_account_deposit",
+				ix: make_deposit_ix(group, account, authority, bank, token_account, vault, token_program, 7, flag),
+				accounts: transfer_in.clone(),
+				args: args((7u64, flag)),
+			});
+			cases.push(Case {
+				name: "lending_account_repay",
+				ix: make_repay_ix(group, account, authority, bank, token_account, vault, token_program, 7, flag),
+				accounts: transfer_in,
+				args: args((7u64, flag)),
+			});
+			cases.push(Case {
+				name: "lending_account_withdraw",
+				ix: make_withdraw_ix(group, account, authority, bank, token_account, vault, token_program, 7, flag, remaining.clone()),
+				accounts: with_remaining(vec![
+					AccountMeta::new_readonly(group, false),
+					AccountMeta::new(account, false),
+					AccountMeta::new_readonly(authority, true),
+					AccountMeta::new(bank, false),
+					AccountMeta::new(token_account, false),
+					AccountMeta::new_readonly(vault_authority, false),
+					AccountMeta::new(vault, false),
+					AccountMeta::new_readonly(token_program, false),
+				], &remaining),
+				args: args((7u64, flag)),
+			});
+		}
+
+		cases
+	}
+
+	/// Every builder targets marginfi with the anchor discriminator of its instruction, followed
+	/// by exactly the borsh encoding of its arguments.
+	#[test]
+	fn round_trips_every_builder() {
+		for case in cases() {
+			assert_eq!(case.ix.program_id, MARGINFI_PROGRAM_ID, "{}", case.name);
+			assert_eq!(case.ix.accounts, case.accounts, "accounts of {}", case.name);
+
+			let sighash = hash(format!("global:{}", case.name).as_bytes());
+			assert_eq!(case.ix.data[..8], sighash.to_bytes()[..8], "discriminator of {}", case.name);
+			assert_eq!(case.ix.data[8..], case.args, "arguments of {}", case.name);
+		}
+	}
+}
\ No newline at end of file
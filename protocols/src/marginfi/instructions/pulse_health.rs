@@ -0,0 +1,20 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::{AccountMeta, Instruction};
+
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
+
+/// `remaining_accounts` must hold the bank followed by its oracle accounts for every active balance.
+pub fn make_pulse_health_ix(marginfi_account: Pubkey, remaining_accounts: Vec<AccountMeta>) -> Instruction {
+	let mut accounts = vec![
+		AccountMeta::new(marginfi_account, false),
+	];
+	accounts.extend(remaining_accounts);
+
+	let pulse_health_ix = Instruction {
+		program_id: MARGINFI_PROGRAM_ID,
+		accounts,
+		data: ix_discriminators::LENDING_ACCOUNT_PULSE_HEALTH.to_vec(),
+	};
+
+	pulse_health_ix
+}
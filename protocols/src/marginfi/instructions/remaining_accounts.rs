@@ -0,0 +1,206 @@
+use solana_pubkey::Pubkey;
+use solana_instruction::AccountMeta;
+
+use crate::marginfi::{Bank, MarginfiUser, get_oracle_keys_for_bank};
+
+/// Builds the bank and oracle remaining accounts that every health-checked marginfi instruction
+/// expects. Balances are emitted in the order the program keeps them in the lending account
+/// (bank key, descending), each bank followed by its oracle accounts.
+pub struct RemainingAccountsComposer<'a> {
+	user: &'a MarginfiUser,
+	closing: Vec<Pubkey>,
+	adding: Vec<(Pubkey, &'a Bank)>,
+}
+
+impl<'a> RemainingAccountsComposer<'a> {
+	pub fn new(user: &'a MarginfiUser) -> Self {
+		Self {
+			user,
+			closing: Vec::new(),
+			adding: Vec::new(),
+		}
+	}
+
+	/// Leaves out a balance that the transaction closes before the health check runs.
+	pub fn close(mut self, bank_pk: Pubkey) -> Self {
+		self.closing.push(bank_pk);
+		self
+	}
+
+	/// Includes a balance that the transaction opens before the health check runs.
+	pub fn add(mut self, bank_pk: Pubkey, bank: &'a Bank) -> Self {
+		self.adding.push((bank_pk, bank));
+		self
+	}
+
+	pub fn compose(self) -> anyhow::Result<Vec<AccountMeta>> {
+		let mut banks: Vec<(Pubkey, &Bank)> = self.user
+			.bank_accounts()
+			.iter()
+			.map(|bank_account| (bank_account.balance.bank_pk, &bank_account.bank))
+			.filter(|(bank_pk, _)| !self.closing.contains(bank_pk))
+			.collect();
+
+		for (bank_pk, bank) in self.adding {
+			if self.closing.contains(&bank_pk) || banks.iter().any(|(pk, _)| *pk == bank_pk) {
+				continue;
+			}
+			banks.push((bank_pk, bank));
+		}
+
+		banks.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+		let mut accounts = Vec::new();
+		for (bank_pk, bank) in banks {
+			accounts.push(AccountMeta::new_readonly(bank_pk, false));
+			for oracle_key in get_oracle_keys_for_bank(bank)? {
+				accounts.push(AccountMeta::new_readonly(oracle_key, false));
+			}
+		}
+
+		Ok(accounts)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytemuck::Zeroable;
+	use fixed::types::I80F48;
+	use pretty_assertions::assert_eq;
+
+	use super::*;
+	use crate::marginfi::{Balance, BankAccount, FixedPriceFeed, MAX_ORACLE_KEYS, MarginfiAccount, OraclePriceFeedAdapter, OracleSetup, RawOracleSetup};
+
+	/// Bank whose oracle keys are all distinct, so any key emitted from the wrong slot shows up.
+	fn bank(setup: OracleSetup) -> (Bank, [Pubkey; MAX_ORACLE_KEYS]) {
+		let mut bank = Bank::zeroed();
+		let keys = [(); MAX_ORACLE_KEYS].map(|_| Pubkey::new_unique());
+		bank.config.oracle_setup = RawOracleSetup(setup as u8);
+		bank.config.oracle_keys = keys;
+		(bank, keys)
+	}
+
+	fn bank_account(bank_pk: Pubkey, bank: Bank) -> BankAccount {
+		let mut balance = Balance::zeroed();
+		balance.active = 1;
+		balance.bank_pk = bank_pk;
+		BankAccount {
+			bank,
+			price_feed: OraclePriceFeedAdapter::Fixed(FixedPriceFeed { price: I80F48::ONE }),
+			balance,
+		}
+	}
+
+	fn user(banks: Vec<(Pubkey, Bank)>) -> MarginfiUser {
+		let bank_accounts = banks.into_iter().map(|(bank_pk, bank)| bank_account(bank_pk, bank)).collect();
+		MarginfiUser::from_bank_accounts(Pubkey::new_unique(), MarginfiAccount::zeroed(), bank_accounts)
+	}
+
+	fn keys(accounts: &[AccountMeta]) -> Vec<Pubkey> {
+		accounts.iter().map(|meta| meta.pubkey).collect()
+	}
+
+	#[test]
+	fn emits_the_oracle_accounts_of_each_setup() {
+		let cases = [
+			(OracleSetup::PythPushOracle, 1),
+			(OracleSetup::SwitchboardPull, 1),
+			(OracleSetup::StakedWithPythPush, 3),
+			(OracleSetup::KaminoPythPush, 2),
+			(OracleSetup::KaminoSwitchboardPull, 2),
+			(OracleSetup::DriftPythPull, 2),
+			(OracleSetup::DriftSwitchboardPull, 2),
+			(OracleSetup::SolendPythPull, 2),
+			(OracleSetup::SolendSwitchboardPull, 2),
+			(OracleSetup::Fixed, 0),
+		];
+
+		for (setup, oracle_count) in cases {
+			let bank_pk = Pubkey::new_unique();
+			let (bank, oracle_keys) = bank(setup);
+			let accounts = RemainingAccountsComposer::new(&user(vec![(bank_pk, bank)])).compose().unwrap();
+
+			let mut expected = vec![bank_pk];
+			expected.extend_from_slice(&oracle_keys[..oracle_count]);
+			assert_eq!(keys(&accounts), expected, "{:?}", setup);
+			assert!(accounts.iter().all(|meta| !meta.is_writable && !meta.is_signer), "{:?}", setup);
+		}
+	}
+
+	#[test]
+	fn keeps_integration_oracles_with_their_bank_across_banks() {
+		// kamino, drift and solend banks price through the oracle and the reserve, spot market or
+		// obligation pool right after it, each pair must stay behind its own bank once banks are sorted
+		let setups = [(OracleSetup::KaminoPythPush, 2), (OracleSetup::DriftSwitchboardPull, 2), (OracleSetup::SolendPythPull, 2), (OracleSetup::PythPushOracle, 1)];
+		let mut banks = Vec::new();
+		let mut expected = Vec::new();
+		for (setup, oracle_count) in setups {
+			let bank_pk = Pubkey::new_unique();
+			let (bank, oracle_keys) = bank(setup);
+			banks.push((bank_pk, bank));
+			expected.push((bank_pk, oracle_keys[..oracle_count].to_vec()));
+		}
+		let accounts = RemainingAccountsComposer::new(&user(banks)).compose().unwrap();
+
+		expected.sort_by(|(a, _), (b, _)| b.cmp(a));
+		let expected: Vec<Pubkey> = expected.into_iter().flat_map(|(bank_pk, oracles)| std::iter::once(bank_pk).chain(oracles)).collect();
+		assert_eq!(keys(&accounts), expected);
+	}
+
+	#[test]
+	fn rejects_banks_without_a_usable_oracle() {
+		for setup in [OracleSetup::None, OracleSetup::PythLegacy, OracleSetup::SwitchboardV2] {
+			let (bank, _) = bank(setup);
+			let result = RemainingAccountsComposer::new(&user(vec![(Pubkey::new_unique(), bank)])).compose();
+
+			assert!(result.is_err(), "{:?}", setup);
+		}
+	}
+
+	#[test]
+	fn orders_banks_by_key_descending() {
+		let mut bank_pks: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+		let banks = bank_pks.iter().map(|bank_pk| (*bank_pk, bank(OracleSetup::Fixed).0)).collect();
+		let accounts = RemainingAccountsComposer::new(&user(banks)).compose().unwrap();
+
+		bank_pks.sort_by(|a, b| b.cmp(a));
+		assert_eq!(keys(&accounts), bank_pks);
+	}
+
+	#[test]
+	fn replaces_closed_balances_with_added_ones() {
+		let (kept, kept_oracles) = bank(OracleSetup::PythPushOracle);
+		let (closed, _) = bank(OracleSetup::PythPushOracle);
+		let (added, added_oracles) = bank(OracleSetup::SwitchboardPull);
+		let (kept_pk, closed_pk, added_pk) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+
+		let user = user(vec![(kept_pk, kept), (closed_pk, closed)]);
+		let accounts = RemainingAccountsComposer::new(&user)
+			.close(closed_pk)
+			.add(added_pk, &added)
+			.compose()
+			.unwrap();
+
+		let mut expected = vec![(kept_pk, kept_oracles[0]), (added_pk, added_oracles[0])];
+		expected.sort_by(|(a, _), (b, _)| b.cmp(a));
+		let expected: Vec<Pubkey> = expected.into_iter().flat_map(|(bank_pk, oracle)| [bank_pk, oracle]).collect();
+		assert_eq!(keys(&accounts), expected);
+	}
+
+	#[test]
+	fn ignores_adding_a_held_or_closed_balance() {
+		let (held, held_oracles) = bank(OracleSetup::PythPushOracle);
+		let (closed, _) = bank(OracleSetup::PythPushOracle);
+		let (held_pk, closed_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+		let user = user(vec![(held_pk, held), (closed_pk, closed)]);
+		let accounts = RemainingAccountsComposer::new(&user)
+			.close(closed_pk)
+			.add(held_pk, &held)
+			.add(closed_pk, &closed)
+			.compose()
+			.unwrap();
+
+		assert_eq!(keys(&accounts), vec![held_pk, held_oracles[0]]);
+	}
+}
@@ -1,7 +1,7 @@
 use solana_pubkey::Pubkey;
 use solana_instruction::{AccountMeta, Instruction};
 
-use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{LIQUIDITY_VAULT_AUTHORITY_SEED, ix_discriminators}};
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::ix_discriminators};
 
 pub fn make_repay_ix(
 	group: Pubkey,
@@ -1,7 +1,7 @@
 use solana_pubkey::Pubkey;
 use solana_instruction::{AccountMeta, Instruction};
 
-use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{LIQUIDITY_VAULT_AUTHORITY_SEED, ix_discriminators}};
+use crate::{consts::MARGINFI_PROGRAM_ID, marginfi::{ix_discriminators, pda}};
 
 pub fn make_withdraw_ix(
 	group: Pubkey,
@@ -12,10 +12,11 @@ pub fn make_withdraw_ix(
 	liquidity_vault: Pubkey,
 	token_program: Pubkey,
 	amount: u64,
-	withdraw_all: Option<bool>
+	withdraw_all: Option<bool>,
+	remaining_accounts: Vec<AccountMeta>
 ) -> Instruction {
-	let (bank_liquidity_vault_authority, expected_bump) = Pubkey::find_program_address(&[LIQUIDITY_VAULT_AUTHORITY_SEED.as_bytes()], &MARGINFI_PROGRAM_ID);
-	let accounts = vec![
+	let (bank_liquidity_vault_authority, _) = pda::find_liquidity_vault_authority(&bank);
+	let mut accounts = vec![
 		AccountMeta::new_readonly(group, false),
 		AccountMeta::new(marginfi_account, false),
 		AccountMeta::new_readonly(authority, true),
@@ -25,6 +26,7 @@ pub fn make_withdraw_ix(
 		AccountMeta::new(liquidity_vault, false),
 		AccountMeta::new_readonly(token_program, false),
 	];
+	accounts.extend(remaining_accounts);
 
 	let mut data = ix_discriminators::LENDING_ACCOUNT_WITHDRAW.to_vec();
 	data.extend_from_slice(&amount.to_le_bytes());
@@ -0,0 +1,358 @@
+use anyhow::Context;
+use fixed::types::I80F48;
+use solana_instruction::{AccountMeta, Instruction};
+use solana_pubkey::Pubkey;
+
+use crate::marginfi::{BalanceSide, Bank, BankAccount, EmodeConfig, LIQUIDATION_INSURANCE_FEE, LIQUIDATION_LIQUIDATOR_FEE, MarginfiUser, OraclePriceType, PriceAdapter, get_oracle_keys_for_bank, instructions::make_liquidate_ix, reconcile_emode_configs, user::{emode_asset_weight_init, emode_asset_weight_maint}};
+
+/// A single classic `lending_account_liquidate` against one asset/liability pair. The liquidator
+/// receives the collateral into its own marginfi account and takes over the matching debt.
+#[derive(Debug, Clone)]
+pub struct ClassicLiquidation {
+  pub asset_bank: Pubkey,
+  pub liab_bank: Pubkey,
+  /// Collateral seized, in native token units
+  pub asset_amount: I80F48,
+  /// Value of the collateral seized, in usd
+  pub asset_value: I80F48,
+  /// Debt moved to the liquidator's account, in native token units
+  pub liab_amount: I80F48,
+  /// Value of the debt moved to the liquidator's account, in usd
+  pub liab_value: I80F48,
+  /// Liquidator fee kept on the seized collateral, in usd
+  pub profit: I80F48,
+}
+
+impl MarginfiUser {
+  /// What taking the account into receivership pays at most, in usd: the withdrawable assets left
+  /// after every liability is repaid, capped by the protocol's fee on the liabilities.
+  pub fn receivership_profit(&self, liquidation_max_fee: I80F48) -> anyhow::Result<I80F48> {
+    let liability = self.liability_value()?;
+    let seizable = self.withdrawable_asset_value()?
+      .checked_sub(liability)
+      .context("seizable value calculation failed")?;
+    let max_fee = liability
+      .checked_mul(liquidation_max_fee)
+      .context("liquidation fee calculation failed")?;
+
+    Ok(seizable.max(I80F48::ZERO).min(max_fee))
+  }
+
+  /// Picks the asset/liability pair that pays the liquidator the most while keeping the
+  /// liquidatee at or below zero maintenance health afterwards, as the program requires.
+  pub fn best_classic_liquidation(&self) -> anyhow::Result<Option<ClassicLiquidation>> {
+    let maint = self.maintenance()?;
+    if maint >= 0 {
+      return Ok(None);
+    }
+
+    let total_fee = LIQUIDATION_LIQUIDATOR_FEE + LIQUIDATION_INSURANCE_FEE;
+    let mut best: Option<ClassicLiquidation> = None;
+
+    let assets = self.bank_accounts()
+      .iter()
+      .filter(|b| !b.balance.is_empty(BalanceSide::Assets) && self.is_bank_withdrawable(b));
+
+    for asset in assets {
+      let asset_total_value = asset.asset_value()?;
+      if asset_total_value <= 0 {
+        continue;
+      }
+      let asset_weight = self.maint_asset_weight(asset);
+
+      let liabs = self.bank_accounts()
+        .iter()
+        .filter(|b| !b.balance.is_empty(BalanceSide::Liabilities) && b.bank.allows_withdraw_and_repay());
+
+      for liab in liabs {
+        let liab_total_value = liab.liability_value()?;
+        let liab_weight: I80F48 = liab.bank.config.liability_weight_maint.into();
+
+        // Every dollar of collateral seized removes `asset_weight` of weighted assets and
+        // `liab_weight * (1 - fees)` of weighted liabilities from the liquidatee.
+        let health_per_usd = liab_weight
+          .checked_mul(I80F48::ONE - total_fee)
+          .context("health gain calculation failed")?
+          - asset_weight;
+        if health_per_usd <= 0 {
+          continue;
+        }
+
+        let max_by_health = (-maint)
+          .checked_div(health_per_usd)
+          .context("max seizable by health calculation failed")?;
+        let max_by_liab = liab_total_value
+          .checked_div(I80F48::ONE - total_fee)
+          .context("max seizable by liability calculation failed")?;
+        let asset_value = max_by_health.min(max_by_liab).min(asset_total_value);
+
+        let asset_amount = asset.bank
+          .get_asset_amount(asset.balance.asset_shares.into())
+          .and_then(|amount| amount.checked_mul(asset_value))
+          .and_then(|amount| amount.checked_div(asset_total_value))
+          .context("seized asset amount calculation failed")?;
+
+        let profit = asset_value
+          .checked_mul(LIQUIDATION_LIQUIDATOR_FEE)
+          .context("liquidator profit calculation failed")?;
+        let liab_value = asset_value - profit;
+
+        if best.as_ref().is_none_or(|b| profit > b.profit) {
+          let liab_amount = liab.bank
+            .get_liability_amount(liab.balance.liability_shares.into())
+            .and_then(|amount| amount.checked_mul(liab_value))
+            .and_then(|amount| amount.checked_div(liab_total_value))
+            .context("taken over liability amount calculation failed")?;
+
+          best = Some(ClassicLiquidation {
+            asset_bank: asset.balance.bank_pk,
+            liab_bank: liab.balance.bank_pk,
+            asset_amount,
+            asset_value,
+            liab_amount,
+            liab_value,
+            profit,
+          });
+        }
+      }
+    }
+
+    Ok(best)
+  }
+
+  /// Maintenance health of this account, as the liquidator, once `liquidation` against
+  /// `liquidatee` is filled into it.
+  pub fn maintenance_after_classic_liquidation(&self, liquidatee: &MarginfiUser, liquidation: &ClassicLiquidation) -> anyhow::Result<I80F48> {
+    self.health_after_classic_liquidation(
+      liquidatee,
+      liquidation,
+      |emode_config, bank_account| Ok(emode_asset_weight_maint(emode_config, &bank_account.bank)),
+      |bank| bank.config.liability_weight_maint.into(),
+    )
+  }
+
+  /// Initial health of this account, as the liquidator, once `liquidation` against `liquidatee`
+  /// is filled into it. The program rejects fills that leave it negative, and collateral of banks
+  /// past their `total_asset_value_init_limit` counts for less here.
+  pub fn init_health_after_classic_liquidation(&self, liquidatee: &MarginfiUser, liquidation: &ClassicLiquidation) -> anyhow::Result<I80F48> {
+    self.health_after_classic_liquidation(
+      liquidatee,
+      liquidation,
+      |emode_config, bank_account| {
+        let price = bank_account.price_feed.get_price_of_type(
+          OraclePriceType::RealTime,
+          None,
+          bank_account.bank.config.oracle_max_confidence
+        )?;
+        let discount = bank_account.bank.init_asset_value_discount(price)?;
+
+        emode_asset_weight_init(emode_config, &bank_account.bank)
+          .checked_mul(discount)
+          .context("discounted initial asset weight calculation failed")
+      },
+      |bank| bank.config.liability_weight_init.into(),
+    )
+  }
+
+  /// The seized collateral and the debt net against existing balances like they do on chain, and
+  /// the emode weights are reconciled again over the borrowing banks after the fill.
+  fn health_after_classic_liquidation(
+    &self,
+    liquidatee: &MarginfiUser,
+    liquidation: &ClassicLiquidation,
+    asset_weight: impl Fn(&EmodeConfig, &BankAccount) -> anyhow::Result<I80F48>,
+    liability_weight: impl Fn(&Bank) -> I80F48,
+  ) -> anyhow::Result<I80F48> {
+    let mut balances: Vec<(Pubkey, &BankAccount, I80F48)> = Vec::with_capacity(self.bank_accounts().len() + 2);
+    for bank_account in self.bank_accounts() {
+      let net = bank_account.asset_value()? - bank_account.liability_value()?;
+      balances.push((bank_account.balance.bank_pk, bank_account, net));
+    }
+
+    for (bank_pk, value) in [(liquidation.asset_bank, liquidation.asset_value), (liquidation.liab_bank, -liquidation.liab_value)] {
+      match balances.iter_mut().find(|(pk, _, _)| *pk == bank_pk) {
+        Some((_, _, net)) => *net += value,
+        None => balances.push((bank_pk, liquidatee.find_bank_account(&bank_pk)?, value)),
+      }
+    }
+
+    let emode_config = reconcile_emode_configs(
+      balances
+        .iter()
+        .filter(|(_, _, net)| *net < 0)
+        .map(|(_, bank_account, _)| bank_account.bank.emode.emode_config),
+    );
+
+    balances.iter().try_fold(I80F48::ZERO, |health, (_, bank_account, net)| {
+      let weighted = match *net >= 0 {
+        true => net.checked_mul(asset_weight(&emode_config, bank_account)?),
+        false => net.checked_mul(liability_weight(&bank_account.bank)),
+      };
+
+      weighted
+        .and_then(|weighted| health.checked_add(weighted))
+        .context("liquidator health calculation failed")
+    })
+  }
+
+  pub fn liquidate_ix(
+    &self,
+    liquidator: &MarginfiUser,
+    authority: Pubkey,
+    liquidation: &ClassicLiquidation,
+    token_program: Pubkey
+  ) -> anyhow::Result<Instruction> {
+    let asset = self.find_bank_account(&liquidation.asset_bank)?;
+    let liab = self.find_bank_account(&liquidation.liab_bank)?;
+
+    let mut remaining_accounts = Vec::new();
+    for oracle_key in get_oracle_keys_for_bank(&asset.bank)? {
+      remaining_accounts.push(AccountMeta::new_readonly(oracle_key, false));
+    }
+    for oracle_key in get_oracle_keys_for_bank(&liab.bank)? {
+      remaining_accounts.push(AccountMeta::new_readonly(oracle_key, false));
+    }
+    let liquidator_accounts = liquidator.remaining_accounts()
+      .add(asset.balance.bank_pk, &asset.bank)
+      .add(liab.balance.bank_pk, &liab.bank)
+      .compose()?;
+    let liquidatee_accounts = self.remaining_accounts().compose()?;
+    let liquidator_count = u8::try_from(liquidator_accounts.len()).map_err(|_| anyhow::anyhow!("{} liquidator health accounts", liquidator_accounts.len()))?;
+    let liquidatee_count = u8::try_from(liquidatee_accounts.len()).map_err(|_| anyhow::anyhow!("{} liquidatee health accounts", liquidatee_accounts.len()))?;
+    remaining_accounts.extend(liquidator_accounts);
+    remaining_accounts.extend(liquidatee_accounts);
+
+    Ok(make_liquidate_ix(
+      self.account().group,
+      asset.balance.bank_pk,
+      liab.balance.bank_pk,
+      *liquidator.pubkey(),
+      authority,
+      *self.pubkey(),
+      liab.bank.liquidity_vault,
+      liab.bank.insurance_vault,
+      token_program,
+      liquidation.asset_amount.to_num(),
+      liquidatee_count,
+      liquidator_count,
+      remaining_accounts
+    ))
+  }
+
+  fn find_bank_account(&self, bank_pk: &Pubkey) -> anyhow::Result<&BankAccount> {
+    self.bank_accounts()
+      .iter()
+      .find(|b| &b.balance.bank_pk == bank_pk)
+      .ok_or_else(|| anyhow::anyhow!("no balance for bank {} in account {}", bank_pk, self.pubkey()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{position, user, weighted_bank};
+
+  fn assert_close(actual: I80F48, expected: f64) {
+    assert!((actual.to_num::<f64>() - expected).abs() < 1e-6, "{actual} != {expected}");
+  }
+
+  /// 10 SOL at 100 weighted by 0.8 against 850 USDC of debt, 50 under water.
+  fn underwater() -> MarginfiUser {
+    user(vec![position(weighted_bank(100.0, 0.8, 1.2), 10.0, 0.0), position(weighted_bank(1.0, 0.9, 1.0), 0.0, 850.0)])
+  }
+
+  /// Deposit of `amount` in the bank the liquidatee holds at `index`.
+  fn deposit_in(liquidatee: &MarginfiUser, index: usize, amount: f64) -> BankAccount {
+    let mut deposit = position(liquidatee.bank_accounts()[index].bank, amount, 0.0);
+    deposit.balance.bank_pk = liquidatee.bank_accounts()[index].balance.bank_pk;
+    deposit
+  }
+
+  #[test]
+  fn leaves_healthy_accounts_alone() {
+    let healthy = user(vec![position(weighted_bank(100.0, 0.8, 1.2), 10.0, 0.0), position(weighted_bank(1.0, 0.9, 1.0), 0.0, 700.0)]);
+
+    assert!(healthy.best_classic_liquidation().unwrap().is_none());
+  }
+
+  #[test]
+  fn seizes_until_the_liquidatee_is_back_at_zero() {
+    let account = underwater();
+    let liquidation = account.best_classic_liquidation().unwrap().unwrap();
+
+    // Every seized dollar gains 1.0 * 0.95 - 0.8 of health, 50 / 0.15 dollars close the gap
+    assert_eq!(liquidation.asset_bank, account.bank_accounts()[0].balance.bank_pk);
+    assert_eq!(liquidation.liab_bank, account.bank_accounts()[1].balance.bank_pk);
+    assert_close(liquidation.asset_value, 1000.0 / 3.0);
+    assert_close(liquidation.asset_amount, 10.0 / 3.0);
+    assert_close(liquidation.profit, 1000.0 / 3.0 * 0.025);
+    assert_close(liquidation.liab_value, 1000.0 / 3.0 * 0.975);
+    assert_close(liquidation.liab_amount, 1000.0 / 3.0 * 0.975);
+  }
+
+  #[test]
+  fn caps_seizures_by_the_liability_and_picks_the_best_pair() {
+    let sol = weighted_bank(100.0, 0.8, 1.2);
+    let (usdc, usdt) = (weighted_bank(1.0, 0.9, 1.0), weighted_bank(1.0, 0.9, 1.1));
+    let account = user(vec![position(sol, 10.0, 0.0), position(usdc, 0.0, 850.0), position(usdt, 0.0, 100.0)]);
+
+    let liquidation = account.best_classic_liquidation().unwrap().unwrap();
+
+    // Repaying the whole 850 USDC takes 850 / 0.95 of collateral, less than health allows
+    assert_eq!(liquidation.liab_bank, account.bank_accounts()[1].balance.bank_pk);
+    assert_close(liquidation.asset_value, 850.0 / 0.95);
+    assert_close(liquidation.profit, 850.0 / 0.95 * 0.025);
+  }
+
+  #[test]
+  fn skips_pairs_that_cannot_improve_health() {
+    let account = user(vec![position(weighted_bank(100.0, 0.8, 1.2), 10.0, 0.0), position(weighted_bank(1.0, 0.9, 0.8), 0.0, 1100.0)]);
+
+    assert!(account.maintenance().unwrap() < 0);
+    assert!(account.best_classic_liquidation().unwrap().is_none());
+  }
+
+  #[test]
+  fn nets_the_fill_into_the_liquidator_balances() {
+    let account = underwater();
+    let liquidation = account.best_classic_liquidation().unwrap().unwrap();
+    let seized = 1000.0 / 3.0;
+
+    let funded = user(vec![deposit_in(&account, 1, 1000.0)]);
+    assert_close(
+      funded.maintenance_after_classic_liquidation(&account, &liquidation).unwrap(),
+      (1000.0 - seized * 0.975) * 0.9 + seized * 0.8,
+    );
+
+    let empty = user(Vec::new());
+    assert_close(
+      empty.maintenance_after_classic_liquidation(&account, &liquidation).unwrap(),
+      seized * 0.8 - seized * 0.975,
+    );
+  }
+
+  #[test]
+  fn weighs_the_fill_by_initial_weights_and_the_init_limit() {
+    let mut account = underwater();
+    let liquidation = account.best_classic_liquidation().unwrap().unwrap();
+    let seized = 1000.0 / 3.0;
+
+    // 100 SOL deposited at 100 against a 5000 limit halves the initial weight of 0.7
+    let mut bank_accounts = account.bank_accounts().to_vec();
+    let sol = &mut bank_accounts[0].bank;
+    sol.config.asset_weight_init = I80F48::from_num(0.7).into();
+    sol.config.total_asset_value_init_limit = 5_000;
+    sol.total_asset_shares = I80F48::from_num(100).into();
+    account = MarginfiUser::from_bank_accounts(*account.pubkey(), *account.account(), bank_accounts);
+
+    let liquidator = user(vec![deposit_in(&account, 1, 1000.0)]);
+    assert_close(
+      liquidator.init_health_after_classic_liquidation(&account, &liquidation).unwrap(),
+      (1000.0 - seized * 0.975) * 0.9 + seized * 0.35,
+    );
+    assert_close(
+      liquidator.maintenance_after_classic_liquidation(&account, &liquidation).unwrap(),
+      (1000.0 - seized * 0.975) * 0.9 + seized * 0.8,
+    );
+  }
+}
\ No newline at end of file
@@ -0,0 +1,174 @@
+use anyhow::Context;
+use fixed::types::I80F48;
+use solana_pubkey::Pubkey;
+
+use crate::marginfi::{MarginfiUser, types::{OraclePriceType, PriceAdapter}};
+
+/// Price of a single mint at which the account crosses zero maintenance health,
+/// assuming every other price stays where it is.
+#[derive(Debug, Clone)]
+pub struct LiquidationPrice {
+  pub mint: Pubkey,
+  /// Current oracle price, in usd
+  pub price: I80F48,
+  /// Price at which maintenance health reaches zero, in usd
+  pub liquidation_price: I80F48,
+  /// Relative move from `price` to `liquidation_price`, `-0.2` means a 20% drop.
+  /// Already liquidatable accounts get the move needed to recover instead.
+  pub price_change: I80F48,
+}
+
+impl MarginfiUser {
+  /// Maintenance-weighted net exposure to `mint`: weighted deposits minus weighted debt across
+  /// every bank of that mint. Maintenance health moves by exactly this much per 100% price move.
+  pub fn maint_exposure(&self, mint: &Pubkey) -> anyhow::Result<I80F48> {
+    self.bank_accounts()
+      .iter()
+      .filter(|b| &b.bank.mint == mint)
+      .try_fold(I80F48::ZERO, |acc, bank_account| {
+        let asset_weight = self.maint_asset_weight(bank_account);
+        let liability_weight: I80F48 = bank_account.bank.config.liability_weight_maint.into();
+
+        let weighted_assets = bank_account.asset_value()?
+          .checked_mul(asset_weight)
+          .context("weighted asset value calculation failed")?;
+        let weighted_liabilities = bank_account.liability_value()?
+          .checked_mul(liability_weight)
+          .context("weighted liability value calculation failed")?;
+
+        anyhow::Ok(acc + weighted_assets - weighted_liabilities)
+      })
+  }
+
+  /// Solves `maintenance - exposure + exposure * f = 0` for the price factor `f` of `mint`.
+  /// Returns `None` when the account has no net exposure to the mint or when no positive
+  /// price would bring it to zero.
+  pub fn liquidation_price(&self, mint: &Pubkey) -> anyhow::Result<Option<LiquidationPrice>> {
+    let bank_account = match self.bank_accounts().iter().find(|b| &b.bank.mint == mint) {
+      Some(bank_account) => bank_account,
+      None => return Ok(None),
+    };
+
+    let exposure = self.maint_exposure(mint)?;
+    if exposure == 0 {
+      return Ok(None);
+    }
+
+    let maint = self.maintenance()?;
+    let factor = I80F48::ONE - maint.checked_div(exposure).context("price factor calculation failed")?;
+    if factor <= 0 {
+      return Ok(None);
+    }
+
+    let price = bank_account.price_feed.get_price_of_type(
+      OraclePriceType::RealTime,
+      None,
+      bank_account.bank.config.oracle_max_confidence
+    )?;
+
+    let liquidation_price = price.checked_mul(factor).context("liquidation price calculation failed")?;
+
+    Ok(Some(LiquidationPrice {
+      mint: *mint,
+      price,
+      liquidation_price,
+      price_change: factor - I80F48::ONE,
+    }))
+  }
+
+  /// Liquidation price of every mint the account holds or owes.
+  pub fn liquidation_prices(&self) -> anyhow::Result<Vec<LiquidationPrice>> {
+    let mut mints: Vec<Pubkey> = self.bank_accounts().iter().map(|b| b.bank.mint).collect();
+    mints.sort();
+    mints.dedup();
+
+    let mut prices = Vec::with_capacity(mints.len());
+    for mint in &mints {
+      if let Some(price) = self.liquidation_price(mint)? {
+        prices.push(price);
+      }
+    }
+
+    Ok(prices)
+  }
+
+  /// The single-mint move closest to liquidation, i.e. the smallest `|price_change|`.
+  pub fn nearest_liquidation_price(&self) -> anyhow::Result<Option<LiquidationPrice>> {
+    Ok(
+      self.liquidation_prices()?
+        .into_iter()
+        .min_by_key(|price| price.price_change.abs())
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{position, user, weighted_bank};
+
+  fn assert_close(actual: I80F48, expected: f64) {
+    assert!((actual.to_num::<f64>() - expected).abs() < 1e-9, "{actual} != {expected}");
+  }
+
+  #[test]
+  fn lone_deposits_have_no_liquidation_price() {
+    let sol = weighted_bank(100.0, 0.8, 1.2);
+    let mint = sol.mint;
+    let user = user(vec![position(sol, 10.0, 0.0)]);
+
+    assert_close(user.maint_exposure(&mint).unwrap(), 800.0);
+    assert!(user.liquidation_price(&mint).unwrap().is_none());
+    assert!(user.liquidation_price(&Pubkey::new_unique()).unwrap().is_none());
+  }
+
+  #[test]
+  fn prices_a_deposit_against_a_borrow() {
+    let (sol, usdc) = (weighted_bank(100.0, 0.8, 1.2), weighted_bank(1.0, 0.9, 1.0));
+    let (sol_mint, usdc_mint) = (sol.mint, usdc.mint);
+    // 800 weighted collateral against 500 of debt leaves 300 of maintenance health
+    let user = user(vec![position(sol, 10.0, 0.0), position(usdc, 0.0, 500.0)]);
+
+    let sol = user.liquidation_price(&sol_mint).unwrap().unwrap();
+    assert_close(sol.price, 100.0);
+    assert_close(sol.liquidation_price, 62.5);
+    assert_close(sol.price_change, -0.375);
+
+    let usdc = user.liquidation_price(&usdc_mint).unwrap().unwrap();
+    assert_close(usdc.liquidation_price, 1.6);
+    assert_close(usdc.price_change, 0.6);
+
+    assert_eq!(user.liquidation_prices().unwrap().len(), 2);
+    assert_eq!(user.nearest_liquidation_price().unwrap().unwrap().mint, sol_mint);
+  }
+
+  #[test]
+  fn hedged_mints_have_no_liquidation_price() {
+    let deposit = weighted_bank(100.0, 1.0, 1.0);
+    let mut borrow = weighted_bank(100.0, 1.0, 1.0);
+    borrow.mint = deposit.mint;
+    let mint = deposit.mint;
+    let usdc = weighted_bank(1.0, 0.9, 1.0);
+    let user = user(vec![position(deposit, 10.0, 0.0), position(borrow, 0.0, 10.0), position(usdc, 100.0, 0.0)]);
+
+    assert_close(user.maint_exposure(&mint).unwrap(), 0.0);
+    assert!(user.liquidation_price(&mint).unwrap().is_none());
+  }
+
+  #[test]
+  fn liquidatable_accounts_get_the_move_to_recover() {
+    let (sol, usdc) = (weighted_bank(100.0, 0.8, 1.2), weighted_bank(1.0, 0.9, 1.0));
+    let (sol_mint, usdc_mint) = (sol.mint, usdc.mint);
+    // 800 weighted collateral against 1000 of debt is 200 under water
+    let user = user(vec![position(sol, 10.0, 0.0), position(usdc, 0.0, 1000.0)]);
+    assert_close(user.maintenance().unwrap(), -200.0);
+
+    let sol = user.liquidation_price(&sol_mint).unwrap().unwrap();
+    assert_close(sol.liquidation_price, 125.0);
+    assert_close(sol.price_change, 0.25);
+
+    let usdc = user.liquidation_price(&usdc_mint).unwrap().unwrap();
+    assert_close(usdc.liquidation_price, 0.8);
+    assert_close(usdc.price_change, -0.2);
+  }
+}
\ No newline at end of file
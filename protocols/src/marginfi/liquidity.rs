@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use fixed::types::I80F48;
+use solana_pubkey::Pubkey;
+
+use crate::{marginfi::{TOTAL_ASSET_VALUE_INIT_LIMIT_INACTIVE, types::Bank}, utils::AccountSource};
+
+/// `amount` sits right after `mint` and `owner` in both spl-token and token-2022 accounts.
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+impl Bank {
+  /// Tokens owed to depositors, in native units.
+  pub fn total_deposits(&self) -> Option<I80F48> {
+    self.get_asset_amount(self.total_asset_shares.into())
+  }
+
+  /// Tokens owed by borrowers, in native units.
+  pub fn total_borrows(&self) -> Option<I80F48> {
+    self.get_liability_amount(self.total_liability_shares.into())
+  }
+
+  /// Factor the initial asset weight gets scaled by once total deposits are worth more than
+  /// `total_asset_value_init_limit` at `price`. One while the limit is off or not reached.
+  pub fn init_asset_value_discount(&self, price: I80F48) -> anyhow::Result<I80F48> {
+    if self.config.total_asset_value_init_limit == TOTAL_ASSET_VALUE_INIT_LIMIT_INACTIVE {
+      return Ok(I80F48::ONE);
+    }
+
+    let total_value = self.total_deposits()
+      .and_then(|deposits| self.get_display_asset(deposits))
+      .and_then(|deposits| deposits.checked_mul(price))
+      .context("total asset value calculation failed")?;
+
+    let limit = I80F48::from_num(self.config.total_asset_value_init_limit);
+    if total_value <= limit {
+      return Ok(I80F48::ONE);
+    }
+
+    limit.checked_div(total_value).context("init limit discount calculation failed")
+  }
+}
+
+/// What a bank can hand out right now. Withdrawals are capped by both the tokens in the
+/// liquidity vault and the deposits not lent out, they differ by uncollected fees and donations.
+/// Liquidations credit collateral and debt past `deposit_limit` and `borrow_limit`, so those
+/// limits never block a liquidation and aren't tracked here.
+#[derive(Debug, Clone, Copy)]
+pub struct BankLiquidity {
+  /// Token balance of `liquidity_vault`, in native units
+  pub vault_balance: u64,
+  /// Deposits minus borrows, in native units
+  pub unborrowed: I80F48,
+}
+
+impl BankLiquidity {
+  pub fn new(bank: &Bank, vault_balance: u64) -> anyhow::Result<Self> {
+    let deposits = bank.total_deposits().context("total deposits calculation failed")?;
+    let borrows = bank.total_borrows().context("total borrows calculation failed")?;
+
+    Ok(Self {
+      vault_balance,
+      unborrowed: (deposits - borrows).max(I80F48::ZERO),
+    })
+  }
+
+  /// Largest withdrawal the bank can serve, in native units.
+  pub fn withdrawable(&self) -> I80F48 {
+    self.unborrowed.min(I80F48::from_num(self.vault_balance))
+  }
+
+  pub fn can_withdraw(&self, amount: I80F48) -> bool {
+    amount <= self.withdrawable()
+  }
+}
+
+/// Reads the liquidity vault of every bank. Banks whose vault is missing or unreadable are left out.
+pub async fn load_bank_liquidity<S: AccountSource>(source: &S, banks: &[(Pubkey, Bank)]) -> anyhow::Result<HashMap<Pubkey, BankLiquidity>> {
+  let vaults: Vec<Pubkey> = banks.iter().map(|(_, bank)| bank.liquidity_vault).collect();
+  let vault_accounts = source.get_multiple(&vaults).await?;
+
+  let mut liquidity = HashMap::with_capacity(banks.len());
+  for ((bank_pk, bank), vault_account) in banks.iter().zip(vault_accounts) {
+    let vault_balance = match vault_account.as_ref().and_then(|account| parse_token_amount(&account.data)) {
+      Some(balance) => balance,
+      None => continue,
+    };
+
+    liquidity.insert(*bank_pk, BankLiquidity::new(bank, vault_balance)?);
+  }
+
+  Ok(liquidity)
+}
+
+fn parse_token_amount(data: &[u8]) -> Option<u64> {
+  let bytes = data.get(TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8)?;
+  Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+  use solana_account::Account;
+
+  use super::*;
+  use crate::test_utils::weighted_bank;
+  use crate::utils::AccountCache;
+
+  /// Bank of 1000 deposited and 400 borrowed tokens, with shares worth 1.5 and 2 tokens.
+  fn lent_out_bank() -> Bank {
+    let mut bank = weighted_bank(1.0, 0.8, 1.2);
+    bank.asset_share_value = I80F48::from_num(1.5).into();
+    bank.liability_share_value = I80F48::from_num(2).into();
+    bank.total_asset_shares = I80F48::from_num(1000.0 / 1.5).into();
+    bank.total_liability_shares = I80F48::from_num(200).into();
+    bank
+  }
+
+  fn token_account(amount: u64) -> Account {
+    let mut data = vec![0u8; 165];
+    data[TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8].copy_from_slice(&amount.to_le_bytes());
+    Account { lamports: 1, data, owner: Pubkey::new_unique(), executable: false, rent_epoch: 0 }
+  }
+
+  fn assert_close(actual: I80F48, expected: f64) {
+    assert!((actual.to_num::<f64>() - expected).abs() < 1e-9, "{actual} != {expected}");
+  }
+
+  #[test]
+  fn converts_total_shares_to_tokens() {
+    let bank = lent_out_bank();
+
+    assert_close(bank.total_deposits().unwrap(), 1000.0);
+    assert_close(bank.total_borrows().unwrap(), 400.0);
+  }
+
+  #[test]
+  fn discounts_init_weights_past_the_asset_value_limit() {
+    let mut bank = lent_out_bank();
+    bank.mint_decimals = 2;
+    // 1000 native units are 10 tokens
+    assert_eq!(bank.init_asset_value_discount(I80F48::from_num(1000)).unwrap(), I80F48::ONE);
+
+    bank.config.total_asset_value_init_limit = 5_000;
+    assert_eq!(bank.init_asset_value_discount(I80F48::from_num(400)).unwrap(), I80F48::ONE);
+    assert_eq!(bank.init_asset_value_discount(I80F48::from_num(500)).unwrap(), I80F48::ONE);
+    assert_close(bank.init_asset_value_discount(I80F48::from_num(1000)).unwrap(), 0.5);
+  }
+
+  #[test]
+  fn caps_withdrawals_by_the_vault_and_the_unborrowed_deposits() {
+    let bank = lent_out_bank();
+
+    let vault_bound = BankLiquidity::new(&bank, 500).unwrap();
+    assert_close(vault_bound.unborrowed, 600.0);
+    assert_eq!(vault_bound.withdrawable(), I80F48::from_num(500));
+    assert!(vault_bound.can_withdraw(I80F48::from_num(500)));
+    assert!(!vault_bound.can_withdraw(I80F48::from_num(501)));
+
+    let lending_bound = BankLiquidity::new(&bank, 800).unwrap();
+    assert_close(lending_bound.withdrawable(), 600.0);
+    assert!(!lending_bound.can_withdraw(I80F48::from_num(601)));
+
+    let mut overborrowed = bank;
+    overborrowed.total_liability_shares = I80F48::from_num(600).into();
+    assert_eq!(BankLiquidity::new(&overborrowed, 800).unwrap().withdrawable(), I80F48::ZERO);
+  }
+
+  #[tokio::test]
+  async fn loads_readable_vaults_only() {
+    let (mut liquid, mut unread, mut malformed) = (lent_out_bank(), lent_out_bank(), lent_out_bank());
+    liquid.liquidity_vault = Pubkey::new_unique();
+    unread.liquidity_vault = Pubkey::new_unique();
+    malformed.liquidity_vault = Pubkey::new_unique();
+
+    let source = AccountCache::new();
+    source.insert(liquid.liquidity_vault, token_account(250));
+    source.insert(malformed.liquidity_vault, Account { data: vec![0u8; 70], ..token_account(0) });
+
+    let banks = [(Pubkey::new_unique(), liquid), (Pubkey::new_unique(), unread), (Pubkey::new_unique(), malformed)];
+    let liquidity = load_bank_liquidity(&source, &banks).await.unwrap();
+
+    assert_eq!(liquidity.len(), 1);
+    assert_eq!(liquidity[&banks[0].0].vault_balance, 250);
+    assert_eq!(liquidity[&banks[0].0].withdrawable(), I80F48::from_num(250));
+  }
+}
\ No newline at end of file
@@ -0,0 +1,708 @@
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
+use anchor_lang::prelude::{Clock, sysvar::clock};
+use anyhow::Context;
+use futures_util::StreamExt;
+use solana_account::Account;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::nonblocking::pubsub_client::PubsubClient;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_pubkey::Pubkey;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+use crate::marginfi::{BankAccount, MarginfiAccountType, MarginfiUser};
+use crate::marginfi::types::{Bank, MarginfiAccount, OraclePriceFeedAdapter, OraclePriceFeedAdapterConfig, get_oracle_keys_for_bank};
+use crate::utils::AccountSource;
+
+/// Parsed banks and their price feeds, shared by every user built from it.
+///
+/// Banks, oracle accounts and the clock are stored with the slot they were observed at and
+/// older updates are dropped. A bank's price feed is rebuilt whenever the bank or one of its
+/// oracles changes; feeds keep the staleness verdict of the clock they were built with until
+/// `refresh_price_feeds` is called. While the subscription of a bank, one of its oracles or the
+/// clock is down, the bank has no usable feed. Banks whose feed changed through `update` or a
+/// subscription going down or coming back are announced to `subscribe` receivers.
+pub struct MarketState {
+  inner: RwLock<Inner>,
+  pubsub: Option<Arc<PubsubClient>>,
+  updates: broadcast::Sender<Pubkey>,
+}
+
+const UPDATES_CAPACITY: usize = 4096;
+const RESUBSCRIBE_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESUBSCRIBE_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+struct Slotted<T> {
+  slot: u64,
+  value: T,
+}
+
+#[derive(Default)]
+struct Inner {
+  clock: Option<Slotted<Clock>>,
+  banks: HashMap<Pubkey, Slotted<Bank>>,
+  oracles: HashMap<Pubkey, Slotted<Account>>,
+  oracle_banks: HashMap<Pubkey, Vec<Pubkey>>,
+  price_feeds: HashMap<Pubkey, Result<OraclePriceFeedAdapter, String>>,
+  /// Every tracked account, with its subscription once that's running
+  watched: HashMap<Pubkey, Option<AbortHandle>>,
+  /// Accounts whose subscription is down
+  disconnected: HashSet<Pubkey>,
+}
+
+impl Default for MarketState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl MarketState {
+  /// State that only changes through `track_banks` and `update`.
+  pub fn new() -> Self {
+    let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+    Self { inner: RwLock::new(Inner::default()), pubsub: None, updates }
+  }
+
+  /// State that subscribes to every bank, oracle and the clock as soon as it starts tracking them.
+  pub fn with_subscriptions(pubsub: Arc<PubsubClient>) -> Self {
+    let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+    Self { inner: RwLock::new(Inner::default()), pubsub: Some(pubsub), updates }
+  }
+
+  /// Receives the pubkey of every bank whose state or price feed changed through `update`.
+  pub fn subscribe(&self) -> broadcast::Receiver<Pubkey> {
+    self.updates.subscribe()
+  }
+
+  pub fn bank(&self, pubkey: &Pubkey) -> Option<Bank> {
+    self.inner.read().unwrap().banks.get(pubkey).map(|bank| bank.value)
+  }
+
+  pub fn bank_pubkeys(&self) -> Vec<Pubkey> {
+    self.inner.read().unwrap().banks.keys().copied().collect()
+  }
+
+  pub fn price_feed(&self, bank_pk: &Pubkey) -> Option<anyhow::Result<OraclePriceFeedAdapter>> {
+    self.inner.read().unwrap().price_feeds.get(bank_pk).map(|feed| feed.clone().map_err(|err| anyhow::anyhow!(err)))
+  }
+
+  pub fn clock(&self) -> Option<Clock> {
+    self.inner.read().unwrap().clock.as_ref().map(|clock| clock.value.clone())
+  }
+
+  /// Fetches the banks that aren't tracked yet together with their oracles (and the clock on first use)
+  /// and starts their subscriptions. Oracles that tracked banks switched to and that have no value
+  /// yet are fetched on the way. The snapshot is stored at slot 0 so any streamed update wins.
+  /// Concurrent callers may fetch the same accounts, but each one is claimed and watched only once.
+  pub async fn track_banks<S: AccountSource>(self: &Arc<Self>, source: &S, bank_pubkeys: &[Pubkey]) -> anyhow::Result<()> {
+    let (mut new_banks, unloaded_oracles, needs_clock) = {
+      let inner = self.inner.read().unwrap();
+      let new_banks: Vec<Pubkey> = bank_pubkeys.iter().filter(|pk| !inner.watched.contains_key(*pk)).copied().collect();
+      (new_banks, inner.unloaded_oracles(), inner.clock.is_none())
+    };
+    new_banks.sort();
+    new_banks.dedup();
+
+    if new_banks.is_empty() && unloaded_oracles.is_empty() {
+      return Ok(());
+    }
+
+    let banks: Vec<(Pubkey, Bank)> = match new_banks.is_empty() {
+      true => Vec::new(),
+      false => new_banks
+        .iter()
+        .zip(source.get_multiple(&new_banks).await?)
+        .filter_map(|(pubkey, account)| {
+          let bank = Bank::try_from_account_data(&account?.data).ok()?;
+          Some((*pubkey, bank))
+        })
+        .collect(),
+    };
+
+    let mut new_oracles: Vec<Pubkey> = {
+      let inner = self.inner.read().unwrap();
+      banks
+        .iter()
+        .filter_map(|(_, bank)| get_oracle_keys_for_bank(bank).ok())
+        .flatten()
+        .filter(|key| !inner.watched.contains_key(key))
+        .chain(unloaded_oracles)
+        .collect()
+    };
+    new_oracles.sort();
+    new_oracles.dedup();
+
+    let oracle_accounts = source.get_multiple(&new_oracles).await?;
+
+    let clock = match needs_clock {
+      true => Some(source.get_clock().await?),
+      false => None,
+    };
+
+    let watch = {
+      let mut inner = self.inner.write().unwrap();
+      let mut watch = Vec::new();
+
+      if let Some(clock) = clock {
+        inner.set_clock(0, clock);
+        if inner.claim(clock::ID) {
+          watch.push(clock::ID);
+        }
+      }
+
+      for (pubkey, account) in new_oracles.iter().zip(oracle_accounts) {
+        if let Some(account) = account {
+          inner.set_oracle(*pubkey, 0, account);
+        }
+        if inner.claim(*pubkey) {
+          watch.push(*pubkey);
+        }
+      }
+
+      for (pubkey, bank) in &banks {
+        if inner.claim(*pubkey) {
+          inner.set_bank(*pubkey, 0, *bank);
+          watch.push(*pubkey);
+        }
+      }
+
+      watch
+    };
+
+    for pubkey in watch {
+      self.spawn_watch(pubkey);
+    }
+
+    Ok(())
+  }
+
+  /// Applies a streamed account update. Returns false when the update is older than what's stored.
+  /// A bank that switched oracles has the new ones watched right away, they're fetched by the next
+  /// `track_banks` unless an update streams in first.
+  pub fn update(self: &Arc<Self>, pubkey: Pubkey, slot: u64, account: Account) -> anyhow::Result<bool> {
+    let mut new_oracles = Vec::new();
+    let changed_banks = {
+      let mut inner = self.inner.write().unwrap();
+
+      if pubkey == clock::ID {
+        let clock: Clock = bincode::deserialize(&account.data)?;
+        return Ok(inner.set_clock(slot, clock));
+      }
+
+      if inner.banks.contains_key(&pubkey) {
+        let bank = Bank::try_from_account_data(&account.data)?;
+        match inner.set_bank(pubkey, slot, bank) {
+          true => {
+            let keys = get_oracle_keys_for_bank(&bank).unwrap_or_default();
+            new_oracles.extend(keys.into_iter().filter(|key| inner.claim(*key)));
+            vec![pubkey]
+          },
+          false => Vec::new(),
+        }
+      } else if inner.oracle_banks.contains_key(&pubkey) {
+        match inner.set_oracle(pubkey, slot, account) {
+          true => inner.oracle_banks.get(&pubkey).cloned().unwrap_or_default(),
+          false => Vec::new(),
+        }
+      } else {
+        anyhow::bail!("{} is not tracked", pubkey)
+      }
+    };
+
+    for oracle in new_oracles {
+      self.spawn_watch(oracle);
+    }
+
+    let applied = !changed_banks.is_empty();
+    for bank_pk in changed_banks {
+      // nobody listening is fine
+      let _ = self.updates.send(bank_pk);
+    }
+
+    Ok(applied)
+  }
+
+  /// Records whether the subscription of `pubkey` is up, rebuilding and announcing the feeds that
+  /// depend on it when that changed.
+  fn set_connected(&self, pubkey: Pubkey, connected: bool) {
+    let changed_banks = {
+      let mut inner = self.inner.write().unwrap();
+
+      let changed = match connected {
+        true => inner.disconnected.remove(&pubkey),
+        false => inner.disconnected.insert(pubkey),
+      };
+      if !changed {
+        return;
+      }
+
+      let banks = inner.dependent_banks(&pubkey);
+      for bank_pk in &banks {
+        inner.rebuild_price_feed(bank_pk);
+      }
+      banks
+    };
+
+    for bank_pk in changed_banks {
+      let _ = self.updates.send(bank_pk);
+    }
+  }
+
+  /// Rebuilds every price feed against the latest clock so feeds that stopped updating go stale.
+  pub fn refresh_price_feeds(&self) {
+    let mut inner = self.inner.write().unwrap();
+    let banks: Vec<Pubkey> = inner.banks.keys().copied().collect();
+
+    for bank_pk in banks {
+      inner.rebuild_price_feed(&bank_pk);
+    }
+  }
+
+  /// Builds the user from tracked banks and price feeds without touching the network.
+  pub fn user_from_account(&self, pubkey: Pubkey, account: MarginfiAccount) -> anyhow::Result<MarginfiUser> {
+    let inner = self.inner.read().unwrap();
+
+    let bank_accounts = account
+      .lending_account
+      .get_active_balances_iter()
+      .map(|balance| {
+        let bank = inner.banks
+          .get(&balance.bank_pk)
+          .ok_or_else(|| anyhow::anyhow!("Missing bank {} for account {}", balance.bank_pk, pubkey))?
+          .value;
+
+        let price_feed = match inner.price_feeds.get(&balance.bank_pk) {
+          Some(Ok(pf)) => pf.clone(),
+          Some(Err(e)) => {
+            anyhow::bail!("Failed to load price feed for bank {} in account {}: {}", balance.bank_pk, pubkey, e);
+          }
+          None => {
+            anyhow::bail!("Missing price feed for bank {} in account {}", balance.bank_pk, pubkey);
+          }
+        };
+
+        Ok(BankAccount { bank, price_feed, balance: *balance })
+      })
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(MarginfiUser::from_bank_accounts(pubkey, account, bank_accounts))
+  }
+
+  /// Fetches only the marginfi accounts, everything else comes from memory.
+  /// Banks seen for the first time are tracked on the way.
+  pub async fn load_users<S: AccountSource>(self: &Arc<Self>, source: &S, pubkeys: &[Pubkey]) -> anyhow::Result<Vec<anyhow::Result<MarginfiUser>>> {
+    let (_, users) = self.load_users_at(source, pubkeys).await?;
+
+    Ok(users)
+  }
+
+  /// `load_users`, also returning the slot the accounts were read at when the source knows it.
+  pub async fn load_users_at<S: AccountSource>(self: &Arc<Self>, source: &S, pubkeys: &[Pubkey]) -> anyhow::Result<(Option<u64>, Vec<anyhow::Result<MarginfiUser>>)> {
+    if pubkeys.is_empty() {
+      return Ok((None, Vec::new()));
+    }
+
+    let (slot, accounts) = source.get_multiple_at(pubkeys).await?;
+    let marginfi_accounts: Vec<anyhow::Result<MarginfiAccount>> = accounts
+      .into_iter()
+      .zip(pubkeys)
+      .map(|(account, pubkey)| {
+        let account = account.ok_or_else(|| anyhow::anyhow!("Failed to load marginfi account {}", pubkey))?;
+        anyhow::Ok(MarginfiAccount::try_from_account_data(&account.data)?)
+      })
+      .collect();
+
+    let bank_pubkeys: Vec<Pubkey> = marginfi_accounts
+      .iter()
+      .flatten()
+      .flat_map(|account| account.lending_account.get_active_balances_iter().map(|balance| balance.bank_pk))
+      .collect();
+
+    self.track_banks(source, &bank_pubkeys).await?;
+
+    let users = marginfi_accounts
+      .into_iter()
+      .zip(pubkeys)
+      .map(|(account, pubkey)| self.user_from_account(*pubkey, account?))
+      .collect();
+
+    Ok((slot, users))
+  }
+
+  fn spawn_watch(self: &Arc<Self>, pubkey: Pubkey) {
+    let Some(client) = self.pubsub.clone() else {
+      return;
+    };
+    let state = Arc::clone(self);
+
+    let watcher = tokio::spawn(async move {
+      state.watch(&client, pubkey).await;
+    });
+
+    // the account may have been dropped in the meantime, e.g. an oracle its bank moved away from
+    match self.inner.write().unwrap().watched.get_mut(&pubkey) {
+      Some(handle) => *handle = Some(watcher.abort_handle()),
+      None => watcher.abort(),
+    }
+  }
+
+  /// Streams updates of `pubkey` until it's dropped. A subscription that ends or fails is retried
+  /// with backoff, and the account counts as disconnected until it's back.
+  async fn watch(self: &Arc<Self>, client: &PubsubClient, pubkey: Pubkey) {
+    let config = RpcAccountInfoConfig {
+      encoding: Some(UiAccountEncoding::Base64),
+      commitment: Some(CommitmentConfig::processed()),
+      data_slice: None,
+      min_context_slot: None,
+    };
+    let mut backoff = RESUBSCRIBE_BACKOFF_MIN;
+
+    loop {
+      match client.account_subscribe(&pubkey, Some(config.clone())).await {
+        Ok((mut stream, _unsub)) => {
+          backoff = RESUBSCRIBE_BACKOFF_MIN;
+          self.set_connected(pubkey, true);
+
+          while let Some(response) = stream.next().await {
+            let account = match response.value.decode::<Account>() {
+              Some(account) => account,
+              None => continue,
+            };
+
+            if let Err(err) = self.update(pubkey, response.context.slot, account) {
+              eprintln!("[{}] failed to apply update: {}", pubkey, err);
+            }
+          }
+
+          eprintln!("[{}] market state subscription ended, resubscribing in {:?}", pubkey, backoff);
+        },
+        Err(err) => eprintln!("[{}] market state subscription error, resubscribing in {:?}: {}", pubkey, backoff, err),
+      }
+
+      self.set_connected(pubkey, false);
+      tokio::time::sleep(backoff).await;
+      backoff = (backoff * 2).min(RESUBSCRIBE_BACKOFF_MAX);
+    }
+  }
+}
+
+impl Inner {
+  /// Marks `pubkey` as tracked. False when it already was, someone else watches it.
+  fn claim(&mut self, pubkey: Pubkey) -> bool {
+    match self.watched.entry(pubkey) {
+      Entry::Occupied(_) => false,
+      Entry::Vacant(entry) => {
+        entry.insert(None);
+        true
+      },
+    }
+  }
+
+  /// Oracles tracked banks price off that no value was seen for yet.
+  fn unloaded_oracles(&self) -> Vec<Pubkey> {
+    self.oracle_banks.keys().filter(|key| !self.oracles.contains_key(*key)).copied().collect()
+  }
+
+  /// Banks whose feed is built from `pubkey`.
+  fn dependent_banks(&self, pubkey: &Pubkey) -> Vec<Pubkey> {
+    if *pubkey == clock::ID {
+      return self.banks.keys().copied().collect();
+    }
+
+    let mut banks = self.oracle_banks.get(pubkey).cloned().unwrap_or_default();
+    if self.banks.contains_key(pubkey) {
+      banks.push(*pubkey);
+    }
+    banks
+  }
+
+  fn set_clock(&mut self, slot: u64, clock: Clock) -> bool {
+    if self.clock.as_ref().is_some_and(|current| current.slot > slot) {
+      return false;
+    }
+
+    self.clock = Some(Slotted { slot, value: clock });
+    true
+  }
+
+  fn set_bank(&mut self, pubkey: Pubkey, slot: u64, bank: Bank) -> bool {
+    if self.banks.get(&pubkey).is_some_and(|current| current.slot > slot) {
+      return false;
+    }
+
+    let keys = get_oracle_keys_for_bank(&bank).unwrap_or_default();
+    let previous = self.banks
+      .get(&pubkey)
+      .and_then(|current| get_oracle_keys_for_bank(&current.value).ok())
+      .unwrap_or_default();
+
+    // a migrated oracle is let go once no tracked bank prices off it anymore
+    for key in previous.into_iter().filter(|key| !keys.contains(key)) {
+      self.unlink_oracle(key, &pubkey);
+    }
+
+    for key in keys {
+      let banks = self.oracle_banks.entry(key).or_default();
+      if !banks.contains(&pubkey) {
+        banks.push(pubkey);
+      }
+    }
+
+    self.banks.insert(pubkey, Slotted { slot, value: bank });
+    self.rebuild_price_feed(&pubkey);
+    true
+  }
+
+  fn set_oracle(&mut self, pubkey: Pubkey, slot: u64, account: Account) -> bool {
+    if self.oracles.get(&pubkey).is_some_and(|current| current.slot > slot) {
+      return false;
+    }
+
+    self.oracles.insert(pubkey, Slotted { slot, value: account });
+
+    let banks = self.oracle_banks.get(&pubkey).cloned().unwrap_or_default();
+    for bank_pk in banks {
+      self.rebuild_price_feed(&bank_pk);
+    }
+    true
+  }
+
+  fn unlink_oracle(&mut self, key: Pubkey, bank_pk: &Pubkey) {
+    let Some(banks) = self.oracle_banks.get_mut(&key) else {
+      return;
+    };
+
+    banks.retain(|pk| pk != bank_pk);
+    if !banks.is_empty() {
+      return;
+    }
+
+    self.oracle_banks.remove(&key);
+    self.oracles.remove(&key);
+    self.disconnected.remove(&key);
+    if let Some(Some(watcher)) = self.watched.remove(&key) {
+      watcher.abort();
+    }
+  }
+
+  fn rebuild_price_feed(&mut self, bank_pk: &Pubkey) {
+    if !self.banks.contains_key(bank_pk) {
+      return;
+    }
+
+    let feed = self.build_price_feed(bank_pk).map_err(|err| err.to_string());
+    self.price_feeds.insert(*bank_pk, feed);
+  }
+
+  fn build_price_feed(&self, bank_pk: &Pubkey) -> anyhow::Result<OraclePriceFeedAdapter> {
+    let bank = &self.banks.get(bank_pk).context("bank is not tracked")?.value;
+    let oracle_keys = get_oracle_keys_for_bank(bank)?;
+
+    let down = std::iter::once(bank_pk)
+      .chain(&oracle_keys)
+      .chain([&clock::ID])
+      .find(|key| self.disconnected.contains(*key));
+    if let Some(key) = down {
+      anyhow::bail!("subscription of {} is down", key);
+    }
+
+    let clock = self.clock.as_ref().context("clock is not tracked")?.value.clone();
+
+    let oracle_accounts = oracle_keys
+      .iter()
+      .map(|key| {
+        self.oracles
+          .get(key)
+          .map(|oracle| oracle.value.clone())
+          .ok_or_else(|| anyhow::anyhow!("oracle {} is not tracked", key))
+      })
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let config = OraclePriceFeedAdapterConfig::from_accounts(bank, oracle_accounts, clock, bank.config.get_oracle_max_age())?;
+    OraclePriceFeedAdapter::try_from_config(config).map_err(|err| anyhow::anyhow!(err))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+
+  use anchor_lang::{Discriminator, prelude::borsh};
+  use fixed::types::I80F48;
+  use pyth_solana_receiver_sdk::{PYTH_PUSH_ORACLE_ID, price_update::{PriceFeedMessage, PriceUpdateV2, VerificationLevel}};
+
+  use super::*;
+  use crate::marginfi::{OraclePriceType, OracleSetup, PriceAdapter, RawOracleSetup};
+  use crate::test_utils::{account, clock_account, fixed_bank, user_account};
+  use crate::utils::AccountCache;
+
+  const PUBLISHED_AT: i64 = 1_000;
+
+  /// In-memory source that records every key it's asked for.
+  #[derive(Default)]
+  struct RecordingSource {
+    accounts: AccountCache,
+    requested: Mutex<Vec<Pubkey>>,
+  }
+
+  impl RecordingSource {
+    fn take_requested(&self) -> Vec<Pubkey> {
+      std::mem::take(&mut *self.requested.lock().unwrap())
+    }
+  }
+
+  impl AccountSource for RecordingSource {
+    async fn get_multiple(&self, keys: &[Pubkey]) -> anyhow::Result<Vec<Option<Account>>> {
+      self.requested.lock().unwrap().extend_from_slice(keys);
+      self.accounts.get_multiple(keys).await
+    }
+
+    async fn get_multiple_at(&self, keys: &[Pubkey]) -> anyhow::Result<(Option<u64>, Vec<Option<Account>>)> {
+      self.requested.lock().unwrap().extend_from_slice(keys);
+      self.accounts.get_multiple_at(keys).await
+    }
+
+    async fn get_clock(&self) -> anyhow::Result<Clock> {
+      self.accounts.get_clock().await
+    }
+  }
+
+  fn clock(unix_timestamp: i64) -> Account {
+    clock_account(&Clock { unix_timestamp, ..Clock::default() })
+  }
+
+  fn pyth_bank(oracle: Pubkey) -> Bank {
+    let mut bank = fixed_bank(0.0);
+    bank.config.oracle_setup = RawOracleSetup(OracleSetup::PythPushOracle as u8);
+    bank.config.oracle_keys[0] = oracle;
+    bank
+  }
+
+  /// Fully verified pyth update of `price` dollars, published at `PUBLISHED_AT`.
+  fn pyth_price(price: i64) -> Account {
+    let update = PriceUpdateV2 {
+      write_authority: Pubkey::default(),
+      verification_level: VerificationLevel::Full,
+      price_message: PriceFeedMessage {
+        feed_id: [1; 32],
+        price,
+        conf: 0,
+        exponent: 0,
+        publish_time: PUBLISHED_AT,
+        prev_publish_time: PUBLISHED_AT,
+        ema_price: price,
+        ema_conf: 0,
+      },
+      posted_slot: 0,
+    };
+    let data = [PriceUpdateV2::DISCRIMINATOR, &borsh::to_vec(&update).unwrap()].concat();
+    Account { lamports: 1, data, owner: PYTH_PUSH_ORACLE_ID, executable: false, rent_epoch: 0 }
+  }
+
+  fn price(state: &MarketState, bank_pk: &Pubkey) -> anyhow::Result<I80F48> {
+    let feed = state.price_feed(bank_pk).context("no price feed")??;
+    feed.get_price_of_type(OraclePriceType::RealTime, None, 0).map_err(|err| anyhow::anyhow!(err))
+  }
+
+  /// A pyth priced bank at 150 and a fixed priced bank at 2, with the clock at publication time.
+  fn market() -> (RecordingSource, [Pubkey; 3]) {
+    let [pyth_pk, oracle, fixed_pk] = [(); 3].map(|_| Pubkey::new_unique());
+    let source = RecordingSource::default();
+    source.accounts.insert(clock::ID, clock(PUBLISHED_AT));
+    source.accounts.insert(pyth_pk, account(&pyth_bank(oracle)));
+    source.accounts.insert(oracle, pyth_price(150));
+    source.accounts.insert(fixed_pk, account(&fixed_bank(2.0)));
+    (source, [pyth_pk, oracle, fixed_pk])
+  }
+
+  #[tokio::test]
+  async fn tracks_banks_with_their_oracles_once() {
+    let (source, [pyth_pk, oracle, fixed_pk]) = market();
+    let state = Arc::new(MarketState::new());
+
+    state.track_banks(&source, &[fixed_pk, pyth_pk, fixed_pk]).await.unwrap();
+
+    let mut banks = vec![pyth_pk, fixed_pk];
+    banks.sort();
+    let mut tracked = state.bank_pubkeys();
+    tracked.sort();
+    assert_eq!(tracked, banks);
+    assert_eq!(source.take_requested(), [banks, vec![oracle]].concat());
+    assert_eq!(state.clock().unwrap().unix_timestamp, PUBLISHED_AT);
+    assert_eq!(price(&state, &pyth_pk).unwrap(), 150);
+    assert_eq!(price(&state, &fixed_pk).unwrap(), 2);
+
+    state.track_banks(&source, &[pyth_pk, fixed_pk]).await.unwrap();
+    assert!(source.take_requested().is_empty());
+  }
+
+  #[tokio::test]
+  async fn applies_newer_updates_and_announces_their_banks() {
+    let (source, [pyth_pk, oracle, fixed_pk]) = market();
+    let state = Arc::new(MarketState::new());
+    state.track_banks(&source, &[pyth_pk, fixed_pk]).await.unwrap();
+    let mut updates = state.subscribe();
+
+    assert!(state.update(oracle, 5, pyth_price(160)).unwrap());
+    assert_eq!(updates.try_recv().unwrap(), pyth_pk);
+    assert_eq!(price(&state, &pyth_pk).unwrap(), 160);
+
+    // observed before the stored update
+    assert!(!state.update(oracle, 4, pyth_price(170)).unwrap());
+    assert_eq!(price(&state, &pyth_pk).unwrap(), 160);
+
+    assert!(state.update(fixed_pk, 5, account(&fixed_bank(3.0))).unwrap());
+    assert_eq!(updates.try_recv().unwrap(), fixed_pk);
+    assert_eq!(price(&state, &fixed_pk).unwrap(), 3);
+
+    assert!(state.update(Pubkey::new_unique(), 5, pyth_price(1)).is_err());
+    assert!(updates.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn refreshes_feeds_against_the_latest_clock() {
+    let (source, [pyth_pk, _, fixed_pk]) = market();
+    let state = Arc::new(MarketState::new());
+    state.track_banks(&source, &[pyth_pk, fixed_pk]).await.unwrap();
+
+    let max_age = pyth_bank(Pubkey::default()).config.get_oracle_max_age() as i64;
+    assert!(state.update(clock::ID, 1, clock(PUBLISHED_AT + max_age + 1)).unwrap());
+
+    // feeds keep the verdict of the clock they were built with until refreshed
+    assert_eq!(price(&state, &pyth_pk).unwrap(), 150);
+    state.refresh_price_feeds();
+    assert!(price(&state, &pyth_pk).is_err());
+    assert_eq!(price(&state, &fixed_pk).unwrap(), 2);
+  }
+
+  #[tokio::test]
+  async fn loads_users_off_the_tracked_banks() {
+    let (source, [pyth_pk, oracle, fixed_pk]) = market();
+    let [user, orphan, missing, unknown_bank] = [(); 4].map(|_| Pubkey::new_unique());
+    source.accounts.insert(user, account(&user_account(0, &[pyth_pk, fixed_pk])));
+    source.accounts.insert(orphan, account(&user_account(0, &[unknown_bank])));
+    let state = Arc::new(MarketState::new());
+
+    let users = state.load_users(&source, &[user, orphan, missing]).await.unwrap();
+
+    let banks: Vec<Pubkey> = users[0].as_ref().unwrap().bank_accounts().iter().map(|bank_account| bank_account.balance.bank_pk).collect();
+    assert_eq!(banks, vec![pyth_pk, fixed_pk]);
+    assert!(users[1].is_err());
+    assert!(users[2].is_err());
+
+    let mut requested = source.take_requested();
+    requested.sort();
+    let mut expected = vec![user, orphan, missing, pyth_pk, fixed_pk, unknown_bank, oracle];
+    expected.sort();
+    assert_eq!(requested, expected);
+
+    // a user on tracked banks only costs its own account
+    state.load_users(&source, &[user]).await.unwrap();
+    assert_eq!(source.take_requested(), vec![user]);
+  }
+}
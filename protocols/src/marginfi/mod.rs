@@ -1,26 +1,38 @@
-mod instructions;
+pub mod instructions;
+mod account_type;
 mod user;
 mod types;
 mod consts;
 mod errors;
 mod events;
 mod filter;
+mod liquidation;
+mod liquidation_price;
+mod liquidity;
 mod macros;
+mod market_state;
+pub mod pda;
 mod prelude;
+pub mod stress;
 mod wrapped_i80f48;
 
 use anchor_lang::Discriminator;
+pub use account_type::*;
 pub use errors::*;
-use events::*;
+pub use events::*;
 use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::nonblocking::pubsub_client::PubsubClient;
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSimulateTransactionConfig};
 use solana_client::rpc_filter::{MemcmpEncodedBytes, Memcmp, RpcFilterType};
 use wrapped_i80f48::*;
 pub use consts::*;
 pub use types::*;
 pub use filter::*;
+pub use liquidation::*;
+pub use liquidation_price::*;
+pub use liquidity::*;
+pub use market_state::*;
 pub use user::*;
 
 use std::sync::Arc;
@@ -29,9 +41,10 @@ use solana_pubkey::Pubkey;
 use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
 use anchor_client::{Client, Cluster, Program};
 use anchor_client::solana_sdk::signature::Keypair;
+use solana_sdk::message::Message;
+use solana_sdk::transaction::Transaction;
 
 use crate::consts::MARGINFI_PROGRAM_ID;
-use crate::utils::parse_account;
 
 pub struct Marginfi {
   pubsub: PubsubClient,
@@ -59,7 +72,7 @@ impl Marginfi {
     let filters = vec![
       RpcFilterType::Memcmp(Memcmp::new(
         0,
-        MemcmpEncodedBytes::Bytes(Vec::from(MarginfiAccount::DISCRIMINATOR))
+        MemcmpEncodedBytes::Bytes(discriminators::ACCOUNT.to_vec())
       )),
     ];
 
@@ -90,17 +103,59 @@ impl Marginfi {
   }
 
   pub async fn get_fee_state(&self) -> anyhow::Result<FeeState> {
-    let (expected_key, expected_bump) = Pubkey::find_program_address(&[FEE_STATE_SEED.as_bytes()], &MARGINFI_PROGRAM_ID);
+    let (expected_key, _) = pda::find_fee_state();
     let account = self.rpc_client.get_account(&expected_key).await?;
-    let fee_state = parse_account::<FeeState>(&account.data).map_err(|err| anyhow::anyhow!(err))?;
+    let fee_state = FeeState::try_from_account_data(&account.data)?;
 
     anyhow::Ok(fee_state)
   }
+
+  /// Simulates `lending_account_pulse_health` for the user and returns the health cache the
+  /// program computed. Nothing is sent, `payer` only needs to exist on chain to cover the fee.
+  pub async fn pulse_health(&self, user: &MarginfiUser, payer: &Pubkey) -> anyhow::Result<HealthCache> {
+    let ix = user.pulse_health_ix()?;
+    let tx = Transaction::new_unsigned(Message::new(&[ix], Some(payer)));
+
+    let config = RpcSimulateTransactionConfig {
+      sig_verify: false,
+      replace_recent_blockhash: true,
+      commitment: Some(self.rpc_client.commitment()),
+      ..Default::default()
+    };
+
+    let result = self.rpc_client
+      .simulate_transaction_with_config(&tx, config)
+      .await?;
+
+    if let Some(err) = result.value.err {
+      anyhow::bail!("pulse health simulation failed for {}: {err:?}", user.pubkey());
+    }
+
+    let logs = result.value.logs.unwrap_or_default();
+    let event = parse_anchor_events::<HealthPulseEvent>(&logs)
+      .into_iter()
+      .find(|event| &event.account == user.pubkey())
+      .ok_or_else(|| anyhow::anyhow!("no HealthPulseEvent for {} in simulation logs", user.pubkey()))?;
+
+    anyhow::Ok(event.health_cache)
+  }
 }
 
-fn parse_anchor_event<T: anchor_lang::AnchorDeserialize>(data: &str) -> anyhow::Result<T> {
+/// Decodes every event of type `T` emitted through `Program data:` log lines.
+pub fn parse_anchor_events<T: anchor_lang::AnchorDeserialize + Discriminator>(logs: &[String]) -> Vec<T> {
+  logs
+    .iter()
+    .filter_map(|log| log.strip_prefix("Program data: "))
+    .filter_map(|data| parse_anchor_event::<T>(data).ok())
+    .collect()
+}
+
+fn parse_anchor_event<T: anchor_lang::AnchorDeserialize + Discriminator>(data: &str) -> anyhow::Result<T> {
   use base64::{Engine as _, engine::general_purpose};
   let decoded = general_purpose::STANDARD.decode(data)?;
+  if decoded.len() < 8 || &decoded[..8] != T::DISCRIMINATOR {
+    anyhow::bail!("event discriminator mismatch");
+  }
   let event_data = &decoded[8..];
   Ok(T::deserialize(&mut &event_data[..])?)
 }
\ No newline at end of file
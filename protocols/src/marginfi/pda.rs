@@ -0,0 +1,173 @@
+use solana_pubkey::Pubkey;
+
+use crate::consts::MARGINFI_PROGRAM_ID;
+use crate::marginfi::{
+  EMISSIONS_AUTH_SEED, EMISSIONS_TOKEN_ACCOUNT_SEED, FEE_STATE_SEED, FEE_VAULT_AUTHORITY_SEED,
+  FEE_VAULT_SEED, INSURANCE_VAULT_AUTHORITY_SEED, INSURANCE_VAULT_SEED, LIQUIDATION_RECORD_SEED,
+  LIQUIDITY_VAULT_AUTHORITY_SEED, LIQUIDITY_VAULT_SEED, MARGINFI_ACCOUNT_SEED, STAKED_SETTINGS_SEED,
+};
+
+/// Which of the three per-bank token vaults to derive.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BankVaultType {
+  Liquidity,
+  Insurance,
+  Fee,
+}
+
+impl BankVaultType {
+  fn seed(&self) -> &'static str {
+    match self {
+      BankVaultType::Liquidity => LIQUIDITY_VAULT_SEED,
+      BankVaultType::Insurance => INSURANCE_VAULT_SEED,
+      BankVaultType::Fee => FEE_VAULT_SEED,
+    }
+  }
+
+  fn authority_seed(&self) -> &'static str {
+    match self {
+      BankVaultType::Liquidity => LIQUIDITY_VAULT_AUTHORITY_SEED,
+      BankVaultType::Insurance => INSURANCE_VAULT_AUTHORITY_SEED,
+      BankVaultType::Fee => FEE_VAULT_AUTHORITY_SEED,
+    }
+  }
+}
+
+/// Token account holding the bank's funds of the given vault type, seeds: `[vault_seed, bank]`.
+pub fn find_bank_vault(bank: &Pubkey, vault_type: BankVaultType) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[vault_type.seed().as_bytes(), bank.as_ref()], &MARGINFI_PROGRAM_ID)
+}
+
+/// Owner of the bank vault of the given type, seeds: `[vault_authority_seed, bank]`.
+pub fn find_bank_vault_authority(bank: &Pubkey, vault_type: BankVaultType) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[vault_type.authority_seed().as_bytes(), bank.as_ref()], &MARGINFI_PROGRAM_ID)
+}
+
+pub fn find_liquidity_vault(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault(bank, BankVaultType::Liquidity)
+}
+
+pub fn find_liquidity_vault_authority(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault_authority(bank, BankVaultType::Liquidity)
+}
+
+pub fn find_insurance_vault(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault(bank, BankVaultType::Insurance)
+}
+
+pub fn find_insurance_vault_authority(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault_authority(bank, BankVaultType::Insurance)
+}
+
+pub fn find_fee_vault(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault(bank, BankVaultType::Fee)
+}
+
+pub fn find_fee_vault_authority(bank: &Pubkey) -> (Pubkey, u8) {
+  find_bank_vault_authority(bank, BankVaultType::Fee)
+}
+
+/// The program-wide fee state, seeds: `[b"feestate"]`.
+pub fn find_fee_state() -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[FEE_STATE_SEED.as_bytes()], &MARGINFI_PROGRAM_ID)
+}
+
+/// Liquidation record of a marginfi account, seeds: `[b"liq_record", marginfi_account]`.
+pub fn find_liquidation_record(marginfi_account: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[LIQUIDATION_RECORD_SEED.as_bytes(), marginfi_account.as_ref()], &MARGINFI_PROGRAM_ID)
+}
+
+/// PDA-based marginfi account (0.1.5 or later), seeds:
+/// `[b"marginfi_account", group, authority, account_index, third_party_id]`.
+pub fn find_marginfi_account(group: &Pubkey, authority: &Pubkey, account_index: u16, third_party_id: Option<u16>) -> (Pubkey, u8) {
+  Pubkey::find_program_address(
+    &[
+      MARGINFI_ACCOUNT_SEED.as_bytes(),
+      group.as_ref(),
+      authority.as_ref(),
+      &account_index.to_le_bytes(),
+      &third_party_id.unwrap_or(0).to_le_bytes(),
+    ],
+    &MARGINFI_PROGRAM_ID,
+  )
+}
+
+/// Authority over a bank's emissions token account, seeds: `[b"emissions_auth_seed", bank, emissions_mint]`.
+pub fn find_emissions_auth(bank: &Pubkey, emissions_mint: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(
+    &[EMISSIONS_AUTH_SEED.as_bytes(), bank.as_ref(), emissions_mint.as_ref()],
+    &MARGINFI_PROGRAM_ID,
+  )
+}
+
+/// A bank's emissions token account, seeds: `[b"emissions_token_account_seed", bank, emissions_mint]`.
+pub fn find_emissions_token_account(bank: &Pubkey, emissions_mint: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(
+    &[EMISSIONS_TOKEN_ACCOUNT_SEED.as_bytes(), bank.as_ref(), emissions_mint.as_ref()],
+    &MARGINFI_PROGRAM_ID,
+  )
+}
+
+/// Staked collateral settings of a group, seeds: `[b"staked_settings", group]`.
+pub fn find_staked_settings(group: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[STAKED_SETTINGS_SEED.as_bytes(), group.as_ref()], &MARGINFI_PROGRAM_ID)
+}
+
+#[cfg(test)]
+mod tests {
+  use solana_pubkey::pubkey;
+
+  use super::*;
+
+  /// Mainnet USDC bank of the main group.
+  const USDC_BANK: Pubkey = pubkey!("2s37akK2eyBbp8DZgCm7RtsaEz8eJP3Nxd4urLHQv7yB");
+  const MAIN_GROUP: Pubkey = pubkey!("4qp6Fx6tnZkY5Wropq9wUYgtFxXKwE6viZxFHg3rdAG8");
+
+  #[test]
+  fn derives_the_usdc_bank_vaults() {
+    assert_eq!(find_liquidity_vault(&USDC_BANK), (pubkey!("7jaiZR5Sk8hdYN9MxTpczTcwbWpb5WEoxSANuUwveuat"), 255));
+    assert_eq!(find_insurance_vault(&USDC_BANK), (pubkey!("GD1uQyAWbC1P9ZCb7BaEPXq83ntCqQCwxiwWhpJJuCaK"), 255));
+    assert_eq!(find_fee_vault(&USDC_BANK), (pubkey!("66bbb81Xo3cwR3J8XNbumHixVHSeLy49Dcb3Pn2kb5Gd"), 254));
+  }
+
+  #[test]
+  fn derives_the_usdc_bank_vault_authorities() {
+    assert_eq!(find_liquidity_vault_authority(&USDC_BANK), (pubkey!("3uxNepDbmkDNq6JhRja5Z8QwbTrfmkKP8AKZV5chYDGG"), 255));
+    assert_eq!(find_insurance_vault_authority(&USDC_BANK), (pubkey!("9Z49zR1igJGX2THxwJJJt8jZha9HRh2USBpuFfho9qU9"), 253));
+    assert_eq!(find_fee_vault_authority(&USDC_BANK), (pubkey!("EdntnNR1XU3hFpoQeztMqExMSggJonZ3bVNwD3NDruK8"), 253));
+  }
+
+  #[test]
+  fn derives_the_usdc_bank_emissions_accounts() {
+    let mint = pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v");
+    assert_eq!(find_emissions_auth(&USDC_BANK, &mint), (pubkey!("FLA5iVCNdKjc84BFrpxKUHfLt8bQEWFeNCCjjk8kub5a"), 252));
+    assert_eq!(find_emissions_token_account(&USDC_BANK, &mint), (pubkey!("2rnUEKPXd6WWnxE5vVHphCh9qAaYNv6mdyRFs2iM2wBD"), 254));
+  }
+
+  #[test]
+  fn derives_the_main_group_staked_settings() {
+    assert_eq!(find_staked_settings(&MAIN_GROUP), (pubkey!("BLf3ZNsDXUw544Gpafg1gZqCb7R6eH1vF4QJr7iMztVV"), 253));
+  }
+
+  #[test]
+  fn derives_the_fee_state() {
+    assert_eq!(find_fee_state(), (pubkey!("HoMNdUF3RDZDPKAARYK1mxcPFfUnPjLmpKYibZzAijev"), 255));
+  }
+
+  #[test]
+  fn derives_pda_marginfi_accounts() {
+    let authority = pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v");
+
+    // no third party id is the same as id 0, index and id are little endian u16s
+    let first = (pubkey!("SSeGM3iifebE852XGhKUDuRKoPK16NMo457Qqb4Hu1C"), 255);
+    assert_eq!(find_marginfi_account(&MAIN_GROUP, &authority, 0, None), first);
+    assert_eq!(find_marginfi_account(&MAIN_GROUP, &authority, 0, Some(0)), first);
+    assert_eq!(find_marginfi_account(&MAIN_GROUP, &authority, 1, Some(7)), (pubkey!("CsRjBesGDtNYwGL3C57vNyDRqtdVALHYoTmJwCrD1BYh"), 255));
+  }
+
+  #[test]
+  fn derives_liquidation_records() {
+    let account = pubkey!("SSeGM3iifebE852XGhKUDuRKoPK16NMo457Qqb4Hu1C");
+    assert_eq!(find_liquidation_record(&account), (pubkey!("4kAKm7VXXj3wWzqyL1cT4pFgUepKrAKhQqxBACrW4RNs"), 254));
+  }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use fixed::types::I80F48;
+use solana_pubkey::Pubkey;
+
+use crate::marginfi::{BalanceSide, MarginfiUser};
+
+/// Relative price moves per mint, `-0.1` is a 10% drop.
+pub type PriceShocks = HashMap<Pubkey, I80F48>;
+
+impl MarginfiUser {
+  /// Copy of the user whose price feeds report prices moved by `shocks`. Mints without a shock keep their price.
+  pub fn with_price_shocks(&self, shocks: &PriceShocks) -> Self {
+    let bank_accounts = self.bank_accounts()
+      .iter()
+      .map(|bank_account| {
+        let mut bank_account = bank_account.clone();
+        if let Some(change) = shocks.get(&bank_account.bank.mint) {
+          bank_account.price_feed = bank_account.price_feed.shocked(I80F48::ONE + change);
+        }
+        bank_account
+      })
+      .collect();
+
+    Self::from_bank_accounts(*self.pubkey(), *self.account(), bank_accounts)
+  }
+}
+
+/// Outcome of a price shock over a set of accounts. Seizable collateral and needed liquidity are
+/// totals over every account that is liquidatable after the shock, valued at shocked prices, so
+/// they are upper bounds for a full receivership of each position.
+#[derive(Debug, Default, Clone)]
+pub struct StressReport {
+  pub accounts_checked: usize,
+  pub accounts_failed: usize,
+  /// Healthy before the shock, liquidatable after it
+  pub newly_liquidatable: Vec<Pubkey>,
+  /// Liquidatable before the shock already
+  pub already_liquidatable: Vec<Pubkey>,
+  /// Withdrawable collateral value per mint, in usd
+  pub seizable_by_mint: HashMap<Pubkey, I80F48>,
+  /// Debt value per mint that has to be repaid, in usd
+  pub liquidity_needed_by_mint: HashMap<Pubkey, I80F48>,
+}
+
+impl StressReport {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Applies `shocks` to the user and adds the result to the report.
+  pub fn record(&mut self, user: &MarginfiUser, shocks: &PriceShocks) {
+    self.accounts_checked += 1;
+    if self.try_record(user, shocks).is_err() {
+      self.accounts_failed += 1;
+    }
+  }
+
+  fn try_record(&mut self, user: &MarginfiUser, shocks: &PriceShocks) -> anyhow::Result<()> {
+    let was_liquidatable = user.eligible_for_liquidation()?;
+    let shocked = user.with_price_shocks(shocks);
+    if !shocked.eligible_for_liquidation()? {
+      return Ok(());
+    }
+
+    let mut seizable = Vec::new();
+    let mut needed = Vec::new();
+    for bank_account in shocked.bank_accounts() {
+      if !bank_account.balance.is_empty(BalanceSide::Assets) && shocked.is_bank_withdrawable(bank_account) {
+        seizable.push((bank_account.bank.mint, bank_account.asset_value()?));
+      }
+      if !bank_account.balance.is_empty(BalanceSide::Liabilities) {
+        needed.push((bank_account.bank.mint, bank_account.liability_value()?));
+      }
+    }
+
+    for (mint, value) in seizable {
+      *self.seizable_by_mint.entry(mint).or_insert(I80F48::ZERO) += value;
+    }
+    for (mint, value) in needed {
+      *self.liquidity_needed_by_mint.entry(mint).or_insert(I80F48::ZERO) += value;
+    }
+
+    match was_liquidatable {
+      true => self.already_liquidatable.push(*user.pubkey()),
+      false => self.newly_liquidatable.push(*user.pubkey()),
+    }
+
+    Ok(())
+  }
+}
+
+/// Runs `shocks` over every user.
+pub fn stress<'a>(users: impl IntoIterator<Item = &'a MarginfiUser>, shocks: &PriceShocks) -> StressReport {
+  let mut report = StressReport::new();
+  for user in users {
+    report.record(user, shocks);
+  }
+
+  report
+}
+
+/// Totals at one level of a price ladder for a single mint.
+#[derive(Debug, Clone)]
+pub struct LadderLevel {
+  /// Relative move of the mint's price, `-0.1` is a 10% drop
+  pub price_change: I80F48,
+  /// Price of the mint at this level, in usd
+  pub price: I80F48,
+  pub liquidatable_accounts: usize,
+  /// Withdrawable collateral of every account liquidatable at this level, in usd
+  pub seizable: I80F48,
+  /// Debt of every account liquidatable at this level, in usd
+  pub repayable: I80F48,
+}
+
+/// Moves only `mint` to each of `price_changes` and totals what becomes liquidatable there.
+/// Every level counts all accounts liquidatable at that price, not just the ones crossing between levels.
+pub fn price_ladder<'a>(users: impl IntoIterator<Item = &'a MarginfiUser> + Clone, mint: &Pubkey, price: I80F48, price_changes: &[I80F48]) -> Vec<LadderLevel> {
+  price_changes
+    .iter()
+    .map(|change| {
+      let shocks = PriceShocks::from([(*mint, *change)]);
+      let report = stress(users.clone(), &shocks);
+
+      LadderLevel {
+        price_change: *change,
+        price: price * (I80F48::ONE + change),
+        liquidatable_accounts: report.newly_liquidatable.len() + report.already_liquidatable.len(),
+        seizable: report.seizable_by_mint.values().copied().sum(),
+        repayable: report.liquidity_needed_by_mint.values().copied().sum(),
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::marginfi::{Bank, OraclePriceType, PriceAdapter};
+  use crate::test_utils::{position, user, weighted_bank};
+
+  fn assert_close(actual: I80F48, expected: f64) {
+    assert!((actual.to_num::<f64>() - expected).abs() < 1e-9, "{actual} != {expected}");
+  }
+
+  /// 10 SOL at 100 weighted by 0.8 against `debt` USDC.
+  fn borrower(sol: Bank, usdc: Bank, debt: f64) -> MarginfiUser {
+    user(vec![position(sol, 10.0, 0.0), position(usdc, 0.0, debt)])
+  }
+
+  fn banks() -> (Bank, Bank) {
+    (weighted_bank(100.0, 0.8, 1.2), weighted_bank(1.0, 0.9, 1.0))
+  }
+
+  #[test]
+  fn shocks_only_the_given_mints() {
+    let (sol, usdc) = banks();
+    let user = borrower(sol, usdc, 500.0);
+    assert_close(user.maintenance().unwrap(), 300.0);
+
+    let shocked = user.with_price_shocks(&PriceShocks::from([(sol.mint, I80F48::from_num(-0.5))]));
+    let prices: Vec<I80F48> = shocked.bank_accounts()
+      .iter()
+      .map(|b| b.price_feed.get_price_of_type(OraclePriceType::RealTime, None, 0).unwrap())
+      .collect();
+    assert_close(prices[0], 50.0);
+    assert_close(prices[1], 1.0);
+
+    assert_close(shocked.maintenance().unwrap(), -100.0);
+    assert!(shocked.eligible_for_liquidation().unwrap());
+    assert!(!user.eligible_for_liquidation().unwrap());
+    assert_eq!(shocked.pubkey(), user.pubkey());
+  }
+
+  #[test]
+  fn splits_newly_and_already_liquidatable_accounts() {
+    let (sol, usdc) = banks();
+    let users = [borrower(sol, usdc, 100.0), borrower(sol, usdc, 500.0), borrower(sol, usdc, 1000.0)];
+
+    let report = stress(&users, &PriceShocks::from([(sol.mint, I80F48::from_num(-0.5))]));
+
+    assert_eq!(report.accounts_checked, 3);
+    assert_eq!(report.accounts_failed, 0);
+    assert_eq!(report.newly_liquidatable, vec![*users[1].pubkey()]);
+    assert_eq!(report.already_liquidatable, vec![*users[2].pubkey()]);
+    // Collateral is valued at the shocked price of 50
+    assert_close(report.seizable_by_mint[&sol.mint], 1000.0);
+    assert_close(report.liquidity_needed_by_mint[&usdc.mint], 1500.0);
+    assert!(!report.seizable_by_mint.contains_key(&usdc.mint));
+  }
+
+  #[test]
+  fn ladders_count_every_liquidatable_account_per_level() {
+    let (sol, usdc) = banks();
+    let users = [borrower(sol, usdc, 100.0), borrower(sol, usdc, 500.0), borrower(sol, usdc, 1000.0)];
+    let changes = [0.0, -0.4375, -0.9375].map(I80F48::from_num);
+
+    let ladder = price_ladder(&users, &sol.mint, I80F48::from_num(100), &changes);
+
+    let levels: Vec<(f64, usize, f64, f64)> = ladder
+      .iter()
+      .map(|l| (l.price.to_num(), l.liquidatable_accounts, l.seizable.to_num(), l.repayable.to_num()))
+      .collect();
+    assert_eq!(levels, vec![
+      (100.0, 1, 1000.0, 1000.0),
+      (56.25, 2, 1125.0, 1500.0),
+      (6.25, 3, 187.5, 1600.0),
+    ]);
+  }
+}
\ No newline at end of file
@@ -146,6 +146,20 @@ impl Bank {
     amount
       .checked_div(div)
   }
+
+  /// Withdrawals and repayments go through on operational and reduce-only banks. Paused banks
+  /// reject everything and killed banks had their balances written off by bankruptcy handling.
+  pub fn allows_withdraw_and_repay(&self) -> bool {
+    matches!(
+      self.config.operational_state.validate(),
+      Ok(BankOperationalState::Operational | BankOperationalState::ReduceOnly)
+    )
+  }
+
+  /// New deposits and borrows are only accepted while the bank is fully operational.
+  pub fn allows_deposit_and_borrow(&self) -> bool {
+    matches!(self.config.operational_state.validate(), Ok(BankOperationalState::Operational))
+  }
 }
 
 #[repr(transparent)]
@@ -271,4 +285,32 @@ impl OracleSetup {
             _ => None,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bank_in(state: u8) -> Bank {
+    let mut bank = Bank::zeroed();
+    bank.config.operational_state = RawBankOperationalState(state);
+    bank
+  }
+
+  #[test]
+  fn gates_actions_by_operational_state() {
+    let cases = [
+      (BankOperationalState::Paused as u8, false, false),
+      (BankOperationalState::Operational as u8, true, true),
+      (BankOperationalState::ReduceOnly as u8, true, false),
+      (BankOperationalState::KilledByBankruptcy as u8, false, false),
+      (9, false, false),
+    ];
+
+    for (state, withdraw_and_repay, deposit_and_borrow) in cases {
+      let bank = bank_in(state);
+      assert_eq!(bank.allows_withdraw_and_repay(), withdraw_and_repay, "state {state}");
+      assert_eq!(bank.allows_deposit_and_borrow(), deposit_and_borrow, "state {state}");
+    }
+  }
 }
\ No newline at end of file
@@ -63,4 +63,54 @@ impl FeeState {
     pub fn from_bytes_mut(v: &mut [u8]) -> &mut Self {
         bytemuck::from_bytes_mut(v)
     }
+
+    /// Whether a global pause is in effect at `current_timestamp`. Pauses lift by themselves
+    /// once `PanicState::PAUSE_DURATION_SECONDS` have passed, even if the flag stays set.
+    pub fn is_paused(&self, current_timestamp: i64) -> bool {
+        !self.panic_state.is_expired(current_timestamp)
+    }
+
+    /// When the current pause lifts on its own, `None` if the pause flag isn't set.
+    pub fn pause_expires_at(&self) -> Option<i64> {
+        self.panic_state
+            .is_paused_flag()
+            .then(|| self.panic_state.pause_start_timestamp + PanicState::PAUSE_DURATION_SECONDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAUSED_AT: i64 = 1_000;
+
+    fn fee_state(pause_flags: u8) -> FeeState {
+        let mut fee_state = FeeState::zeroed();
+        fee_state.panic_state.pause_flags = pause_flags;
+        fee_state.panic_state.pause_start_timestamp = PAUSED_AT;
+        fee_state
+    }
+
+    #[test]
+    fn pauses_lift_once_they_expire() {
+        let fee_state = fee_state(PanicState::FLAG_PAUSED);
+        let expires_at = PAUSED_AT + PanicState::PAUSE_DURATION_SECONDS;
+
+        assert_eq!(fee_state.pause_expires_at(), Some(expires_at));
+        assert!(fee_state.is_paused(PAUSED_AT));
+        assert!(fee_state.is_paused(expires_at - 1));
+        assert!(!fee_state.is_paused(expires_at));
+        assert!(!fee_state.is_paused(expires_at + 1));
+        // Clocks behind the pause start count as paused
+        assert!(fee_state.is_paused(PAUSED_AT - 1));
+    }
+
+    #[test]
+    fn unflagged_states_are_not_paused() {
+        let fee_state = fee_state(0);
+
+        assert_eq!(fee_state.pause_expires_at(), None);
+        assert!(!fee_state.is_paused(PAUSED_AT));
+        assert!(!fee_state.is_paused(PAUSED_AT + 1));
+    }
 }
\ No newline at end of file
@@ -2,15 +2,13 @@ use super::super::consts::{
   MIN_PYTH_PUSH_VERIFICATION_LEVEL, NATIVE_STAKE_ID, PYTH_ID, SPL_SINGLE_POOL_ID,
   SWITCHBOARD_PULL_ID,
 };
-use anchor_lang::prelude::sysvar::clock;
 use anchor_lang::prelude::*;
 use anchor_client::solana_sdk::{borsh::try_from_slice_unchecked, stake::state::StakeStateV2};
 use serde::{Deserialize, Serialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
 use crate::marginfi::types::helpers::{boxed_price_serde, current_result_serde};
 use solana_account::Account;
 use crate::marginfi::types::{MinimalSpotMarket, SolendMinimalReserve};
-use crate::utils::parse_account;
+use crate::utils::{AccountSource, parse_account};
 use crate::{check, check_eq, debug, live, math_error};
 use super::super::prelude::*;
 use anchor_spl::token::Mint;
@@ -64,38 +62,28 @@ pub enum OraclePriceFeedAdapterConfigError {
   RpcError,
 }
 
-async fn get_multiple_accounts(
-  client: &RpcClient,
+async fn get_multiple_accounts<S: AccountSource>(
+  source: &S,
   keys: &[Pubkey],
 ) -> anyhow::Result<Vec<solana_account::Account>> {
-  const BATCH_SIZE: usize = 100;
-  
   if keys.is_empty() {
     return Ok(Vec::new());
   }
-  
-  let mut all_accounts = Vec::with_capacity(keys.len());
-  
-  for chunk in keys.chunks(BATCH_SIZE) {
-    let accounts = client
-      .get_multiple_accounts(chunk)
-      .await
-      .map_err(|e| anyhow::anyhow!(OraclePriceFeedAdapterConfigError::RpcError).context(e))?;
-    
-    let accounts: Vec<_> = accounts
-      .into_iter()
-      .enumerate()
-      .map(|(i, maybe_account)| -> anyhow::Result<_> {  // Explicitly specify anyhow::Result
-        maybe_account.ok_or_else(|| {
-          anyhow::anyhow!("Oracle account not found: {}", chunk[i])
-        })
+
+  let accounts = source
+    .get_multiple(keys)
+    .await
+    .map_err(|e| anyhow::anyhow!(OraclePriceFeedAdapterConfigError::RpcError).context(e))?;
+
+  accounts
+    .into_iter()
+    .enumerate()
+    .map(|(i, maybe_account)| -> anyhow::Result<_> {  // Explicitly specify anyhow::Result
+      maybe_account.ok_or_else(|| {
+        anyhow::anyhow!("Oracle account not found: {}", keys[i])
       })
-      .collect::<anyhow::Result<Vec<_>>>()?;
-    
-    all_accounts.extend(accounts);
-  }
-  
-  Ok(all_accounts)
+    })
+    .collect::<anyhow::Result<Vec<_>>>()
 }
 
 
@@ -143,8 +131,8 @@ pub struct OraclePriceFeedAdapterConfig<'info> {
 }
 
 impl<'info> OraclePriceFeedAdapterConfig<'info> {
-  pub async fn load_multiple(
-    client: &RpcClient,
+  pub async fn load_multiple<S: AccountSource>(
+    source: &S,
     banks: &'info [Bank]
   ) -> anyhow::Result<Vec<Self>> {
     let max_ages: Vec<u64> = banks
@@ -152,22 +140,21 @@ impl<'info> OraclePriceFeedAdapterConfig<'info> {
       .map(|bank| bank.config.get_oracle_max_age())
       .collect();
     
-    Self::load_multiple_with_max_ages(client, banks, &max_ages).await
+    Self::load_multiple_with_max_ages(source, banks, &max_ages).await
   }
 
-  pub async fn load_multiple_with_max_ages(
-    client: &RpcClient,
+  pub async fn load_multiple_with_max_ages<S: AccountSource>(
+    source: &S,
     banks: &'info [Bank],
     max_ages: &[u64]
   ) -> anyhow::Result<Vec<Self>> {
-    let clock_account = client.get_account(&clock::ID).await?;
-    let clock: Clock = bincode::deserialize(&clock_account.data)?;
+    let clock = source.get_clock().await?;
     
-    Self::load_multiple_with_clock_and_max_ages(client, banks, clock, max_ages).await
+    Self::load_multiple_with_clock_and_max_ages(source, banks, clock, max_ages).await
   }
 
-  pub async fn load_multiple_with_clock_and_max_ages(
-    client: &RpcClient,
+  pub async fn load_multiple_with_clock_and_max_ages<S: AccountSource>(
+    source: &S,
     banks: &'info [Bank],
     clock: Clock,
     max_ages: &[u64]
@@ -201,7 +188,7 @@ impl<'info> OraclePriceFeedAdapterConfig<'info> {
     let oracle_accounts = if unique_oracle_keys.is_empty() {
       Vec::new()
     } else {
-      get_multiple_accounts(client, &unique_oracle_keys).await?
+      get_multiple_accounts(source, &unique_oracle_keys).await?
     };
 
     let mut configs = Vec::with_capacity(banks.len());
@@ -214,35 +201,46 @@ impl<'info> OraclePriceFeedAdapterConfig<'info> {
         .map(|&idx| oracle_accounts[idx].clone())
         .collect();
       
-      let accounts = build_oracle_accounts(bank, bank_oracle_accounts)?;
-      
-      configs.push(Self {
-        bank,
-        accounts,
-        clock: clock.clone(),
-        max_age: max_ages[i],
-      });
+      configs.push(Self::from_accounts(bank, bank_oracle_accounts, clock.clone(), max_ages[i])?);
     }
 
     Ok(configs)
   }
 
-  pub async fn load_with_clock(
-    client: &RpcClient,
+  /// Builds the config from oracle accounts that were already fetched, ordered like
+  /// `get_oracle_keys_for_bank` returns their keys.
+  pub fn from_accounts(
+    bank: &'info Bank,
+    oracle_accounts: Vec<solana_account::Account>,
+    clock: Clock,
+    max_age: u64
+  ) -> anyhow::Result<Self> {
+    let accounts = build_oracle_accounts(bank, oracle_accounts)?;
+
+    Ok(Self {
+      bank,
+      accounts,
+      clock,
+      max_age,
+    })
+  }
+
+  pub async fn load_with_clock<S: AccountSource>(
+    source: &S,
     bank: &'info Bank,
     clock: Clock
   ) -> anyhow::Result<Self> {
-    Self::load_with_clock_and_max_age(client, bank, clock, bank.config.get_oracle_max_age()).await
+    Self::load_with_clock_and_max_age(source, bank, clock, bank.config.get_oracle_max_age()).await
   }
 
-  pub async fn load_with_clock_and_max_age(
-    client: &RpcClient,
+  pub async fn load_with_clock_and_max_age<S: AccountSource>(
+    source: &S,
     bank: &'info Bank,
     clock: Clock,
     max_age: u64
   ) -> anyhow::Result<Self> {
     let mut configs = Self::load_multiple_with_clock_and_max_ages(
-      client,
+      source,
       std::slice::from_ref(bank),
       clock,
       &[max_age]
@@ -252,7 +250,7 @@ impl<'info> OraclePriceFeedAdapterConfig<'info> {
   }
 }
 
-fn get_oracle_keys_for_bank(bank: &Bank) -> anyhow::Result<Vec<Pubkey>> {
+pub fn get_oracle_keys_for_bank(bank: &Bank) -> anyhow::Result<Vec<Pubkey>> {
   match bank.config.oracle_setup.validate().map_err(|err| anyhow::anyhow!(err))? {
     OracleSetup::None => {
       Err(anyhow::anyhow!(MarginfiError::OracleNotSetup))
@@ -364,9 +362,15 @@ pub enum OraclePriceFeedAdapter {
   PythPushOracle(PythPushOraclePriceFeed),
   SwitchboardPull(SwitchboardPullPriceFeed),
   Fixed(FixedPriceFeed),
+  Shocked(ShockedPriceFeed),
 }
 
 impl OraclePriceFeedAdapter {
+  /// Same feed with every price it reports multiplied by `factor`, used for what-if scenarios.
+  pub fn shocked(self, factor: I80F48) -> Self {
+    OraclePriceFeedAdapter::Shocked(ShockedPriceFeed { inner: Box::new(self), factor })
+  }
+
   pub fn try_from_config<'info>(config: OraclePriceFeedAdapterConfig<'info>) -> MarginfiResult<Self> {
       match config.accounts {
         OracleAccounts::None => {
@@ -504,6 +508,24 @@ impl OraclePriceFeedAdapter {
   }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShockedPriceFeed {
+  pub inner: Box<OraclePriceFeedAdapter>,
+  pub factor: I80F48,
+}
+
+impl PriceAdapter for ShockedPriceFeed {
+  fn get_price_of_type(
+      &self,
+      oracle_price_type: OraclePriceType,
+      bias: Option<PriceBias>,
+      oracle_max_confidence: u32,
+  ) -> MarginfiResult<I80F48> {
+      let price = self.inner.get_price_of_type(oracle_price_type, bias, oracle_max_confidence)?;
+      Ok(price.checked_mul(self.factor).ok_or_else(math_error!())?)
+  }
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct FixedPriceFeed {
   pub price: I80F48,
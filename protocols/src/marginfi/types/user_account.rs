@@ -2,7 +2,6 @@ use crate::{
   assert_struct_align, assert_struct_size,
 };
 
-use crate::consts::MARGINFI_PROGRAM_ID;
 use super::super::consts::{discriminators, ASSET_TAG_DEFAULT, EMPTY_BALANCE_THRESHOLD};
 use bytemuck::{Pod, Zeroable};
 use fixed::types::I80F48;
@@ -80,19 +79,28 @@ impl MarginfiAccount {
       authority: &Pubkey,
       account_index: u16,
       third_party_id: Option<u16>,
-      program_id: &Pubkey,
   ) -> (Pubkey, u8) {
-      use super::super::consts::MARGINFI_ACCOUNT_SEED;
-      Pubkey::find_program_address(
-          &[
-              MARGINFI_ACCOUNT_SEED.as_bytes(),
-              group.as_ref(),
-              authority.as_ref(),
-              &account_index.to_le_bytes(),
-              &third_party_id.unwrap_or(0).to_le_bytes(),
-          ],
-          program_id,
-      )
+      super::super::pda::find_marginfi_account(group, authority, account_index, third_party_id)
+  }
+
+  pub fn has_flag(&self, flag: u64) -> bool {
+    self.account_flags & flag != 0
+  }
+
+  pub fn is_disabled(&self) -> bool {
+    self.has_flag(ACCOUNT_DISABLED)
+  }
+
+  pub fn is_in_flashloan(&self) -> bool {
+    self.has_flag(ACCOUNT_IN_FLASHLOAN)
+  }
+
+  pub fn is_in_receivership(&self) -> bool {
+    self.has_flag(ACCOUNT_IN_RECEIVERSHIP)
+  }
+
+  pub fn is_in_deleverage(&self) -> bool {
+    self.has_flag(ACCOUNT_IN_DELEVERAGE)
   }
 }
 
@@ -207,4 +215,50 @@ impl Balance {
           _padding: [0; 1],
       }
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::user_account;
+
+  const FLAGS: [u64; 6] = [
+    ACCOUNT_DISABLED,
+    ACCOUNT_IN_FLASHLOAN,
+    ACCOUNT_FLAG_DEPRECATED,
+    ACCOUNT_TRANSFER_AUTHORITY_DEPRECATED,
+    ACCOUNT_IN_RECEIVERSHIP,
+    ACCOUNT_IN_DELEVERAGE,
+  ];
+
+  fn accessors(account: &MarginfiAccount) -> [bool; 4] {
+    [account.is_disabled(), account.is_in_flashloan(), account.is_in_receivership(), account.is_in_deleverage()]
+  }
+
+  #[test]
+  fn reads_each_flag_bit_alone() {
+    let cases = [
+      (ACCOUNT_DISABLED, [true, false, false, false]),
+      (ACCOUNT_IN_FLASHLOAN, [false, true, false, false]),
+      (ACCOUNT_FLAG_DEPRECATED, [false, false, false, false]),
+      (ACCOUNT_TRANSFER_AUTHORITY_DEPRECATED, [false, false, false, false]),
+      (ACCOUNT_IN_RECEIVERSHIP, [false, false, true, false]),
+      (ACCOUNT_IN_DELEVERAGE, [false, false, false, true]),
+    ];
+
+    for (flag, expected) in cases {
+      let account = user_account(flag, &[]);
+      assert_eq!(accessors(&account), expected, "flag {flag:#b}");
+      for other in FLAGS {
+        assert_eq!(account.has_flag(other), other == flag, "flag {flag:#b}, other {other:#b}");
+      }
+    }
+  }
+
+  #[test]
+  fn reads_combined_flags() {
+    assert_eq!(accessors(&user_account(0, &[])), [false; 4]);
+    assert_eq!(accessors(&user_account(FLAGS.iter().fold(0, |acc, flag| acc | flag), &[])), [true; 4]);
+    assert_eq!(accessors(&user_account(ACCOUNT_DISABLED | ACCOUNT_IN_DELEVERAGE, &[])), [true, false, false, true]);
+  }
 }
\ No newline at end of file
@@ -1,11 +1,10 @@
 use anyhow::Context;
 use fixed::types::I80F48;
 use serde::{Deserialize, Serialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_instruction::Instruction;
 use solana_pubkey::Pubkey;
 
-use crate::{marginfi::{RiskTier, instructions::{make_end_liquidation_ix, make_repay_ix, make_start_liquidation_ix, make_withdraw_ix}, types::{Balance, BalanceSide, Bank, EmodeConfig, MarginfiAccount, OraclePriceFeedAdapter, OraclePriceFeedAdapterConfig, OraclePriceType, PriceAdapter, reconcile_emode_configs}}, utils::parse_account};
+use crate::{marginfi::{MarginfiAccountType, RiskTier, instructions::{RemainingAccountsComposer, make_end_flashloan_ix, make_end_liquidation_ix, make_pulse_health_ix, make_repay_ix, make_start_flashloan_ix, make_start_liquidation_ix, make_withdraw_ix}, types::{Balance, BalanceSide, Bank, EmodeConfig, MarginfiAccount, OraclePriceFeedAdapter, OraclePriceFeedAdapterConfig, OraclePriceType, PriceAdapter, reconcile_emode_configs}}, utils::AccountSource};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MarginfiUser {
@@ -16,8 +15,8 @@ pub struct MarginfiUser {
 }
 
 impl MarginfiUser {
-  pub async fn from_accounts(
-    rpc_client: &RpcClient, 
+  pub async fn from_accounts<S: AccountSource>(
+    source: &S,
     account_pubkeys: &[Pubkey],
     accounts: &[solana_account::Account]
   ) -> anyhow::Result<Vec<anyhow::Result<Self>>> {
@@ -27,7 +26,7 @@ impl MarginfiUser {
 
     let marginfi_accounts: Vec<Option<MarginfiAccount>> = accounts
       .iter()
-      .map(|account| parse_account::<MarginfiAccount>(&account.data).ok())
+      .map(|account| MarginfiAccount::try_from_account_data(&account.data).ok())
       .collect();
   
     let mut all_bank_pubkeys: Vec<Pubkey> = marginfi_accounts
@@ -44,15 +43,15 @@ impl MarginfiUser {
     all_bank_pubkeys.sort();
     all_bank_pubkeys.dedup();
   
-    let bank_accounts_data = rpc_client
-      .get_multiple_accounts(&all_bank_pubkeys)
+    let bank_accounts_data = source
+      .get_multiple(&all_bank_pubkeys)
       .await?;
   
     let all_banks: Vec<Option<Bank>> = bank_accounts_data
       .into_iter()
       .map(|opt_account| {
         opt_account.and_then(|account| {
-          parse_account::<Bank>(&account.data).ok()
+          Bank::try_from_account_data(&account.data).ok()
         })
       })
       .collect();
@@ -71,7 +70,7 @@ impl MarginfiUser {
       .collect();
   
     let all_configs_result = OraclePriceFeedAdapterConfig::load_multiple(
-      rpc_client, 
+      source,
       &successfully_loaded_banks
     ).await;
   
@@ -135,46 +134,55 @@ impl MarginfiUser {
           });
         }
   
-        let reconciled_emode_config = reconcile_emode_configs(
-          banks
-            .iter()
-            .filter(|b| !b.balance.is_empty(BalanceSide::Liabilities))
-            .map(|b| b.bank.emode.emode_config),
-        );
-  
-        Ok(Self {
-					pubkey: *pubkey,
-          account,
-          bank_accounts: banks,
-          emode_config: reconciled_emode_config,
-        })
+        Ok(Self::from_bank_accounts(*pubkey, account, banks))
       })
       .collect();
   
     Ok(user_accounts)
   }
 
-  pub async fn from_pubkeys(
-    rpc_client: &RpcClient, 
+  pub async fn from_pubkeys<S: AccountSource>(
+    source: &S,
     account_pubkeys: &[Pubkey]
   ) -> anyhow::Result<Vec<anyhow::Result<Self>>> {
     if account_pubkeys.is_empty() {
       return Ok(Vec::new());
     }
   
-    let marginfi_accounts_data = rpc_client
-      .get_multiple_accounts(account_pubkeys)
+    let marginfi_accounts_data = source
+      .get_multiple(account_pubkeys)
       .await?;
 
-    Self::from_accounts(rpc_client, account_pubkeys, &marginfi_accounts_data.into_iter().flatten().collect::<Vec<_>>()).await
+    Self::from_accounts(source, account_pubkeys, &marginfi_accounts_data.into_iter().flatten().collect::<Vec<_>>()).await
   }
   
-  pub async fn from_pubkey(rpc_client: &RpcClient, account_pubkey: &Pubkey) -> anyhow::Result<Self> {
-    let results = Self::from_pubkeys(rpc_client, &[*account_pubkey]).await?;
+  pub async fn from_pubkey<S: AccountSource>(source: &S, account_pubkey: &Pubkey) -> anyhow::Result<Self> {
+    let results = Self::from_pubkeys(source, &[*account_pubkey]).await?;
     results.into_iter().next()
       .ok_or(anyhow::anyhow!("Failed to load account"))?
   }
 
+  /// Assembles a user from banks and price feeds that were resolved elsewhere.
+  pub fn from_bank_accounts(pubkey: Pubkey, account: MarginfiAccount, bank_accounts: Vec<BankAccount>) -> Self {
+    let emode_config = reconcile_emode_configs(
+      bank_accounts
+        .iter()
+        .filter(|b| !b.balance.is_empty(BalanceSide::Liabilities))
+        .map(|b| b.bank.emode.emode_config),
+    );
+
+    Self {
+      pubkey,
+      account,
+      bank_accounts,
+      emode_config,
+    }
+  }
+
+  pub fn pubkey(&self) -> &Pubkey {
+    &self.pubkey
+  }
+
   pub fn account(&self) -> &MarginfiAccount {
     &self.account
   }
@@ -222,13 +230,11 @@ impl MarginfiUser {
   }
 
 	pub fn is_bank_withdrawable(&self, bank_account: &BankAccount) -> bool {
-		let bank_asset_weight: I80F48 = bank_account.bank.config.asset_weight_maint.into();
-		let asset_weight: I80F48 = if let Some(emode_entry) = self.emode_config.find_with_tag(bank_account.bank.emode.emode_tag) {
-			let emode_weight = I80F48::from(emode_entry.asset_weight_maint);
-			std::cmp::max(bank_asset_weight, emode_weight)
-		} else {
-			bank_asset_weight
-		};
+		if !bank_account.bank.allows_withdraw_and_repay() {
+			return false;
+		}
+
+		let asset_weight = self.maint_asset_weight(bank_account);
 		if bank_account.bank.config.risk_tier.validate() == Ok(RiskTier::Isolated) && asset_weight == 0 {
 			return false;
 		}
@@ -236,6 +242,13 @@ impl MarginfiUser {
 		true
 	}
 
+  /// If an emode entry exists for this bank's emode tag in the reconciled config of
+  /// all borrowing banks, use its weight, otherwise use the weight designated on the
+  /// collateral bank itself. If the bank's weight is higher, always use that weight.
+  pub fn maint_asset_weight(&self, bank_account: &BankAccount) -> I80F48 {
+    emode_asset_weight_maint(&self.emode_config, &bank_account.bank)
+  }
+
   pub fn maintenance(&self) -> anyhow::Result<I80F48> {
     let mut total_asset_value: I80F48 = I80F48::ZERO;
     let mut total_liability_value: I80F48 = I80F48::ZERO;
@@ -243,16 +256,7 @@ impl MarginfiUser {
       let asset_value = bank_account.asset_value()?;
       let liability_value = bank_account.liability_value()?;
 
-      // If an emode entry exists for this bank's emode tag in the reconciled config of
-      // all borrowing banks, use its weight, otherwise use the weight designated on the
-      // collateral bank itself. If the bank's weight is higher, always use that weight.
-      let bank_asset_weight: I80F48 = bank_account.bank.config.asset_weight_maint.into();
-      let asset_weight: I80F48 = if let Some(emode_entry) = self.emode_config.find_with_tag(bank_account.bank.emode.emode_tag) {
-        let emode_weight = I80F48::from(emode_entry.asset_weight_maint);
-        std::cmp::max(bank_asset_weight, emode_weight)
-      } else {
-        bank_asset_weight
-      };
+      let asset_weight = self.maint_asset_weight(bank_account);
       let liability_weight: I80F48 = bank_account.bank.config.liability_weight_maint.into();
 
       total_asset_value += asset_value.checked_mul(asset_weight)
@@ -264,18 +268,97 @@ impl MarginfiUser {
     anyhow::Ok(total_asset_value - total_liability_value)
   }
 
+  /// Liabilities sitting in paused or killed banks can't be repaid, so nobody can liquidate them.
+  pub fn can_repay_any_liability(&self) -> bool {
+    self.bank_accounts
+      .iter()
+      .filter(|b| !b.balance.is_empty(BalanceSide::Liabilities))
+      .any(|b| b.bank.allows_withdraw_and_repay())
+  }
+
+  /// False when at least one liability sits in a bank that doesn't accept repayments.
+  pub fn can_repay_all_liabilities(&self) -> bool {
+    self.bank_accounts
+      .iter()
+      .filter(|b| !b.balance.is_empty(BalanceSide::Liabilities))
+      .all(|b| b.bank.allows_withdraw_and_repay())
+  }
+
+  /// Why the program won't let anyone liquidate this account right now, regardless of health.
+  /// Receivership and deleverage mean someone else is already working on the account.
+  pub fn liquidation_blocker(&self) -> Option<&'static str> {
+    if self.account.is_disabled() {
+      Some("account is disabled")
+    } else if self.account.is_in_receivership() {
+      Some("account is already in receivership")
+    } else if self.account.is_in_deleverage() {
+      Some("account is being deleveraged")
+    } else {
+      None
+    }
+  }
+
   pub fn eligible_for_liquidation(&self) -> anyhow::Result<bool> {
+    if self.liquidation_blocker().is_some() {
+      return Ok(false);
+    }
+
     let maint = self.maintenance()?;
 
     Ok(maint < 0)
   }
 
-	pub fn start_liquidation_ix(&self, liquidation_receiver: Pubkey) -> Instruction {
-		make_start_liquidation_ix(self.pubkey.clone(), self.account.liquidation_record, liquidation_receiver)
+	/// Composer for the bank and oracle remaining accounts of health-checked instructions.
+	pub fn remaining_accounts(&self) -> RemainingAccountsComposer<'_> {
+		RemainingAccountsComposer::new(self)
+	}
+
+	pub fn start_liquidation_ix(&self, liquidation_receiver: Pubkey) -> anyhow::Result<Instruction> {
+		Ok(make_start_liquidation_ix(
+			self.pubkey,
+			self.account.liquidation_record,
+			liquidation_receiver,
+			self.remaining_accounts().compose()?
+		))
+	}
+
+	/// `closed_banks` are the balances fully withdrawn or repaid between start and end of the
+	/// liquidation, they are left out of the health check.
+	pub fn end_liquidation_ix(&self, liquidation_receiver: Pubkey, global_fee_wallet: Pubkey, closed_banks: &[Pubkey]) -> anyhow::Result<Instruction> {
+		let remaining_accounts = closed_banks
+			.iter()
+			.fold(self.remaining_accounts(), |composer, bank_pk| composer.close(*bank_pk))
+			.compose()?;
+
+		Ok(make_end_liquidation_ix(
+			self.pubkey,
+			self.account.liquidation_record,
+			liquidation_receiver,
+			global_fee_wallet,
+			remaining_accounts
+		))
+	}
+
+	/// `end_index` is the position of the matching `end_flashloan_ix` in the transaction.
+	pub fn start_flashloan_ix(&self, authority: Pubkey, end_index: u64) -> Instruction {
+		make_start_flashloan_ix(self.pubkey, authority, end_index)
+	}
+
+	/// The health check withdrawals and borrows skip during the flashloan runs here instead.
+	/// `closed_banks` are the balances fully withdrawn or repaid in between, as for
+	/// `end_liquidation_ix`.
+	pub fn end_flashloan_ix(&self, authority: Pubkey, closed_banks: &[Pubkey]) -> anyhow::Result<Instruction> {
+		let remaining_accounts = closed_banks
+			.iter()
+			.fold(self.remaining_accounts(), |composer, bank_pk| composer.close(*bank_pk))
+			.compose()?;
+
+		Ok(make_end_flashloan_ix(self.pubkey, authority, remaining_accounts))
 	}
 
-	pub fn end_liquidation_ix(&self, liquidation_receiver: Pubkey, global_fee_wallet: Pubkey) -> Instruction {
-		make_end_liquidation_ix(self.pubkey.clone(), self.account.liquidation_record, liquidation_receiver, global_fee_wallet)
+	/// Asks the program for its own view of this account's health, see `Marginfi::pulse_health`.
+	pub fn pulse_health_ix(&self) -> anyhow::Result<Instruction> {
+		Ok(make_pulse_health_ix(self.pubkey, self.remaining_accounts().compose()?))
 	}
 
 	pub fn withdraw_ix(
@@ -286,8 +369,13 @@ impl MarginfiUser {
 		token_program: Pubkey,
 		amount: I80F48,
 		withdraw_all: Option<bool>
-	) -> Instruction {
-		make_withdraw_ix(
+	) -> anyhow::Result<Instruction> {
+		let remaining_accounts = match withdraw_all {
+			Some(true) => self.remaining_accounts().close(bank_account.balance.bank_pk).compose()?,
+			_ => self.remaining_accounts().compose()?,
+		};
+
+		Ok(make_withdraw_ix(
 			self.account.group,
 			self.pubkey,
 			authority,
@@ -296,8 +384,9 @@ impl MarginfiUser {
 			bank_account.bank.liquidity_vault,
 			token_program,
 			amount.to_num(),
-			withdraw_all
-		)
+			withdraw_all,
+			remaining_accounts
+		))
 	}
 
 	pub fn repay_ix(
@@ -323,6 +412,30 @@ impl MarginfiUser {
 	}
 }
 
+/// Maintenance weight of `bank`'s collateral under `emode_config`, the reconciled config of the
+/// borrowing banks. See `MarginfiUser::maint_asset_weight`.
+pub(crate) fn emode_asset_weight_maint(emode_config: &EmodeConfig, bank: &Bank) -> I80F48 {
+  let bank_asset_weight: I80F48 = bank.config.asset_weight_maint.into();
+  if let Some(emode_entry) = emode_config.find_with_tag(bank.emode.emode_tag) {
+    let emode_weight = I80F48::from(emode_entry.asset_weight_maint);
+    std::cmp::max(bank_asset_weight, emode_weight)
+  } else {
+    bank_asset_weight
+  }
+}
+
+/// Initial weight of `bank`'s collateral under `emode_config`, the same way as
+/// `emode_asset_weight_maint`. The `total_asset_value_init_limit` discount isn't applied.
+pub(crate) fn emode_asset_weight_init(emode_config: &EmodeConfig, bank: &Bank) -> I80F48 {
+  let bank_asset_weight: I80F48 = bank.config.asset_weight_init.into();
+  if let Some(emode_entry) = emode_config.find_with_tag(bank.emode.emode_tag) {
+    let emode_weight = I80F48::from(emode_entry.asset_weight_init);
+    std::cmp::max(bank_asset_weight, emode_weight)
+  } else {
+    bank_asset_weight
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BankAccount {
   pub bank: Bank,
@@ -0,0 +1,94 @@
+//! Account fixtures for tests, here and in the workers through the `test-utils` feature.
+
+use anchor_lang::prelude::{Clock, sysvar::clock};
+use bytemuck::Zeroable;
+use fixed::types::I80F48;
+use solana_account::Account;
+use solana_pubkey::Pubkey;
+
+use crate::{
+  consts::MARGINFI_PROGRAM_ID,
+  marginfi::{
+    Balance, Bank, BankAccount, BankOperationalState, FixedPriceFeed, MarginfiAccount, MarginfiAccountType, MarginfiUser,
+    OraclePriceFeedAdapter, OracleSetup, RawBankOperationalState, RawOracleSetup,
+  },
+  utils::AccountCache,
+};
+
+/// Marginfi owned account holding the discriminator of `T` followed by `value`.
+pub fn account<T: MarginfiAccountType>(value: &T) -> Account {
+  Account { lamports: 1, data: account_data(value), owner: MARGINFI_PROGRAM_ID, executable: false, rent_epoch: 0 }
+}
+
+pub fn account_data<T: MarginfiAccountType>(value: &T) -> Vec<u8> {
+  [&T::DISCRIMINATOR[..], bytemuck::bytes_of(value)].concat()
+}
+
+/// Clock sysvar account, as the runtime serializes it.
+pub fn clock_account(clock: &Clock) -> Account {
+  Account { lamports: 1, data: bincode::serialize(clock).unwrap(), owner: Pubkey::default(), executable: false, rent_epoch: 0 }
+}
+
+/// Bank priced by a fixed oracle of `price`, with no other config set.
+pub fn fixed_bank(price: f64) -> Bank {
+  let mut bank = Bank::zeroed();
+  bank.config.oracle_setup = RawOracleSetup(OracleSetup::Fixed as u8);
+  bank.config.fixed_price = I80F48::from_num(price).into();
+  bank
+}
+
+/// Operational fixed price bank of a fresh mint with unit share values and no decimals, so
+/// shares are token amounts. Initial and maintenance weights both start at the given ones.
+pub fn weighted_bank(price: f64, asset_weight: f64, liability_weight: f64) -> Bank {
+  let mut bank = fixed_bank(price);
+  bank.mint = Pubkey::new_unique();
+  bank.asset_share_value = I80F48::ONE.into();
+  bank.liability_share_value = I80F48::ONE.into();
+  bank.config.operational_state = RawBankOperationalState(BankOperationalState::Operational as u8);
+  bank.config.asset_weight_init = I80F48::from_num(asset_weight).into();
+  bank.config.asset_weight_maint = I80F48::from_num(asset_weight).into();
+  bank.config.liability_weight_init = I80F48::from_num(liability_weight).into();
+  bank.config.liability_weight_maint = I80F48::from_num(liability_weight).into();
+  bank
+}
+
+/// Active balance of `assets` and `liabilities` tokens in `bank`, under a fresh bank key.
+pub fn position(bank: Bank, assets: f64, liabilities: f64) -> BankAccount {
+  let mut balance = Balance::zeroed();
+  balance.active = 1;
+  balance.bank_pk = Pubkey::new_unique();
+  balance.asset_shares = I80F48::from_num(assets).into();
+  balance.liability_shares = I80F48::from_num(liabilities).into();
+
+  let price_feed = OraclePriceFeedAdapter::Fixed(FixedPriceFeed { price: bank.config.fixed_price.into() });
+  BankAccount { bank, price_feed, balance }
+}
+
+pub fn user(positions: Vec<BankAccount>) -> MarginfiUser {
+  MarginfiUser::from_bank_accounts(Pubkey::new_unique(), MarginfiAccount::zeroed(), positions)
+}
+
+/// User with `flags` and an empty active balance in each of `banks`.
+pub fn user_account(flags: u64, banks: &[Pubkey]) -> MarginfiAccount {
+  let mut account = MarginfiAccount::zeroed();
+  account.account_flags = flags;
+  for (balance, bank_pk) in account.lending_account.balances.iter_mut().zip(banks) {
+    balance.active = 1;
+    balance.bank_pk = *bank_pk;
+  }
+  account
+}
+
+/// Chain with a clock at slot zero and a fixed price bank for each of `banks`.
+pub fn chain(banks: &[Pubkey]) -> AccountCache {
+  let cache = AccountCache::new();
+  cache.insert(clock::ID, clock_account(&Clock::default()));
+  for bank_pk in banks {
+    cache.insert(*bank_pk, account(&fixed_bank(1.0)));
+  }
+  cache
+}
+
+pub fn insert_user(cache: &AccountCache, pubkey: Pubkey, flags: u64, banks: &[Pubkey]) {
+  cache.insert(pubkey, account(&user_account(flags, banks)));
+}
\ No newline at end of file
@@ -0,0 +1,343 @@
+use std::{collections::HashMap, future::Future, path::Path, sync::RwLock};
+
+use anchor_lang::prelude::{Clock, sysvar::clock};
+use anyhow::Context;
+use serde::Deserialize;
+use solana_account::Account;
+use solana_account_decoder::UiAccount;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_pubkey::Pubkey;
+
+/// Anything that can hand out raw accounts and the current clock.
+/// Loaders take this instead of an `RpcClient` so users can be built offline.
+pub trait AccountSource {
+  /// Returns one entry per key, in the same order, `None` for missing accounts.
+  fn get_multiple(&self, keys: &[Pubkey]) -> impl Future<Output = anyhow::Result<Vec<Option<Account>>>> + Send;
+
+  /// Like `get_multiple`, also returning the slot the accounts were read at when the source knows
+  /// it. Reads split into several requests report the oldest slot.
+  fn get_multiple_at(&self, keys: &[Pubkey]) -> impl Future<Output = anyhow::Result<(Option<u64>, Vec<Option<Account>>)>> + Send;
+
+  fn get_clock(&self) -> impl Future<Output = anyhow::Result<Clock>> + Send;
+}
+
+impl AccountSource for RpcClient {
+  async fn get_multiple(&self, keys: &[Pubkey]) -> anyhow::Result<Vec<Option<Account>>> {
+    const BATCH_SIZE: usize = 100;
+
+    let mut accounts = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(BATCH_SIZE) {
+      accounts.extend(self.get_multiple_accounts(chunk).await?);
+    }
+
+    Ok(accounts)
+  }
+
+  async fn get_multiple_at(&self, keys: &[Pubkey]) -> anyhow::Result<(Option<u64>, Vec<Option<Account>>)> {
+    const BATCH_SIZE: usize = 100;
+
+    let mut slot: Option<u64> = None;
+    let mut accounts = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(BATCH_SIZE) {
+      let response = self.get_multiple_accounts_with_commitment(chunk, self.commitment()).await?;
+      slot = Some(slot.map_or(response.context.slot, |slot| slot.min(response.context.slot)));
+      accounts.extend(response.value);
+    }
+
+    Ok((slot, accounts))
+  }
+
+  async fn get_clock(&self) -> anyhow::Result<Clock> {
+    let clock_account = self.get_account(&clock::ID).await?;
+    parse_clock(&clock_account)
+  }
+}
+
+/// In-memory account store. Holds whatever was inserted or preloaded from another source,
+/// the clock is read from the clock sysvar account like any other account.
+#[derive(Default)]
+pub struct AccountCache {
+  accounts: RwLock<HashMap<Pubkey, Account>>,
+}
+
+impl AccountCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert(&self, pubkey: Pubkey, account: Account) {
+    self.accounts.write().unwrap().insert(pubkey, account);
+  }
+
+  pub fn extend(&self, accounts: impl IntoIterator<Item = (Pubkey, Account)>) {
+    self.accounts.write().unwrap().extend(accounts);
+  }
+
+  pub fn remove(&self, pubkey: &Pubkey) -> Option<Account> {
+    self.accounts.write().unwrap().remove(pubkey)
+  }
+
+  pub fn len(&self) -> usize {
+    self.accounts.read().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Fetches `keys` plus the clock sysvar from `source` and stores everything that exists.
+  pub async fn preload<S: AccountSource>(&self, source: &S, keys: &[Pubkey]) -> anyhow::Result<()> {
+    let mut keys = keys.to_vec();
+    keys.push(clock::ID);
+
+    let accounts = source.get_multiple(&keys).await?;
+    self.extend(
+      keys
+        .into_iter()
+        .zip(accounts)
+        .filter_map(|(pubkey, account)| account.map(|account| (pubkey, account)))
+    );
+
+    Ok(())
+  }
+}
+
+impl AccountSource for AccountCache {
+  async fn get_multiple(&self, keys: &[Pubkey]) -> anyhow::Result<Vec<Option<Account>>> {
+    Ok(lookup(&self.accounts.read().unwrap(), keys))
+  }
+
+  async fn get_multiple_at(&self, keys: &[Pubkey]) -> anyhow::Result<(Option<u64>, Vec<Option<Account>>)> {
+    Ok((None, self.get_multiple(keys).await?))
+  }
+
+  async fn get_clock(&self) -> anyhow::Result<Clock> {
+    let accounts = self.accounts.read().unwrap();
+    let clock_account = accounts.get(&clock::ID).context("clock sysvar is not cached")?;
+    parse_clock(clock_account)
+  }
+}
+
+/// Directory of account dumps written by `solana account <pubkey> --output json`.
+/// Every `*.json` file is read once on open, the clock comes from a dump of the clock sysvar.
+pub struct AccountDumpDir {
+  accounts: HashMap<Pubkey, Account>,
+}
+
+#[derive(Deserialize)]
+struct KeyedAccountDump {
+  pubkey: String,
+  account: UiAccount,
+}
+
+impl AccountDumpDir {
+  pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    let path = path.as_ref();
+    let mut accounts = HashMap::new();
+
+    for entry in std::fs::read_dir(path).with_context(|| format!("failed to read {}", path.display()))? {
+      let file = entry?.path();
+      if file.extension().is_none_or(|ext| ext != "json") {
+        continue;
+      }
+
+      let raw = std::fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+      let dump: KeyedAccountDump = serde_json::from_str(&raw).with_context(|| format!("invalid account dump {}", file.display()))?;
+      let pubkey = dump.pubkey.parse::<Pubkey>().with_context(|| format!("invalid pubkey in {}", file.display()))?;
+      let account = dump.account
+        .decode::<Account>()
+        .ok_or_else(|| anyhow::anyhow!("unsupported account encoding in {}", file.display()))?;
+
+      accounts.insert(pubkey, account);
+    }
+
+    Ok(Self { accounts })
+  }
+
+  pub fn pubkeys(&self) -> impl Iterator<Item = &Pubkey> {
+    self.accounts.keys()
+  }
+}
+
+impl AccountSource for AccountDumpDir {
+  async fn get_multiple(&self, keys: &[Pubkey]) -> anyhow::Result<Vec<Option<Account>>> {
+    Ok(lookup(&self.accounts, keys))
+  }
+
+  async fn get_multiple_at(&self, keys: &[Pubkey]) -> anyhow::Result<(Option<u64>, Vec<Option<Account>>)> {
+    Ok((None, self.get_multiple(keys).await?))
+  }
+
+  async fn get_clock(&self) -> anyhow::Result<Clock> {
+    let clock_account = self.accounts.get(&clock::ID).context("no clock sysvar dump")?;
+    parse_clock(clock_account)
+  }
+}
+
+fn lookup(accounts: &HashMap<Pubkey, Account>, keys: &[Pubkey]) -> Vec<Option<Account>> {
+  keys.iter().map(|key| accounts.get(key).cloned()).collect()
+}
+
+fn parse_clock(account: &Account) -> anyhow::Result<Clock> {
+  Ok(bincode::deserialize(&account.data)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use async_trait::async_trait;
+  use solana_account_decoder::{UiAccountEncoding, encode_ui_account};
+  use solana_client::{
+    client_error::Result as ClientResult,
+    rpc_client::RpcClientConfig,
+    rpc_request::RpcRequest,
+    rpc_sender::{RpcSender, RpcTransportStats},
+  };
+
+  use super::*;
+  use crate::test_utils;
+
+  /// Answers `getMultipleAccounts` with an account owned by each requested key, so the order can
+  /// be checked, at a slot that drops with every request. Records the size of each request.
+  #[derive(Clone, Default)]
+  struct RecordingSender {
+    requests: Arc<Mutex<Vec<usize>>>,
+  }
+
+  #[async_trait]
+  impl RpcSender for RecordingSender {
+    async fn send(&self, request: RpcRequest, params: serde_json::Value) -> ClientResult<serde_json::Value> {
+      assert_eq!(request, RpcRequest::GetMultipleAccounts);
+
+      let keys: Vec<String> = serde_json::from_value(params[0].clone()).unwrap();
+      let mut requests = self.requests.lock().unwrap();
+      requests.push(keys.len());
+
+      let value: Vec<UiAccount> = keys.iter().map(|key| ui_account(&owned_by(key.parse().unwrap()))).collect();
+      Ok(serde_json::json!({ "context": { "slot": 1_000 - requests.len() as u64 }, "value": value }))
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+      RpcTransportStats::default()
+    }
+
+    fn url(&self) -> String {
+      "recording".to_string()
+    }
+  }
+
+  fn owned_by(owner: Pubkey) -> Account {
+    Account { lamports: 1, data: vec![1, 2, 3], owner, executable: false, rent_epoch: 0 }
+  }
+
+  fn ui_account(account: &Account) -> UiAccount {
+    encode_ui_account(&Pubkey::default(), account, UiAccountEncoding::Base64, None, None)
+  }
+
+  fn clock_account(slot: u64) -> Account {
+    test_utils::clock_account(&Clock { slot, ..Clock::default() })
+  }
+
+  /// Empty directory under the system temp dir.
+  fn dump_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("account-source-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn write_dump(dir: &Path, pubkey: &Pubkey, account: &Account) {
+    let dump = serde_json::json!({ "pubkey": pubkey.to_string(), "account": ui_account(account) });
+    std::fs::write(dir.join(format!("{}.json", pubkey)), dump.to_string()).unwrap();
+  }
+
+  #[tokio::test]
+  async fn splits_rpc_reads_at_the_batch_size() {
+    for (key_count, batches) in [(100, vec![100]), (101, vec![100, 1]), (201, vec![100, 100, 1])] {
+      let keys: Vec<Pubkey> = (0..key_count).map(|_| Pubkey::new_unique()).collect();
+
+      let sender = RecordingSender::default();
+      let rpc = RpcClient::new_sender(sender.clone(), RpcClientConfig::default());
+      let owners: Vec<Pubkey> = rpc.get_multiple(&keys).await.unwrap().into_iter().map(|account| account.unwrap().owner).collect();
+      assert_eq!(owners, keys);
+      assert_eq!(*sender.requests.lock().unwrap(), batches);
+
+      let sender = RecordingSender::default();
+      let rpc = RpcClient::new_sender(sender.clone(), RpcClientConfig::default());
+      let (slot, accounts) = rpc.get_multiple_at(&keys).await.unwrap();
+      assert_eq!(accounts.len(), key_count);
+      assert_eq!(*sender.requests.lock().unwrap(), batches);
+      // the last request answered at the oldest slot
+      assert_eq!(slot, Some(1_000 - batches.len() as u64));
+    }
+  }
+
+  #[tokio::test]
+  async fn caches_hits_and_misses() {
+    let cache = AccountCache::new();
+    let [hit, miss] = [(); 2].map(|_| Pubkey::new_unique());
+    cache.insert(hit, owned_by(hit));
+
+    assert_eq!(cache.get_multiple(&[miss, hit, miss]).await.unwrap(), vec![None, Some(owned_by(hit)), None]);
+    assert_eq!(cache.get_multiple_at(&[hit]).await.unwrap(), (None, vec![Some(owned_by(hit))]));
+    assert!(cache.get_clock().await.is_err());
+
+    cache.insert(clock::ID, clock_account(42));
+    assert_eq!(cache.get_clock().await.unwrap().slot, 42);
+
+    assert_eq!(cache.remove(&hit), Some(owned_by(hit)));
+    assert_eq!(cache.get_multiple(&[hit]).await.unwrap(), vec![None]);
+    assert_eq!(cache.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn preloads_existing_accounts_and_the_clock() {
+    let source = AccountCache::new();
+    let [present, missing] = [(); 2].map(|_| Pubkey::new_unique());
+    source.insert(present, owned_by(present));
+    source.insert(clock::ID, clock_account(7));
+
+    let cache = AccountCache::new();
+    cache.preload(&source, &[present, missing]).await.unwrap();
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get_multiple(&[present, missing]).await.unwrap(), vec![Some(owned_by(present)), None]);
+    assert_eq!(cache.get_clock().await.unwrap().slot, 7);
+  }
+
+  #[tokio::test]
+  async fn reads_back_dumped_accounts() {
+    let dir = dump_dir("round-trip");
+    let [dumped, missing] = [(); 2].map(|_| Pubkey::new_unique());
+    write_dump(&dir, &dumped, &owned_by(dumped));
+    write_dump(&dir, &clock::ID, &clock_account(9));
+    std::fs::write(dir.join("notes.txt"), "not an account").unwrap();
+
+    let dumps = AccountDumpDir::open(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut pubkeys: Vec<Pubkey> = dumps.pubkeys().copied().collect();
+    pubkeys.sort();
+    let mut expected = vec![dumped, clock::ID];
+    expected.sort();
+    assert_eq!(pubkeys, expected);
+
+    assert_eq!(dumps.get_multiple(&[dumped, missing]).await.unwrap(), vec![Some(owned_by(dumped)), None]);
+    assert_eq!(dumps.get_clock().await.unwrap().slot, 9);
+  }
+
+  #[tokio::test]
+  async fn rejects_missing_dumps() {
+    let dir = dump_dir("missing");
+    let dumps = AccountDumpDir::open(&dir).unwrap();
+    assert!(dumps.get_clock().await.is_err());
+
+    std::fs::write(dir.join("broken.json"), "{}").unwrap();
+    assert!(AccountDumpDir::open(&dir).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(AccountDumpDir::open(&dir).is_err());
+  }
+}
\ No newline at end of file
@@ -1,3 +1,5 @@
 mod parse_account;
+mod account_source;
 
-pub use parse_account::*;
\ No newline at end of file
+pub use parse_account::*;
+pub use account_source::*;
\ No newline at end of file
@@ -1,9 +1,12 @@
 use bytemuck::Pod;
 
+/// Untyped parse for foreign accounts that only share the 8 byte prefix convention.
+/// Marginfi's own accounts should go through `MarginfiAccountType`, which checks the discriminator.
 pub fn parse_account<T: Pod>(
   data: &[u8],
 ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
-  let marginfi_account = bytemuck::try_from_bytes::<T>(&data[8..])
+  let body = data.get(8..).ok_or("account data shorter than its discriminator")?;
+  let marginfi_account = bytemuck::try_from_bytes::<T>(body)
       .map_err(|e| format!("account data parse failed: {:?}", e))?;
   
   Ok(*marginfi_account)
@@ -3,9 +3,9 @@ mod config;
 use std::sync::Arc;
 
 use config::Config;
-use connections::{PubRedis, Redis, SubRedis, queue_keys};
+use connections::{DEFAULT_BLOCK_TIMEOUT, IndexStore, PubRedis, Publisher, Redis, SubRedis, Subscriber, queue_keys};
 use fixed::types::I80F48;
-use protocols::marginfi::{AccountFilter, Marginfi};
+use protocols::{marginfi::{AccountFilter, Marginfi, MarginfiUser}, utils::AccountSource};
 use solana_pubkey::Pubkey;
 use tokio::{signal, sync::Semaphore, time::Instant};
 
@@ -34,19 +34,23 @@ async fn start(config: Config) -> anyhow::Result<()> {
     max_maint_percentage: config.max_maint_percentage,
     min_maint: config.min_maint,
     max_maint: config.max_maint,
+    with_flags: config.with_flags,
+    without_flags: config.without_flags,
   });
 
   let marginfi = Arc::new(Marginfi::new(config.http_url, config.ws_url).await?);
   let redis = Redis::new(&config.redis_url).await?;
-  let pub_redis = PubRedis::new(&config.pubsub_url).await?;
-  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?;
+  let pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
+  let consumer = hostname::get()?.to_string_lossy().into_owned();
+  let mut sub_redis = SubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend).with_consumer(consumer);
+  sub_redis.spawn_reaper(queue_keys::REM_QUEUE);
   println!("connection established, listening");
 
   let semaphore = Arc::new(Semaphore::new(config.capacity));
 
   loop {
     tokio::select! {
-      result = sub_redis.builder::<()>(queue_keys::REM_QUEUE, config.accounts_batch_size).recv() => {
+      result = sub_redis.builder::<()>(queue_keys::REM_QUEUE, config.accounts_batch_size).block(DEFAULT_BLOCK_TIMEOUT).recv() => {
         let results = match result {
           Ok(messages) => messages,
           Err(err) => {
@@ -57,7 +61,7 @@ async fn start(config: Config) -> anyhow::Result<()> {
 
         for result in &results {
           if let Err(err) = result {
-            println!("failed to accept a message: {}", err);
+            println!("dead-lettered a malformed message: {}", err);
           }
         }
 
@@ -72,11 +76,23 @@ async fn start(config: Config) -> anyhow::Result<()> {
         let pub_redis_clone = pub_redis.clone();
         let marginfi_clone = Arc::clone(&marginfi);
         let filter_clone = Arc::clone(&filter);
+        let mut sub_redis_clone = sub_redis.clone();
         tokio::spawn(async move {
           let _guard = permit.acquire().await.unwrap();
 
-          if let Err(err) = handle(&marginfi_clone, redis_clone, pub_redis_clone, accounts, &filter_clone).await {
-            println!("error removing accounts: {}", err);
+          let delivered = accounts.clone();
+          match handle(marginfi_clone.rpc_ref(), redis_clone, pub_redis_clone, accounts, &filter_clone).await {
+            Ok(()) => if let Err(err) = sub_redis_clone.ack(queue_keys::REM_QUEUE, &delivered).await {
+              println!("failed to ack {} accounts: {}", delivered.len(), err);
+            },
+            Err(err) => {
+              println!("error removing accounts: {}", err);
+              match sub_redis_clone.fail(queue_keys::REM_QUEUE, &delivered, &format!("{:#}", err)).await {
+                Ok(0) => {},
+                Ok(dead) => println!("dead-lettered {} accounts", dead),
+                Err(err) => println!("failed to report {} accounts: {}", delivered.len(), err),
+              }
+            },
           };
         });
       }
@@ -90,10 +106,10 @@ async fn start(config: Config) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedis, accounts: Vec<Pubkey>, filter: &AccountFilter<T>) -> anyhow::Result<()>
+async fn handle<T, S: AccountSource, I: IndexStore, P: Publisher>(source: &S, mut index: I, mut pub_redis: P, accounts: Vec<Pubkey>, filter: &AccountFilter<T>) -> anyhow::Result<()>
   where I80F48: PartialOrd<T> {
   let start = Instant::now();
-  let items = check_pubkeys(marginfi, &accounts, filter).await?;
+  let items = check_pubkeys(source, &accounts, filter).await?;
   let duration = start.elapsed();
 
   let len = items.len();
@@ -101,7 +117,8 @@ async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedi
     return Ok(());
   }
 
-  let (removed_banks, accounts_removed) = redis.rem_multiple(items).await?;
+  let items: Vec<Pubkey> = items.into_iter().copied().collect();
+  let (removed_banks, accounts_removed) = index.rem_multiple(&items).await?;
 
   if accounts_removed > 0 {
     println!("* removed {} accounts ({:?})", accounts_removed, duration);
@@ -116,9 +133,9 @@ async fn handle<T>(marginfi: &Marginfi, mut redis: Redis, mut pub_redis: PubRedi
   Ok(())
 }
 
-async fn check_pubkeys<'a, T>(protocol: &Marginfi, pubkeys: &'a [Pubkey], filter: &AccountFilter<T>) -> anyhow::Result<Vec<&'a Pubkey>>
+async fn check_pubkeys<'a, T, S: AccountSource>(source: &S, pubkeys: &'a [Pubkey], filter: &AccountFilter<T>) -> anyhow::Result<Vec<&'a Pubkey>>
   where I80F48: PartialOrd<T> {
-  let users = protocol.load_users(pubkeys).await?;
+  let users = MarginfiUser::from_pubkeys(source, pubkeys).await?;
   
   let mut hits = Vec::new();
   for (result, pubkey) in users.into_iter().zip(pubkeys) {
@@ -144,4 +161,66 @@ async fn check_pubkeys<'a, T>(protocol: &Marginfi, pubkeys: &'a [Pubkey], filter
   }
 
   anyhow::Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeSet;
+
+  use connections::{MemoryIndex, MemoryQueues};
+  use protocols::{marginfi::ACCOUNT_DISABLED, test_utils::{chain, insert_user}, utils::AccountCache};
+
+  use super::*;
+
+  /// Chain with the users, which hold no balances so no banks are loaded.
+  fn users(users: &[(Pubkey, u64)]) -> AccountCache {
+    let cache = chain(&[]);
+    for (pubkey, flags) in users {
+      insert_user(&cache, *pubkey, *flags, &[]);
+    }
+    cache
+  }
+
+  fn filter() -> AccountFilter<I80F48> {
+    AccountFilter { without_flags: ACCOUNT_DISABLED, ..Default::default() }
+  }
+
+  #[tokio::test]
+  async fn removes_accounts_failing_the_filter_and_queues_their_last_banks() {
+    let [healthy, disabled, x, y] = [(); 4].map(|_| Pubkey::new_unique());
+    let cache = users(&[(healthy, 0), (disabled, ACCOUNT_DISABLED)]);
+
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(healthy, vec![y]), (disabled, vec![x, y])]).await.unwrap();
+    let queues = MemoryQueues::new();
+
+    handle(&cache, index.clone(), queues.publisher(), vec![healthy, disabled], &filter()).await.unwrap();
+
+    let snapshot = index.snapshot();
+    assert_eq!(snapshot.accounts, BTreeSet::from([healthy]));
+    assert_eq!(snapshot.banks, BTreeSet::from([y]));
+    assert!(snapshot.check(None).is_empty());
+
+    let queued = queues.subscriber().read::<()>(queue_keys::BANK_REM_QUEUE, 10).await.unwrap();
+    let queued: Vec<Pubkey> = queued.into_iter().map(|result| result.unwrap().0).collect();
+    assert_eq!(queued, vec![x]);
+  }
+
+  #[tokio::test]
+  async fn queues_a_removed_bank_that_is_already_waiting_once() {
+    let [disabled, x] = [(); 2].map(|_| Pubkey::new_unique());
+    let cache = users(&[(disabled, ACCOUNT_DISABLED)]);
+
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(disabled, vec![x])]).await.unwrap();
+    let queues = MemoryQueues::new();
+    queues.publisher().publish(queue_keys::BANK_REM_QUEUE, &[(x, ())]).await.unwrap();
+
+    handle(&cache, index.clone(), queues.publisher(), vec![disabled], &filter()).await.unwrap();
+    // a second delivery of the same account finds nothing left to remove
+    handle(&cache, index.clone(), queues.publisher(), vec![disabled], &filter()).await.unwrap();
+
+    assert!(index.snapshot().banks.is_empty());
+    assert_eq!(queues.queued(queue_keys::BANK_REM_QUEUE), 1);
+  }
 }
\ No newline at end of file
@@ -1,10 +1,12 @@
 use anyhow::Context;
+use connections::QueueBackend;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
   pub(crate) http_url: String,
   pub(crate) ws_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
 }
 
 impl Config {
@@ -13,10 +15,12 @@ impl Config {
     let http_url = std::env::var("HTTP_URL").context("\"HTTP_URL\" is required")?;
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
     let config = Config {
       http_url,
       ws_url,
       pubsub_url,
+      pubsub_backend,
     };
 
     Ok(config)
@@ -1,7 +1,7 @@
 mod config;
 
 use config::Config;
-use connections::{PubRedis, queue_keys};
+use connections::{PubRedis, Publisher, queue_keys};
 
 use protocols::marginfi::Marginfi;
 use tokio::time::{Instant, sleep, Duration};
@@ -26,7 +26,7 @@ async fn main() {
 }
 
 async fn search(config: Config) -> anyhow::Result<()> {  
-  let mut pub_redis = PubRedis::new(&config.pubsub_url).await?;
+  let mut pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
 
   let marginfi = Marginfi::new(config.http_url, config.ws_url).await?;
 
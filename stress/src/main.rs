@@ -0,0 +1,136 @@
+mod config;
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Context;
+use config::Config;
+use connections::Redis;
+use fixed::types::I80F48;
+use protocols::marginfi::{MarketState, stress::{PriceShocks, StressReport}};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_pubkey::Pubkey;
+use tokio::time::Instant;
+
+const USAGE: &str = "usage: stress <mint>=<change%> [<mint>=<change%> ...], e.g. stress So11111111111111111111111111111111111111112=-10%";
+
+#[tokio::main]
+async fn main() {
+  let config = Config::open().unwrap();
+
+  let result = start(config).await;
+
+  if let Err(err) = result {
+    eprintln!("error: {err}");
+
+    err.chain()
+      .skip(1)
+      .for_each(|cause| eprintln!("caused by:\n  {cause}"));
+  }
+}
+
+async fn start(config: Config) -> anyhow::Result<()> {
+  let shocks = parse_shocks(std::env::args().skip(1))?;
+  if shocks.is_empty() {
+    anyhow::bail!(USAGE);
+  }
+
+  let rpc_client = RpcClient::new(config.http_url);
+  let mut redis = Redis::new(&config.redis_url).await?;
+  let market = Arc::new(MarketState::new());
+
+  let pubkeys = redis.get_all_accounts().await?;
+  println!("* stressing {} accounts", pubkeys.len());
+  for (mint, change) in &shocks {
+    println!("  {} {}%", mint, *change * I80F48::from_num(100));
+  }
+
+  let start = Instant::now();
+  let mut report = StressReport::new();
+  for chunk in pubkeys.chunks(config.accounts_batch_size.max(1)) {
+    for result in market.load_users(&rpc_client, chunk).await? {
+      match result {
+        Ok(user) => report.record(&user, &shocks),
+        Err(_) => {
+          report.accounts_checked += 1;
+          report.accounts_failed += 1;
+        }
+      }
+    }
+  }
+
+  println!("* checked {} accounts, {} failed to load or evaluate ({:?})", report.accounts_checked, report.accounts_failed, start.elapsed());
+  println!("* {} already liquidatable, {} newly liquidatable", report.already_liquidatable.len(), report.newly_liquidatable.len());
+  for pubkey in &report.newly_liquidatable {
+    println!("  {}", pubkey);
+  }
+
+  println!("* seizable collateral per mint");
+  print_totals(&report.seizable_by_mint);
+
+  println!("* liquidity needed per mint");
+  print_totals(&report.liquidity_needed_by_mint);
+
+  Ok(())
+}
+
+fn print_totals(totals: &HashMap<Pubkey, I80F48>) {
+  let mut totals: Vec<_> = totals.iter().collect();
+  totals.sort_by(|a, b| b.1.cmp(a.1));
+
+  for (mint, value) in totals {
+    println!("  {}: {}$", mint, value);
+  }
+}
+
+/// Parses `<mint>=<change>` pairs where change is a percentage, `-10` and `-10%` both mean a 10% drop.
+fn parse_shocks(args: impl Iterator<Item = String>) -> anyhow::Result<PriceShocks> {
+  let mut shocks = PriceShocks::new();
+
+  for arg in args {
+    let (mint, change) = arg.split_once('=').with_context(|| format!("invalid shock \"{}\", {}", arg, USAGE))?;
+    let mint = mint.parse::<Pubkey>().with_context(|| format!("invalid mint \"{}\"", mint))?;
+    let change = change
+      .trim_end_matches('%')
+      .parse::<f64>()
+      .with_context(|| format!("invalid price change \"{}\"", change))?;
+
+    if !change.is_finite() {
+      anyhow::bail!("price change for {} must be a finite number", mint);
+    }
+    if change <= -100.0 {
+      anyhow::bail!("price change for {} must be above -100%", mint);
+    }
+
+    let change = I80F48::checked_from_num(change / 100.0).with_context(|| format!("price change for {} is too large", mint))?;
+    shocks.insert(mint, change);
+  }
+
+  Ok(shocks)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[String]) -> anyhow::Result<PriceShocks> {
+    parse_shocks(args.iter().cloned())
+  }
+
+  #[test]
+  fn parses_percent_changes() {
+    let mint = Pubkey::new_unique();
+
+    let shocks = parse(&[format!("{}=-10%", mint)]).unwrap();
+    assert_eq!(shocks.get(&mint), Some(&I80F48::from_num(-0.1)));
+    assert_eq!(parse(&[format!("{}=25", mint)]).unwrap().get(&mint), Some(&I80F48::from_num(0.25)));
+  }
+
+  #[test]
+  fn rejects_changes_that_are_not_prices() {
+    let mint = Pubkey::new_unique();
+
+    for change in ["NaN", "inf", "-inf", "-100", "-150%", "1e40", "ten"] {
+      assert!(parse(&[format!("{}={}", mint, change)]).is_err(), "{} was accepted", change);
+    }
+  }
+}
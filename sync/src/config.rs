@@ -1,9 +1,11 @@
 use anyhow::Context;
+use connections::QueueBackend;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
   pub(crate) redis_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
 }
 
 impl Config {
@@ -11,9 +13,11 @@ impl Config {
     let _ = dotenvy::dotenv();
     let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
     let config = Config {
       redis_url,
-      pubsub_url
+      pubsub_url,
+      pubsub_backend
     };
 
     Ok(config)
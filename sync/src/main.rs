@@ -3,7 +3,7 @@ mod config;
 use std::time::Duration;
 
 use config::Config;
-use connections::{PubRedis, Redis, queue_keys};
+use connections::{PubRedis, Publisher, Redis, queue_keys};
 use tokio::{signal, time};
 
 #[tokio::main]
@@ -23,7 +23,7 @@ async fn main() {
 
 async fn start(config: Config) -> anyhow::Result<()> {
   let mut redis = Redis::new(&config.redis_url).await?;
-  let mut pubredis = PubRedis::new(&config.pubsub_url).await?;
+  let mut pubredis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
 
   loop {
     tokio::select! {
@@ -40,7 +40,7 @@ async fn start(config: Config) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn broadcast(redis: &mut Redis, pub_redis: &mut PubRedis) -> anyhow::Result<()> {
+async fn broadcast<P: Publisher>(redis: &mut Redis, pub_redis: &mut P) -> anyhow::Result<()> {
   let accounts = redis.get_all_accounts().await?;
   if accounts.is_empty() {
     println!("* no accounts synced");
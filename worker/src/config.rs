@@ -1,12 +1,29 @@
 use anyhow::Context;
+use connections::{QueueBackend, QueueOrder};
+use solana_pubkey::{Pubkey, pubkey};
+
+/// The main marginfi group on mainnet
+const MAIN_GROUP: Pubkey = pubkey!("4qp6Fx6tnZkY5Wropq9wUYgtFxXKwE6viZxFHg3rdAG8");
+const JUPITER_URL: &str = "https://api.jup.ag/swap/v2";
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
   pub(crate) http_url: String,
   pub(crate) ws_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
+  pub(crate) liquidation_order: QueueOrder,
   pub(crate) capacity: usize,
-  pub(crate) asset_haircut: f64
+  pub(crate) liquidation_max_age_slots: u64,
+  pub(crate) asset_haircut: f64,
+  pub(crate) marginfi_group: Pubkey,
+  /// Keypair file of the authority that signs and pays for classic liquidations
+  pub(crate) liquidator_keypair: Option<String>,
+  /// Keypair file of the liquidator's marginfi account, it's initialized when missing
+  pub(crate) liquidator_account_keypair: Option<String>,
+  /// Swap api the collateral of classic liquidations is swapped through
+  pub(crate) jupiter_url: String,
+  pub(crate) swap_slippage_bps: u16,
 }
 
 impl Config {
@@ -15,14 +32,39 @@ impl Config {
     let http_url = std::env::var("HTTP_URL").context("\"HTTP_URL\" is required")?;
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
+    let liquidation_order = std::env::var("LIQUIDATION_ORDER")
+      .ok()
+      .filter(|s| !s.is_empty())
+      .map(|s| s.parse::<QueueOrder>())
+      .transpose()
+      .context("invalid \"LIQUIDATION_ORDER\" value")?
+      .unwrap_or_default();
     let capacity = env_usize("CAPACITY", 1).context("invalid \"CAPACITY\" value")?;
+    let liquidation_max_age_slots = env_usize("LIQUIDATION_MAX_AGE_SLOTS", 8).context("invalid \"LIQUIDATION_MAX_AGE_SLOTS\" value")? as u64;
     let asset_haircut = std::env::var("ASSET_HAIRCUT").ok().filter(|s| !s.is_empty()).and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.95);
+    let marginfi_group = std::env::var("MARGINFI_GROUP").ok().filter(|s| !s.is_empty()).map(|v| v.parse::<Pubkey>()).transpose().context("invalid \"MARGINFI_GROUP\" value")?.unwrap_or(MAIN_GROUP);
+    let liquidator_keypair = std::env::var("LIQUIDATOR_KEYPAIR").ok().filter(|s| !s.is_empty());
+    let liquidator_account_keypair = std::env::var("LIQUIDATOR_ACCOUNT_KEYPAIR").ok().filter(|s| !s.is_empty());
+    let jupiter_url = std::env::var("JUPITER_URL").ok().filter(|s| !s.is_empty()).unwrap_or(JUPITER_URL.to_string());
+    let swap_slippage_bps = env_usize("SWAP_SLIPPAGE_BPS", 50).context("invalid \"SWAP_SLIPPAGE_BPS\" value")? as u16;
+    if liquidator_keypair.is_some() != liquidator_account_keypair.is_some() {
+      anyhow::bail!("\"LIQUIDATOR_KEYPAIR\" and \"LIQUIDATOR_ACCOUNT_KEYPAIR\" are required together");
+    }
     let config = Config {
       http_url,
       ws_url,
       pubsub_url,
+      pubsub_backend,
+      liquidation_order,
       capacity,
+      liquidation_max_age_slots,
       asset_haircut,
+      marginfi_group,
+      liquidator_keypair,
+      liquidator_account_keypair,
+      jupiter_url,
+      swap_slippage_bps,
     };
 
     Ok(config)
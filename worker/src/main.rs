@@ -1,20 +1,38 @@
 mod config;
 
-use std::{collections::{HashMap, HashSet}, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use config::Config;
-use connections::{SubRedis, queue_keys};
+use connections::{DEFAULT_BLOCK_TIMEOUT, PubRedis, QueueOrder, SubRedis, Subscriber, queue_keys};
 use fixed::types::I80F48;
+use futures_util::StreamExt;
 use jupiter_swap_api_client::build::BuildInstructionsResponse;
-use protocols::marginfi::{BalanceSide, BankAccount, FeeState, Marginfi, MarginfiUser};
+use protocols::marginfi::{BalanceSide, Bank, BankAccount, BankLiquidity, ClassicLiquidation, FeeState, Marginfi, MarginfiUser, instructions::make_initialize_account_ix, load_bank_liquidity};
 use solana_account::Account;
-use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcSimulateTransactionConfig};
+use solana_client::{nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient}, rpc_config::RpcSimulateTransactionConfig};
 use solana_compute_budget_interface::ComputeBudgetInstruction;
 use solana_instruction::Instruction;
 use solana_pubkey::Pubkey;
-use solana_sdk::{message::{AddressLookupTableAccount, VersionedMessage, v0}, signature::Keypair, signer::Signer, transaction::VersionedTransaction};
+use solana_sdk::{message::{AddressLookupTableAccount, VersionedMessage, v0}, signature::{Keypair, Signature, read_keypair_file}, signer::Signer, transaction::VersionedTransaction};
 use spl_associated_token_account::get_associated_token_address_with_program_id;
-use tokio::{signal, sync::Semaphore};
+use tokio::{signal, sync::{Mutex, OwnedSemaphorePermit, Semaphore, mpsc, watch}};
+
+const FEE_STATE_REFRESH_SECS: u64 = 30;
+/// Accounts whose liquidation failed, lost races included, are checked again after this long in
+/// case they're still underwater.
+const FAILED_LIQUIDATION_RECHECK: Duration = Duration::from_secs(5);
+const CLASSIC_LIQUIDATION_CU_LIMIT: u32 = 400_000;
+const CLASSIC_UNWIND_CU_LIMIT: u32 = 1_000_000;
+const SLOT_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+type Candidates = anyhow::Result<Vec<anyhow::Result<(Pubkey, MarginfiUser)>>>;
+
+/// Marginfi account classic liquidations are filled into. Every fill moves its health, so fills
+/// are planned and sent one at a time under the lock and the account is reloaded after each one.
+struct Liquidator {
+  authority: Keypair,
+  user: Mutex<MarginfiUser>,
+}
 
 #[tokio::main]
 async fn main() {
@@ -33,68 +51,245 @@ async fn main() {
 
 async fn start(config: Config) -> anyhow::Result<()> {
   let marginfi = Arc::new(Marginfi::new(config.http_url.clone(), config.ws_url.clone()).await?);
-  let fee_state = Arc::new(marginfi.get_fee_state().await?);
+  let fee_state = marginfi.get_fee_state().await?;
   let liquidation_max_fee: I80F48 = fee_state.liquidation_max_fee.into();
   let liquidation_flat_sol_fee: I80F48 = fee_state.liquidation_flat_sol_fee.into();
 
 	println!("FeeState is currently defined as liquidation_max_fee = {}% liquidation_flat_sol_fee = {} SOL", liquidation_max_fee.checked_mul(I80F48::from_num(100)).unwrap_or(I80F48::ZERO), liquidation_flat_sol_fee.checked_div(I80F48::from_num(1_000_000_000)).unwrap_or(I80F48::ZERO));
 
-  let mut subredis = SubRedis::new(&config.pubsub_url).await?;
+  let liquidator = match (&config.liquidator_keypair, &config.liquidator_account_keypair) {
+    (Some(authority), Some(account)) => {
+      let authority = read_keypair_file(authority).map_err(|err| anyhow::anyhow!("failed to read \"LIQUIDATOR_KEYPAIR\": {}", err))?;
+      let account = read_keypair_file(account).map_err(|err| anyhow::anyhow!("failed to read \"LIQUIDATOR_ACCOUNT_KEYPAIR\": {}", err))?;
+      let user = load_liquidator(&marginfi, config.marginfi_group, &authority, &account).await?;
+      println!("classic liquidation enabled through {} (maintenance {}$)", user.pubkey(), user.maintenance()?);
+
+      Some(Arc::new(Liquidator { authority, user: Mutex::new(user) }))
+    },
+    _ => None,
+  };
+
+  let fee_state = Arc::new(RwLock::new(fee_state));
+  let marginfi_clone = Arc::clone(&marginfi);
+  let fee_state_clone = Arc::clone(&fee_state);
+  tokio::spawn(async move {
+    refresh_fee_state(&marginfi_clone, &fee_state_clone).await;
+  });
+
+  let consumer = hostname::get()?.to_string_lossy().into_owned();
+  let subredis = SubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend).with_consumer(consumer);
+  subredis.spawn_reaper(queue_keys::LIQUIDATION_QUEUE);
+  let pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
   println!("connection established, listening");
 
   let semaphore = Arc::new(Semaphore::new(config.capacity));
+  let slot = spawn_slot_tracker(config.ws_url.clone());
+  let (shutdown, shutdown_rx) = watch::channel(false);
+  let mut candidates = spawn_reader(subredis.clone(), Arc::clone(&marginfi), config.clone(), Arc::clone(&semaphore), slot, shutdown_rx);
 
   loop {
-    tokio::select! {
-      result = subredis.builder::<MarginfiUser>(queue_keys::LIQUIDATION_QUEUE, 1).recv() => {
-        let mut accounts = match result {
-          Ok(messages) => messages,
-          Err(err) => {
-            println!("error while reading: {}", err);
-            continue
-          },
-        };
-        
-        let result = match accounts.pop() {
-          Some(account) => account,
-          None => continue,
-        };
-        let (pubkey, account) = match result {
-          Ok((pubkey, account)) => (pubkey, account),
-          Err(err) => {
-            println!("error parsing arguments: {}", err);
-            continue
-          },
-        };
-        
-        let permit = semaphore.clone();
-        let config_clone = config.clone();
-        let marginfi_clone = Arc::clone(&marginfi);
-        let fee_state_clone = Arc::clone(&fee_state);
-        tokio::spawn(async move {
-          let _guard = permit.acquire().await.unwrap();
-
-          if let Err(err) = handle(config_clone, &marginfi_clone, &fee_state_clone, pubkey, account).await {
-            println!("error liquidating accounts: {}", err);
-          };
-        });
+    let (permit, result) = tokio::select! {
+      received = candidates.recv() => match received {
+        Some(received) => received,
+        // the reader stopped after shutting down, everything it read was handed over
+        None => break,
+      },
+      _ = signal::ctrl_c(), if !*shutdown.borrow() => {
+        println!("shutting down, finishing what was already read");
+        let _ = shutdown.send(true);
+        continue
       }
-      _ = signal::ctrl_c() => {
-        println!("shutting down");
+    };
+
+    let mut accounts = match result {
+      Ok(messages) => messages,
+      Err(err) => {
+        println!("error while reading: {}", err);
+        continue
+      },
+    };
+    
+    let result = match accounts.pop() {
+      Some(account) => account,
+      None => continue,
+    };
+    let (pubkey, account) = match result {
+      Ok((pubkey, account)) => (pubkey, account),
+      Err(err) => {
+        println!("dead-lettered a malformed message: {}", err);
+        continue
+      },
+    };
+    
+    let config_clone = config.clone();
+    let marginfi_clone = Arc::clone(&marginfi);
+    let fee_state_snapshot = *fee_state.read().unwrap();
+    let liquidator_clone = liquidator.clone();
+    let mut subredis_clone = subredis.clone();
+    let mut pub_redis_clone = pub_redis.clone();
+    tokio::spawn(async move {
+      let _guard = permit;
+      // priority reads are final, only fifo entries are leased
+      let leased = config_clone.liquidation_order == QueueOrder::Fifo;
+
+      match handle(config_clone, &marginfi_clone, &fee_state_snapshot, liquidator_clone.as_deref(), pubkey, account).await {
+        Ok(()) => if leased {
+          if let Err(err) = subredis_clone.ack(queue_keys::LIQUIDATION_QUEUE, &[pubkey]).await {
+            println!("failed to ack {}: {}", pubkey, err);
+          }
+        },
+        Err(err) => {
+          println!("error liquidating accounts: {}", err);
+          if leased {
+            match subredis_clone.fail(queue_keys::LIQUIDATION_QUEUE, &[pubkey], &format!("{:#}", err)).await {
+              Ok(0) => {},
+              Ok(_) => println!("dead-lettered {}", pubkey),
+              Err(err) => println!("failed to report {}: {}", pubkey, err),
+            }
+          }
+          if let Err(err) = pub_redis_clone.schedule(queue_keys::CHECK_QUEUE, &[(pubkey, ())], FAILED_LIQUIDATION_RECHECK, "liquidation failed").await {
+            println!("failed to schedule a recheck of {}: {}", pubkey, err);
+          }
+        },
+      };
+    });
+  }
+
+  // every permit back means every candidate in flight was handled
+  let _ = semaphore.acquire_many(config.capacity as u32).await;
+
+  Ok(())
+}
+
+/// Reads candidates from its own task, so shutting down can't cancel a read halfway through and
+/// lose what it took, priority reads being final. Stops between reads once `shutdown` flips.
+fn spawn_reader(
+  mut subredis: SubRedis,
+  marginfi: Arc<Marginfi>,
+  config: Config,
+  semaphore: Arc<Semaphore>,
+  slot: Arc<AtomicU64>,
+  shutdown: watch::Receiver<bool>,
+) -> mpsc::Receiver<(OwnedSemaphorePermit, Candidates)> {
+  let (tx, rx) = mpsc::channel(1);
+  tokio::spawn(async move {
+    while !*shutdown.borrow() {
+      // only read once there's room to work, so what waits stays ordered by the queue
+      let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
+      if *shutdown.borrow() {
+        break;
+      }
+
+      let result = read_candidates(&mut subredis, &marginfi, &config, &slot).await;
+      if tx.send((permit, result)).await.is_err() {
         break;
       }
     }
-  }
+  });
 
-  Ok(())
+  rx
 }
 
-async fn handle(config: Config, marginfi: &Marginfi, fee_state: &FeeState, pubkey: Pubkey, account: MarginfiUser) -> anyhow::Result<()> {
+/// Latest slot seen by a slot subscription, 0 until the first notification. The subscription is
+/// renewed whenever it drops.
+fn spawn_slot_tracker(ws_url: String) -> Arc<AtomicU64> {
+  let slot = Arc::new(AtomicU64::new(0));
+  let tracked = Arc::clone(&slot);
+  tokio::spawn(async move {
+    loop {
+      match PubsubClient::new(&ws_url).await {
+        Ok(client) => match client.slot_subscribe().await {
+          Ok((mut stream, _unsubscribe)) => {
+            while let Some(info) = stream.next().await {
+              tracked.fetch_max(info.slot, Ordering::Relaxed);
+            }
+            println!("slot subscription dropped, resubscribing");
+          },
+          Err(err) => println!("failed to subscribe to slots: {}", err),
+        },
+        Err(err) => println!("failed to connect to {}: {}", ws_url, err),
+      }
+
+      tokio::time::sleep(SLOT_RESUBSCRIBE_DELAY).await;
+    }
+  });
+
+  slot
+}
+
+/// Next candidate off the liquidation queue. Priority queues hand out the most valuable one and
+/// drop the ones observed more than `liquidation_max_age_slots` ago.
+async fn read_candidates(subredis: &mut SubRedis, marginfi: &Marginfi, config: &Config, slot: &AtomicU64) -> Candidates {
+  match config.liquidation_order {
+    QueueOrder::Fifo => subredis.builder::<MarginfiUser>(queue_keys::LIQUIDATION_QUEUE, 1).block(DEFAULT_BLOCK_TIMEOUT).recv().await,
+    QueueOrder::Priority => {
+      // the rpc is only asked until the slot subscription delivered its first slot
+      let slot = match slot.load(Ordering::Relaxed) {
+        0 => marginfi.rpc_ref().get_slot().await?,
+        slot => slot,
+      };
+      let min_slot = slot.saturating_sub(config.liquidation_max_age_slots);
+      let batch = subredis.read_ranked::<MarginfiUser>(queue_keys::LIQUIDATION_QUEUE, 1, min_slot, Some(DEFAULT_BLOCK_TIMEOUT)).await?;
+      if batch.stale > 0 {
+        println!("dropped {} candidates older than slot {}", batch.stale, min_slot);
+      }
+
+      Ok(batch.items)
+    },
+  }
+}
+
+async fn handle(config: Config, marginfi: &Marginfi, fee_state: &FeeState, liquidator: Option<&Liquidator>, pubkey: Pubkey, account: MarginfiUser) -> anyhow::Result<()> {
   println!("RECEIVED {}", pubkey);
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+	if fee_state.is_paused(now) {
+		println!("{} skipped, protocol is paused until {}", pubkey, fee_state.pause_expires_at().unwrap_or_default());
+		return anyhow::Ok(());
+	}
+
+	if !account.can_repay_any_liability() {
+		println!("{} skipped, no liability can be repaid right now", pubkey);
+		return anyhow::Ok(());
+	}
+
   let withdrawable_assets = account.withdrawable_asset_value()?;
 	let liability = account.liability_value()?;
 	let seizable = withdrawable_assets.checked_sub(liability).ok_or(anyhow::anyhow!("Math error at {}", line!()))?;
 
+	let asset_banks: Vec<(Pubkey, Bank)> = account.bank_accounts()
+		.iter()
+		.filter(|b| !b.balance.is_empty(BalanceSide::Assets))
+		.map(|b| (b.balance.bank_pk, b.bank))
+		.collect();
+	let liquidity = load_bank_liquidity(marginfi.rpc_ref(), &asset_banks).await?;
+
+	// receivership has to close out every liability, while it can't the classic path is the only one
+	let receivership_profit = match account.can_repay_all_liabilities() {
+		true => Some(account.receivership_profit(fee_state.liquidation_max_fee.into())?),
+		false => None,
+	};
+
+	if let Some(liquidator) = liquidator {
+		let mut liquidator_user = liquidator.user.lock().await;
+
+		let classic = plan_classic_liquidation(&account, &liquidator_user, &liquidity)?
+			.filter(|classic| receivership_profit.is_none_or(|receivership_profit| classic.profit > receivership_profit));
+		if let Some(classic) = classic {
+			println!(
+				"{} classic liquidation pays more: {}$ vs {} for receivership (seize {}$ of {}, repay {}$ of {})",
+				pubkey, classic.profit, receivership_profit.map_or("nothing".to_string(), |profit| format!("{}$", profit)), classic.asset_value, classic.asset_bank, classic.liab_value, classic.liab_bank
+			);
+			let signature = fill_classic_liquidation(&config, marginfi, &liquidator.authority, &mut liquidator_user, &account, &classic).await?;
+			println!("{} liquidated through {} in {}", pubkey, liquidator_user.pubkey(), signature);
+			return anyhow::Ok(());
+		}
+	}
+
+	if receivership_profit.is_none() {
+		println!("{} has liabilities in paused banks, receivership can't close it out", pubkey);
+		return anyhow::Ok(());
+	}
+
 	// TODO: the liquidation will pass as long as health improves which means theres no need to repay the whole loan
 	// TODO: profitability checks
 	// TODO:
@@ -109,7 +304,7 @@ async fn handle(config: Config, marginfi: &Marginfi, fee_state: &FeeState, pubke
   
   println!("{}$ to make, max {}$ (w: {}, l: {})", seizable, liability.checked_mul(fee_state.liquidation_max_fee.into()).unwrap_or(I80F48::ZERO), withdrawable_assets, liability);
 
-	let swaps = calculate_swap_pairs(&account)?;
+	let swaps = calculate_swap_pairs(&account, &liquidity)?;
 	let max_assets = liability
 		+ liability
 			.checked_mul(fee_state.liquidation_max_fee.into())
@@ -121,7 +316,8 @@ async fn handle(config: Config, marginfi: &Marginfi, fee_state: &FeeState, pubke
 		.checked_mul(haircut)
 		.ok_or(anyhow::anyhow!("Math error at {}", line!()))?;
 
-	let assets_to_withdraw = select_assets_to_withdraw(&account, &swaps, assets_needed)?;
+	let assets_to_withdraw = select_assets_to_withdraw(&account, &liquidity, &swaps, assets_needed)?;
+	println!("{} needs receivership over {} assets, which isn't sent yet", pubkey, assets_to_withdraw.len());
 
 	// let mint_pubkeys: Vec<Pubkey> = assets_to_withdraw.iter()
 	// 	.map(|a| a.mint)
@@ -145,20 +341,220 @@ async fn handle(config: Config, marginfi: &Marginfi, fee_state: &FeeState, pubke
   Ok(())
 }
 
-fn build_available_assets_map(user: &MarginfiUser) -> HashMap<Pubkey, AssetNode> {
+/// Classic liquidation moves the seized collateral and the matching debt into the liquidator's
+/// own marginfi account, so it is only an option while that account passes the initial health
+/// check the program runs after the fill, and while the asset bank can pay the collateral back
+/// out for the unwind.
+fn plan_classic_liquidation(account: &MarginfiUser, liquidator: &MarginfiUser, liquidity: &HashMap<Pubkey, BankLiquidity>) -> anyhow::Result<Option<ClassicLiquidation>> {
+	let liquidation = match account.best_classic_liquidation()? {
+		Some(liquidation) => liquidation,
+		None => return anyhow::Ok(None),
+	};
+
+	let health_after = liquidator.init_health_after_classic_liquidation(account, &liquidation)?;
+	if health_after <= 0 {
+		println!("liquidator {} cannot absorb {}$ of debt from {}", liquidator.pubkey(), liquidation.liab_value, account.pubkey());
+		return anyhow::Ok(None);
+	}
+
+	let withdrawable = liquidity.get(&liquidation.asset_bank).is_some_and(|liquidity| liquidity.can_withdraw(liquidation.asset_amount));
+	if !withdrawable {
+		println!("{} has no liquidity to unwind {} seized from {}", liquidation.asset_bank, liquidation.asset_amount, account.pubkey());
+		return anyhow::Ok(None);
+	}
+
+	anyhow::Ok(Some(liquidation))
+}
+
+/// Sends `liquidation`, reloads the liquidator's account once it landed and unwinds the fill.
+/// A failed unwind leaves the position with the liquidator, the liquidation itself still stands.
+async fn fill_classic_liquidation(
+	config: &Config,
+	marginfi: &Marginfi,
+	authority: &Keypair,
+	liquidator: &mut MarginfiUser,
+	account: &MarginfiUser,
+	liquidation: &ClassicLiquidation
+) -> anyhow::Result<Signature> {
+	let liab_mint = find_bank_account(account, &liquidation.liab_bank)?.bank.mint;
+	let token_program = marginfi.rpc_ref().get_account(&liab_mint).await?.owner;
+
+	let instructions = [
+		ComputeBudgetInstruction::set_compute_unit_limit(CLASSIC_LIQUIDATION_CU_LIMIT),
+		account.liquidate_ix(liquidator, authority.pubkey(), liquidation, token_program)?,
+	];
+	let signature = send_instructions(marginfi.rpc_ref(), &instructions, &[], &[authority]).await?;
+
+	let liquidator_pubkey = *liquidator.pubkey();
+	*liquidator = MarginfiUser::from_pubkey(marginfi.rpc_ref(), &liquidator_pubkey).await?;
+
+	match unwind_classic_liquidation(config, marginfi, authority, liquidator, liquidation).await {
+		Ok(unwind) => {
+			println!("unwound {} in {}", signature, unwind);
+			*liquidator = MarginfiUser::from_pubkey(marginfi.rpc_ref(), &liquidator_pubkey).await?;
+		},
+		Err(err) => println!("failed to unwind {}, the position stays with {}: {:#}", signature, liquidator_pubkey, err),
+	}
+
+	anyhow::Ok(signature)
+}
+
+/// Withdraws the seized collateral, swaps it into the debt's mint and repays the debt taken over,
+/// all inside one flashloan.
+async fn unwind_classic_liquidation(
+	config: &Config,
+	marginfi: &Marginfi,
+	authority: &Keypair,
+	liquidator: &MarginfiUser,
+	liquidation: &ClassicLiquidation
+) -> anyhow::Result<Signature> {
+	let asset = find_bank_account(liquidator, &liquidation.asset_bank)?;
+	let liab = find_bank_account(liquidator, &liquidation.liab_bank)?;
+	let mints = marginfi.rpc_ref().get_multiple_accounts(&[asset.bank.mint, liab.bank.mint]).await?;
+	let (asset_token_program, liab_token_program) = match mints.as_slice() {
+		[Some(asset_mint), Some(liab_mint)] => (asset_mint.owner, liab_mint.owner),
+		_ => anyhow::bail!("mints of {} and {} not found", liquidation.asset_bank, liquidation.liab_bank),
+	};
+	let asset_token_account = get_associated_token_address_with_program_id(&authority.pubkey(), &asset.bank.mint, &asset_token_program);
+	let liab_token_account = get_associated_token_address_with_program_id(&authority.pubkey(), &liab.bank.mint, &liab_token_program);
+
+	let swap = match asset.bank.mint == liab.bank.mint {
+		true => None,
+		false => Some(build_swap(config, authority.pubkey(), asset.bank.mint, liab.bank.mint, liquidation.asset_amount.to_num()).await?),
+	};
+	let swaps: Vec<BuildInstructionsResponse> = swap.into_iter().collect();
+
+	let mut instructions = vec![ComputeBudgetInstruction::set_compute_unit_limit(CLASSIC_UNWIND_CU_LIMIT)];
+	instructions.extend(swap_cu_price_ix(&swaps));
+	instructions.push(
+		spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+			&authority.pubkey(),
+			&authority.pubkey(),
+			&asset.bank.mint,
+			&asset_token_program,
+		)
+	);
+
+	// the withdraw runs while the liquidator still holds the debt it took over, so the health
+	// check is deferred to the end of a flashloan, once the debt is repaid
+	let mut unwind = vec![liquidator.withdraw_ix(authority.pubkey(), asset, asset_token_account, asset_token_program, liquidation.asset_amount, Some(false))?];
+	for swap in &swaps {
+		unwind.extend(swap.setup_instructions.clone());
+		unwind.push(swap.swap_instruction.clone());
+		unwind.extend(swap.cleanup_instruction.clone());
+	}
+	unwind.push(liquidator.repay_ix(authority.pubkey(), liab, liab_token_account, liab_token_program, liquidation.liab_amount, Some(false)));
+
+	let end_index = (instructions.len() + 1 + unwind.len()) as u64;
+	instructions.push(liquidator.start_flashloan_ix(authority.pubkey(), end_index));
+	instructions.extend(unwind);
+	instructions.push(liquidator.end_flashloan_ix(authority.pubkey(), &[])?);
+
+	send_instructions(marginfi.rpc_ref(), &instructions, &swap_lookup_tables(&swaps), &[authority]).await
+}
+
+/// Route for swapping `amount` of `input_mint` held by `taker` into `output_mint`.
+async fn build_swap(config: &Config, taker: Pubkey, input_mint: Pubkey, output_mint: Pubkey, amount: u64) -> anyhow::Result<BuildInstructionsResponse> {
+	let response = reqwest::Client::new()
+		.get(format!("{}/build", config.jupiter_url))
+		.query(&[
+			("inputMint", input_mint.to_string()),
+			("outputMint", output_mint.to_string()),
+			("amount", amount.to_string()),
+			("taker", taker.to_string()),
+			("slippageBps", config.swap_slippage_bps.to_string()),
+		])
+		.send()
+		.await?
+		.error_for_status()?;
+
+	anyhow::Ok(response.json().await?)
+}
+
+fn find_bank_account<'a>(user: &'a MarginfiUser, bank_pk: &Pubkey) -> anyhow::Result<&'a BankAccount> {
+	user.bank_accounts()
+		.iter()
+		.find(|b| &b.balance.bank_pk == bank_pk)
+		.ok_or(anyhow::anyhow!("bank {} missing from {}", bank_pk, user.pubkey()))
+}
+
+/// Loads the liquidator's marginfi account, initializing it in `group` first when it doesn't
+/// exist yet.
+async fn load_liquidator(marginfi: &Marginfi, group: Pubkey, authority: &Keypair, account: &Keypair) -> anyhow::Result<MarginfiUser> {
+	let rpc_client = marginfi.rpc_ref();
+	let existing = rpc_client.get_account_with_commitment(&account.pubkey(), rpc_client.commitment()).await?.value;
+
+	if existing.is_none() {
+		let ix = make_initialize_account_ix(group, account.pubkey(), authority.pubkey(), authority.pubkey());
+		let signature = send_instructions(rpc_client, &[ix], &[], &[authority, account]).await?;
+		println!("initialized liquidator account {} in {}", account.pubkey(), signature);
+	}
+
+	MarginfiUser::from_pubkey(rpc_client, &account.pubkey()).await
+}
+
+/// Signs with `signers`, the first one pays, and waits for the transaction to confirm.
+async fn send_instructions(rpc_client: &RpcClient, instructions: &[Instruction], lookup_tables: &[AddressLookupTableAccount], signers: &[&Keypair]) -> anyhow::Result<Signature> {
+	let payer = signers.first().ok_or(anyhow::anyhow!("no signer to pay for the transaction"))?;
+	let blockhash = rpc_client.get_latest_blockhash().await?;
+	let message = v0::Message::try_compile(&payer.pubkey(), instructions, lookup_tables, blockhash)?;
+	let tx = VersionedTransaction::try_new(VersionedMessage::V0(message), signers)?;
+
+	anyhow::Ok(rpc_client.send_and_confirm_transaction(&tx).await?)
+}
+
+/// Keeps the fee state current so a protocol pause (and its expiry) is picked up without a restart.
+async fn refresh_fee_state(marginfi: &Marginfi, fee_state: &RwLock<FeeState>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(FEE_STATE_REFRESH_SECS));
+  loop {
+    interval.tick().await;
+
+    match marginfi.get_fee_state().await {
+      Ok(latest) => {
+        let mut current = fee_state.write().unwrap();
+        if latest.panic_state.is_paused_flag() != current.panic_state.is_paused_flag() {
+          println!("protocol pause flag changed: paused = {}", latest.panic_state.is_paused_flag());
+        }
+        *current = latest;
+      },
+      Err(err) => println!("failed to refresh fee state: {}", err),
+    }
+  }
+}
+
+/// Withdrawable collateral per mint, capped by what each bank's vault can actually pay out.
+/// Banks without liquidity info are left out rather than assumed to be liquid.
+fn build_available_assets_map(user: &MarginfiUser, liquidity: &HashMap<Pubkey, BankLiquidity>) -> HashMap<Pubkey, AssetNode> {
 	let bank_accounts = user.bank_accounts();
 	let available: HashMap<Pubkey, AssetNode> = bank_accounts
 		.iter()
-		.filter(|b| !b.balance.is_empty(BalanceSide::Assets) || !user.is_bank_withdrawable(*b))
-		.filter_map(|b| 
+		.filter(|b| !b.balance.is_empty(BalanceSide::Assets) && user.is_bank_withdrawable(b))
+		.filter_map(|b| {
+			let shares: I80F48 = b.balance.asset_shares.into();
+			let usd_value = b.asset_value().ok()?;
+
+			let bank_liquidity = liquidity.get(&b.balance.bank_pk)?;
+			let withdrawable_shares = bank_liquidity.withdrawable()
+				.checked_div(b.bank.asset_share_value.into())?;
+			if withdrawable_shares <= 0 {
+				println!("{} has no liquidity to withdraw {} from", b.balance.bank_pk, user.pubkey());
+				return None;
+			}
+
+			let amount = shares.min(withdrawable_shares);
+			let usd_value = match amount < shares {
+				true => usd_value.checked_mul(amount)?.checked_div(shares)?,
+				false => usd_value,
+			};
+
 			Some(
 				(b.bank.mint.clone(), AssetNode {
 					bank: b.clone(),
-					amount: b.balance.asset_shares.into(),
-					usd_value: b.asset_value().ok()?
+					amount,
+					usd_value,
 				})
 			)
-		)
+		})
 		.collect();
 
 	available
@@ -174,10 +570,11 @@ pub struct AssetToWithdraw {
 
 pub fn select_assets_to_withdraw(
 	user: &MarginfiUser,
+	liquidity: &HashMap<Pubkey, BankLiquidity>,
 	swaps: &[SwapPair],
 	target_usd: I80F48,
 ) -> anyhow::Result<Vec<AssetToWithdraw>> {
-	let available = build_available_assets_map(&user);
+	let available = build_available_assets_map(user, liquidity);
 	let mut swap_totals: HashMap<Pubkey, (I80F48, I80F48)> = HashMap::new();
 	
 	for swap in swaps {
@@ -263,8 +660,8 @@ pub struct SwapPair {
 	pub from_amount_usd: I80F48
 }
 
-pub fn calculate_swap_pairs(user: &MarginfiUser) -> anyhow::Result<Vec<SwapPair>> {
-	let mut available = build_available_assets_map(&user);
+pub fn calculate_swap_pairs(user: &MarginfiUser, liquidity: &HashMap<Pubkey, BankLiquidity>) -> anyhow::Result<Vec<SwapPair>> {
+	let mut available = build_available_assets_map(user, liquidity);
 	let bank_accounts = user.bank_accounts();
 
 	let needed: HashMap<Pubkey, AssetNode> = bank_accounts
@@ -379,47 +776,17 @@ pub async fn build_liquidation_tx(
 ) -> anyhow::Result<()> {
 	let payer_pubkey = payer.pubkey();
 
-  let (cu_price_ix, _) = swap_responses
-    .iter()
-    .flat_map(|s| s.compute_budget_instructions.iter())
-    .filter(|ix| {
-			ix.program_id == solana_compute_budget_interface::ID
-				&& ix.data.first() == Some(&3u8)
-    })
-    .filter_map(|ix| {
-			if ix.data.len() >= 9 {
-				Some((ix, u64::from_le_bytes(ix.data[1..9].try_into().ok()?)))
-			} else {
-				None
-			}
-    })
-    .max_by(|(_, cu_price_a), (_, cu_price_b)| Ord::cmp(cu_price_a, cu_price_b))
-    .unzip();
-
-  let lookup_tables: Vec<AddressLookupTableAccount> = swap_responses
-		.iter()
-		.flat_map(|s| {
-			s.addresses_by_lookup_table_address
-				.clone()
-				.unwrap_or_default()
-				.into_iter()
-		})
-		.fold(HashMap::new(), |mut map, (key, addresses)| {
-			map.entry(key).or_insert(addresses);
-			map
-		})
-		.into_iter()
-		.map(|(key, addresses)| AddressLookupTableAccount { key, addresses })
-		.collect();
+  let cu_price_ix = swap_cu_price_ix(&swap_responses);
+  let lookup_tables = swap_lookup_tables(&swap_responses);
 
   let swap_instructions = build_liquidation_instructions(
 		user,
 		payer,
 		&swap_responses,
-		cu_price_ix.map(|ix| ix.clone()),
+		cu_price_ix,
 		assets_to_withdraw,
 		fee_state.global_fee_wallet
-	);
+	)?;
 
   let blockhash = rpc_client.get_latest_blockhash().await?;
 
@@ -466,6 +833,47 @@ pub async fn build_liquidation_tx(
   Ok(())
 }
 
+/// Highest compute unit price any of the swaps asks for.
+fn swap_cu_price_ix(swap_responses: &[BuildInstructionsResponse]) -> Option<Instruction> {
+  let (cu_price_ix, _) = swap_responses
+    .iter()
+    .flat_map(|s| s.compute_budget_instructions.iter())
+    .filter(|ix| {
+			ix.program_id == solana_compute_budget_interface::ID
+				&& ix.data.first() == Some(&3u8)
+    })
+    .filter_map(|ix| {
+			if ix.data.len() >= 9 {
+				Some((ix, u64::from_le_bytes(ix.data[1..9].try_into().ok()?)))
+			} else {
+				None
+			}
+    })
+    .max_by(|(_, cu_price_a), (_, cu_price_b)| Ord::cmp(cu_price_a, cu_price_b))
+    .unzip();
+
+  cu_price_ix.cloned()
+}
+
+/// Lookup tables of every swap, each table once.
+fn swap_lookup_tables(swap_responses: &[BuildInstructionsResponse]) -> Vec<AddressLookupTableAccount> {
+  swap_responses
+		.iter()
+		.flat_map(|s| {
+			s.addresses_by_lookup_table_address
+				.clone()
+				.unwrap_or_default()
+				.into_iter()
+		})
+		.fold(HashMap::new(), |mut map, (key, addresses)| {
+			map.entry(key).or_insert(addresses);
+			map
+		})
+		.into_iter()
+		.map(|(key, addresses)| AddressLookupTableAccount { key, addresses })
+		.collect()
+}
+
 fn build_liquidation_instructions(
 	user: &MarginfiUser,
 	payer: &Keypair,
@@ -473,14 +881,14 @@ fn build_liquidation_instructions(
   cu_price_ix: Option<Instruction>,
 	assets_to_withdraw: Vec<(AssetToWithdraw, Account)>,
 	global_fee_wallet: Pubkey
-) -> Vec<Instruction> {
+) -> anyhow::Result<Vec<Instruction>> {
   let mut instructions = Vec::new();
 
   if let Some(ix) = cu_price_ix {
 		instructions.push(ix);
   }
 
-	instructions.push(user.start_liquidation_ix(payer.pubkey()));
+	instructions.push(user.start_liquidation_ix(payer.pubkey())?);
 
 	for (asset, mint_account) in assets_to_withdraw {
 		let token_program = mint_account.owner;
@@ -508,7 +916,7 @@ fn build_liquidation_instructions(
 				token_program,
 				asset.amount,
 				Some(false)
-			)
+			)?
 		);
 	}
 
@@ -551,9 +959,9 @@ fn build_liquidation_instructions(
 		}
   }
 
-	instructions.push(user.end_liquidation_ix(payer.pubkey(), global_fee_wallet));
+	instructions.push(user.end_liquidation_ix(payer.pubkey(), global_fee_wallet, &[])?);
 
-  instructions
+  Ok(instructions)
 }
 
 // async fn liquidate()
\ No newline at end of file
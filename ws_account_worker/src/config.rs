@@ -1,10 +1,12 @@
 use anyhow::Context;
+use connections::QueueBackend;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
   pub(crate) ws_url: String,
   pub(crate) redis_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
 }
 
 impl Config {
@@ -13,10 +15,12 @@ impl Config {
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
     let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
     let config = Config {
       ws_url,
       redis_url,
       pubsub_url,
+      pubsub_backend,
     };
 
     Ok(config)
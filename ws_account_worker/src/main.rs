@@ -1,11 +1,11 @@
 mod config;
 
-use std::{collections::HashMap, str::FromStr};
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
 use std::sync::Arc;
 use std::time::Duration;
-use connections::Redis;
+use connections::{IndexStore, PubRedis, Publisher, Redis, queue_keys};
 use futures_util::StreamExt;
-use protocols::{consts::MARGINFI_PROGRAM_ID, marginfi::{MARGINFI_ACCOUNT_SEED, MarginfiAccount, discriminators}, utils::parse_account};
+use protocols::{consts::MARGINFI_PROGRAM_ID, marginfi::{AccountParseError, MARGINFI_ACCOUNT_SEED, MarginfiAccount, MarginfiAccountType, discriminators}};
 use redis::aio::ConnectionManager;
 use solana_client::nonblocking::pubsub_client::PubsubClient;
 use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
@@ -37,6 +37,7 @@ async fn main() {
 
 async fn start(config: Config) -> anyhow::Result<()> {
   let mut redis = Redis::new(&config.redis_url).await?;
+  let mut pub_redis = PubRedis::new(&config.pubsub_url).await?.with_backend(config.pubsub_backend);
   let client = PubsubClient::new(&config.ws_url).await?;
   let config = RpcProgramAccountsConfig {
     filters: Some(vec![
@@ -60,7 +61,11 @@ async fn start(config: Config) -> anyhow::Result<()> {
   while let Some(response) = stream.next().await {
     let pk = response.value.pubkey.clone();
 
-    if let Err(err) = handle(&mut redis, response).await {
+    let result = match decode(response) {
+      Ok((pubkey, slot, data)) => handle(&mut redis, &mut pub_redis, &pubkey, slot, &data).await,
+      Err(err) => Err(err),
+    };
+    if let Err(err) = result {
       println!("error handling {}: {err}", pk);
     }
   }
@@ -68,28 +73,131 @@ async fn start(config: Config) -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn handle(redis: &mut Redis, response: Response<RpcKeyedAccount>) -> anyhow::Result<()> {
+/// Account, slot and raw data of a program update.
+fn decode(response: Response<RpcKeyedAccount>) -> anyhow::Result<(Pubkey, u64, Vec<u8>)> {
   let pk = Pubkey::from_str(&response.value.pubkey)?;
   let data = match response.value.account.data.decode() {
     Some(data) => data,
     None => anyhow::bail!("update with no data"),
   };
 
-  let account = parse_account::<MarginfiAccount>(&data).map_err(|err| anyhow::anyhow!(err))?;
-  
-  if !redis.exists_multiple([&pk]).await?[0] {
+  Ok((pk, response.context.slot, data))
+}
+
+async fn handle<I: IndexStore, P: Publisher>(index: &mut I, pub_redis: &mut P, pk: &Pubkey, slot: u64, data: &[u8]) -> anyhow::Result<()> {
+  // borrowed straight out of the update, copied only when the data isn't aligned for a borrow
+  let account = match MarginfiAccount::try_ref_from_account_data(data) {
+    Ok(account) => Cow::Borrowed(account),
+    Err(AccountParseError::Misaligned) => Cow::Owned(MarginfiAccount::try_from_account_data(data)?),
+    Err(err) => return Err(err.into()),
+  };
+
+  if !index.exists_multiple(&[*pk]).await?[0] {
     return Ok(());
   }
 
   let bank_accounts: Vec<_> = account
     .lending_account
-    .balances
-    .iter()
-    .map(|balance| &balance.bank_pk)
+    .get_active_balances_iter()
+    .map(|balance| balance.bank_pk)
     .collect();
 
-  redis.rem_multiple([&pk]).await?;
-  redis.add_multiple([&pk], vec![bank_accounts]).await?;
+  let (added_banks, removed_banks) = index.set_account_banks(pk, &bank_accounts).await?;
+  if !added_banks.is_empty() {
+    let _ = pub_redis.builder::<()>(queue_keys::BANK_ADD_QUEUE).items(added_banks.into_iter().map(|bank| (bank, ()))).send().await?;
+  }
+  if !removed_banks.is_empty() {
+    let _ = pub_redis.builder::<()>(queue_keys::BANK_REM_QUEUE).items(removed_banks.into_iter().map(|bank| (bank, ()))).send().await?;
+  }
+
+  // check_worker keeps its own copy of every indexed account
+  let _ = pub_redis.builder::<(u64, &MarginfiAccount)>(queue_keys::ACCOUNT_UPDATE_QUEUE).item(*pk, (slot, &*account)).send().await?;
 
   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeSet;
+
+  use connections::{MemoryIndex, MemoryQueues, Subscriber};
+  use protocols::test_utils::user_account;
+
+  use super::*;
+
+  fn account_data(flags: u64, banks: &[Pubkey]) -> Vec<u8> {
+    protocols::test_utils::account_data(&user_account(flags, banks))
+  }
+
+  async fn read_banks(queues: &MemoryQueues, queue: &str) -> Vec<Pubkey> {
+    let batch = queues.subscriber().read::<()>(queue, 10).await.unwrap();
+    batch.into_iter().map(|result| result.unwrap().0).collect()
+  }
+
+  #[tokio::test]
+  async fn ignores_accounts_that_arent_indexed() {
+    let [account, x] = [(); 2].map(|_| Pubkey::new_unique());
+    let mut index = MemoryIndex::new();
+    let queues = MemoryQueues::new();
+
+    handle(&mut index, &mut queues.publisher(), &account, 1, &account_data(0, &[x])).await.unwrap();
+
+    assert!(index.snapshot().banks.is_empty());
+    assert_eq!(queues.queued(queue_keys::BANK_ADD_QUEUE), 0);
+    assert_eq!(queues.queued(queue_keys::ACCOUNT_UPDATE_QUEUE), 0);
+  }
+
+  #[tokio::test]
+  async fn follows_the_banks_of_indexed_accounts() {
+    let [account, other, x, y, z] = [(); 5].map(|_| Pubkey::new_unique());
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(account, vec![x, y]), (other, vec![y])]).await.unwrap();
+    let queues = MemoryQueues::new();
+
+    handle(&mut index, &mut queues.publisher(), &account, 1, &account_data(0, &[y, z])).await.unwrap();
+
+    let snapshot = index.snapshot();
+    assert_eq!(snapshot.account_banks[&account], BTreeSet::from([y, z]));
+    assert_eq!(snapshot.banks, BTreeSet::from([y, z]));
+    assert!(snapshot.check(None).is_empty());
+    assert_eq!(read_banks(&queues, queue_keys::BANK_ADD_QUEUE).await, vec![z]);
+    assert_eq!(read_banks(&queues, queue_keys::BANK_REM_QUEUE).await, vec![x]);
+  }
+
+  #[tokio::test]
+  async fn keeps_the_newest_waiting_update_of_an_account() {
+    let [account, x] = [(); 2].map(|_| Pubkey::new_unique());
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(account, vec![x])]).await.unwrap();
+    let queues = MemoryQueues::new();
+    let mut publisher = queues.publisher();
+
+    handle(&mut index, &mut publisher, &account, 11, &account_data(1, &[x])).await.unwrap();
+    // an update observed earlier but delivered later
+    handle(&mut index, &mut publisher, &account, 10, &account_data(2, &[x])).await.unwrap();
+
+    let batch = queues.subscriber().read::<(u64, MarginfiAccount)>(queue_keys::ACCOUNT_UPDATE_QUEUE, 10).await.unwrap();
+    let updates: Vec<(Pubkey, u64, u64)> = batch
+      .into_iter()
+      .map(|result| result.map(|(pubkey, (slot, account))| (pubkey, slot, account.account_flags)).unwrap())
+      .collect();
+    assert_eq!(updates, vec![(account, 11, 1)]);
+    assert_eq!(queues.queued(queue_keys::BANK_ADD_QUEUE), 0);
+  }
+
+  #[tokio::test]
+  async fn copies_updates_that_are_misaligned() {
+    let [account, x] = [(); 2].map(|_| Pubkey::new_unique());
+    let mut index = MemoryIndex::new();
+    index.add_multiple(&[(account, vec![])]).await.unwrap();
+    let queues = MemoryQueues::new();
+
+    // one byte in front of an aligned allocation leaves the account off its alignment
+    let data = [&[0][..], &account_data(0, &[x])].concat();
+    assert_eq!(MarginfiAccount::try_ref_from_account_data(&data[1..]).unwrap_err(), AccountParseError::Misaligned);
+    handle(&mut index, &mut queues.publisher(), &account, 1, &data[1..]).await.unwrap();
+
+    assert_eq!(index.snapshot().account_banks[&account], BTreeSet::from([x]));
+    assert_eq!(queues.queued(queue_keys::ACCOUNT_UPDATE_QUEUE), 1);
+  }
 }
\ No newline at end of file
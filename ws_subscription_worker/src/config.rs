@@ -1,4 +1,5 @@
 use anyhow::Context;
+use connections::QueueBackend;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
@@ -6,6 +7,7 @@ pub struct Config {
   pub(crate) ws_url: String,
   pub(crate) redis_url: String,
   pub(crate) pubsub_url: String,
+  pub(crate) pubsub_backend: QueueBackend,
   pub(crate) heartbeat_url: String,
   pub(crate) accounts_batch_size: usize,
   pub(crate) accounts_accept_batch_size: usize,
@@ -18,6 +20,7 @@ impl Config {
     let ws_url = std::env::var("WS_URL").context("\"WS_URL\" is required")?;
     let redis_url = std::env::var("REDIS_CONNECTION").context("\"REDIS_CONNECTION\" is required")?;
     let pubsub_url = std::env::var("PUBSUB_CONNECTION").context("\"PUBSUB_CONNECTION\" is required")?;
+    let pubsub_backend = QueueBackend::from_env()?;
     let heartbeat_url = std::env::var("HEARTBEAT_REDIS").context("\"HEARTBEAT_REDIS\" is required")?;
     let accounts_batch_size = env_usize("ACCOUNTS_BATCH_SIZE", 1000).context("invalid \"ACCOUNTS_BATCH_SIZE\" value")?;
     let accounts_accept_batch_size = env_usize("ACCOUNTS_ACCEPT_BATCH_SIZE", 1000).context("invalid \"ACCOUNTS_ACCEPT_BATCH_SIZE\" value")?;
@@ -26,6 +29,7 @@ impl Config {
       ws_url,
       redis_url,
       pubsub_url,
+      pubsub_backend,
       heartbeat_url,
       accounts_batch_size,
       accounts_accept_batch_size,
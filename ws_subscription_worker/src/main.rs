@@ -3,17 +3,16 @@ mod config;
 use std::{collections::HashMap, sync::atomic::AtomicU64};
 use std::sync::Arc;
 use anyhow::bail;
-use connections::{PubRedis, Redis, SubRedis, queue_keys};
+use connections::{DEFAULT_BLOCK_TIMEOUT, PubRedis, Publisher, Redis, SubRedis, Subscriber, queue_keys};
 use futures_util::StreamExt;
-use protocols::marginfi::{Bank, load_price_update_v2_checked_data};
-use protocols::utils::parse_account;
+use protocols::marginfi::{Bank, MarginfiAccountType, load_price_update_v2_checked_data};
 use redis::aio::ConnectionManager;
 use solana_client::nonblocking::pubsub_client::PubsubClient;
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_client::rpc_config::RpcAccountInfoConfig;
 use solana_pubkey::Pubkey;
 use tokio::signal;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
 use tokio::task::JoinHandle;
 use redis::AsyncTypedCommands;
 use solana_commitment_config::CommitmentConfig;
@@ -21,6 +20,8 @@ use uuid::Uuid;
 
 use crate::config::Config;
 
+const READ_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
 type Subscriptions = Arc<Mutex<HashMap<String, JoinHandle<()>>>>;
 
 #[tokio::main]
@@ -62,8 +63,10 @@ impl Worker {
 
     let rpc_client = RpcClient::new(config.http_url.clone());
     let pubsub = Arc::new(PubsubClient::new(&config.ws_url).await?);
-    let pub_redis = PubRedis::new(&config.redis_url).await?;
-    let sub_redis = SubRedis::new(&config.redis_url).await?;
+    let pub_redis = PubRedis::new(&config.redis_url).await?.with_backend(config.pubsub_backend);
+    let sub_redis = SubRedis::new(&config.redis_url).await?.with_backend(config.pubsub_backend).with_consumer(id.clone());
+    sub_redis.spawn_reaper(queue_keys::BANK_ADD_QUEUE);
+    sub_redis.spawn_reaper(queue_keys::BANK_REM_QUEUE);
     let redis = Redis::new(&config.redis_url).await?;
     let client = redis::Client::open(config.heartbeat_url.clone())?;
     let con = ConnectionManager::new(client).await?;
@@ -106,7 +109,7 @@ impl Worker {
           None => continue,
         };
 
-        let bank = match parse_account::<Bank>(&account.data) {
+        let bank = match Bank::try_from_account_data(&account.data) {
           Ok(bank) => bank,
           Err(err) => {
             println!("failed to parse account data of {}: {}", pubkey, err);
@@ -157,69 +160,76 @@ impl Worker {
     subs.lock().await.insert(pubkey.to_string(), handle);
   }
 
-  async fn drop_subscription(&self, pubkey: Pubkey) {
+  async fn drop_subscription(&self, pubkey: Pubkey) -> bool {
     let mut map = self.subs.lock().await;
-    if let Some(handle) = map.remove(&pubkey.to_string()) {
-      handle.abort();
+    match map.remove(&pubkey.to_string()) {
+      Some(handle) => {
+        handle.abort();
+        true
+      },
+      None => false,
+    }
+  }
+
+  async fn release(&mut self, accounts: &[Pubkey]) -> anyhow::Result<()> {
+    for pubkey in accounts {
+      if self.drop_subscription(*pubkey).await {
+        let lease_key = format!("bank:lease:{}", pubkey);
+        let _: i64 = redis::cmd("DEL").arg(&lease_key).query_async(&mut self.heartbeat_redis).await?;
+      }
+    }
+
+    println!("stopped listening to {} banks", accounts.len());
+    Ok(())
+  }
+
+  async fn report(&mut self, queue: &str, bank_accounts: &[Pubkey], err: anyhow::Error) {
+    match self.sub_redis.fail(queue, bank_accounts, &format!("{:#}", err)).await {
+      Ok(0) => {},
+      Ok(dead) => eprintln!("dead-lettered {} banks", dead),
+      Err(err) => eprintln!("failed to report {} banks: {}", bank_accounts.len(), err),
     }
   }
 
   async fn listen_for_events(&mut self) -> anyhow::Result<()> {
-    let mut rem_sub_redis = self.sub_redis.clone();
+    let batch_size = self.config.accounts_batch_size;
+    let mut bank_adds = spawn_consumer(self.sub_redis.clone(), queue_keys::BANK_ADD_QUEUE, batch_size);
+    let mut bank_rems = spawn_consumer(self.sub_redis.clone(), queue_keys::BANK_REM_QUEUE, batch_size);
     loop {
       tokio::select! {
-        result = self.sub_redis.builder::<()>(queue_keys::BANK_ADD_QUEUE, self.config.accounts_batch_size).recv() => {
-          let results = match result {
-            Ok(messages) => messages,
-            Err(err) => {
-              println!("error while reading: {}", err);
-              continue
-            },
+        bank_accounts = bank_adds.recv() => {
+          let bank_accounts = match bank_accounts {
+            Some(bank_accounts) => bank_accounts,
+            None => break,
           };
-  
-          for result in &results {
-            if let Err(err) = result {
-              println!("failed to accept a message: {}", err);
-            }
-          }
-  
-          let bank_accounts: Vec<_> = results.into_iter().filter_map(|result| result.map(|(pk, _)| pk).ok()).collect();
-          
-          if bank_accounts.is_empty() {
-            continue;
-          }
-          
+
           let banks_amount = bank_accounts.len();
-          if let Err(err) = self.claim(bank_accounts).await {
-            eprintln!("failed to claim {} banks: {}", banks_amount, err);
-          }
-        }
-        result = rem_sub_redis.builder::<()>(queue_keys::BANK_REM_QUEUE, self.config.accounts_batch_size).recv() => {
-          let results = match result {
-            Ok(messages) => messages,
+          match self.claim(bank_accounts.clone()).await {
+            Ok(()) => if let Err(err) = self.sub_redis.ack(queue_keys::BANK_ADD_QUEUE, &bank_accounts).await {
+              eprintln!("failed to ack {} banks: {}", banks_amount, err);
+            },
             Err(err) => {
-              println!("error while reading: {}", err);
-              continue
+              eprintln!("failed to claim {} banks: {}", banks_amount, err);
+              self.report(queue_keys::BANK_ADD_QUEUE, &bank_accounts, err).await;
             },
-          };
-  
-          for result in &results {
-            if let Err(err) = result {
-              println!("failed to accept a message: {}", err);
-            }
-          }
-  
-          let bank_accounts: Vec<_> = results.into_iter().filter_map(|result| result.map(|(pk, _)| pk).ok()).collect();
-          
-          if bank_accounts.is_empty() {
-            continue;
           }
-          
+        }
+        bank_accounts = bank_rems.recv() => {
+          let bank_accounts = match bank_accounts {
+            Some(bank_accounts) => bank_accounts,
+            None => break,
+          };
+
           let banks_amount = bank_accounts.len();
-          for bank in bank_accounts {
-            self.drop_subscription(bank).await;
+          match self.release(&bank_accounts).await {
+            Ok(()) => if let Err(err) = self.sub_redis.ack(queue_keys::BANK_REM_QUEUE, &bank_accounts).await {
+              eprintln!("failed to ack {} banks: {}", banks_amount, err);
+            },
+            Err(err) => {
+              eprintln!("failed to release {} banks: {}", banks_amount, err);
+              self.report(queue_keys::BANK_REM_QUEUE, &bank_accounts, err).await;
+            },
           }
-          println!("stopped listening to {} banks", banks_amount);
         }
         _ = signal::ctrl_c() => {
           println!("shutting down");
@@ -232,6 +242,41 @@ impl Worker {
   }
 }
 
+/// Reads the bank accounts of `queue` from their own task, so a read of one queue is never cancelled
+/// halfway through by the other. A batch is only read once the previous one was taken off the channel.
+fn spawn_consumer(mut sub_redis: SubRedis, queue: &'static str, batch_size: usize) -> mpsc::Receiver<Vec<Pubkey>> {
+  let (tx, rx) = mpsc::channel(1);
+  tokio::spawn(async move {
+    loop {
+      let results = match sub_redis.builder::<()>(queue, batch_size).block(DEFAULT_BLOCK_TIMEOUT).recv().await {
+        Ok(results) => results,
+        Err(err) => {
+          println!("error while reading {}: {}", queue, err);
+          tokio::time::sleep(READ_RETRY_DELAY).await;
+          continue
+        },
+      };
+
+      for result in &results {
+        if let Err(err) = result {
+          println!("dead-lettered a malformed message: {}", err);
+        }
+      }
+
+      let bank_accounts: Vec<_> = results.into_iter().filter_map(|result| result.map(|(pk, _)| pk).ok()).collect();
+
+      if bank_accounts.is_empty() {
+        continue;
+      }
+
+      if tx.send(bank_accounts).await.is_err() {
+        break;
+      }
+    }
+  });
+  rx
+}
+
 async fn heartbeat_loop(
   mut redis: redis::aio::ConnectionManager,
   worker_id: String,
@@ -332,8 +377,14 @@ async fn subscribe_to_bank(client: Arc<PubsubClient>, mut pub_redis: PubRedis, m
   Ok(())
 }
 
-async fn trigger(pub_redis: &mut PubRedis, redis: &mut Redis, bank: &Pubkey) -> anyhow::Result<()> {
-  let accounts = redis.get_accounts_by_bank(bank).await?;
+/// check_worker reprices the accounts it booked on its own, only the ones it hasn't seen yet are
+/// sent its way to be loaded.
+async fn trigger<P: Publisher>(pub_redis: &mut P, redis: &mut Redis, bank: &Pubkey) -> anyhow::Result<()> {
+  let accounts = redis.get_unbooked_accounts_by_bank(bank).await?;
+  if accounts.is_empty() {
+    return anyhow::Ok(());
+  }
+
   println!("* triggering {} accounts", accounts.len());
   let _ = pub_redis.builder::<()>(queue_keys::CHECK_QUEUE).items(accounts.into_iter().map(|account| (account, ()))).send().await?;
 